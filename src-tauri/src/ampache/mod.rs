@@ -0,0 +1,101 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use reqwest::header::HeaderMap;
+use serde::de::DeserializeOwned;
+
+use crate::client::ServerClient;
+use crate::errors::ApiError;
+use crate::formatter::{encode_query_value, generate_sha256, server_address};
+use crate::models::Library;
+
+mod responses;
+mod source;
+pub use source::AmpacheSource;
+
+use responses::{AmpacheError, AmpacheHandshake};
+
+//Version of the API responses are asked for in
+const API_VERSION: &str = "6.0.0";
+
+fn create_ampache_url(library: &Library, action: &str) -> String {
+    format!(
+        "{}/server/json.server.php?action={}",
+        server_address(library),
+        action
+    )
+}
+
+/* handshake, which starts a session. The passphrase is sha256(timestamp + sha256(password)), so
+*  the keyring keeps sha256(password) in place of a password hash and sessions can be renewed
+*  without the password.
+*  https://ampache.org/api/api-json-methods#handshake */
+pub async fn handshake(
+    client: &ServerClient,
+    library: &Library,
+) -> Result<AmpacheHandshake, ApiError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| ApiError::Transport(e.to_string()))?
+        .as_secs();
+    let passphrase = generate_sha256(&format!("{}{}", timestamp, library.hashed_password));
+    let url = format!(
+        "{}&auth={}&timestamp={}&version={}&user={}",
+        create_ampache_url(library, "handshake"),
+        passphrase,
+        timestamp,
+        API_VERSION,
+        encode_query_value(&library.username)
+    );
+    get_ampache_response(client, &url).await
+}
+
+/* Any other action, with the auth token of a session. Query parameters are appended as given
+*  (e.g. "&offset=0&limit=500"). An expired session fails with Ampache error 4701. */
+pub async fn call_action<T: DeserializeOwned>(
+    client: &ServerClient,
+    library: &Library,
+    auth: &str,
+    action: &str,
+    query: &str,
+) -> Result<T, ApiError> {
+    let url = create_action_url(library, auth, action, query);
+    get_ampache_response(client, &url).await
+}
+
+/* The URL of a binary action (stream, get_art), which is fetched as it is */
+pub fn create_action_url(library: &Library, auth: &str, action: &str, query: &str) -> String {
+    format!(
+        "{}&auth={}{}",
+        create_ampache_url(library, action),
+        auth,
+        query
+    )
+}
+
+async fn get_ampache_response<T: DeserializeOwned>(
+    client: &ServerClient,
+    url: &str,
+) -> Result<T, ApiError> {
+    let mut body = client
+        .get_json::<serde_json::Value>(url, HeaderMap::new())
+        .await?;
+    if let Some(error) = body.get_mut("error") {
+        let error = serde_json::from_value::<AmpacheError>(error.take())
+            .map_err(|e| ApiError::Parse(e.to_string()))?;
+        return Err(to_api_error(error));
+    }
+    serde_json::from_value::<T>(body).map_err(|e| ApiError::Parse(e.to_string()))
+}
+
+/* Ampache error codes are kept as they are (see ApiError::kind for what each means)
+*  https://ampache.org/api/api-errors */
+fn to_api_error(error: AmpacheError) -> ApiError {
+    let code = match &error.error_code {
+        serde_json::Value::String(code) => code.parse().unwrap_or(0),
+        code => code.as_u64().unwrap_or(0) as u32,
+    };
+    ApiError::Ampache {
+        code,
+        message: error.error_message,
+    }
+}
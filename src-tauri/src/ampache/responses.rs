@@ -0,0 +1,109 @@
+use serde::Deserialize;
+
+/* Ampache JSON API types (only the fields Polyphonic uses)
+*  https://ampache.org/api/ */
+
+/* A new session, with the catalog's last update times (ISO 8601) */
+#[derive(Deserialize, Debug)]
+pub struct AmpacheHandshake {
+    pub auth: String,
+    pub session_expire: Option<String>,
+    pub update: Option<String>,
+    pub add: Option<String>,
+    pub clean: Option<String>,
+}
+
+/* Failed calls still answer 200, with this as "error" in place of the result */
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AmpacheError {
+    //A string in API 6, a number before that
+    pub error_code: serde_json::Value,
+    #[serde(default)]
+    pub error_message: String,
+}
+
+/* One page of a listing. Each action names its list after what it holds. */
+#[derive(Deserialize, Debug)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct AmpacheList<T> {
+    #[serde(
+        default,
+        alias = "artist",
+        alias = "album",
+        alias = "song",
+        alias = "playlist"
+    )]
+    pub items: Vec<T>,
+    pub total_count: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AmpacheNameId {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AmpacheArtist {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub flag: bool,
+    pub rating: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AmpacheAlbum {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub artist: Option<AmpacheNameId>,
+    //Seconds
+    pub time: Option<u32>,
+    pub year: Option<u32>,
+    #[serde(default)]
+    pub has_art: bool,
+    #[serde(default)]
+    pub flag: bool,
+    pub rating: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AmpacheSong {
+    pub id: String,
+    #[serde(default)]
+    pub title: String,
+    pub artist: Option<AmpacheNameId>,
+    pub album: Option<AmpacheNameId>,
+    pub track: Option<u32>,
+    pub disk: Option<u32>,
+    //Seconds
+    pub time: Option<u32>,
+    pub year: Option<u32>,
+    pub mime: Option<String>,
+    #[serde(default)]
+    pub has_art: bool,
+    #[serde(default)]
+    pub flag: bool,
+    pub rating: Option<u32>,
+    pub replaygain_track_gain: Option<f64>,
+    pub replaygain_album_gain: Option<f64>,
+    pub replaygain_track_peak: Option<f64>,
+    pub replaygain_album_peak: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AmpachePlaylist {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub owner: String,
+    //Number of songs
+    pub items: Option<u32>,
+    //Unix time, on servers that report it
+    pub last_update: Option<i64>,
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+
+use crate::{
+    client::ServerClient,
+    downloads::now_millis,
+    errors::ApiError,
+    formatter::{encode_query_value, namespace_id, strip_namespace},
+    models::{Album, Artist, Library, Playlist, Song, TranscodeSettings},
+    music::{cover_art_key, SyncProgressReporter},
+    source::{MusicSource, SourceAlbum},
+    subsonic::get_album_art,
+};
+
+use super::{
+    call_action, create_action_url, handshake,
+    responses::{
+        AmpacheAlbum, AmpacheArtist, AmpacheHandshake, AmpacheList, AmpacheNameId, AmpachePlaylist,
+        AmpacheSong,
+    },
+};
+
+const PAGE_SIZE: u32 = 500;
+
+//Sessions are renewed this long (ms) before they are due to expire, so a stream URL isn't handed
+//out with a token that is about to stop working
+const EXPIRY_MARGIN: i64 = 60_000;
+
+struct AmpacheSession {
+    auth: String,
+    //When the session expires (ms since the epoch), if the server said
+    expires: Option<i64>,
+}
+
+/* An Ampache server. A session is started on first use and renewed whenever it expires, including
+*  part way through a sync. */
+pub struct AmpacheSource {
+    library: Library,
+    client: ServerClient,
+    session: Mutex<Option<AmpacheSession>>,
+}
+
+impl AmpacheSource {
+    pub fn new(library: Library, client: ServerClient) -> Self {
+        AmpacheSource {
+            library,
+            client,
+            session: Mutex::new(None),
+        }
+    }
+
+    /* Handshake, keeping the new session */
+    async fn start_session(
+        &self,
+        session: &mut Option<AmpacheSession>,
+    ) -> Result<AmpacheHandshake, ApiError> {
+        let response = handshake(&self.client, &self.library).await?;
+        *session = Some(AmpacheSession {
+            auth: response.auth.clone(),
+            expires: response.session_expire.as_deref().and_then(parse_date),
+        });
+        Ok(response)
+    }
+
+    /* The auth token of the current session, starting a new one if there is none yet or it is
+     *  about to expire */
+    async fn auth(&self) -> Result<String, ApiError> {
+        let mut session = self.session.lock().await;
+        if let Some(current) = session.as_ref() {
+            let expiring = current
+                .expires
+                .is_some_and(|expires| expires - EXPIRY_MARGIN <= now_millis());
+            if !expiring {
+                return Ok(current.auth.clone());
+            }
+        }
+        Ok(self.start_session(&mut session).await?.auth)
+    }
+
+    /* A new session in place of one the server refused. Calls running alongside each other see
+     *  the same token expire, so only the first of them handshakes again. */
+    async fn renew(&self, expired_auth: &str) -> Result<String, ApiError> {
+        let mut session = self.session.lock().await;
+        if let Some(current) = session.as_ref() {
+            if current.auth != expired_auth {
+                return Ok(current.auth.clone());
+            }
+        }
+        Ok(self.start_session(&mut session).await?.auth)
+    }
+
+    /* An action, tried again with a new session if the current one has expired */
+    async fn call<T: DeserializeOwned>(&self, action: &str, query: &str) -> Result<T, ApiError> {
+        let auth = self.auth().await?;
+        match call_action(&self.client, &self.library, &auth, action, query).await {
+            //A refused handshake, or an auth token that expired
+            Err(ApiError::Ampache {
+                code: 4701 | 401, ..
+            }) => {
+                let auth = self.renew(&auth).await?;
+                call_action(&self.client, &self.library, &auth, action, query).await
+            }
+            result => result,
+        }
+    }
+
+    /* Every item of a listing, a page at a time */
+    async fn get_all<T: DeserializeOwned>(
+        &self,
+        action: &str,
+        query: &str,
+        mut progress: Option<&mut SyncProgressReporter<'_>>,
+    ) -> Result<Vec<T>, anyhow::Error> {
+        let mut items: Vec<T> = vec![];
+        loop {
+            let page_query = format!("{}&offset={}&limit={}", query, items.len(), PAGE_SIZE);
+            let page: AmpacheList<T> = match self.call(action, &page_query).await {
+                Ok(page) => page,
+                //Older servers report an empty listing as not found
+                Err(ApiError::Ampache {
+                    code: 4704 | 404, ..
+                }) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(progress) = progress.as_deref_mut() {
+                if items.is_empty() {
+                    let total = page.total_count.unwrap_or(0);
+                    progress.start(total.div_ceil(PAGE_SIZE).max(1) as usize);
+                }
+                progress.advance();
+            }
+            let page_size = page.items.len();
+            items.extend(page.items);
+            if page_size < PAGE_SIZE as usize {
+                break;
+            }
+        }
+        Ok(items)
+    }
+}
+
+#[async_trait]
+impl MusicSource for AmpacheSource {
+    fn library(&self) -> &Library {
+        &self.library
+    }
+
+    /* A handshake, which checks the credentials (ping answers whether or not they are valid) */
+    async fn ping(&self) -> Result<(), anyhow::Error> {
+        let mut session = self.session.lock().await;
+        self.start_session(&mut session).await?;
+        Ok(())
+    }
+
+    /* The handshake says when the catalogs last had songs added, updated or cleaned out */
+    async fn changed_since(&self, since: i64) -> Result<Option<bool>, anyhow::Error> {
+        let mut session = self.session.lock().await;
+        let response = self.start_session(&mut session).await?;
+        let last_change = [&response.update, &response.add, &response.clean]
+            .into_iter()
+            .filter_map(|date| date.as_deref().and_then(parse_date))
+            .max();
+        Ok(last_change.map(|last_change| last_change > since))
+    }
+
+    /* artists, album artists only */
+    async fn list_artists(
+        &self,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Artist>, anyhow::Error> {
+        let artists: Vec<AmpacheArtist> = self
+            .get_all("artists", "&album_artist=1", Some(progress))
+            .await?;
+        let library_id = &self.library.id;
+        Ok(artists
+            .iter()
+            .map(|artist| Artist {
+                id: namespace_id(library_id, &artist.id),
+                name: artist.name.clone(),
+                library_id: library_id.clone(),
+                starred: starred(artist.flag),
+                rating: artist.rating.unwrap_or(0),
+            })
+            .collect())
+    }
+
+    /* albums, whatever the artists */
+    async fn list_albums(
+        &self,
+        _artists: &[Artist],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<SourceAlbum>, anyhow::Error> {
+        let albums: Vec<AmpacheAlbum> = self.get_all("albums", "", Some(progress)).await?;
+        Ok(albums
+            .iter()
+            .map(|album| SourceAlbum {
+                album: self.to_album(album),
+                changed: None,
+            })
+            .collect())
+    }
+
+    /* songs, keeping those of the given albums */
+    async fn list_songs(
+        &self,
+        albums: &[Album],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Song>, anyhow::Error> {
+        let songs: Vec<AmpacheSong> = self.get_all("songs", "", Some(progress)).await?;
+        let album_ids: HashSet<&String> = albums.iter().map(|album| &album.id).collect();
+        Ok(songs
+            .iter()
+            .map(|song| self.to_song(song))
+            .filter(|song| album_ids.contains(&song.album_id))
+            .collect())
+    }
+
+    /* get_art for each album */
+    async fn fetch_art(
+        &self,
+        albums: &[Album],
+        cover_art_dir: &Path,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<HashMap<String, String>, anyhow::Error> {
+        //Create cover_art folder (one subfolder per library) if it doesn't exist
+        std::fs::create_dir_all(cover_art_dir.join(&self.library.id))?;
+
+        let auth = self.auth().await?;
+        let cover_art_dir = cover_art_dir.to_string_lossy().to_string();
+        let mut futures = vec![];
+        for album in albums.iter().filter(|album| !album.cover_art.is_empty()) {
+            let query = format!(
+                "&type=album&id={}",
+                encode_query_value(strip_namespace(&self.library.id, &album.id))
+            );
+            let url = create_action_url(&self.library, &auth, "get_art", &query);
+            futures.push(get_album_art(
+                url,
+                album.cover_art.clone(),
+                &self.client,
+                &cover_art_dir,
+            ));
+        }
+
+        let mut cover_art_map: HashMap<String, String> = HashMap::new();
+        progress.start(futures.len());
+        let mut stream =
+            futures::stream::iter(futures).buffer_unordered(self.client.max_concurrent_requests());
+        while let Some(result) = stream.next().await {
+            progress.advance();
+            match result {
+                Ok(file_name) => {
+                    cover_art_map.insert(
+                        Path::new(&file_name)
+                            .with_extension("")
+                            .to_string_lossy()
+                            .to_string(),
+                        file_name,
+                    );
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Ok(cover_art_map)
+    }
+
+    /* playlists, leaving out smart (search) playlists */
+    async fn playlists(&self) -> Result<Vec<Playlist>, anyhow::Error> {
+        let playlists: Vec<AmpachePlaylist> =
+            self.get_all("playlists", "&hide_search=1", None).await?;
+        Ok(playlists
+            .iter()
+            .map(|playlist| self.to_playlist(playlist))
+            .collect())
+    }
+
+    /* playlist_songs */
+    async fn playlist(&self, playlist_id: &str) -> Result<(Playlist, Vec<String>), anyhow::Error> {
+        let playlist = self
+            .playlists()
+            .await?
+            .into_iter()
+            .find(|playlist| playlist.id == playlist_id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown playlist {}", playlist_id))?;
+
+        let query = format!(
+            "&filter={}",
+            encode_query_value(strip_namespace(&self.library.id, playlist_id))
+        );
+        let songs: Vec<AmpacheSong> = self.get_all("playlist_songs", &query, None).await?;
+        let song_ids = songs
+            .iter()
+            .map(|song| namespace_id(&self.library.id, &song.id))
+            .collect();
+        Ok((playlist, song_ids))
+    }
+
+    /* stream, which carries the auth token of a session, so an expiring one is renewed first
+     *  https://ampache.org/api/api-json-methods#stream */
+    async fn stream_url(
+        &self,
+        song_id: &str,
+        transcode: &TranscodeSettings,
+    ) -> Result<String, anyhow::Error> {
+        let auth = self.auth().await?;
+        let mut query = format!(
+            "&type=song&id={}",
+            encode_query_value(strip_namespace(&self.library.id, song_id))
+        );
+        if let Some(format) = &transcode.format {
+            query.push_str(&format!("&format={}", encode_query_value(format)));
+        }
+        //In bits per second
+        if let Some(max_bit_rate) = transcode
+            .max_bit_rate
+            .filter(|max_bit_rate| *max_bit_rate > 0)
+        {
+            query.push_str(&format!("&bitrate={}", max_bit_rate * 1000));
+        }
+        Ok(create_action_url(&self.library, &auth, "stream", &query))
+    }
+}
+
+impl AmpacheSource {
+    fn to_album(&self, album: &AmpacheAlbum) -> Album {
+        let library_id = &self.library.id;
+        Album {
+            id: namespace_id(library_id, &album.id),
+            name: album.name.clone(),
+            artist_id: namespace_id(library_id, name_id(&album.artist).0),
+            artist_name: name_id(&album.artist).1.to_string(),
+            library_id: library_id.clone(),
+            cover_art: if album.has_art {
+                cover_art_key(library_id, &album.id)
+            } else {
+                String::new()
+            },
+            year: album.year.filter(|year| *year > 0),
+            duration: album.time.unwrap_or(0),
+            starred: starred(album.flag),
+            rating: album.rating.unwrap_or(0),
+        }
+    }
+
+    fn to_song(&self, song: &AmpacheSong) -> Song {
+        let library_id = &self.library.id;
+        let (album_id, album_name) = name_id(&song.album);
+        Song {
+            id: namespace_id(library_id, &song.id),
+            title: song.title.clone(),
+            artist_id: namespace_id(library_id, name_id(&song.artist).0),
+            artist_name: name_id(&song.artist).1.to_string(),
+            album_id: namespace_id(library_id, album_id),
+            album_name: album_name.to_string(),
+            library_id: library_id.clone(),
+            track: song.track.filter(|track| *track > 0),
+            duration: song.time,
+            disc_number: song.disk.filter(|disk| *disk > 0).unwrap_or(1),
+            year: song.year.filter(|year| *year > 0),
+            content_type: song.mime.clone().unwrap_or_default(),
+            //Songs share their album's art
+            cover_art: if song.has_art && !album_id.is_empty() {
+                cover_art_key(library_id, album_id)
+            } else {
+                String::new()
+            },
+            starred: starred(song.flag),
+            rating: song.rating.unwrap_or(0),
+            replay_gain_track: song.replaygain_track_gain,
+            replay_gain_album: song.replaygain_album_gain,
+            replay_gain_track_peak: song.replaygain_track_peak,
+            replay_gain_album_peak: song.replaygain_album_peak,
+        }
+    }
+
+    fn to_playlist(&self, playlist: &AmpachePlaylist) -> Playlist {
+        let song_count = playlist.items.unwrap_or(0);
+        Playlist {
+            id: namespace_id(&self.library.id, &playlist.id),
+            library_id: self.library.id.clone(),
+            name: playlist.name.clone(),
+            owner: playlist.owner.clone(),
+            created: String::new(),
+            //Servers that don't say when a playlist last changed at least change its song count
+            modified: match playlist.last_update {
+                Some(last_update) => last_update.to_string(),
+                None => song_count.to_string(),
+            },
+            song_count,
+            duration: 0,
+        }
+    }
+}
+
+/* ISO 8601 date as ms since the epoch */
+fn parse_date(date: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(date)
+        .ok()
+        .map(|date| date.timestamp_millis())
+}
+
+fn name_id(name_id: &Option<AmpacheNameId>) -> (&str, &str) {
+    name_id.as_ref().map_or(("", ""), |name_id| {
+        (name_id.id.as_str(), name_id.name.as_str())
+    })
+}
+
+/* Flags have no date, so their time is left empty (starred is only checked for being set) */
+fn starred(flag: bool) -> Option<String> {
+    if flag {
+        Some(String::new())
+    } else {
+        None
+    }
+}
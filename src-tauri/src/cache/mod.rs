@@ -0,0 +1,215 @@
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use sqlx::{Pool, Sqlite};
+use tauri::{AppHandle, Manager};
+
+use crate::{
+    db::{
+        db_connect, delete_cache_entry, delete_cache_kind, get_cache_size, get_cache_usage,
+        get_eviction_candidates, get_legacy_cover_art_libraries, get_setting, upsert_cache_entries,
+    },
+    downloads::now_millis,
+    formatter::generate_sha256,
+    models::{CacheEntry, CacheKind, CacheSettings, CacheStats},
+};
+
+pub const CACHE_SETTINGS_KEY: &str = "cache";
+
+pub async fn get_cache_settings(pool: &Pool<Sqlite>) -> Result<CacheSettings, anyhow::Error> {
+    Ok(get_setting::<CacheSettings>(pool, CACHE_SETTINGS_KEY)
+        .await?
+        .unwrap_or_default())
+}
+
+/* cover_art/<library_id>/<file>, alongside the DB (the frontend resolves art keys against it) */
+pub fn cover_art_dir(app_handle: &AppHandle) -> Result<PathBuf, anyhow::Error> {
+    Ok(app_handle.path().app_config_dir()?.join("cover_art"))
+}
+
+pub fn audio_cache_dir(app_handle: &AppHandle, library_id: &str) -> Result<PathBuf, anyhow::Error> {
+    let dir = app_handle
+        .path()
+        .app_cache_dir()?
+        .join("audio")
+        .join(library_id);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/* Record the art a sync fetched (or found already on disk), then trim the art cache to its quota */
+pub async fn track_cover_art(
+    pool: &Pool<Sqlite>,
+    app_handle: &AppHandle,
+    library_id: &str,
+    cover_art_map: &HashMap<String, String>,
+) -> Result<(), anyhow::Error> {
+    let dir = cover_art_dir(app_handle)?;
+    let now = now_millis();
+    let entries: Vec<CacheEntry> = cover_art_map
+        .values()
+        .filter_map(|key| {
+            let path = dir.join(key);
+            let size = fs::metadata(&path).ok()?.len() as i64;
+            Some(CacheEntry {
+                kind: CacheKind::Art.as_str().to_string(),
+                key: key.clone(),
+                library_id: library_id.to_string(),
+                path: path.to_string_lossy().to_string(),
+                size,
+                content_type: None,
+                last_accessed: now,
+            })
+        })
+        .collect();
+    upsert_cache_entries(pool, &entries).await?;
+    enforce_quota(pool, CacheKind::Art).await?;
+    Ok(())
+}
+
+/* Art fetched before IDs were namespaced sits directly in cover_art/. Each file is moved into the
+*  folder of the library whose albums use it (copied if several do), and files no album uses are
+*  deleted, so art keeps showing after an upgrade without waiting for a full sync. */
+pub async fn relocate_legacy_cover_art(app_handle: &AppHandle) -> Result<(), anyhow::Error> {
+    let dir = cover_art_dir(app_handle)?;
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let files: Vec<PathBuf> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let pool = db_connect(app_handle).await?;
+    for path in files {
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name.to_string(),
+            None => continue,
+        };
+        for library_id in get_legacy_cover_art_libraries(&pool, &file_name).await? {
+            let library_dir = dir.join(&library_id);
+            fs::create_dir_all(&library_dir)?;
+            let target = library_dir.join(&file_name);
+            if !target.exists() {
+                fs::copy(&path, &target)?;
+            }
+        }
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+/* Store a fully fetched song under its cache key (see TranscodeSettings::cache_key), then trim the
+*  cache to its quota */
+pub async fn cache_audio(
+    pool: &Pool<Sqlite>,
+    app_handle: &AppHandle,
+    library_id: &str,
+    key: &str,
+    content_type: Option<String>,
+    body: &[u8],
+) -> Result<(), anyhow::Error> {
+    let settings = get_cache_settings(pool).await?;
+    if settings.audio_quota_bytes <= 0 || body.len() as i64 > settings.audio_quota_bytes {
+        return Ok(());
+    }
+    //Hashed, as keys hold characters that aren't safe in file names and sanitizing them can collide
+    let file_name = generate_sha256(key);
+    let path = audio_cache_dir(app_handle, library_id)?.join(file_name);
+    fs::write(&path, body)?;
+    let entry = CacheEntry {
+        kind: CacheKind::Audio.as_str().to_string(),
+        key: key.to_string(),
+        library_id: library_id.to_string(),
+        path: path.to_string_lossy().to_string(),
+        size: body.len() as i64,
+        content_type,
+        last_accessed: now_millis(),
+    };
+    upsert_cache_entries(pool, &[entry]).await?;
+    enforce_quota(pool, CacheKind::Audio).await?;
+    Ok(())
+}
+
+/* Evict least recently used files until the cache fits its quota. Returns the bytes freed. */
+pub async fn enforce_quota(pool: &Pool<Sqlite>, kind: CacheKind) -> Result<i64, anyhow::Error> {
+    let settings = get_cache_settings(pool).await?;
+    let quota = match kind {
+        CacheKind::Art => settings.art_quota_bytes,
+        CacheKind::Audio => settings.audio_quota_bytes,
+    };
+    let mut size = get_cache_size(pool, kind).await?;
+    if size <= quota {
+        return Ok(0);
+    }
+
+    let mut freed = 0;
+    for entry in get_eviction_candidates(pool, kind).await? {
+        if size <= quota {
+            break;
+        }
+        match fs::remove_file(&entry.path) {
+            Ok(_) => {}
+            //Already gone, so only the entry needs removing
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                println!("Error: Failed to evict {}: {}", entry.path, e);
+                continue;
+            }
+        }
+        delete_cache_entry(pool, kind, &entry.key).await?;
+        size -= entry.size;
+        freed += entry.size;
+    }
+    Ok(freed)
+}
+
+/* Delete every file of one kind. Art for pinned albums is removed too; it is fetched again when next shown. */
+pub async fn clear_cache(
+    pool: &Pool<Sqlite>,
+    app_handle: &AppHandle,
+    kind: CacheKind,
+) -> Result<(), anyhow::Error> {
+    let dir = match kind {
+        CacheKind::Art => cover_art_dir(app_handle)?,
+        CacheKind::Audio => app_handle.path().app_cache_dir()?.join("audio"),
+    };
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    delete_cache_kind(pool, kind).await?;
+    Ok(())
+}
+
+pub async fn get_cache_stats(pool: &Pool<Sqlite>) -> Result<Vec<CacheStats>, anyhow::Error> {
+    let mut stats: HashMap<String, CacheStats> = HashMap::new();
+    for (library_id, kind, bytes, files) in get_cache_usage(pool).await? {
+        let library_stats = stats
+            .entry(library_id.clone())
+            .or_insert_with(|| CacheStats {
+                library_id,
+                ..Default::default()
+            });
+        match kind.as_str() {
+            "art" => {
+                library_stats.art_bytes = bytes;
+                library_stats.art_files = files;
+            }
+            "audio" => {
+                library_stats.audio_bytes = bytes;
+                library_stats.audio_files = files;
+            }
+            "download" => {
+                library_stats.download_bytes = bytes;
+                library_stats.download_files = files;
+            }
+            _ => {}
+        }
+    }
+    let mut stats: Vec<CacheStats> = stats.into_values().collect();
+    stats.sort_by(|a, b| a.library_id.cmp(&b.library_id));
+    Ok(stats)
+}
@@ -0,0 +1,347 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+    Client, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::errors::ApiError;
+use crate::responses::{SubsonicResponse, SubsonicStatus};
+
+pub struct ServerClientConfig {
+    pub connect_timeout: Duration,
+    // Applies to API calls only. Streams/downloads can legitimately take longer than this.
+    pub request_timeout: Duration,
+    pub max_retries: u32,
+    pub retry_base_delay: Duration,
+    pub max_concurrent_requests: usize,
+}
+
+impl Default for ServerClientConfig {
+    fn default() -> Self {
+        ServerClientConfig {
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            max_retries: 3,
+            retry_base_delay: Duration::from_millis(250),
+            max_concurrent_requests: 16,
+        }
+    }
+}
+
+/* Shared HTTP client for every server call, Subsonic or not (managed as app state). Cloning is
+*  cheap, and all clones share the same connection pool and concurrency cap. */
+#[derive(Clone)]
+pub struct ServerClient {
+    http: Client,
+    config: Arc<ServerClientConfig>,
+    permits: Arc<Semaphore>,
+}
+
+impl ServerClient {
+    pub fn new(config: ServerClientConfig) -> Self {
+        let http = Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()
+            .expect("failed to build HTTP client");
+        ServerClient {
+            http,
+            permits: Arc::new(Semaphore::new(config.max_concurrent_requests)),
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.config.max_concurrent_requests
+    }
+
+    /* API call. Failed responses (status "failed") are mapped to their Subsonic error code. */
+    pub async fn get_response<T: DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> Result<SubsonicResponse<T>, ApiError> {
+        let body = self
+            .execute(
+                url,
+                Some(self.config.request_timeout),
+                HeaderMap::new(),
+                |res| res.bytes(),
+            )
+            .await?;
+        parse_response(&body)
+    }
+
+    /* API call of a server that isn't Subsonic (e.g. Jellyfin), which reports errors with the
+     *  HTTP status instead */
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+    ) -> Result<T, ApiError> {
+        let body = self
+            .execute(url, Some(self.config.request_timeout), headers, |res| {
+                res.bytes()
+            })
+            .await?;
+        serde_json::from_slice::<T>(&body).map_err(|e| ApiError::Parse(e.to_string()))
+    }
+
+    /* POST with a JSON body, e.g. to log in. Not retried, as it may not be safe to repeat. A 401
+     *  means the credentials were refused. */
+    pub async fn post_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        body: &B,
+    ) -> Result<T, ApiError> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|e| ApiError::Transport(e.to_string()))?;
+        let res = self
+            .http
+            .post(url)
+            .headers(headers)
+            .json(body)
+            .timeout(self.config.request_timeout)
+            .send()
+            .await?;
+        if res.status() == StatusCode::UNAUTHORIZED {
+            return Err(ApiError::Http {
+                status: StatusCode::UNAUTHORIZED.as_u16(),
+                message: "The server refused the login".to_string(),
+            });
+        }
+        let body = res.error_for_status()?.bytes().await?;
+        serde_json::from_slice::<T>(&body).map_err(|e| ApiError::Parse(e.to_string()))
+    }
+
+    /* Binary payloads (cover art, audio). No overall timeout, as these can be large. Servers
+     *  report errors for these endpoints as a regular JSON response instead of the payload. */
+    pub async fn get_bytes(&self, url: &str) -> Result<Vec<u8>, ApiError> {
+        let (_, body) = self.get_bytes_with_type(url).await?;
+        Ok(body)
+    }
+
+    /* A binary payload with its content type */
+    pub async fn get_bytes_with_type(
+        &self,
+        url: &str,
+    ) -> Result<(Option<String>, Vec<u8>), ApiError> {
+        let (content_type, body) = self
+            .execute(url, None, HeaderMap::new(), |res| async move {
+                let content_type = content_type(&res).map(|content_type| content_type.to_string());
+                res.bytes().await.map(|bytes| (content_type, bytes))
+            })
+            .await?;
+        if content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.contains("json"))
+        {
+            parse_response::<SubsonicStatus>(&body)?;
+        }
+        Ok((content_type, body.to_vec()))
+    }
+
+    /* Bytes start..=end of a binary payload, requested with an HTTP Range header. The body is read
+     *  as it arrives and never past the range, so memory use is bounded by the range. Servers that
+     *  ignore the header (e.g. when transcoding) answer with the whole payload and a 200 status,
+     *  which is read through up to start and dropped after end. */
+    pub async fn get_byte_range(
+        &self,
+        url: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<ByteRangeResponse, ApiError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&format!("bytes={}-{}", start, end))
+                .map_err(|e| ApiError::Transport(e.to_string()))?,
+        );
+        let length = end.saturating_sub(start) + 1;
+        let response = self
+            .execute(url, None, headers, |mut res| async move {
+                let content_type = content_type(&res).map(|content_type| content_type.to_string());
+                let is_json = content_type
+                    .as_deref()
+                    .is_some_and(|content_type| content_type.contains("json"));
+                let partial = res.status() == StatusCode::PARTIAL_CONTENT;
+                let mut total = payload_size(&res);
+                //Errors come back as JSON in place of the payload, and are read from the start
+                let mut skip = if partial || is_json { 0 } else { start };
+                let mut body: Vec<u8> = vec![];
+                while (body.len() as u64) < length {
+                    let chunk = match res.chunk().await? {
+                        Some(chunk) => chunk,
+                        None => {
+                            //The payload ended within the range, so its size is now known
+                            if !is_json {
+                                total = total.or(Some(start + body.len() as u64));
+                            }
+                            break;
+                        }
+                    };
+                    let skipped = skip.min(chunk.len() as u64) as usize;
+                    skip -= skipped as u64;
+                    let wanted = (length - body.len() as u64) as usize;
+                    let rest = &chunk[skipped..];
+                    body.extend_from_slice(&rest[..rest.len().min(wanted)]);
+                }
+                Ok(ByteRangeResponse {
+                    content_type,
+                    is_json,
+                    total,
+                    body,
+                })
+            })
+            .await?;
+        if response.is_json {
+            parse_response::<SubsonicStatus>(&response.body)?;
+        }
+        Ok(response)
+    }
+
+    /* A binary payload from byte start on, read as the caller needs it (e.g. a song while it
+     *  plays). Servers that ignore the Range header have the bytes before start skipped, like
+     *  get_byte_range. Not held to the concurrency cap, as it stays open for as long as it's read. */
+    pub async fn open_stream(&self, url: &str, start: u64) -> Result<ByteStream, ApiError> {
+        let mut request = self.http.get(url);
+        if start > 0 {
+            request = request.header(RANGE, format!("bytes={}-", start));
+        }
+        let res = request.send().await?.error_for_status()?;
+        let content_type = content_type(&res).map(|content_type| content_type.to_string());
+        if content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.contains("json"))
+        {
+            //An error in place of the payload
+            parse_response::<SubsonicStatus>(&res.bytes().await?)?;
+            return Err(ApiError::Parse("Expected a binary payload".to_string()));
+        }
+        let partial = res.status() == StatusCode::PARTIAL_CONTENT;
+        Ok(ByteStream {
+            content_type,
+            total: payload_size(&res),
+            skip: if partial { 0 } else { start },
+            response: res,
+        })
+    }
+
+    /* Runs a GET (and reads its body) under the concurrency cap, retrying network errors and 5xx
+     *  responses with exponential backoff */
+    async fn execute<T, F, Fut>(
+        &self,
+        url: &str,
+        timeout: Option<Duration>,
+        headers: HeaderMap,
+        read: F,
+    ) -> Result<T, ApiError>
+    where
+        F: Fn(Response) -> Fut,
+        Fut: Future<Output = Result<T, reqwest::Error>>,
+    {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|e| ApiError::Transport(e.to_string()))?;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.http.get(url).headers(headers.clone());
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+            let result = match request.send().await.and_then(|res| res.error_for_status()) {
+                Ok(res) => read(res).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.config.max_retries && is_retryable(&e) => {
+                    tokio::time::sleep(self.config.retry_base_delay * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+pub struct ByteRangeResponse {
+    pub content_type: Option<String>,
+    is_json: bool,
+    // Size of the whole payload, if the server reported it
+    pub total: Option<u64>,
+    // Bytes from the requested start, at most up to the requested end
+    pub body: Vec<u8>,
+}
+
+/* An open binary payload (see ServerClient::open_stream) */
+pub struct ByteStream {
+    pub content_type: Option<String>,
+    // Size of the whole payload, if the server reported it
+    pub total: Option<u64>,
+    // Bytes still to be dropped to reach the requested start
+    skip: u64,
+    response: Response,
+}
+
+impl ByteStream {
+    /* The next bytes of the payload, or None at its end */
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, ApiError> {
+        while let Some(chunk) = self.response.chunk().await? {
+            let skipped = self.skip.min(chunk.len() as u64) as usize;
+            self.skip -= skipped as u64;
+            if skipped < chunk.len() {
+                return Ok(Some(chunk[skipped..].to_vec()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub fn parse_response<T: DeserializeOwned>(body: &[u8]) -> Result<SubsonicResponse<T>, ApiError> {
+    let status = serde_json::from_slice::<SubsonicResponse<SubsonicStatus>>(body)
+        .map_err(|e| ApiError::Parse(e.to_string()))?;
+    if status.data.status != "ok" {
+        return Err(match &status.data.error {
+            Some(error) => ApiError::from_response(error),
+            None => ApiError::Generic("Request failed".to_string()),
+        });
+    }
+    serde_json::from_slice::<SubsonicResponse<T>>(body).map_err(|e| ApiError::Parse(e.to_string()))
+}
+
+fn content_type(res: &Response) -> Option<&str> {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+}
+
+/* Size of the whole payload: "bytes 0-1023/4096" on partial responses, the length otherwise */
+fn payload_size(res: &Response) -> Option<u64> {
+    if res.status() == StatusCode::PARTIAL_CONTENT {
+        res.headers()
+            .get(CONTENT_RANGE)
+            .and_then(|content_range| content_range.to_str().ok())
+            .and_then(|content_range| content_range.rsplit('/').next())
+            .and_then(|total| total.parse::<u64>().ok())
+    } else {
+        res.content_length()
+    }
+}
+
+fn is_retryable(error: &reqwest::Error) -> bool {
+    match error.status() {
+        Some(status) => status.is_server_error(),
+        None => error.is_timeout() || error.is_connect() || error.is_request() || error.is_body(),
+    }
+}
@@ -1,17 +1,53 @@
 use std::collections::HashMap;
-use std::fs;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use tauri::{AppHandle, Manager};
+use sqlx::{Pool, Sqlite};
+use tauri::{AppHandle, State};
 
-use crate::db::db_connect;
-use crate::formatter::{generate_md5, generate_salt, get_library_hash, save_library_hash};
-use crate::models::{Library, LibraryConfig};
-use crate::music::sync_library;
-use crate::subsonic::{get_album_list, get_playlist_songs, ping_server, stream};
+use crate::ampache::handshake;
+use crate::cache::{clear_cache, enforce_quota, CACHE_SETTINGS_KEY};
+use crate::client::ServerClient;
+use crate::db::{
+    db_connect, get_album_by_cover_art, get_album_song_types, get_library, get_playlist_song_ids,
+    get_setting, replace_starred, set_setting, touch_cache_entries, update_rating, update_starred,
+};
+use crate::downloads::{now_millis, pin_and_download, SongDownload};
+use crate::errors::CommandError;
+use crate::formatter::{
+    generate_md5, generate_salt, generate_sha256, namespace_id, save_library_hash, strip_namespace,
+};
+use crate::jellyfin::authenticate;
+use crate::local::watch_library;
+use crate::models::{
+    CacheKind, CacheSettings, CacheStats, Download, DownloadPin, DspPreset, DspSettings,
+    Favourites, Library, LibraryConfig, LibraryKind, LocalLibraryConfig, NetworkProfile,
+    PlaybackItem, PlaybackSettings, Playlist, RatingTarget, SavedQueue, ScrobbleStatus, SearchPage,
+    SearchResults, SyncResult, TranscodeSettings,
+};
+use crate::music::{library_changed, refetch_cover_art, refresh_playlist, sync_library, SyncState};
+use crate::playback::{
+    dsp_response, load_dsp_presets, output_devices, Player, PlayerCommand, DSP_PRESET_KEY,
+    PLAYBACK_SETTINGS_KEY,
+};
+use crate::scrobble::{reached_scrobble_threshold, submit_scrobble};
+use crate::source::open_source;
+use crate::streaming::NETWORK_PROFILE_KEY;
+use crate::subsonic::{
+    get_album_list, get_play_queue, get_playlist_songs, get_playlists, get_starred, ping_server,
+    save_play_queue, scrobble, SubsonicSource,
+};
 
 #[tauri::command]
-pub async fn add_server(library: LibraryConfig) -> Result<Library, String> {
+pub async fn add_server(
+    library: LibraryConfig,
+    client: State<'_, ServerClient>,
+) -> Result<Library, CommandError> {
+    if library.kind == LibraryKind::Jellyfin {
+        return add_jellyfin_server(library, &client).await;
+    }
+    if library.kind == LibraryKind::Ampache {
+        return add_ampache_server(library, &client).await;
+    }
     //Create salt and hashed password
     let salt = generate_salt();
     let hashed_password = generate_md5(&library.password, &salt);
@@ -31,9 +67,12 @@ pub async fn add_server(library: LibraryConfig) -> Result<Library, String> {
         hashed_password,
         salt,
         last_scanned: since_the_epoch.unwrap().as_millis().to_string(),
+        kind: LibraryKind::Subsonic,
+        paths: vec![],
+        user_id: "".to_string(),
     };
 
-    match ping_server(&library).await {
+    match ping_server(&client, &library).await {
         Ok(_) => {
             //Save library hash to keyring
             match save_library_hash(&library) {
@@ -41,113 +80,261 @@ pub async fn add_server(library: LibraryConfig) -> Result<Library, String> {
                     library.hashed_password = "".to_string();
                     Ok(library)
                 }
-                Err(_) => Err("=ERROR: Failed to save library hash".to_string()),
+                Err(_) => Err(CommandError::internal("Failed to save library hash")),
             }
         }
-        Err(_) => Err("=ERROR: Failed to connect to server".to_string()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/* Jellyfin is logged in to once with the password. The access token takes its place in the
+*  keyring, and the ID of the user it belongs to is kept with the library. */
+async fn add_jellyfin_server(
+    config: LibraryConfig,
+    client: &ServerClient,
+) -> Result<Library, CommandError> {
+    let mut library = Library {
+        id: config.id,
+        name: config.name,
+        host: config.host,
+        port: config.port,
+        username: config.username,
+        hashed_password: "".to_string(),
+        salt: "".to_string(),
+        //Never scanned, so the first sync fetches everything
+        last_scanned: "".to_string(),
+        kind: LibraryKind::Jellyfin,
+        paths: vec![],
+        user_id: "".to_string(),
+    };
+    let auth = authenticate(client, &library, &config.password).await?;
+    library.hashed_password = auth.access_token;
+    library.user_id = auth.user.id;
+    match save_library_hash(&library) {
+        Ok(_) => {
+            library.hashed_password = "".to_string();
+            Ok(library)
+        }
+        Err(_) => Err(CommandError::internal("Failed to save library hash")),
+    }
+}
+
+/* Ampache sessions are started from sha256(password), which is kept in the keyring so expired
+*  sessions can be renewed. A handshake checks the credentials. */
+async fn add_ampache_server(
+    config: LibraryConfig,
+    client: &ServerClient,
+) -> Result<Library, CommandError> {
+    let mut library = Library {
+        id: config.id,
+        name: config.name,
+        host: config.host,
+        port: config.port,
+        username: config.username,
+        hashed_password: generate_sha256(&config.password),
+        salt: "".to_string(),
+        //Never scanned, so the first sync fetches everything
+        last_scanned: "".to_string(),
+        kind: LibraryKind::Ampache,
+        paths: vec![],
+        user_id: "".to_string(),
+    };
+    handshake(client, &library).await?;
+    match save_library_hash(&library) {
+        Ok(_) => {
+            library.hashed_password = "".to_string();
+            Ok(library)
+        }
+        Err(_) => Err(CommandError::internal("Failed to save library hash")),
+    }
+}
+
+/* A library of folders on this machine. Nothing is scanned until it is synced. */
+#[tauri::command]
+pub async fn add_local_library(library: LocalLibraryConfig) -> Result<Library, CommandError> {
+    if library.paths.is_empty() {
+        return Err(CommandError::invalid_argument(
+            "At least one folder is required",
+        ));
+    }
+    for path in &library.paths {
+        if !std::path::Path::new(path).is_dir() {
+            return Err(CommandError::invalid_argument(format!(
+                "{} is not a folder",
+                path
+            )));
+        }
     }
+    Ok(Library {
+        id: library.id,
+        name: library.name,
+        host: "".to_string(),
+        port: None,
+        username: "".to_string(),
+        hashed_password: "".to_string(),
+        salt: "".to_string(),
+        //Never scanned, so the first sync reads every file
+        last_scanned: "".to_string(),
+        kind: LibraryKind::Local,
+        paths: library.paths,
+        user_id: "".to_string(),
+    })
 }
 
 #[tauri::command]
 pub async fn sync_collection(
     libraries: Vec<Library>,
     app_handle: AppHandle,
-) -> Result<String, String> {
-    for mut library in libraries {
-        //Get hashed password from keyring
-        match get_library_hash(&library) {
-            Ok(hashed_password) => {
-                library.hashed_password = hashed_password;
-                //Sync library
-                match sync_library(&library, &app_handle).await {
-                    Ok(_) => println!("Library synced"),
-                    Err(e) => println!("Error: {}", e),
-                }
+    sync_state: State<'_, SyncState>,
+) -> Result<Vec<SyncResult>, CommandError> {
+    let mut results: Vec<SyncResult> = vec![];
+    sync_state.begin();
+    for library in libraries {
+        if sync_state.is_cancelled() {
+            break;
+        }
+        let library_id = library.id.clone();
+        //Server credentials come from the keyring
+        let source = match open_source(&app_handle, library) {
+            Ok(source) => source,
+            Err(e) => {
+                results.push(SyncResult {
+                    library_id,
+                    diff: None,
+                    error: Some(e.into()),
+                });
+                continue;
+            }
+        };
+        //Waits for a rescan of the library (after its files changed) to finish first
+        let _lock = sync_state.lock_library(&library_id).await;
+        let mut result = match sync_library(
+            source.as_ref(),
+            &app_handle,
+            sync_state.register(&library_id),
+        )
+        .await
+        {
+            Ok(diff) => SyncResult {
+                library_id,
+                diff: Some(diff),
+                error: None,
+            },
+            Err(e) => SyncResult {
+                library_id,
+                diff: None,
+                error: Some(e.into()),
+            },
+        };
+        //Local libraries are kept up to date from then on as their files change
+        if source.library().kind == LibraryKind::Local {
+            if let Err(e) = watch_library(&app_handle, source.library()) {
+                result.error.get_or_insert(e.into());
             }
-            Err(_) => println!("Error: Failed to get hashed password"),
         }
+        results.push(result);
     }
-    Ok("Collection synced".to_string())
+    Ok(results)
 }
 
+/* Whether any of the libraries changed since it was last synced, so the collection needs a sync */
 #[tauri::command]
-pub async fn load_songs(
-    mut library: Library,
-    song_ids: Vec<String>,
-) -> Result<Vec<Vec<u8>>, String> {
-    //Get library pass
-    let hashed_password = get_library_hash(&library).unwrap();
-    library.hashed_password = hashed_password;
-
-    let mut song_data: Vec<Vec<u8>> = vec![];
-    for song_id in song_ids {
-        match stream(&library, &song_id).await {
-            Ok(song) => {
-                song_data.push(song);
+pub async fn libraries_changed(
+    libraries: Vec<Library>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    for library in libraries {
+        let source = match open_source(&app_handle, library) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("Error: {}", e);
+                continue;
             }
-            Err(e) => println!("Error: {}", e),
+        };
+        if library_changed(source.as_ref(), &pool).await {
+            return Ok(true);
         }
     }
-    Ok(song_data)
+    Ok(false)
+}
+
+#[tauri::command]
+pub async fn cancel_sync(sync_state: State<'_, SyncState>) -> Result<bool, CommandError> {
+    sync_state.cancel();
+    Ok(true)
 }
 
 #[tauri::command]
-pub async fn get_libraries(app_handle: AppHandle) -> Result<Vec<Library>, String> {
-    let libraries = crate::db::get_libraries(&app_handle).await.unwrap();
+pub async fn get_libraries(app_handle: AppHandle) -> Result<Vec<Library>, CommandError> {
+    let libraries = crate::db::get_libraries(&app_handle).await?;
     Ok(libraries)
 }
 
+/* Album lists, scrobbling, stars and ratings, pins, the server play queue and playlist editing
+*  call the Subsonic API directly, which other kinds of library don't have */
+fn require_subsonic(library: &Library) -> Result<(), CommandError> {
+    if library.kind != LibraryKind::Subsonic {
+        return Err(CommandError::unsupported(format!(
+            "Not supported for {} libraries",
+            library.kind.as_str()
+        )));
+    }
+    Ok(())
+}
+
 #[tauri::command]
-pub async fn get_recently_played(library: Library) -> Result<Vec<String>, String> {
+pub async fn get_recently_played(
+    library: Library,
+    client: State<'_, ServerClient>,
+) -> Result<Vec<String>, CommandError> {
+    require_subsonic(&library)?;
     let mut album_ids = vec![];
-    match get_album_list(&library, "recent".to_string()).await {
-        Ok(album_list_response) => {
-            for album in album_list_response.data.album_list_2.album {
-                album_ids.push(album.id);
-            }
-        }
-        Err(e) => println!("Error: {}", e),
+    let album_list_response = get_album_list(&client, &library, "recent".to_string()).await?;
+    for album in album_list_response.data.album_list_2.album {
+        album_ids.push(namespace_id(&library.id, &album.id));
     }
     Ok(album_ids)
 }
 
 #[tauri::command]
-pub async fn get_recently_added(library: Library) -> Result<Vec<String>, String> {
+pub async fn get_recently_added(
+    library: Library,
+    client: State<'_, ServerClient>,
+) -> Result<Vec<String>, CommandError> {
+    require_subsonic(&library)?;
     let mut album_ids = vec![];
-    match get_album_list(&library, "newest".to_string()).await {
-        Ok(album_list_response) => {
-            for album in album_list_response.data.album_list_2.album {
-                album_ids.push(album.id);
-            }
-        }
-        Err(e) => println!("Error: {}", e),
+    let album_list_response = get_album_list(&client, &library, "newest".to_string()).await?;
+    for album in album_list_response.data.album_list_2.album {
+        album_ids.push(namespace_id(&library.id, &album.id));
     }
     Ok(album_ids)
 }
 
+/* Song IDs of a playlist, in order. Read from the entries stored by the last sync, so this works
+*  offline; a playlist whose entries haven't been stored yet is fetched from its source. */
 #[tauri::command]
 pub async fn get_songs_for_playlist(
     library: Library,
     playlist_id: String,
-) -> Result<Vec<String>, String> {
-    let mut song_ids = vec![];
-    match get_playlist_songs(&library, &playlist_id).await {
-        Ok(song_list_response) => {
-            for song in song_list_response.data.playlist.entry {
-                song_ids.push(song.id);
-            }
-        }
-        Err(e) => println!("Error: {}", e),
+    app_handle: AppHandle,
+) -> Result<Vec<String>, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let song_ids = get_playlist_song_ids(&pool, &playlist_id).await?;
+    if !song_ids.is_empty() {
+        return Ok(song_ids);
     }
-    Ok(song_ids)
+    let source = open_source(&app_handle, library)?;
+    refresh_playlist(source.as_ref(), &pool, &playlist_id).await?;
+    Ok(get_playlist_song_ids(&pool, &playlist_id).await?)
 }
 
 #[tauri::command]
 pub async fn update_library_modified(
     app_handle: AppHandle,
     data: HashMap<String, String>,
-) -> Result<bool, String> {
-    let pool = db_connect(&app_handle).await.unwrap();
+) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
     for (key, value) in data {
         println!("{}: {}", key, value);
         match sqlx::query("UPDATE libraries SET last_scanned = (?) WHERE id = (?)")
@@ -164,12 +351,811 @@ pub async fn update_library_modified(
 }
 
 #[tauri::command]
-pub async fn clear_cover_art_cache(app_handle: AppHandle) -> Result<bool, String> {
-    let binding = app_handle.path().app_config_dir().unwrap();
-    let app_data_dir = binding.to_str().unwrap();
-    let data_dir_string = app_data_dir.to_string();
+pub async fn clear_cover_art_cache(app_handle: AppHandle) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    clear_cache(&pool, &app_handle, CacheKind::Art).await?;
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn scrobble_now_playing(
+    library: Library,
+    song_id: String,
+    client: State<'_, ServerClient>,
+) -> Result<bool, CommandError> {
+    require_subsonic(&library)?;
+    //Now playing is best effort, so it is never queued
+    let song_id = strip_namespace(&library.id, &song_id);
+    scrobble(&client, &library, song_id, None, false).await?;
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn scrobble_submission(
+    library: Library,
+    song_id: String,
+    played_at: i64,
+    played_seconds: u32,
+    duration: u32,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<ScrobbleStatus, CommandError> {
+    require_subsonic(&library)?;
+    if !reached_scrobble_threshold(played_seconds, duration) {
+        return Ok(ScrobbleStatus::BelowThreshold);
+    }
+    let pool = db_connect(&app_handle).await?;
+    let status = submit_scrobble(&client, &pool, &library, &song_id, played_at).await?;
+    Ok(status)
+}
+
+#[tauri::command]
+pub async fn flush_scrobble_queue(
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<u32, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let mut submitted = 0;
+    for library in crate::db::get_libraries(&app_handle).await? {
+        if library.kind != LibraryKind::Subsonic {
+            continue;
+        }
+        match crate::scrobble::flush_scrobble_queue(&client, &pool, &library).await {
+            Ok(count) => submitted += count,
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+    Ok(submitted)
+}
+
+/* Star or unstar an item. The local DB is updated first so the UI reflects it immediately, and is
+*  put back if the server rejects the change. */
+#[tauri::command]
+pub async fn set_starred(
+    library: Library,
+    target: RatingTarget,
+    id: String,
+    starred: bool,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<bool, CommandError> {
+    require_subsonic(&library)?;
+    let pool = db_connect(&app_handle).await?;
+    let starred_at = starred.then(|| chrono::Utc::now().to_rfc3339());
+    let previous = update_starred(&pool, target, &id, starred_at).await?;
+    let remote_id = strip_namespace(&library.id, &id);
+    if let Err(e) =
+        crate::subsonic::set_starred(&client, &library, target, remote_id, starred).await
+    {
+        update_starred(&pool, target, &id, previous).await?;
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+/* Rate an item from 1 to 5 (0 removes the rating), updating the local DB optimistically */
+#[tauri::command]
+pub async fn set_rating(
+    library: Library,
+    target: RatingTarget,
+    id: String,
+    rating: u32,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<bool, CommandError> {
+    require_subsonic(&library)?;
+    if rating > 5 {
+        let message = "Rating must be between 0 and 5";
+        return Err(CommandError::invalid_argument(message));
+    }
+    let pool = db_connect(&app_handle).await?;
+    let previous = update_rating(&pool, target, &id, rating).await?;
+    let remote_id = strip_namespace(&library.id, &id);
+    if let Err(e) = crate::subsonic::set_rating(&client, &library, remote_id, rating).await {
+        update_rating(&pool, target, &id, previous).await?;
+        return Err(e.into());
+    }
+    Ok(true)
+}
+
+/* Fetch the server's favourites (getStarred2) and bring the local starred columns in line with
+*  them, so stars added from other clients show up without a full sync */
+#[tauri::command]
+pub async fn get_favourites(
+    library: Library,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<Favourites, CommandError> {
+    require_subsonic(&library)?;
+    let starred = get_starred(&client, &library).await?.data.starred_2;
+    let library_id = &library.id;
+    let artists: Vec<(String, String)> = starred
+        .artist
+        .into_iter()
+        .map(|artist| {
+            (
+                namespace_id(library_id, &artist.id),
+                artist.starred.unwrap_or_default(),
+            )
+        })
+        .collect();
+    let albums: Vec<(String, String)> = starred
+        .album
+        .into_iter()
+        .map(|album| {
+            (
+                namespace_id(library_id, &album.id),
+                album.starred.unwrap_or_default(),
+            )
+        })
+        .collect();
+    let songs: Vec<(String, String)> = starred
+        .song
+        .into_iter()
+        .map(|song| {
+            (
+                namespace_id(library_id, &song.id),
+                song.starred.unwrap_or_default(),
+            )
+        })
+        .collect();
+
+    let pool = db_connect(&app_handle).await?;
+    let mut tx = pool.begin().await?;
+    replace_starred(&mut tx, RatingTarget::Artist, library_id, &artists).await?;
+    replace_starred(&mut tx, RatingTarget::Album, library_id, &albums).await?;
+    replace_starred(&mut tx, RatingTarget::Song, library_id, &songs).await?;
+    tx.commit().await?;
+
+    Ok(Favourites {
+        library_id: library_id.clone(),
+        artists: artists.into_iter().map(|(id, _)| id).collect(),
+        albums: albums.into_iter().map(|(id, _)| id).collect(),
+        songs: songs.into_iter().map(|(id, _)| id).collect(),
+    })
+}
+
+#[tauri::command]
+pub async fn search_library(
+    library: Library,
+    query: String,
+    page: Option<SearchPage>,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<SearchResults, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let page = page.unwrap_or_default();
+    let results = crate::search::search_library(&client, &pool, &library, &query, &page).await?;
+    Ok(results)
+}
+
+/* Keep an album available offline. Returns the number of songs downloaded. */
+#[tauri::command]
+pub async fn pin_album(
+    library: Library,
+    album_id: String,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<usize, CommandError> {
+    require_subsonic(&library)?;
+    let pool = db_connect(&app_handle).await?;
+    let songs = get_album_song_types(&pool, &album_id)
+        .await?
+        .into_iter()
+        .map(|(song_id, content_type)| SongDownload {
+            song_id,
+            content_type,
+        })
+        .collect();
+    let pin = DownloadPin {
+        id: album_id,
+        library_id: library.id.clone(),
+        kind: "album".to_string(),
+        created_at: now_millis(),
+    };
+    let downloaded = pin_and_download(&app_handle, &client, &pool, &library, pin, songs).await?;
+    Ok(downloaded)
+}
+
+/* Keep a playlist available offline, using its current entries on the server */
+#[tauri::command]
+pub async fn pin_playlist(
+    library: Library,
+    playlist_id: String,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<usize, CommandError> {
+    require_subsonic(&library)?;
+    let playlist_response = get_playlist_songs(
+        &client,
+        &library,
+        strip_namespace(&library.id, &playlist_id),
+    )
+    .await?;
+    let songs = playlist_response
+        .data
+        .playlist
+        .entry
+        .into_iter()
+        .map(|song| SongDownload {
+            song_id: namespace_id(&library.id, &song.id),
+            content_type: song.content_type,
+        })
+        .collect();
+    let pool = db_connect(&app_handle).await?;
+    let pin = DownloadPin {
+        id: playlist_id,
+        library_id: library.id.clone(),
+        kind: "playlist".to_string(),
+        created_at: now_millis(),
+    };
+    let downloaded = pin_and_download(&app_handle, &client, &pool, &library, pin, songs).await?;
+    Ok(downloaded)
+}
+
+#[tauri::command]
+pub async fn unpin(pin_id: String, app_handle: AppHandle) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    crate::downloads::unpin(&pool, &pin_id).await?;
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn get_downloads(app_handle: AppHandle) -> Result<Vec<Download>, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let downloads = crate::db::get_downloads(&pool).await?;
+    Ok(downloads)
+}
+
+#[tauri::command]
+pub async fn get_download_pins(app_handle: AppHandle) -> Result<Vec<DownloadPin>, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let pins = crate::db::get_download_pins(&pool).await?;
+    Ok(pins)
+}
+
+#[tauri::command]
+pub async fn get_cache_stats(app_handle: AppHandle) -> Result<Vec<CacheStats>, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let stats = crate::cache::get_cache_stats(&pool).await?;
+    Ok(stats)
+}
+
+#[tauri::command]
+pub async fn get_cache_settings(app_handle: AppHandle) -> Result<CacheSettings, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let settings = crate::cache::get_cache_settings(&pool).await?;
+    Ok(settings)
+}
+
+/* Save new quotas, evicting straight away if the caches no longer fit */
+#[tauri::command]
+pub async fn set_cache_settings(
+    settings: CacheSettings,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    if settings.art_quota_bytes < 0 || settings.audio_quota_bytes < 0 {
+        let message = "Cache quotas can't be negative";
+        return Err(CommandError::invalid_argument(message));
+    }
+    let pool = db_connect(&app_handle).await?;
+    set_setting(&pool, CACHE_SETTINGS_KEY, &settings).await?;
+    enforce_quota(&pool, CacheKind::Art).await?;
+    enforce_quota(&pool, CacheKind::Audio).await?;
+    Ok(true)
+}
+
+/* Mark cover art as used (the webview loads it from disk directly, so the backend can't see it) */
+#[tauri::command]
+pub async fn touch_cover_art(
+    keys: Vec<String>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    touch_cache_entries(&pool, CacheKind::Art, &keys, now_millis()).await?;
+    Ok(true)
+}
+
+/* Fetch art that has gone from the cache since it was synced. Takes the file name the album was
+*  stored with and returns the one the art is saved as now, or None if its source has none. */
+#[tauri::command]
+pub async fn fetch_cover_art(
+    cover_art: String,
+    app_handle: AppHandle,
+) -> Result<Option<String>, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let album = get_album_by_cover_art(&pool, &cover_art)
+        .await?
+        .ok_or_else(|| CommandError::invalid_argument("No album uses this cover art"))?;
+    let library = get_library(&pool, &album.library_id)
+        .await?
+        .ok_or_else(|| CommandError::invalid_argument("Unknown library"))?;
+    let source = open_source(&app_handle, library)?;
+    let file_name = refetch_cover_art(source.as_ref(), &pool, &app_handle, album).await?;
+    Ok(file_name)
+}
 
-    let cover_art_path = format!("{}/cover_art", data_dir_string);
-    fs::remove_dir_all(cover_art_path).unwrap();
+#[tauri::command]
+pub async fn get_network_profile(app_handle: AppHandle) -> Result<NetworkProfile, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let profile = get_setting::<NetworkProfile>(&pool, NETWORK_PROFILE_KEY).await?;
+    Ok(profile.unwrap_or_default())
+}
+
+/* Switch profile. Songs already playing keep their transcode until they are next requested. */
+#[tauri::command]
+pub async fn set_network_profile(
+    profile: NetworkProfile,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    set_setting(&pool, NETWORK_PROFILE_KEY, &profile).await?;
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn get_transcode_settings(
+    library_id: String,
+    profile: NetworkProfile,
+    app_handle: AppHandle,
+) -> Result<TranscodeSettings, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let settings = crate::db::get_transcode_settings(&pool, &library_id, profile).await?;
+    Ok(settings.unwrap_or_default())
+}
+
+#[tauri::command]
+pub async fn set_transcode_settings(
+    library_id: String,
+    profile: NetworkProfile,
+    settings: TranscodeSettings,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    //Sent to the server as is, so only plain format names are accepted
+    if let Some(format) = &settings.format {
+        if format.is_empty() || !format.chars().all(|c| c.is_ascii_alphanumeric()) {
+            let message = format!("Invalid transcode format: {}", format);
+            return Err(CommandError::invalid_argument(message));
+        }
+    }
+    let pool = db_connect(&app_handle).await?;
+    crate::db::set_transcode_settings(&pool, &library_id, profile, &settings).await?;
+    Ok(true)
+}
+
+/* Replace the playback queue and play it from `index`, `position` seconds in when resuming a saved queue */
+#[tauri::command]
+pub async fn play_queue(
+    items: Vec<PlaybackItem>,
+    index: usize,
+    position: Option<f64>,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    if index >= items.len() {
+        return Err(CommandError::invalid_argument("Queue index out of range"));
+    }
+    let position = position.unwrap_or(0.0).max(0.0);
+    player.send(
+        &app_handle,
+        PlayerCommand::PlayQueue {
+            items,
+            index,
+            position,
+        },
+    );
+    Ok(true)
+}
+
+/* Replace the playback queue after it was edited. `index` is the position of the playing song in
+*  it, if it is still there. */
+#[tauri::command]
+pub async fn update_queue(
+    items: Vec<PlaybackItem>,
+    index: Option<usize>,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    player.send(&app_handle, PlayerCommand::UpdateQueue { items, index });
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn play(player: State<'_, Player>, app_handle: AppHandle) -> Result<bool, CommandError> {
+    player.send(&app_handle, PlayerCommand::Play);
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn pause(player: State<'_, Player>, app_handle: AppHandle) -> Result<bool, CommandError> {
+    player.send(&app_handle, PlayerCommand::Pause);
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn seek(
+    position: f64,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    if !position.is_finite() || position < 0.0 {
+        return Err(CommandError::invalid_argument("Invalid seek position"));
+    }
+    player.send(&app_handle, PlayerCommand::Seek(position));
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn next_track(
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    player.send(&app_handle, PlayerCommand::Next);
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn previous_track(
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    player.send(&app_handle, PlayerCommand::Previous);
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn set_volume(
+    volume: f32,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    player.send(&app_handle, PlayerCommand::SetVolume(volume));
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn get_playback_settings(
+    app_handle: AppHandle,
+) -> Result<PlaybackSettings, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let settings = get_setting::<PlaybackSettings>(&pool, PLAYBACK_SETTINGS_KEY).await?;
+    Ok(settings.unwrap_or_default())
+}
+
+/* Crossfade and ReplayGain settings. Applied to the song already playing as well as later ones. */
+#[tauri::command]
+pub async fn set_playback_settings(
+    settings: PlaybackSettings,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    if !(0.0..=12.0).contains(&settings.crossfade_seconds) {
+        return Err(CommandError::invalid_argument(
+            "Crossfade must be between 0 and 12 seconds",
+        ));
+    }
+    if !(-15.0..=15.0).contains(&settings.replay_gain_preamp_db) {
+        return Err(CommandError::invalid_argument(
+            "Preamp must be between -15 and 15 dB",
+        ));
+    }
+    let pool = db_connect(&app_handle).await?;
+    set_setting(&pool, PLAYBACK_SETTINGS_KEY, &settings).await?;
+    player.send(&app_handle, PlayerCommand::SetSettings(settings));
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn get_dsp_presets(app_handle: AppHandle) -> Result<Vec<DspPreset>, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    Ok(crate::db::get_dsp_presets(&pool).await?)
+}
+
+/* Create or update a preset, returning its ID */
+#[tauri::command]
+pub async fn save_dsp_preset(
+    preset: DspPreset,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<i64, CommandError> {
+    validate_dsp_settings(&preset.settings)?;
+    let pool = db_connect(&app_handle).await?;
+    let id = crate::db::save_dsp_preset(&pool, &preset).await?;
+    reload_dsp_presets(&pool, &player, &app_handle).await?;
+    Ok(id)
+}
+
+#[tauri::command]
+pub async fn delete_dsp_preset(
+    id: i64,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    crate::db::delete_dsp_preset(&pool, id).await?;
+    reload_dsp_presets(&pool, &player, &app_handle).await?;
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn get_active_dsp_preset(app_handle: AppHandle) -> Result<Option<i64>, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    let id = get_setting::<Option<i64>>(&pool, DSP_PRESET_KEY).await?;
+    Ok(id.flatten())
+}
+
+/* The preset for songs whose library and output device have none bound. None turns the chain off. */
+#[tauri::command]
+pub async fn set_active_dsp_preset(
+    id: Option<i64>,
+    player: State<'_, Player>,
+    app_handle: AppHandle,
+) -> Result<bool, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    set_setting(&pool, DSP_PRESET_KEY, &id).await?;
+    reload_dsp_presets(&pool, &player, &app_handle).await?;
+    Ok(true)
+}
+
+/* Gain in dB of DSP settings at each frequency, for drawing the EQ curve */
+#[tauri::command]
+pub async fn get_dsp_response(
+    settings: DspSettings,
+    frequencies: Vec<f64>,
+) -> Result<Vec<f64>, CommandError> {
+    validate_dsp_settings(&settings)?;
+    Ok(dsp_response(&settings, &frequencies))
+}
+
+#[tauri::command]
+pub async fn get_output_devices() -> Result<Vec<String>, CommandError> {
+    Ok(output_devices()?)
+}
+
+fn validate_dsp_settings(settings: &DspSettings) -> Result<(), CommandError> {
+    if !(-24.0..=24.0).contains(&settings.preamp_db) {
+        return Err(CommandError::invalid_argument(
+            "Preamp must be between -24 and 24 dB",
+        ));
+    }
+    if !(-1.0..=1.0).contains(&settings.balance) {
+        return Err(CommandError::invalid_argument(
+            "Balance must be between -1 and 1",
+        ));
+    }
+    for band in &settings.bands {
+        if !(20.0..=20000.0).contains(&band.frequency) {
+            return Err(CommandError::invalid_argument(
+                "Band frequencies must be between 20 Hz and 20 kHz",
+            ));
+        }
+        if !(-24.0..=24.0).contains(&band.gain_db) {
+            return Err(CommandError::invalid_argument(
+                "Band gains must be between -24 and 24 dB",
+            ));
+        }
+        if !(0.1..=20.0).contains(&band.q) {
+            return Err(CommandError::invalid_argument(
+                "Band Q must be between 0.1 and 20",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/* Hand the engine the current presets, so changes apply to the song playing */
+async fn reload_dsp_presets(
+    pool: &Pool<Sqlite>,
+    player: &Player,
+    app_handle: &AppHandle,
+) -> Result<(), CommandError> {
+    let presets = load_dsp_presets(pool).await?;
+    player.send(app_handle, PlayerCommand::SetDspPresets(presets));
+    Ok(())
+}
+
+/* The queue as it was when the app was last closed */
+#[tauri::command]
+pub async fn get_saved_queue(app_handle: AppHandle) -> Result<SavedQueue, CommandError> {
+    let pool = db_connect(&app_handle).await?;
+    Ok(crate::db::get_saved_queue(&pool).await?)
+}
+
+/* Save the local queue to a server, for other clients to continue from. Only the library's own
+*  songs can be saved there, so songs from other libraries are left out. */
+#[tauri::command]
+pub async fn save_server_play_queue(
+    library: Library,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<bool, CommandError> {
+    require_subsonic(&library)?;
+    let pool = db_connect(&app_handle).await?;
+    let queue = crate::db::get_saved_queue(&pool).await?;
+    let song_ids: Vec<&str> = queue
+        .items
+        .iter()
+        .filter(|item| item.library_id == library.id)
+        .map(|item| strip_namespace(&library.id, &item.song_id))
+        .collect();
+    let current = queue
+        .index
+        .and_then(|index| queue.items.get(index))
+        .filter(|item| item.library_id == library.id)
+        .map(|item| strip_namespace(&library.id, &item.song_id));
+    let position_ms = (queue.position * 1000.0) as i64;
+    save_play_queue(&client, &library, &song_ids, current, position_ms).await?;
+    Ok(true)
+}
+
+/* The queue last saved to a server, by this or another client. None if there isn't one. */
+#[tauri::command]
+pub async fn get_server_play_queue(
+    library: Library,
+    client: State<'_, ServerClient>,
+) -> Result<Option<SavedQueue>, CommandError> {
+    require_subsonic(&library)?;
+    let response = get_play_queue(&client, &library).await?;
+    let play_queue = match response.data.play_queue {
+        Some(play_queue) => play_queue,
+        None => return Ok(None),
+    };
+    let index = play_queue
+        .current
+        .as_ref()
+        .and_then(|current| play_queue.entry.iter().position(|song| &song.id == current));
+    let changed = play_queue
+        .changed
+        .as_ref()
+        .and_then(|changed| chrono::DateTime::parse_from_rfc3339(changed).ok())
+        .map_or(0, |changed| changed.timestamp_millis());
+    let items = play_queue
+        .entry
+        .iter()
+        .map(|song| PlaybackItem {
+            library_id: library.id.clone(),
+            song_id: namespace_id(&library.id, &song.id),
+        })
+        .collect();
+    Ok(Some(SavedQueue {
+        items,
+        index,
+        position: play_queue.position.unwrap_or(0) as f64 / 1000.0,
+        changed,
+    }))
+}
+
+#[tauri::command]
+pub async fn create_playlist(
+    library: Library,
+    name: String,
+    song_ids: Vec<String>,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<Playlist, CommandError> {
+    require_subsonic(&library)?;
+    if name.trim().is_empty() {
+        return Err(CommandError::invalid_argument(
+            "Playlist name can't be empty",
+        ));
+    }
+    let song_ids: Vec<&str> = song_ids
+        .iter()
+        .map(|song_id| strip_namespace(&library.id, song_id))
+        .collect();
+    let response = crate::subsonic::create_playlist(&client, &library, &name, &song_ids).await?;
+    let playlist_id = match response.data.playlist {
+        Some(playlist) => playlist.info.id,
+        //Older servers don't say which playlist they made, so take the newest with the name
+        None => get_playlists(&client, &library)
+            .await?
+            .into_iter()
+            .filter(|playlist| playlist.name == name)
+            .max_by(|a, b| a.created.cmp(&b.created))
+            .map(|playlist| playlist.id)
+            .ok_or_else(|| CommandError::internal("Created playlist not found"))?,
+    };
+    let pool = db_connect(&app_handle).await?;
+    let source = SubsonicSource::new(library, client.inner().clone());
+    let playlist = refresh_playlist(&source, &pool, &playlist_id).await?;
+    Ok(playlist)
+}
+
+/* Rename a playlist, and add or remove songs. `song_indexes_to_remove` are positions in the
+*  playlist before the update; added songs go on the end. */
+#[tauri::command]
+pub async fn update_playlist(
+    library: Library,
+    playlist_id: String,
+    name: Option<String>,
+    song_ids_to_add: Vec<String>,
+    song_indexes_to_remove: Vec<u32>,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<Playlist, CommandError> {
+    require_subsonic(&library)?;
+    if name.as_ref().is_some_and(|name| name.trim().is_empty()) {
+        return Err(CommandError::invalid_argument(
+            "Playlist name can't be empty",
+        ));
+    }
+    let server_id = strip_namespace(&library.id, &playlist_id);
+    let song_ids: Vec<&str> = song_ids_to_add
+        .iter()
+        .map(|song_id| strip_namespace(&library.id, song_id))
+        .collect();
+    crate::subsonic::update_playlist(
+        &client,
+        &library,
+        server_id,
+        name.as_deref(),
+        &song_ids,
+        &song_indexes_to_remove,
+    )
+    .await?;
+    let pool = db_connect(&app_handle).await?;
+    let source = SubsonicSource::new(library, client.inner().clone());
+    let playlist = refresh_playlist(&source, &pool, server_id).await?;
+    Ok(playlist)
+}
+
+/* Put a playlist's songs in a new order. There is no move in the API, so every entry is removed
+*  and added back in one update. `song_ids` must be the playlist's current songs. */
+#[tauri::command]
+pub async fn reorder_playlist(
+    library: Library,
+    playlist_id: String,
+    song_ids: Vec<String>,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<Playlist, CommandError> {
+    require_subsonic(&library)?;
+    let server_id = strip_namespace(&library.id, &playlist_id);
+    let song_ids: Vec<&str> = song_ids
+        .iter()
+        .map(|song_id| strip_namespace(&library.id, song_id))
+        .collect();
+    let current = get_playlist_songs(&client, &library, server_id).await?;
+    let mut current_ids: Vec<&str> = current
+        .data
+        .playlist
+        .entry
+        .iter()
+        .map(|song| song.id.as_str())
+        .collect();
+    //Guards against dropping songs added elsewhere since the playlist was loaded
+    let mut sorted_ids = song_ids.clone();
+    sorted_ids.sort();
+    current_ids.sort();
+    if sorted_ids != current_ids {
+        return Err(CommandError::invalid_argument(
+            "Playlist has changed, reload it and try again",
+        ));
+    }
+    let indexes: Vec<u32> = (0..current_ids.len() as u32).collect();
+    crate::subsonic::update_playlist(&client, &library, server_id, None, &song_ids, &indexes)
+        .await?;
+    let pool = db_connect(&app_handle).await?;
+    let source = SubsonicSource::new(library, client.inner().clone());
+    let playlist = refresh_playlist(&source, &pool, server_id).await?;
+    Ok(playlist)
+}
+
+#[tauri::command]
+pub async fn delete_playlist(
+    library: Library,
+    playlist_id: String,
+    app_handle: AppHandle,
+    client: State<'_, ServerClient>,
+) -> Result<bool, CommandError> {
+    require_subsonic(&library)?;
+    crate::subsonic::delete_playlist(
+        &client,
+        &library,
+        strip_namespace(&library.id, &playlist_id),
+    )
+    .await?;
+    let pool = db_connect(&app_handle).await?;
+    crate::db::delete_playlist(&pool, &playlist_id).await?;
     Ok(true)
 }
@@ -1,11 +1,19 @@
-use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use sqlx::{Pool, Sqlite};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{Pool, QueryBuilder, Sqlite, SqliteConnection};
 use tauri::{AppHandle, Manager};
 
 use crate::{
     formatter::get_library_hash,
-    models::{Album, Artist, DBLibrary, Library, Playlist, Song},
+    models::{
+        Album, Artist, CacheEntry, CacheKind, DBLibrary, Download, DownloadPin, DspPreset, Library,
+        LibraryKind, LocalFile, NetworkProfile, PlaybackItem, Playlist, QueuedScrobble,
+        RatingTarget, ReplayGain, SavedQueue, Song, TableDiff, TranscodeSettings,
+    },
 };
 
 pub async fn db_connect(app_handle: &AppHandle) -> Result<Pool<Sqlite>, anyhow::Error> {
@@ -17,20 +25,31 @@ pub async fn db_connect(app_handle: &AppHandle) -> Result<Pool<Sqlite>, anyhow::
     Ok(pool)
 }
 
-pub async fn insert_library(pool: &Pool<Sqlite>, library: &Library) -> Result<(), anyhow::Error> {
-    //TODO: Check if there's a more efficient way to do this
+/* Rows per multi-row INSERT. Songs bind 15 values per row, which keeps a full batch well under
+*  SQLite's bound parameter limit. Full batches share the same SQL text, so sqlx's per-connection
+*  statement cache prepares each statement once per sync rather than once per row. */
+const INSERT_BATCH_SIZE: usize = 500;
+
+pub async fn insert_library(
+    conn: &mut SqliteConnection,
+    library: &Library,
+) -> Result<(), anyhow::Error> {
     let library_id = &library.id;
     let library_name = &library.name;
     let library_host = &library.host;
     let library_port = library.port.unwrap_or(-1);
     let library_username = &library.username;
     let library_salt = &library.salt;
+    let library_user_id = &library.user_id;
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH);
     let library_last_scanned = since_the_epoch.unwrap().as_millis().to_string();
+    let library_paths = serde_json::to_string(&library.paths)?;
 
+    //The folders of a local library can change after it was first added
     sqlx::query(
-        "INSERT OR IGNORE INTO libraries (id, name, host, port, username, salt, last_scanned) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        "INSERT INTO libraries (id, name, host, port, username, salt, last_scanned, kind, paths, user_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(id) DO UPDATE SET paths = excluded.paths",
     )
     .bind(library_id)
     .bind(library_name)
@@ -39,200 +58,407 @@ pub async fn insert_library(pool: &Pool<Sqlite>, library: &Library) -> Result<()
     .bind(library_username)
     .bind(library_salt)
     .bind(library_last_scanned)
-    .execute(pool)
+    .bind(library.kind.as_str())
+    .bind(library_paths)
+    .bind(library_user_id)
+    .execute(&mut *conn)
     .await?;
     Ok(())
 }
 
 pub async fn insert_artists(
-    pool: &Pool<Sqlite>,
-    artists: &Vec<Artist>,
-    artist_ids: &Vec<String>,
-) -> Result<(), anyhow::Error> {
-    //TODO: Check if there's a more efficient way to do this
-    for artist in artists {
-        let artist_id = &artist.id;
-        let artist_name = &artist.name;
-        let library_id = &artist.library_id;
-
-        sqlx::query("INSERT OR IGNORE INTO artists (id, name, library_id) VALUES (?, ?, ?)")
-            .bind(artist_id)
-            .bind(artist_name)
-            .bind(library_id)
-            .execute(pool)
-            .await?;
-    }
-
-    let placeholders: String = artist_ids
-        .iter()
-        .map(|_| "?")
-        .collect::<Vec<_>>()
-        .join(", ");
-    let query = format!("DELETE FROM artists WHERE id NOT IN ({})", placeholders);
-    let mut query_object = sqlx::query(&query);
-    for id in artist_ids {
-        query_object = query_object.bind(id);
+    conn: &mut SqliteConnection,
+    library_id: &String,
+    artists: &[Artist],
+) -> Result<TableDiff, anyhow::Error> {
+    let existing_ids = get_existing_ids(conn, "artists", library_id).await?;
+    let mut changed_ids: Vec<String> = vec![];
+    for chunk in artists.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO artists (id, name, library_id, starred, rating) ",
+        );
+        query_builder.push_values(chunk, |mut row, artist| {
+            row.push_bind(&artist.id)
+                .push_bind(&artist.name)
+                .push_bind(&artist.library_id)
+                .push_bind(&artist.starred)
+                .push_bind(artist.rating);
+        });
+        query_builder.push(
+            " ON CONFLICT(id) DO UPDATE SET name = excluded.name, starred = excluded.starred,
+            rating = excluded.rating
+            WHERE artists.name IS NOT excluded.name OR artists.starred IS NOT excluded.starred
+            OR artists.rating IS NOT excluded.rating
+            RETURNING id",
+        );
+        changed_ids.extend(
+            query_builder
+                .build_query_scalar::<String>()
+                .fetch_all(&mut *conn)
+                .await?,
+        );
     }
 
-    query_object.execute(pool).await?;
+    Ok(to_table_diff(&existing_ids, changed_ids, vec![]))
+}
 
-    Ok(())
+/* Remove a library's artists that weren't part of the latest sync. Run once albums are written,
+*  as an album still pointing at a removed artist would fail its foreign key. */
+pub async fn delete_missing_artists(
+    conn: &mut SqliteConnection,
+    library_id: &String,
+    artist_ids: &[String],
+) -> Result<Vec<String>, anyhow::Error> {
+    delete_missing(conn, "artists", library_id, artist_ids).await
 }
 
 pub async fn insert_albums(
-    pool: &Pool<Sqlite>,
-    albums: &Vec<Album>,
-    album_ids: &Vec<String>,
+    conn: &mut SqliteConnection,
+    library_id: &String,
+    albums: &[Album],
+    album_ids: &[String],
     cover_art_map: &HashMap<String, String>,
-) -> Result<(), anyhow::Error> {
-    //TODO: Check if there's a more efficient way to do this
+) -> Result<TableDiff, anyhow::Error> {
+    let existing_ids = get_existing_ids(conn, "albums", library_id).await?;
+    let mut changed_ids: Vec<String> = vec![];
     let blank_string = String::from("");
-    for album in albums {
-        let album_id = &album.id;
-        let album_name = &album.name;
-        let album_artist_id = &album.artist_id;
-        let album_artist_name = &album.artist_name;
-        // The ID of the library that this album belongs to.
-        let album_library_id = &album.library_id;
-        let album_cover_art = cover_art_map.get(&album.cover_art).unwrap_or(&blank_string);
-        let album_year = album.year.unwrap_or(9999);
-        let album_duration = album.duration;
-
-        sqlx::query(
-            "INSERT OR IGNORE INTO albums (id, name, artist_id, artist_name, library_id, cover_art, year, duration) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
-        )
-        .bind(album_id)
-        .bind(album_name)
-        .bind(album_artist_id)
-        .bind(album_artist_name)
-        .bind(album_library_id)
-        .bind(album_cover_art)
-        .bind(album_year)
-        .bind(album_duration)
-        .execute(pool)
-        .await?;
+    for chunk in albums.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO albums (id, name, artist_id, artist_name, library_id, cover_art, year, duration, starred, rating) ",
+        );
+        query_builder.push_values(chunk, |mut row, album| {
+            row.push_bind(&album.id)
+                .push_bind(&album.name)
+                .push_bind(&album.artist_id)
+                .push_bind(&album.artist_name)
+                // The ID of the library that this album belongs to.
+                .push_bind(&album.library_id)
+                .push_bind(cover_art_map.get(&album.cover_art).unwrap_or(&blank_string))
+                .push_bind(album.year.unwrap_or(9999))
+                .push_bind(album.duration)
+                .push_bind(&album.starred)
+                .push_bind(album.rating);
+        });
+        // Art that couldn't be fetched this time keeps whatever was cached before
+        query_builder.push(
+            " ON CONFLICT(id) DO UPDATE SET name = excluded.name, artist_id = excluded.artist_id,
+            artist_name = excluded.artist_name, cover_art = COALESCE(NULLIF(excluded.cover_art, ''), albums.cover_art),
+            year = excluded.year, duration = excluded.duration, starred = excluded.starred, rating = excluded.rating
+            WHERE albums.name IS NOT excluded.name OR albums.artist_id IS NOT excluded.artist_id
+            OR albums.artist_name IS NOT excluded.artist_name OR albums.year IS NOT excluded.year
+            OR albums.duration IS NOT excluded.duration OR albums.starred IS NOT excluded.starred
+            OR albums.rating IS NOT excluded.rating
+            OR (excluded.cover_art != '' AND albums.cover_art IS NOT excluded.cover_art)
+            RETURNING id",
+        );
+        changed_ids.extend(
+            query_builder
+                .build_query_scalar::<String>()
+                .fetch_all(&mut *conn)
+                .await?,
+        );
+    }
+
+    let removed_ids = delete_missing(conn, "albums", library_id, album_ids).await?;
+    Ok(to_table_diff(&existing_ids, changed_ids, removed_ids))
+}
+
+pub async fn insert_songs(
+    conn: &mut SqliteConnection,
+    library_id: &String,
+    songs: &[Song],
+    song_ids: &[String],
+    cover_art_map: &HashMap<String, String>,
+) -> Result<TableDiff, anyhow::Error> {
+    let existing_ids = get_existing_ids(conn, "songs", library_id).await?;
+    let mut changed_ids: Vec<String> = vec![];
+    let blank_string = String::from("");
+    for chunk in songs.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO songs (id, title, artist_id, artist_name, album_id, album_name, library_id, track, disc_number, year, duration, content_type, cover_art, starred, rating, replay_gain_track, replay_gain_album, replay_gain_track_peak, replay_gain_album_peak) ",
+        );
+        query_builder.push_values(chunk, |mut row, song| {
+            row.push_bind(&song.id)
+                .push_bind(&song.title)
+                .push_bind(&song.artist_id)
+                .push_bind(&song.artist_name)
+                .push_bind(&song.album_id)
+                .push_bind(&song.album_name)
+                .push_bind(&song.library_id)
+                .push_bind(song.track.unwrap_or(0))
+                .push_bind(song.disc_number)
+                .push_bind(song.year)
+                .push_bind(song.duration.unwrap_or(0))
+                .push_bind(&song.content_type)
+                .push_bind(cover_art_map.get(&song.cover_art).unwrap_or(&blank_string))
+                .push_bind(&song.starred)
+                .push_bind(song.rating)
+                .push_bind(song.replay_gain_track)
+                .push_bind(song.replay_gain_album)
+                .push_bind(song.replay_gain_track_peak)
+                .push_bind(song.replay_gain_album_peak);
+        });
+        query_builder.push(
+            " ON CONFLICT(id) DO UPDATE SET title = excluded.title, artist_id = excluded.artist_id,
+            artist_name = excluded.artist_name, album_id = excluded.album_id, album_name = excluded.album_name,
+            track = excluded.track, disc_number = excluded.disc_number, year = excluded.year,
+            duration = excluded.duration, content_type = excluded.content_type,
+            cover_art = COALESCE(NULLIF(excluded.cover_art, ''), songs.cover_art),
+            starred = excluded.starred, rating = excluded.rating,
+            replay_gain_track = excluded.replay_gain_track, replay_gain_album = excluded.replay_gain_album,
+            replay_gain_track_peak = excluded.replay_gain_track_peak, replay_gain_album_peak = excluded.replay_gain_album_peak
+            WHERE songs.title IS NOT excluded.title OR songs.artist_id IS NOT excluded.artist_id
+            OR songs.artist_name IS NOT excluded.artist_name OR songs.album_id IS NOT excluded.album_id
+            OR songs.album_name IS NOT excluded.album_name OR songs.track IS NOT excluded.track
+            OR songs.disc_number IS NOT excluded.disc_number OR songs.year IS NOT excluded.year
+            OR songs.duration IS NOT excluded.duration OR songs.content_type IS NOT excluded.content_type
+            OR songs.starred IS NOT excluded.starred OR songs.rating IS NOT excluded.rating
+            OR songs.replay_gain_track IS NOT excluded.replay_gain_track OR songs.replay_gain_album IS NOT excluded.replay_gain_album
+            OR songs.replay_gain_track_peak IS NOT excluded.replay_gain_track_peak
+            OR songs.replay_gain_album_peak IS NOT excluded.replay_gain_album_peak
+            OR (excluded.cover_art != '' AND songs.cover_art IS NOT excluded.cover_art)
+            RETURNING id",
+        );
+        changed_ids.extend(
+            query_builder
+                .build_query_scalar::<String>()
+                .fetch_all(&mut *conn)
+                .await?,
+        );
     }
 
-    let placeholders: String = album_ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
-    let query = format!("DELETE FROM albums WHERE id NOT IN ({})", placeholders);
-    let mut query_object = sqlx::query(&query);
-    for id in album_ids {
-        query_object = query_object.bind(id);
+    let removed_ids = delete_missing(conn, "songs", library_id, song_ids).await?;
+    Ok(to_table_diff(&existing_ids, changed_ids, removed_ids))
+}
+
+pub async fn insert_playlists(
+    conn: &mut SqliteConnection,
+    library_id: &String,
+    playlists: &[Playlist],
+    playlist_ids: &[String],
+) -> Result<TableDiff, anyhow::Error> {
+    let existing_ids = get_existing_ids(conn, "playlists", library_id).await?;
+    let mut changed_ids: Vec<String> = vec![];
+    for chunk in playlists.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO playlists (id, library_id, name, owner, created, modified, song_count, duration) ",
+        );
+        query_builder.push_values(chunk, |mut row, playlist| {
+            row.push_bind(&playlist.id)
+                .push_bind(&playlist.library_id)
+                .push_bind(&playlist.name)
+                .push_bind(&playlist.owner)
+                .push_bind(&playlist.created)
+                .push_bind(&playlist.modified)
+                .push_bind(playlist.song_count)
+                .push_bind(playlist.duration);
+        });
+        query_builder.push(
+            " ON CONFLICT(id) DO UPDATE SET name = excluded.name, owner = excluded.owner,
+            created = excluded.created, modified = excluded.modified,
+            song_count = excluded.song_count, duration = excluded.duration
+            WHERE playlists.name IS NOT excluded.name OR playlists.owner IS NOT excluded.owner
+            OR playlists.created IS NOT excluded.created OR playlists.modified IS NOT excluded.modified
+            OR playlists.song_count IS NOT excluded.song_count OR playlists.duration IS NOT excluded.duration
+            RETURNING id",
+        );
+        changed_ids.extend(
+            query_builder
+                .build_query_scalar::<String>()
+                .fetch_all(&mut *conn)
+                .await?,
+        );
     }
 
-    query_object.execute(pool).await?;
+    let removed_ids = delete_missing(conn, "playlists", library_id, playlist_ids).await?;
+    Ok(to_table_diff(&existing_ids, changed_ids, removed_ids))
+}
 
+pub async fn upsert_playlist(
+    pool: &Pool<Sqlite>,
+    playlist: &Playlist,
+    song_ids: &[String],
+) -> Result<(), anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO playlists (id, library_id, name, owner, created, modified, song_count, duration)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&playlist.id)
+    .bind(&playlist.library_id)
+    .bind(&playlist.name)
+    .bind(&playlist.owner)
+    .bind(&playlist.created)
+    .bind(&playlist.modified)
+    .bind(playlist.song_count)
+    .bind(playlist.duration)
+    .execute(&mut *tx)
+    .await?;
+    replace_playlist_songs(&mut tx, &playlist.id, song_ids).await?;
+    tx.commit().await?;
     Ok(())
 }
 
-pub async fn insert_songs(
+pub async fn delete_playlist(
     pool: &Pool<Sqlite>,
-    songs: &Vec<Song>,
-    song_ids: &Vec<String>,
-    cover_art_map: &HashMap<String, String>,
+    playlist_id: &String,
 ) -> Result<(), anyhow::Error> {
-    //TODO: Check if there's a more efficient way to do this
-    let blank_string = String::from("");
-    for song in songs {
-        let song_id = &song.id;
-        let song_title = &song.title;
-        let song_artist_id = &song.artist_id;
-        let song_artist_name = &song.artist_name;
-        let song_album_id = &song.album_id;
-        let song_album_name = &song.album_name;
-        let song_library_id = &song.library_id;
-        let song_track = song.track.unwrap_or(0);
-        let song_disc_number = song.disc_number;
-        let song_duration = song.duration.unwrap_or(0);
-        let song_content_type = &song.content_type;
-        let song_cover_art = cover_art_map.get(&song.cover_art).unwrap_or(&blank_string);
-
-        sqlx::query(
-            "INSERT OR IGNORE INTO songs (id, title, artist_id, artist_name, album_id, album_name, library_id, track, disc_number, duration, content_type, cover_art) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-        )
-        .bind(song_id)
-        .bind(song_title)
-        .bind(song_artist_id)
-        .bind(song_artist_name)
-        .bind(song_album_id)
-        .bind(song_album_name)
-        .bind(song_library_id)
-        .bind(song_track)
-        .bind(song_disc_number)
-        .bind(song_duration)
-        .bind(song_content_type)
-        .bind(song_cover_art)
-        .execute(pool)
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM playlist_songs WHERE playlist_id = ?")
+        .bind(playlist_id)
+        .execute(&mut *tx)
         .await?;
-    }
+    sqlx::query("DELETE FROM playlists WHERE id = ?")
+        .bind(playlist_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
 
-    let placeholders: String = song_ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
-    let query = format!("DELETE FROM songs WHERE id NOT IN ({})", placeholders);
-    let mut query_object = sqlx::query(&query);
-    for id in song_ids {
-        query_object = query_object.bind(id);
-    }
+/* `modified` of a library's playlists whose entries are stored. Playlists missing from here need
+*  their entries fetched. */
+pub async fn get_stored_playlist_versions(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<HashMap<String, String>, anyhow::Error> {
+    let versions = sqlx::query_as::<_, (String, String)>(
+        "SELECT id, modified FROM playlists WHERE library_id = ?
+        AND (song_count = 0 OR EXISTS (SELECT 1 FROM playlist_songs WHERE playlist_id = playlists.id))",
+    )
+    .bind(library_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(versions.into_iter().collect())
+}
 
-    query_object.execute(pool).await?;
+pub async fn get_playlist_song_ids(
+    pool: &Pool<Sqlite>,
+    playlist_id: &String,
+) -> Result<Vec<String>, anyhow::Error> {
+    let song_ids = sqlx::query_scalar::<_, String>(
+        "SELECT song_id FROM playlist_songs WHERE playlist_id = ? ORDER BY position",
+    )
+    .bind(playlist_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(song_ids)
+}
+
+pub async fn replace_playlist_songs(
+    conn: &mut SqliteConnection,
+    playlist_id: &String,
+    song_ids: &[String],
+) -> Result<(), anyhow::Error> {
+    sqlx::query("DELETE FROM playlist_songs WHERE playlist_id = ?")
+        .bind(playlist_id)
+        .execute(&mut *conn)
+        .await?;
+    let numbered: Vec<(usize, &String)> = song_ids.iter().enumerate().collect();
+    for chunk in numbered.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO playlist_songs (playlist_id, position, song_id) ",
+        );
+        query_builder.push_values(chunk, |mut row, (position, song_id)| {
+            row.push_bind(playlist_id)
+                .push_bind(*position as i64)
+                .push_bind(*song_id);
+        });
+        query_builder.build().execute(&mut *conn).await?;
+    }
     Ok(())
 }
 
-pub async fn insert_playlists(
-    pool: &Pool<Sqlite>,
-    playlists: &Vec<Playlist>,
-    playlist_ids: &Vec<String>,
+/* Entries of playlists that no longer exist */
+pub async fn delete_orphaned_playlist_songs(
+    conn: &mut SqliteConnection,
 ) -> Result<(), anyhow::Error> {
-    for playlist in playlists {
-        sqlx::query(
-            "INSERT OR IGNORE INTO playlists (id, library_id, name, owner, created, modified, song_count, duration) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
-        )
-        .bind(playlist.id.clone())
-        .bind(playlist.library_id.clone())
-        .bind(playlist.name.clone())
-        .bind(playlist.owner.clone())
-        .bind(playlist.created.clone())
-        .bind(playlist.modified.clone())
-        .bind(playlist.song_count)
-        .bind(playlist.duration)
-        .execute(pool)
+    sqlx::query("DELETE FROM playlist_songs WHERE playlist_id NOT IN (SELECT id FROM playlists)")
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+async fn get_existing_ids(
+    conn: &mut SqliteConnection,
+    table: &str,
+    library_id: &String,
+) -> Result<HashSet<String>, anyhow::Error> {
+    let query = format!("SELECT id FROM {} WHERE library_id = ?", table);
+    let ids = sqlx::query_scalar::<_, String>(&query)
+        .bind(library_id)
+        .fetch_all(&mut *conn)
         .await?;
+    Ok(ids.into_iter().collect())
+}
+
+/* Upserts return every row they inserted or changed; rows that already existed were updated */
+fn to_table_diff(
+    existing_ids: &HashSet<String>,
+    changed_ids: Vec<String>,
+    removed_ids: Vec<String>,
+) -> TableDiff {
+    let (updated, added): (Vec<String>, Vec<String>) = changed_ids
+        .into_iter()
+        .partition(|id| existing_ids.contains(id));
+    TableDiff {
+        added,
+        updated,
+        removed: removed_ids,
     }
+}
 
-    let placeholders: String = playlist_ids
-        .iter()
-        .map(|_| "?")
-        .collect::<Vec<_>>()
-        .join(", ");
-    let query = format!("DELETE FROM playlists WHERE id NOT IN ({})", placeholders);
-    let mut query_object = sqlx::query(&query);
-    for id in playlist_ids {
-        query_object = query_object.bind(id);
+/* Remove a library's rows that weren't part of the latest sync. The synced IDs are staged in a
+*  temp table instead of a NOT IN (?, ?, ...) list, which large libraries would push past SQLite's
+*  bound parameter limit. */
+async fn delete_missing(
+    conn: &mut SqliteConnection,
+    table: &str,
+    library_id: &String,
+    ids: &[String],
+) -> Result<Vec<String>, anyhow::Error> {
+    sqlx::query("CREATE TEMP TABLE IF NOT EXISTS synced_ids (id TEXT PRIMARY KEY)")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM temp.synced_ids")
+        .execute(&mut *conn)
+        .await?;
+    for chunk in ids.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder =
+            QueryBuilder::<Sqlite>::new("INSERT OR IGNORE INTO temp.synced_ids (id) ");
+        query_builder.push_values(chunk, |mut row, id| {
+            row.push_bind(id);
+        });
+        query_builder.build().execute(&mut *conn).await?;
     }
 
-    query_object.execute(pool).await?;
-    Ok(())
+    let query = format!(
+        "DELETE FROM {} WHERE library_id = ? AND id NOT IN (SELECT id FROM temp.synced_ids) RETURNING id",
+        table
+    );
+    let removed_ids = sqlx::query_scalar::<_, String>(&query)
+        .bind(library_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(removed_ids)
 }
 
 pub async fn get_libraries(app_handle: &AppHandle) -> Result<Vec<Library>, anyhow::Error> {
     let mut libraries_with_hash: Vec<Library> = Vec::new();
     let db = db_connect(app_handle).await?;
     let libraries = sqlx::query_as::<_, DBLibrary>(
-        "SELECT id, name, host, port, username, salt, last_scanned FROM libraries ORDER BY id COLLATE NOCASE ASC",
+        "SELECT id, name, host, port, username, salt, last_scanned, kind, paths, user_id FROM libraries ORDER BY id COLLATE NOCASE ASC",
     )
     .fetch_all(&db)
     .await?;
 
     for library in libraries {
-        let mut real_library = Library {
-            id: library.id,
-            name: library.name,
-            host: library.host,
-            port: library.port,
-            username: library.username,
-            hashed_password: "".to_string(),
-            salt: library.salt,
-            last_scanned: library.last_scanned,
-        };
+        let mut real_library = to_library(library);
+        //Local libraries have no password
+        if real_library.kind == LibraryKind::Local {
+            libraries_with_hash.push(real_library);
+            continue;
+        }
         match get_library_hash(&real_library) {
             Ok(hashed_password) => {
                 real_library.hashed_password = hashed_password;
@@ -244,17 +470,854 @@ pub async fn get_libraries(app_handle: &AppHandle) -> Result<Vec<Library>, anyho
     Ok(libraries_with_hash)
 }
 
-pub async fn delete_unused_artists(pool: &Pool<Sqlite>) -> Result<(), anyhow::Error> {
-    let query_object = sqlx::query("DELETE FROM artists WHERE id NOT IN (SELECT artist_id FROM albums)");
-    query_object.execute(pool).await?;
-    Ok(())
+/* A single library, with its hashed password from the keyring */
+pub async fn get_library(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<Option<Library>, anyhow::Error> {
+    let library = sqlx::query_as::<_, DBLibrary>(
+        "SELECT id, name, host, port, username, salt, last_scanned, kind, paths, user_id FROM libraries WHERE id = (?)",
+    )
+    .bind(library_id)
+    .fetch_optional(pool)
+    .await?;
+
+    match library {
+        Some(library) => {
+            let mut real_library = to_library(library);
+            if real_library.kind != LibraryKind::Local {
+                real_library.hashed_password = get_library_hash(&real_library)?;
+            }
+            Ok(Some(real_library))
+        }
+        None => Ok(None),
+    }
+}
+
+/* A stored library, without its hashed password */
+fn to_library(library: DBLibrary) -> Library {
+    Library {
+        id: library.id,
+        name: library.name,
+        host: library.host,
+        port: library.port,
+        username: library.username,
+        hashed_password: "".to_string(),
+        salt: library.salt,
+        last_scanned: library.last_scanned,
+        kind: LibraryKind::from_str(&library.kind),
+        paths: serde_json::from_str(&library.paths).unwrap_or_default(),
+        user_id: library.user_id,
+    }
+}
+
+pub async fn delete_unused_artists(
+    conn: &mut SqliteConnection,
+) -> Result<Vec<String>, anyhow::Error> {
+    let query_object = sqlx::query_scalar::<_, String>(
+        "DELETE FROM artists WHERE id NOT IN (SELECT artist_id FROM albums) RETURNING id",
+    );
+    let removed_ids = query_object.fetch_all(&mut *conn).await?;
+    Ok(removed_ids)
 }
 
-pub async fn update_last_scanned(pool: &Pool<Sqlite>, library_id: &String) -> Result<(), anyhow::Error> {
+pub async fn update_last_scanned(
+    conn: &mut SqliteConnection,
+    library_id: &String,
+) -> Result<(), anyhow::Error> {
     let query_object = sqlx::query("UPDATE libraries SET last_scanned = (?) WHERE id = (?)");
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH);
     let library_last_scanned = since_the_epoch.unwrap().as_millis().to_string();
-    query_object.bind(library_last_scanned).bind(library_id).execute(pool).await?;
+    query_object
+        .bind(library_last_scanned)
+        .bind(library_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_last_scanned(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<Option<String>, anyhow::Error> {
+    let last_scanned = sqlx::query_scalar::<_, Option<String>>(
+        "SELECT last_scanned FROM libraries WHERE id = (?)",
+    )
+    .bind(library_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(last_scanned.flatten())
+}
+
+/* Albums as last synced, so a listing can be compared against them */
+pub async fn get_stored_albums(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<Vec<Album>, anyhow::Error> {
+    let albums = sqlx::query_as::<_, Album>(
+        "SELECT id, name, artist_id, artist_name, library_id, cover_art, year, duration, starred, rating
+        FROM albums WHERE library_id = (?)",
+    )
+    .bind(library_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(albums)
+}
+
+/* An album whose art is saved as the given file (relative to cover_art/) */
+pub async fn get_album_by_cover_art(
+    pool: &Pool<Sqlite>,
+    cover_art: &str,
+) -> Result<Option<Album>, anyhow::Error> {
+    let album = sqlx::query_as::<_, Album>(
+        "SELECT id, name, artist_id, artist_name, library_id, cover_art, year, duration, starred, rating
+        FROM albums WHERE cover_art = (?) LIMIT 1",
+    )
+    .bind(cover_art)
+    .fetch_optional(pool)
+    .await?;
+    Ok(album)
+}
+
+/* Point the albums and songs using one art file at another */
+pub async fn replace_cover_art(
+    pool: &Pool<Sqlite>,
+    old_cover_art: &str,
+    new_cover_art: &str,
+) -> Result<(), anyhow::Error> {
+    for table in ["albums", "songs"] {
+        sqlx::query(&format!(
+            "UPDATE {} SET cover_art = (?) WHERE cover_art = (?)",
+            table
+        ))
+        .bind(new_cover_art)
+        .bind(old_cover_art)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/* (song ID, album ID) pairs for every song in a library */
+pub async fn get_song_album_ids(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let song_album_ids = sqlx::query_as::<_, (String, String)>(
+        "SELECT id, album_id FROM songs WHERE library_id = (?)",
+    )
+    .bind(library_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(song_album_ids)
+}
+
+pub async fn enqueue_scrobble(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+    song_id: &String,
+    played_at: i64,
+) -> Result<(), anyhow::Error> {
+    sqlx::query("INSERT INTO scrobble_queue (library_id, song_id, played_at) VALUES (?, ?, ?)")
+        .bind(library_id)
+        .bind(song_id)
+        .bind(played_at)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_queued_scrobbles(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<Vec<QueuedScrobble>, anyhow::Error> {
+    let scrobbles = sqlx::query_as::<_, QueuedScrobble>(
+        "SELECT id, library_id, song_id, played_at FROM scrobble_queue WHERE library_id = (?) ORDER BY played_at ASC",
+    )
+    .bind(library_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(scrobbles)
+}
+
+pub async fn delete_queued_scrobble(pool: &Pool<Sqlite>, id: i64) -> Result<(), anyhow::Error> {
+    sqlx::query("DELETE FROM scrobble_queue WHERE id = (?)")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/* Set (or clear) the starred timestamp, returning the previous one so it can be restored */
+pub async fn update_starred(
+    pool: &Pool<Sqlite>,
+    target: RatingTarget,
+    id: &String,
+    starred: Option<String>,
+) -> Result<Option<String>, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let select = format!("SELECT starred FROM {} WHERE id = ?", target.table());
+    let previous = sqlx::query_scalar::<_, Option<String>>(&select)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .flatten();
+    let update = format!("UPDATE {} SET starred = ? WHERE id = ?", target.table());
+    sqlx::query(&update)
+        .bind(starred)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(previous)
+}
+
+/* Set the rating (0 clears it), returning the previous one so it can be restored */
+pub async fn update_rating(
+    pool: &Pool<Sqlite>,
+    target: RatingTarget,
+    id: &String,
+    rating: u32,
+) -> Result<u32, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let select = format!("SELECT rating FROM {} WHERE id = ?", target.table());
+    let previous = sqlx::query_scalar::<_, Option<u32>>(&select)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .flatten()
+        .unwrap_or(0);
+    let update = format!("UPDATE {} SET rating = ? WHERE id = ?", target.table());
+    sqlx::query(&update)
+        .bind(rating)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(previous)
+}
+
+/* Make a library's starred column match the server's favourites. Favourites lists are small, so
+*  the starred items are updated one by one. */
+pub async fn replace_starred(
+    conn: &mut SqliteConnection,
+    target: RatingTarget,
+    library_id: &String,
+    starred: &Vec<(String, String)>,
+) -> Result<(), anyhow::Error> {
+    let clear = format!(
+        "UPDATE {} SET starred = NULL WHERE library_id = ? AND starred IS NOT NULL",
+        target.table()
+    );
+    sqlx::query(&clear)
+        .bind(library_id)
+        .execute(&mut *conn)
+        .await?;
+    let update = format!("UPDATE {} SET starred = ? WHERE id = ?", target.table());
+    for (id, starred_at) in starred {
+        sqlx::query(&update)
+            .bind(starred_at)
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/* Rebuild a library's rows in the search_index FTS5 table from its artists, albums and songs.
+*  Runs at the end of each sync, inside the sync's transaction. */
+pub async fn rebuild_search_index(
+    conn: &mut SqliteConnection,
+    library_id: &String,
+) -> Result<(), anyhow::Error> {
+    sqlx::query("DELETE FROM search_index WHERE library_id = ?")
+        .bind(library_id)
+        .execute(&mut *conn)
+        .await?;
+    for query in [
+        "INSERT INTO search_index (kind, id, library_id, title, artist, album) SELECT 'artist', id, library_id, name, name, '' FROM artists WHERE library_id = ?",
+        "INSERT INTO search_index (kind, id, library_id, title, artist, album) SELECT 'album', id, library_id, name, artist_name, name FROM albums WHERE library_id = ?",
+        "INSERT INTO search_index (kind, id, library_id, title, artist, album) SELECT 'song', id, library_id, title, artist_name, album_name FROM songs WHERE library_id = ?",
+    ] {
+        sqlx::query(query).bind(library_id).execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/* IDs of one kind of item ("artist", "album" or "song") matching an FTS5 query, best match first */
+pub async fn search_index(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+    kind: &str,
+    fts_query: &String,
+    count: u32,
+    offset: u32,
+) -> Result<Vec<String>, anyhow::Error> {
+    let ids = sqlx::query_scalar::<_, String>(
+        "SELECT id FROM search_index WHERE search_index MATCH ? AND kind = ? AND library_id = ? ORDER BY rank LIMIT ? OFFSET ?",
+    )
+    .bind(fts_query)
+    .bind(kind)
+    .bind(library_id)
+    .bind(count)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    Ok(ids)
+}
+
+pub async fn insert_download(
+    pool: &Pool<Sqlite>,
+    download: &Download,
+) -> Result<(), anyhow::Error> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO downloads (song_id, library_id, path, size, checksum, source, content_type, downloaded_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&download.song_id)
+    .bind(&download.library_id)
+    .bind(&download.path)
+    .bind(download.size)
+    .bind(&download.checksum)
+    .bind(&download.source)
+    .bind(&download.content_type)
+    .bind(download.downloaded_at)
+    .execute(pool)
+    .await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn get_download(
+    pool: &Pool<Sqlite>,
+    song_id: &String,
+) -> Result<Option<Download>, anyhow::Error> {
+    let download = sqlx::query_as::<_, Download>(
+        "SELECT song_id, library_id, path, size, checksum, source, content_type, downloaded_at FROM downloads WHERE song_id = (?)",
+    )
+    .bind(song_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(download)
+}
+
+pub async fn get_downloads(pool: &Pool<Sqlite>) -> Result<Vec<Download>, anyhow::Error> {
+    let downloads = sqlx::query_as::<_, Download>(
+        "SELECT song_id, library_id, path, size, checksum, source, content_type, downloaded_at FROM downloads ORDER BY downloaded_at DESC",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(downloads)
+}
+
+/* Create (or refresh) a pin along with the songs it covers */
+pub async fn insert_download_pin(
+    pool: &Pool<Sqlite>,
+    pin: &DownloadPin,
+    song_ids: &[String],
+) -> Result<(), anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT OR REPLACE INTO download_pins (id, library_id, kind, created_at) VALUES (?, ?, ?, ?)")
+        .bind(&pin.id)
+        .bind(&pin.library_id)
+        .bind(&pin.kind)
+        .bind(pin.created_at)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM download_pin_songs WHERE pin_id = (?)")
+        .bind(&pin.id)
+        .execute(&mut *tx)
+        .await?;
+    for chunk in song_ids.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT OR IGNORE INTO download_pin_songs (pin_id, song_id) ",
+        );
+        query_builder.push_values(chunk, |mut row, song_id| {
+            row.push_bind(&pin.id).push_bind(song_id);
+        });
+        query_builder.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn get_download_pins(pool: &Pool<Sqlite>) -> Result<Vec<DownloadPin>, anyhow::Error> {
+    let pins = sqlx::query_as::<_, DownloadPin>(
+        "SELECT id, library_id, kind, created_at FROM download_pins ORDER BY created_at DESC",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(pins)
+}
+
+/* Remove a pin, and the downloads no other pin covers. Returns the paths of those downloads so
+*  their files can be deleted. */
+pub async fn delete_download_pin(
+    pool: &Pool<Sqlite>,
+    pin_id: &String,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM download_pin_songs WHERE pin_id = (?)")
+        .bind(pin_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM download_pins WHERE id = (?)")
+        .bind(pin_id)
+        .execute(&mut *tx)
+        .await?;
+    let paths = sqlx::query_scalar::<_, String>(
+        "DELETE FROM downloads WHERE song_id NOT IN (SELECT song_id FROM download_pin_songs) RETURNING path",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(paths)
+}
+
+/* (song ID, content type) for every song on an album */
+pub async fn get_album_song_types(
+    pool: &Pool<Sqlite>,
+    album_id: &String,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let songs = sqlx::query_as::<_, (String, String)>(
+        "SELECT id, content_type FROM songs WHERE album_id = (?) ORDER BY disc_number, track",
+    )
+    .bind(album_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(songs)
+}
+
+/* Settings are stored as JSON, one row per group */
+pub async fn get_setting<T: DeserializeOwned>(
+    pool: &Pool<Sqlite>,
+    key: &str,
+) -> Result<Option<T>, anyhow::Error> {
+    let value = sqlx::query_scalar::<_, String>("SELECT value FROM settings WHERE key = (?)")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+    match value {
+        Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn set_setting<T: Serialize>(
+    pool: &Pool<Sqlite>,
+    key: &str,
+    value: &T,
+) -> Result<(), anyhow::Error> {
+    sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")
+        .bind(key)
+        .bind(serde_json::to_string(value)?)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn upsert_cache_entries(
+    pool: &Pool<Sqlite>,
+    entries: &[CacheEntry],
+) -> Result<(), anyhow::Error> {
+    for chunk in entries.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT OR REPLACE INTO cache_entries (kind, key, library_id, path, size, content_type, last_accessed) ",
+        );
+        query_builder.push_values(chunk, |mut row, entry| {
+            row.push_bind(&entry.kind)
+                .push_bind(&entry.key)
+                .push_bind(&entry.library_id)
+                .push_bind(&entry.path)
+                .push_bind(entry.size)
+                .push_bind(&entry.content_type)
+                .push_bind(entry.last_accessed);
+        });
+        query_builder.build().execute(pool).await?;
+    }
+    Ok(())
+}
+
+pub async fn get_cache_entry(
+    pool: &Pool<Sqlite>,
+    kind: CacheKind,
+    key: &String,
+) -> Result<Option<CacheEntry>, anyhow::Error> {
+    let entry = sqlx::query_as::<_, CacheEntry>(
+        "SELECT kind, key, library_id, path, size, content_type, last_accessed FROM cache_entries WHERE kind = ? AND key = ?",
+    )
+    .bind(kind.as_str())
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+    Ok(entry)
+}
+
+pub async fn touch_cache_entries(
+    pool: &Pool<Sqlite>,
+    kind: CacheKind,
+    keys: &[String],
+    accessed_at: i64,
+) -> Result<(), anyhow::Error> {
+    for chunk in keys.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder =
+            QueryBuilder::<Sqlite>::new("UPDATE cache_entries SET last_accessed = ");
+        query_builder
+            .push_bind(accessed_at)
+            .push(" WHERE kind = ")
+            .push_bind(kind.as_str())
+            .push(" AND key IN (");
+        let mut separated = query_builder.separated(", ");
+        for key in chunk {
+            separated.push_bind(key);
+        }
+        separated.push_unseparated(")");
+        query_builder.build().execute(pool).await?;
+    }
+    Ok(())
+}
+
+/* Libraries whose albums use an art file from before art keys were namespaced. Matches albums
+*  whether or not migration 6 has prefixed their cover_art with the library ID yet. */
+pub async fn get_legacy_cover_art_libraries(
+    pool: &Pool<Sqlite>,
+    file_name: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    let library_ids = sqlx::query_scalar::<_, String>(
+        "SELECT DISTINCT library_id FROM albums WHERE cover_art = (?) OR cover_art = library_id || '/' || (?)",
+    )
+    .bind(file_name)
+    .bind(file_name)
+    .fetch_all(pool)
+    .await?;
+    Ok(library_ids)
+}
+
+pub async fn get_cache_size(pool: &Pool<Sqlite>, kind: CacheKind) -> Result<i64, anyhow::Error> {
+    let size = sqlx::query_scalar::<_, i64>(
+        "SELECT COALESCE(SUM(size), 0) FROM cache_entries WHERE kind = (?)",
+    )
+    .bind(kind.as_str())
+    .fetch_one(pool)
+    .await?;
+    Ok(size)
+}
+
+/* Entries that may be evicted, least recently used first. Art for pinned albums and downloaded
+*  songs is kept so they can still be browsed offline, and audio for downloaded songs is left to
+*  the download manager. */
+pub async fn get_eviction_candidates(
+    pool: &Pool<Sqlite>,
+    kind: CacheKind,
+) -> Result<Vec<CacheEntry>, anyhow::Error> {
+    let entries = sqlx::query_as::<_, CacheEntry>(
+        "SELECT kind, key, library_id, path, size, content_type, last_accessed FROM cache_entries AS entry
+        WHERE entry.kind = ?
+        AND NOT EXISTS (SELECT 1 FROM albums JOIN download_pins ON download_pins.id = albums.id
+            WHERE download_pins.kind = 'album' AND albums.cover_art = entry.key)
+        AND NOT EXISTS (SELECT 1 FROM songs JOIN downloads ON downloads.song_id = songs.id
+            WHERE songs.cover_art = entry.key)
+        AND NOT EXISTS (SELECT 1 FROM downloads WHERE downloads.song_id = entry.key)
+        ORDER BY entry.last_accessed ASC",
+    )
+    .bind(kind.as_str())
+    .fetch_all(pool)
+    .await?;
+    Ok(entries)
+}
+
+pub async fn delete_cache_entry(
+    pool: &Pool<Sqlite>,
+    kind: CacheKind,
+    key: &String,
+) -> Result<(), anyhow::Error> {
+    sqlx::query("DELETE FROM cache_entries WHERE kind = ? AND key = ?")
+        .bind(kind.as_str())
+        .bind(key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_cache_kind(pool: &Pool<Sqlite>, kind: CacheKind) -> Result<(), anyhow::Error> {
+    sqlx::query("DELETE FROM cache_entries WHERE kind = (?)")
+        .bind(kind.as_str())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/* (library ID, kind, bytes, files) for each cache */
+pub async fn get_cache_usage(
+    pool: &Pool<Sqlite>,
+) -> Result<Vec<(String, String, i64, i64)>, anyhow::Error> {
+    let usage = sqlx::query_as::<_, (String, String, i64, i64)>(
+        "SELECT library_id, kind, COALESCE(SUM(size), 0), COUNT(*) FROM cache_entries GROUP BY library_id, kind
+        UNION ALL
+        SELECT library_id, 'download', COALESCE(SUM(size), 0), COUNT(*) FROM downloads GROUP BY library_id",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(usage)
+}
+
+pub async fn get_transcode_settings(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+    profile: NetworkProfile,
+) -> Result<Option<TranscodeSettings>, anyhow::Error> {
+    let settings = sqlx::query_as::<_, TranscodeSettings>(
+        "SELECT format, max_bit_rate, estimate_content_length FROM transcode_settings WHERE library_id = ? AND profile = ?",
+    )
+    .bind(library_id)
+    .bind(profile.as_str())
+    .fetch_optional(pool)
+    .await?;
+    Ok(settings)
+}
+
+pub async fn set_transcode_settings(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+    profile: NetworkProfile,
+    settings: &TranscodeSettings,
+) -> Result<(), anyhow::Error> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO transcode_settings (library_id, profile, format, max_bit_rate, estimate_content_length) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(library_id)
+    .bind(profile.as_str())
+    .bind(&settings.format)
+    .bind(settings.max_bit_rate)
+    .bind(settings.estimate_content_length)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_replay_gain(
+    pool: &Pool<Sqlite>,
+    song_id: &String,
+) -> Result<Option<ReplayGain>, anyhow::Error> {
+    let replay_gain = sqlx::query_as::<_, ReplayGain>(
+        "SELECT replay_gain_track AS track_gain, replay_gain_album AS album_gain, replay_gain_track_peak AS track_peak,
+        replay_gain_album_peak AS album_peak, replay_gain_computed AS computed_gain, replay_gain_computed_peak AS computed_peak
+        FROM songs WHERE id = (?)",
+    )
+    .bind(song_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(replay_gain)
+}
+
+pub async fn set_computed_replay_gain(
+    pool: &Pool<Sqlite>,
+    song_id: &String,
+    gain: f64,
+    peak: f64,
+) -> Result<(), anyhow::Error> {
+    sqlx::query(
+        "UPDATE songs SET replay_gain_computed = ?, replay_gain_computed_peak = ? WHERE id = ?",
+    )
+    .bind(gain)
+    .bind(peak)
+    .bind(song_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_dsp_presets(pool: &Pool<Sqlite>) -> Result<Vec<DspPreset>, anyhow::Error> {
+    let rows = sqlx::query_as::<_, (i64, String, Option<String>, Option<String>, String)>(
+        "SELECT id, name, library_id, device, settings FROM dsp_presets ORDER BY name",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut presets = vec![];
+    for (id, name, library_id, device, settings) in rows {
+        presets.push(DspPreset {
+            id: Some(id),
+            name,
+            library_id,
+            device,
+            settings: serde_json::from_str(&settings)?,
+        });
+    }
+    Ok(presets)
+}
+
+/* Insert or update a preset, returning its ID. A library or device is bound to one preset at a
+*  time, so binding it here unbinds it from any other. */
+pub async fn save_dsp_preset(
+    pool: &Pool<Sqlite>,
+    preset: &DspPreset,
+) -> Result<i64, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let settings = serde_json::to_string(&preset.settings)?;
+    let id = match preset.id {
+        Some(id) => {
+            sqlx::query("UPDATE dsp_presets SET name = ?, library_id = ?, device = ?, settings = ? WHERE id = ?")
+                .bind(&preset.name)
+                .bind(&preset.library_id)
+                .bind(&preset.device)
+                .bind(&settings)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            id
+        }
+        None => {
+            sqlx::query_scalar::<_, i64>(
+                "INSERT INTO dsp_presets (name, library_id, device, settings) VALUES (?, ?, ?, ?) RETURNING id",
+            )
+            .bind(&preset.name)
+            .bind(&preset.library_id)
+            .bind(&preset.device)
+            .bind(&settings)
+            .fetch_one(&mut *tx)
+            .await?
+        }
+    };
+    sqlx::query("UPDATE dsp_presets SET library_id = NULL WHERE library_id = ? AND id != ?")
+        .bind(&preset.library_id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE dsp_presets SET device = NULL WHERE device = ? AND id != ?")
+        .bind(&preset.device)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+pub async fn delete_dsp_preset(pool: &Pool<Sqlite>, id: i64) -> Result<(), anyhow::Error> {
+    sqlx::query("DELETE FROM dsp_presets WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_saved_queue(pool: &Pool<Sqlite>) -> Result<SavedQueue, anyhow::Error> {
+    let items = sqlx::query_as::<_, (String, String)>(
+        "SELECT library_id, song_id FROM play_queue_items ORDER BY item_index",
+    )
+    .fetch_all(pool)
+    .await?;
+    let state = sqlx::query_as::<_, (Option<i64>, f64, i64)>(
+        "SELECT current_index, position, changed FROM play_queue_state WHERE id = 0",
+    )
+    .fetch_optional(pool)
+    .await?;
+    let (index, position, changed) = state.unwrap_or((None, 0.0, 0));
+    Ok(SavedQueue {
+        items: items
+            .into_iter()
+            .map(|(library_id, song_id)| PlaybackItem {
+                library_id,
+                song_id,
+            })
+            .collect(),
+        index: index.map(|index| index as usize),
+        position,
+        changed,
+    })
+}
+
+pub async fn save_queue_items(
+    pool: &Pool<Sqlite>,
+    items: &[PlaybackItem],
+) -> Result<(), anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM play_queue_items")
+        .execute(&mut *tx)
+        .await?;
+    let numbered: Vec<(usize, &PlaybackItem)> = items.iter().enumerate().collect();
+    for chunk in numbered.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO play_queue_items (item_index, library_id, song_id) ",
+        );
+        query_builder.push_values(chunk, |mut row, (index, item)| {
+            row.push_bind(*index as i64)
+                .push_bind(&item.library_id)
+                .push_bind(&item.song_id);
+        });
+        query_builder.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn save_queue_state(
+    pool: &Pool<Sqlite>,
+    index: Option<usize>,
+    position: f64,
+    changed: i64,
+) -> Result<(), anyhow::Error> {
+    sqlx::query("INSERT OR REPLACE INTO play_queue_state (id, current_index, position, changed) VALUES (0, ?, ?, ?)")
+        .bind(index.map(|index| index as i64))
+        .bind(position)
+        .bind(changed)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_local_file(
+    pool: &Pool<Sqlite>,
+    song_id: &String,
+) -> Result<Option<LocalFile>, anyhow::Error> {
+    let local_file = sqlx::query_as::<_, LocalFile>(
+        "SELECT path, library_id, song_id, content_type, modified, size FROM local_files WHERE song_id = (?)",
+    )
+    .bind(song_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(local_file)
+}
+
+/* The file index of a local library, with the tags stored for each file */
+pub async fn get_local_files(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<Vec<LocalFile>, anyhow::Error> {
+    let local_files = sqlx::query_as::<_, LocalFile>(
+        "SELECT path, library_id, song_id, content_type, modified, size, track FROM local_files WHERE library_id = (?)",
+    )
+    .bind(library_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(local_files)
+}
+
+/* Replace the file index of a local library with the files found by a scan */
+pub async fn replace_local_files(
+    conn: &mut SqliteConnection,
+    library_id: &String,
+    files: &[LocalFile],
+) -> Result<(), anyhow::Error> {
+    sqlx::query("DELETE FROM local_files WHERE library_id = (?)")
+        .bind(library_id)
+        .execute(&mut *conn)
+        .await?;
+    for chunk in files.chunks(INSERT_BATCH_SIZE) {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT OR REPLACE INTO local_files (path, library_id, song_id, content_type, modified, size, track) ",
+        );
+        query_builder.push_values(chunk, |mut row, file| {
+            row.push_bind(&file.path)
+                .push_bind(&file.library_id)
+                .push_bind(&file.song_id)
+                .push_bind(&file.content_type)
+                .push_bind(file.modified)
+                .push_bind(file.size)
+                .push_bind(&file.track);
+        });
+        query_builder.build().execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{Pool, Sqlite};
+
+use super::{
+    delete_missing_artists, get_album_by_cover_art, get_eviction_candidates, insert_albums,
+    insert_artists, insert_library, insert_songs, replace_cover_art, upsert_cache_entries,
+};
+use crate::formatter::namespace_id;
+use crate::models::{Album, Artist, CacheEntry, CacheKind, Library, LibraryKind, Song};
+
+/* A fresh in-memory database with every migration applied. A single connection, as each
+*  connection to sqlite::memory: would otherwise open its own empty database. */
+async fn test_pool() -> Pool<Sqlite> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    for migration in crate::migrations() {
+        sqlx::raw_sql(migration.sql).execute(&pool).await.unwrap();
+    }
+    pool
+}
+
+fn library(id: &str) -> Library {
+    Library {
+        id: id.to_string(),
+        name: id.to_string(),
+        host: String::from("http://localhost"),
+        port: None,
+        username: String::from("user"),
+        hashed_password: String::new(),
+        salt: String::new(),
+        last_scanned: String::new(),
+        kind: LibraryKind::Subsonic,
+        paths: vec![],
+        user_id: String::new(),
+    }
+}
+
+fn artist(library_id: &str, id: &str) -> Artist {
+    Artist {
+        id: namespace_id(library_id, id),
+        name: format!("Artist {}", id),
+        library_id: library_id.to_string(),
+        starred: None,
+        rating: 0,
+    }
+}
+
+fn album(library_id: &str, id: &str, artist_id: &str) -> Album {
+    Album {
+        id: namespace_id(library_id, id),
+        name: format!("Album {}", id),
+        artist_id: namespace_id(library_id, artist_id),
+        artist_name: format!("Artist {}", artist_id),
+        library_id: library_id.to_string(),
+        cover_art: String::new(),
+        year: Some(2000),
+        duration: 180,
+        starred: None,
+        rating: 0,
+    }
+}
+
+fn song(library_id: &str, id: &str, album_id: &str, artist_id: &str) -> Song {
+    Song {
+        id: namespace_id(library_id, id),
+        title: format!("Song {}", id),
+        artist_id: namespace_id(library_id, artist_id),
+        artist_name: format!("Artist {}", artist_id),
+        album_id: namespace_id(library_id, album_id),
+        album_name: format!("Album {}", album_id),
+        library_id: library_id.to_string(),
+        track: Some(1),
+        duration: Some(180),
+        disc_number: 1,
+        year: Some(2000),
+        content_type: String::from("audio/flac"),
+        cover_art: String::new(),
+        starred: None,
+        rating: 0,
+        replay_gain_track: None,
+        replay_gain_album: None,
+        replay_gain_track_peak: None,
+        replay_gain_album_peak: None,
+    }
+}
+
+/* Sync a library the way sync_library does, with the given server IDs for each table */
+async fn sync(pool: &Pool<Sqlite>, library_id: &str, server_ids: &[&str]) -> Vec<String> {
+    let artists: Vec<Artist> = server_ids.iter().map(|id| artist(library_id, id)).collect();
+    let albums: Vec<Album> = server_ids
+        .iter()
+        .map(|id| album(library_id, id, id))
+        .collect();
+    let songs: Vec<Song> = server_ids
+        .iter()
+        .map(|id| song(library_id, id, id, id))
+        .collect();
+    let artist_ids: Vec<String> = artists.iter().map(|artist| artist.id.clone()).collect();
+    let album_ids: Vec<String> = albums.iter().map(|album| album.id.clone()).collect();
+    let song_ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+    let library_id = library_id.to_string();
+
+    let mut tx = pool.begin().await.unwrap();
+    insert_library(&mut tx, &library(&library_id))
+        .await
+        .unwrap();
+    insert_artists(&mut tx, &library_id, &artists)
+        .await
+        .unwrap();
+    insert_albums(&mut tx, &library_id, &albums, &album_ids, &HashMap::new())
+        .await
+        .unwrap();
+    let diff = insert_songs(&mut tx, &library_id, &songs, &song_ids, &HashMap::new())
+        .await
+        .unwrap();
+    delete_missing_artists(&mut tx, &library_id, &artist_ids)
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+    diff.removed
+}
+
+async fn ids(pool: &Pool<Sqlite>, table: &str, library_id: &str) -> Vec<String> {
+    let query = format!("SELECT id FROM {} WHERE library_id = ? ORDER BY id", table);
+    sqlx::query_scalar::<_, String>(&query)
+        .bind(library_id)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn syncing_one_library_keeps_the_rows_of_another() {
+    let pool = test_pool().await;
+    sync(&pool, "a", &["1", "2"]).await;
+    sync(&pool, "b", &["1", "2", "3"]).await;
+
+    //Library A loses a song, which only removes A's rows
+    let removed = sync(&pool, "a", &["1"]).await;
+    assert_eq!(removed, vec![String::from("a:2")]);
+    for table in ["artists", "albums", "songs"] {
+        assert_eq!(ids(&pool, table, "a").await, vec!["a:1"]);
+        assert_eq!(ids(&pool, table, "b").await, vec!["b:1", "b:2", "b:3"]);
+    }
+
+    //An empty sync of A still leaves B alone
+    sync(&pool, "a", &[]).await;
+    assert!(ids(&pool, "songs", "a").await.is_empty());
+    assert_eq!(ids(&pool, "songs", "b").await, vec!["b:1", "b:2", "b:3"]);
+}
+
+#[tokio::test]
+async fn same_server_id_in_two_libraries_gives_two_rows() {
+    let pool = test_pool().await;
+    sync(&pool, "a", &["42"]).await;
+    sync(&pool, "b", &["42"]).await;
+
+    let rows = sqlx::query_as::<_, (String, String, String)>(
+        "SELECT id, library_id, album_id FROM songs ORDER BY id",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            (
+                String::from("a:42"),
+                String::from("a"),
+                String::from("a:42")
+            ),
+            (
+                String::from("b:42"),
+                String::from("b"),
+                String::from("b:42")
+            ),
+        ]
+    );
+    for table in ["artists", "albums"] {
+        assert_eq!(ids(&pool, table, "a").await, vec!["a:42"]);
+        assert_eq!(ids(&pool, table, "b").await, vec!["b:42"]);
+    }
+}
+
+/* Benchmark for the batched, transactional upsert. Run with
+*  cargo test --release --lib upsert_50k_songs -- --ignored --nocapture */
+#[tokio::test]
+#[ignore]
+async fn upsert_50k_songs() {
+    const SONG_COUNT: usize = 50_000;
+    let pool = test_pool().await;
+    let library_id = String::from("bench");
+    let songs: Vec<Song> = (0..SONG_COUNT)
+        .map(|i| song(&library_id, &i.to_string(), &(i / 10).to_string(), "1"))
+        .collect();
+    let song_ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+
+    let mut tx = pool.begin().await.unwrap();
+    insert_library(&mut tx, &library(&library_id))
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+
+    //First sync inserts every row, the second finds nothing to change
+    for label in ["insert", "unchanged resync"] {
+        let start = std::time::Instant::now();
+        let mut tx = pool.begin().await.unwrap();
+        let diff = insert_songs(&mut tx, &library_id, &songs, &song_ids, &HashMap::new())
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        println!(
+            "{} of {} songs: {:?} ({} added, {} updated)",
+            label,
+            SONG_COUNT,
+            start.elapsed(),
+            diff.added.len(),
+            diff.updated.len()
+        );
+    }
+    assert_eq!(ids(&pool, "songs", &library_id).await.len(), SONG_COUNT);
+}
+
+fn cache_entry(key: &str, last_accessed: i64) -> CacheEntry {
+    CacheEntry {
+        kind: CacheKind::Art.as_str().to_string(),
+        key: key.to_string(),
+        library_id: String::from("a"),
+        path: format!("/cache/{}", key),
+        size: 1,
+        content_type: None,
+        last_accessed,
+    }
+}
+
+#[tokio::test]
+async fn eviction_skips_kept_art_and_tolerates_null_art() {
+    let pool = test_pool().await;
+    sync(&pool, "a", &["1", "2"]).await;
+    sqlx::query("UPDATE songs SET cover_art = 'a/1.jpg' WHERE id = 'a:1'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    //A downloaded song without art, which made NOT IN match nothing
+    sqlx::query("UPDATE songs SET cover_art = NULL WHERE id = 'a:2'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO downloads (song_id, library_id) VALUES ('a:1', 'a'), ('a:2', 'a')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    upsert_cache_entries(
+        &pool,
+        &[cache_entry("a/1.jpg", 1), cache_entry("a/3.jpg", 2)],
+    )
+    .await
+    .unwrap();
+
+    let candidates = get_eviction_candidates(&pool, CacheKind::Art)
+        .await
+        .unwrap();
+    let keys: Vec<&str> = candidates.iter().map(|entry| entry.key.as_str()).collect();
+    assert_eq!(keys, vec!["a/3.jpg"]);
+}
+
+#[tokio::test]
+async fn refetched_art_in_another_format_replaces_the_old_file_name() {
+    let pool = test_pool().await;
+    sync(&pool, "a", &["1", "2"]).await;
+    for table in ["albums", "songs"] {
+        let query = format!(
+            "UPDATE {} SET cover_art = 'a/1.jpg' WHERE id = 'a:1'",
+            table
+        );
+        sqlx::query(&query).execute(&pool).await.unwrap();
+    }
+
+    replace_cover_art(&pool, "a/1.jpg", "a/1.webp")
+        .await
+        .unwrap();
+
+    assert!(get_album_by_cover_art(&pool, "a/1.jpg")
+        .await
+        .unwrap()
+        .is_none());
+    let album = get_album_by_cover_art(&pool, "a/1.webp")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(album.id, "a:1");
+    let song_art: String = sqlx::query_scalar("SELECT cover_art FROM songs WHERE id = 'a:1'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(song_art, "a/1.webp");
+}
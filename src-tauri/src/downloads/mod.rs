@@ -0,0 +1,179 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use sqlx::{Pool, Sqlite};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::{
+    client::ServerClient,
+    db::{delete_download_pin, get_downloads, insert_download, insert_download_pin},
+    errors::ApiError,
+    formatter::strip_namespace,
+    models::{Download, DownloadPin, DownloadProgress, Library},
+    subsonic::{download, stream_raw},
+};
+
+//Songs downloaded at once for a pin. Kept low so downloads don't starve playback.
+const DOWNLOAD_CONCURRENCY: usize = 4;
+
+/* A song to download, with the content type reported for it by the server */
+pub struct SongDownload {
+    pub song_id: String,
+    pub content_type: String,
+}
+
+/* Pin an album or playlist, then download whichever of its songs aren't on disk yet. Songs that
+*  fail are left for the next time the pin is refreshed. Returns the number of songs downloaded. */
+pub async fn pin_and_download(
+    app_handle: &AppHandle,
+    client: &ServerClient,
+    pool: &Pool<Sqlite>,
+    library: &Library,
+    pin: DownloadPin,
+    songs: Vec<SongDownload>,
+) -> Result<usize, anyhow::Error> {
+    let song_ids: Vec<String> = songs.iter().map(|song| song.song_id.clone()).collect();
+    insert_download_pin(pool, &pin, &song_ids).await?;
+
+    let downloaded: HashSet<String> = get_downloads(pool)
+        .await?
+        .into_iter()
+        .map(|download| download.song_id)
+        .collect();
+    let missing: Vec<SongDownload> = songs
+        .into_iter()
+        .filter(|song| !downloaded.contains(&song.song_id))
+        .collect();
+
+    let dir = downloads_dir(app_handle, &library.id)?;
+    let mut progress = DownloadProgress {
+        pin_id: pin.id.clone(),
+        done: 0,
+        total: missing.len(),
+        failed: 0,
+    };
+    emit_progress(app_handle, &progress);
+    //Collected first, as a lazy map over the songs makes the command's future not Send
+    let downloads: Vec<_> = missing
+        .iter()
+        .map(|song| download_song(client, pool, &dir, library, song))
+        .collect();
+    let mut results = futures::stream::iter(downloads).buffer_unordered(DOWNLOAD_CONCURRENCY);
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(_) => progress.done += 1,
+            Err(e) => {
+                println!("Error: {}", e);
+                progress.failed += 1;
+            }
+        }
+        emit_progress(app_handle, &progress);
+    }
+    Ok(progress.done)
+}
+
+/* Remove a pin and delete the files of songs no other pin covers */
+pub async fn unpin(pool: &Pool<Sqlite>, pin_id: &String) -> Result<(), anyhow::Error> {
+    for path in delete_download_pin(pool, pin_id).await? {
+        if let Err(e) = fs::remove_file(&path) {
+            println!("Error: Failed to remove {}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+async fn download_song(
+    client: &ServerClient,
+    pool: &Pool<Sqlite>,
+    dir: &Path,
+    library: &Library,
+    song: &SongDownload,
+) -> Result<Download, anyhow::Error> {
+    let remote_id = strip_namespace(&library.id, &song.song_id);
+    let (body, source) = match download(client, library, remote_id).await {
+        Ok(body) => (body, "download"),
+        //Downloading is a separate permission on most servers, streaming the original file isn't
+        Err(ApiError::NotAuthorized(_)) => {
+            (stream_raw(client, library, remote_id).await?, "stream")
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let path = dir.join(format!(
+        "{}.{}",
+        sanitize_file_name(remote_id),
+        extension_for(&song.content_type)
+    ));
+    fs::write(&path, &body)?;
+
+    let download = Download {
+        song_id: song.song_id.clone(),
+        library_id: library.id.clone(),
+        path: path.to_string_lossy().to_string(),
+        size: body.len() as i64,
+        checksum: checksum(&body),
+        source: source.to_string(),
+        content_type: song.content_type.clone(),
+        downloaded_at: now_millis(),
+    };
+    insert_download(pool, &download).await?;
+    Ok(download)
+}
+
+pub fn checksum(bytes: &[u8]) -> String {
+    use md5::{Digest, Md5};
+    let mut hasher = Md5::new();
+    hasher.update(bytes);
+    hex::encode(hasher.finalize())
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+fn downloads_dir(app_handle: &AppHandle, library_id: &str) -> Result<PathBuf, anyhow::Error> {
+    let dir = app_handle
+        .path()
+        .app_data_dir()?
+        .join("downloads")
+        .join(sanitize_file_name(library_id));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn emit_progress(app_handle: &AppHandle, progress: &DownloadProgress) {
+    if let Err(e) = app_handle.emit("download-progress", progress.clone()) {
+        println!("Error: {}", e);
+    }
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn extension_for(content_type: &str) -> &'static str {
+    match content_type {
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/flac" | "audio/x-flac" => "flac",
+        "audio/ogg" | "audio/vorbis" => "ogg",
+        "audio/opus" => "opus",
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" | "audio/aac" => "m4a",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "audio/x-ms-wma" => "wma",
+        "audio/aiff" | "audio/x-aiff" => "aiff",
+        _ => "bin",
+    }
+}
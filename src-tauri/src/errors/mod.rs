@@ -0,0 +1,196 @@
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::responses::SubsonicError;
+
+/* Server failures. Subsonic reports each documented error code in its response
+*  (https://opensubsonic.netlify.app/docs/responses/error/), other servers with the HTTP status. */
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("Failed to reach server: {0}")]
+    Transport(String),
+    #[error("Failed to parse response: {0}")]
+    Parse(String),
+    #[error("Server error: {0}")]
+    Generic(String),
+    #[error("Required parameter is missing: {0}")]
+    MissingParameter(String),
+    #[error("Client must upgrade: {0}")]
+    ClientMustUpgrade(String),
+    #[error("Server must upgrade: {0}")]
+    ServerMustUpgrade(String),
+    #[error("Wrong username or password: {0}")]
+    WrongCredentials(String),
+    #[error("Token authentication not supported: {0}")]
+    TokenAuthNotSupported(String),
+    #[error("Authentication mechanism not supported: {0}")]
+    AuthMechanismNotSupported(String),
+    #[error("Multiple conflicting authentication mechanisms provided: {0}")]
+    ConflictingAuthMechanisms(String),
+    #[error("Invalid API key: {0}")]
+    InvalidApiKey(String),
+    #[error("User is not authorized for the given operation: {0}")]
+    NotAuthorized(String),
+    #[error("Trial period is over: {0}")]
+    TrialExpired(String),
+    #[error("Requested data was not found: {0}")]
+    NotFound(String),
+    #[error("Server error {code}: {message}")]
+    Unknown { code: u32, message: String },
+    #[error("Server responded with HTTP {status}: {message}")]
+    Http { status: u16, message: String },
+    #[error("Ampache error {code}: {message}")]
+    Ampache { code: u32, message: String },
+}
+
+impl ApiError {
+    pub fn from_response(error: &SubsonicError) -> Self {
+        let message = error.message.clone();
+        match error.code {
+            0 => ApiError::Generic(message),
+            10 => ApiError::MissingParameter(message),
+            20 => ApiError::ClientMustUpgrade(message),
+            30 => ApiError::ServerMustUpgrade(message),
+            40 => ApiError::WrongCredentials(message),
+            41 => ApiError::TokenAuthNotSupported(message),
+            42 => ApiError::AuthMechanismNotSupported(message),
+            43 => ApiError::ConflictingAuthMechanisms(message),
+            44 => ApiError::InvalidApiKey(message),
+            50 => ApiError::NotAuthorized(message),
+            60 => ApiError::TrialExpired(message),
+            70 => ApiError::NotFound(message),
+            code => ApiError::Unknown { code, message },
+        }
+    }
+
+    /* Subsonic error code, or the HTTP status for servers that report errors with it (None for
+     *  failures that never reached a response) */
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            ApiError::Transport(_) | ApiError::Parse(_) => None,
+            ApiError::Generic(_) => Some(0),
+            ApiError::MissingParameter(_) => Some(10),
+            ApiError::ClientMustUpgrade(_) => Some(20),
+            ApiError::ServerMustUpgrade(_) => Some(30),
+            ApiError::WrongCredentials(_) => Some(40),
+            ApiError::TokenAuthNotSupported(_) => Some(41),
+            ApiError::AuthMechanismNotSupported(_) => Some(42),
+            ApiError::ConflictingAuthMechanisms(_) => Some(43),
+            ApiError::InvalidApiKey(_) => Some(44),
+            ApiError::NotAuthorized(_) => Some(50),
+            ApiError::TrialExpired(_) => Some(60),
+            ApiError::NotFound(_) => Some(70),
+            ApiError::Unknown { code, .. } => Some(*code),
+            ApiError::Http { status, .. } => Some(*status as u32),
+            ApiError::Ampache { code, .. } => Some(*code),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Transport(_) => "transport",
+            ApiError::Parse(_) => "parse",
+            ApiError::Generic(_) => "generic",
+            ApiError::MissingParameter(_) => "missing_parameter",
+            ApiError::ClientMustUpgrade(_) => "client_must_upgrade",
+            ApiError::ServerMustUpgrade(_) => "server_must_upgrade",
+            ApiError::WrongCredentials(_) => "wrong_credentials",
+            ApiError::TokenAuthNotSupported(_) => "token_auth_not_supported",
+            ApiError::AuthMechanismNotSupported(_) => "auth_mechanism_not_supported",
+            ApiError::ConflictingAuthMechanisms(_) => "conflicting_auth_mechanisms",
+            ApiError::InvalidApiKey(_) => "invalid_api_key",
+            ApiError::NotAuthorized(_) => "not_authorized",
+            ApiError::TrialExpired(_) => "trial_expired",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Unknown { .. } => "unknown",
+            ApiError::Http { status: 401, .. } => "wrong_credentials",
+            ApiError::Http { status: 403, .. } => "not_authorized",
+            ApiError::Http { status: 404, .. } => "not_found",
+            ApiError::Http { .. } => "http",
+            //Ampache API 5+ codes, with the older ones they replaced (https://ampache.org/api/api-errors)
+            ApiError::Ampache { code, .. } => match code {
+                4701 | 401 => "wrong_credentials",
+                4700 | 4703 | 4742 | 403 => "not_authorized",
+                4704 | 404 => "not_found",
+                4705 | 405 => "missing_parameter",
+                4710 | 400 => "generic",
+                _ => "unknown",
+            },
+        }
+    }
+}
+
+/* reqwest includes the URL in its errors, and URLs carry credentials (u/t/s, api_key, auth), so
+*  it is dropped before the error can be logged or reach the frontend */
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        let error = error.without_url();
+        match error.status() {
+            Some(status) => ApiError::Http {
+                status: status.as_u16(),
+                message: status.canonical_reason().unwrap_or_default().to_string(),
+            },
+            None => ApiError::Transport(error.to_string()),
+        }
+    }
+}
+
+/* Error payload returned to the frontend by commands, e.g.
+*  { "kind": "wrong_credentials", "code": 40, "message": "..." } */
+#[derive(Debug, Serialize)]
+pub struct CommandError {
+    pub kind: String,
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn internal(message: impl Into<String>) -> Self {
+        CommandError {
+            kind: "internal".to_string(),
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        CommandError {
+            kind: "invalid_argument".to_string(),
+            code: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn unsupported(message: impl Into<String>) -> Self {
+        CommandError {
+            kind: "unsupported".to_string(),
+            code: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ApiError> for CommandError {
+    fn from(error: ApiError) -> Self {
+        CommandError {
+            kind: error.kind().to_string(),
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for CommandError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<ApiError>() {
+            Ok(api_error) => api_error.into(),
+            Err(error) => CommandError::internal(error.to_string()),
+        }
+    }
+}
+
+impl From<sqlx::Error> for CommandError {
+    fn from(error: sqlx::Error) -> Self {
+        CommandError::internal(error.to_string())
+    }
+}
@@ -2,8 +2,8 @@ use crate::models::Library;
 use keyring::Entry;
 
 /* String + Library Formatting */
-pub fn create_connection_string(library: &Library, endpoint: &str) -> String {
-    let host = match library.port {
+pub fn server_address(library: &Library) -> String {
+    match library.port {
         Some(port) => {
             if port != -1 {
                 format!("{}:{}", library.host, port)
@@ -12,7 +12,11 @@ pub fn create_connection_string(library: &Library, endpoint: &str) -> String {
             }
         }
         None => library.host.clone(),
-    };
+    }
+}
+
+pub fn create_connection_string(library: &Library, endpoint: &str) -> String {
+    let host = server_address(library);
     let conn_string = format!(
         "{}/rest/{}.view?u={}&t={}&s={}&v=1.16.1&c=Polyphonic&f=json",
         host, endpoint, library.username, library.hashed_password, library.salt
@@ -20,6 +24,28 @@ pub fn create_connection_string(library: &Library, endpoint: &str) -> String {
     conn_string
 }
 
+/* Free-form text (e.g. search queries) passed as a URL query parameter */
+pub fn encode_query_value(value: &str) -> String {
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+/* Library-scoped IDs
+*  Server IDs are only unique within a single server, so everything stored locally is prefixed
+*  with the ID of the library it came from. */
+pub fn namespace_id(library_id: &str, id: &str) -> String {
+    if id.is_empty() {
+        return String::new();
+    }
+    format!("{}:{}", library_id, id)
+}
+
+pub fn strip_namespace<'a>(library_id: &str, id: &'a str) -> &'a str {
+    id.strip_prefix(library_id)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .unwrap_or(id)
+}
+
 /* Security-related functions */
 pub fn generate_md5(password: &str, salt: &str) -> String {
     use md5::{Digest, Md5};
@@ -28,6 +54,13 @@ pub fn generate_md5(password: &str, salt: &str) -> String {
     hex::encode(hasher.finalize())
 }
 
+pub fn generate_sha256(value: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(value);
+    hex::encode(hasher.finalize())
+}
+
 pub fn generate_salt() -> String {
     use rand::Rng;
     let mut rng = rand::thread_rng();
@@ -0,0 +1,83 @@
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+use crate::client::ServerClient;
+use crate::errors::ApiError;
+use crate::formatter::server_address;
+use crate::models::Library;
+
+mod responses;
+mod source;
+pub use source::JellyfinSource;
+
+use responses::{JellyfinAuthRequest, JellyfinAuthResponse, JellyfinItems};
+
+/* Requests are made with the access token from logging in (kept in the keyring in place of a
+*  password hash) */
+fn create_jellyfin_url(library: &Library, path: &str) -> String {
+    format!(
+        "{}/{}?api_key={}",
+        server_address(library),
+        path,
+        library.hashed_password
+    )
+}
+
+/* Sessions are per device, so each library logs in as its own */
+pub fn device_id(library: &Library) -> String {
+    format!("polyphonic-{}", library.id)
+}
+
+/* Users/AuthenticateByName
+*  https://api.jellyfin.org/#tag/User/operation/AuthenticateUserByName */
+pub async fn authenticate(
+    client: &ServerClient,
+    library: &Library,
+    password: &str,
+) -> Result<JellyfinAuthResponse, ApiError> {
+    let url = format!("{}/Users/AuthenticateByName", server_address(library));
+    let authorization = format!(
+        "MediaBrowser Client=\"Polyphonic\", Device=\"Polyphonic\", DeviceId=\"{}\", Version=\"{}\"",
+        device_id(library),
+        env!("CARGO_PKG_VERSION")
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&authorization).map_err(|e| ApiError::Transport(e.to_string()))?,
+    );
+    let body = JellyfinAuthRequest {
+        username: library.username.clone(),
+        pw: password.to_string(),
+    };
+    client.post_json(&url, headers, &body).await
+}
+
+/* Users/{userId} (fails if the access token was revoked) */
+pub async fn ping_jellyfin(client: &ServerClient, library: &Library) -> Result<(), ApiError> {
+    let url = create_jellyfin_url(library, &format!("Users/{}", library.user_id));
+    client
+        .get_json::<serde_json::Value>(&url, HeaderMap::new())
+        .await?;
+    Ok(())
+}
+
+/* One page of a listing of items (Users/{userId}/Items, Artists/AlbumArtists, ...), filtered by
+*  the given query parameters
+*  https://api.jellyfin.org/#tag/Items */
+pub async fn get_items_page(
+    client: &ServerClient,
+    library: &Library,
+    path: &str,
+    query: &str,
+    start_index: u32,
+    limit: u32,
+) -> Result<JellyfinItems, ApiError> {
+    let url = format!(
+        "{}&{}&StartIndex={}&Limit={}",
+        create_jellyfin_url(library, path),
+        query,
+        start_index,
+        limit
+    );
+    client.get_json(&url, HeaderMap::new()).await
+}
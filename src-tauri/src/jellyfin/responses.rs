@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/* Jellyfin API types (only the fields Polyphonic uses)
+*  https://api.jellyfin.org/ */
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinAuthRequest {
+    pub username: String,
+    pub pw: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinAuthResponse {
+    pub user: JellyfinUser,
+    pub access_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinUser {
+    pub id: String,
+}
+
+/* One page of items */
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinItems {
+    #[serde(default)]
+    pub items: Vec<JellyfinItem>,
+    #[serde(default)]
+    pub total_record_count: u32,
+}
+
+/* An artist, album, song or playlist (BaseItemDto) */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinItem {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub album_id: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    #[serde(default)]
+    pub album_artists: Vec<JellyfinNameId>,
+    #[serde(default)]
+    pub artist_items: Vec<JellyfinNameId>,
+    pub album_primary_image_tag: Option<String>,
+    //Track and disc numbers
+    pub index_number: Option<u32>,
+    pub parent_index_number: Option<u32>,
+    pub production_year: Option<u32>,
+    //In ticks of 100ns
+    pub run_time_ticks: Option<i64>,
+    pub container: Option<String>,
+    //ReplayGain-style track gain in dB, on servers that measure it (10.9+)
+    pub normalization_gain: Option<f64>,
+    #[serde(default)]
+    pub image_tags: HashMap<String, String>,
+    pub user_data: Option<JellyfinUserData>,
+    pub date_created: Option<String>,
+    pub child_count: Option<u32>,
+    pub etag: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinNameId {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinUserData {
+    #[serde(default)]
+    pub is_favorite: bool,
+}
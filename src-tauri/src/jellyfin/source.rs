@@ -0,0 +1,577 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use crate::{
+    client::ServerClient,
+    formatter::{encode_query_value, namespace_id, strip_namespace},
+    models::{Album, Artist, Library, Playlist, Song, TranscodeSettings},
+    music::{cover_art_key, SyncProgressReporter},
+    source::{MusicSource, SourceAlbum},
+    subsonic::get_album_art,
+};
+
+use super::{
+    create_jellyfin_url, device_id, get_items_page, ping_jellyfin, responses::JellyfinItem,
+};
+
+#[cfg(not(test))]
+const PAGE_SIZE: u32 = 500;
+//Small pages in tests, so the recorded listings span several
+#[cfg(test)]
+const PAGE_SIZE: u32 = 2;
+
+//Formats played as they are when no transcoding is asked for
+const DIRECT_PLAY_CONTAINERS: &str = "mp3,flac,ogg,opus,m4a,aac,wav,webm";
+
+//Ticks (of 100ns) in a second
+const TICKS_PER_SECOND: i64 = 10_000_000;
+
+/* A Jellyfin server, as the user that logged in */
+pub struct JellyfinSource {
+    library: Library,
+    client: ServerClient,
+}
+
+impl JellyfinSource {
+    pub fn new(library: Library, client: ServerClient) -> Self {
+        JellyfinSource { library, client }
+    }
+
+    fn user_id(&self) -> &str {
+        &self.library.user_id
+    }
+
+    /* Every item of a listing, a page at a time */
+    async fn get_all_items(
+        &self,
+        path: &str,
+        query: &str,
+        mut progress: Option<&mut SyncProgressReporter<'_>>,
+    ) -> Result<Vec<JellyfinItem>, anyhow::Error> {
+        let mut items: Vec<JellyfinItem> = vec![];
+        loop {
+            let page = get_items_page(
+                &self.client,
+                &self.library,
+                path,
+                query,
+                items.len() as u32,
+                PAGE_SIZE,
+            )
+            .await?;
+            if let Some(progress) = progress.as_deref_mut() {
+                if items.is_empty() {
+                    progress.start(page.total_record_count.div_ceil(PAGE_SIZE).max(1) as usize);
+                }
+                progress.advance();
+            }
+            let page_size = page.items.len();
+            items.extend(page.items);
+            if page_size < PAGE_SIZE as usize || items.len() >= page.total_record_count as usize {
+                break;
+            }
+        }
+        Ok(items)
+    }
+}
+
+#[async_trait]
+impl MusicSource for JellyfinSource {
+    fn library(&self) -> &Library {
+        &self.library
+    }
+
+    async fn ping(&self) -> Result<(), anyhow::Error> {
+        Ok(ping_jellyfin(&self.client, &self.library).await?)
+    }
+
+    /* Every MusicArtist item */
+    async fn list_artists(
+        &self,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Artist>, anyhow::Error> {
+        let path = format!("Users/{}/Items", self.user_id());
+        let query =
+            "IncludeItemTypes=MusicArtist&Recursive=true&SortBy=SortName&Fields=DateCreated";
+        let items = self.get_all_items(&path, query, Some(progress)).await?;
+        Ok(items.iter().map(|item| self.to_artist(item)).collect())
+    }
+
+    /* Every MusicAlbum item, whatever the artists */
+    async fn list_albums(
+        &self,
+        _artists: &[Artist],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<SourceAlbum>, anyhow::Error> {
+        let path = format!("Users/{}/Items", self.user_id());
+        let query = "IncludeItemTypes=MusicAlbum&Recursive=true&SortBy=SortName&Fields=DateCreated";
+        let items = self.get_all_items(&path, query, Some(progress)).await?;
+        Ok(items
+            .iter()
+            .map(|item| SourceAlbum {
+                album: self.to_album(item),
+                changed: None,
+            })
+            .collect())
+    }
+
+    /* Every Audio item, keeping those of the given albums */
+    async fn list_songs(
+        &self,
+        albums: &[Album],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Song>, anyhow::Error> {
+        let path = format!("Users/{}/Items", self.user_id());
+        let query = "IncludeItemTypes=Audio&Recursive=true&SortBy=SortName&Fields=DateCreated";
+        let items = self.get_all_items(&path, query, Some(progress)).await?;
+        let album_ids: HashSet<&String> = albums.iter().map(|album| &album.id).collect();
+        Ok(items
+            .iter()
+            .map(|item| self.to_song(item))
+            .filter(|song| album_ids.contains(&song.album_id))
+            .collect())
+    }
+
+    /* Items/{id}/Images/Primary for each album */
+    async fn fetch_art(
+        &self,
+        albums: &[Album],
+        cover_art_dir: &Path,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<HashMap<String, String>, anyhow::Error> {
+        //Create cover_art folder (one subfolder per library) if it doesn't exist
+        std::fs::create_dir_all(cover_art_dir.join(&self.library.id))?;
+
+        let cover_art_dir = cover_art_dir.to_string_lossy().to_string();
+        let mut futures = vec![];
+        for album in albums.iter().filter(|album| !album.cover_art.is_empty()) {
+            let item_id = strip_namespace(&self.library.id, &album.id);
+            let url =
+                create_jellyfin_url(&self.library, &format!("Items/{}/Images/Primary", item_id));
+            futures.push(get_album_art(
+                url,
+                album.cover_art.clone(),
+                &self.client,
+                &cover_art_dir,
+            ));
+        }
+
+        let mut cover_art_map: HashMap<String, String> = HashMap::new();
+        progress.start(futures.len());
+        let mut stream =
+            futures::stream::iter(futures).buffer_unordered(self.client.max_concurrent_requests());
+        while let Some(result) = stream.next().await {
+            progress.advance();
+            match result {
+                Ok(file_name) => {
+                    cover_art_map.insert(
+                        Path::new(&file_name)
+                            .with_extension("")
+                            .to_string_lossy()
+                            .to_string(),
+                        file_name,
+                    );
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Ok(cover_art_map)
+    }
+
+    async fn playlists(&self) -> Result<Vec<Playlist>, anyhow::Error> {
+        let path = format!("Users/{}/Items", self.user_id());
+        let query = "IncludeItemTypes=Playlist&Recursive=true&Fields=DateCreated,ChildCount,Etag";
+        let items = self.get_all_items(&path, query, None).await?;
+        Ok(items.iter().map(|item| self.to_playlist(item)).collect())
+    }
+
+    /* Playlists/{id}/Items */
+    async fn playlist(&self, playlist_id: &str) -> Result<(Playlist, Vec<String>), anyhow::Error> {
+        let server_id = strip_namespace(&self.library.id, playlist_id);
+        let path = format!("Users/{}/Items", self.user_id());
+        let query = format!("Ids={}&Fields=DateCreated,ChildCount,Etag", server_id);
+        let playlist = self
+            .get_all_items(&path, &query, None)
+            .await?
+            .first()
+            .map(|item| self.to_playlist(item))
+            .ok_or_else(|| anyhow::anyhow!("Unknown playlist {}", playlist_id))?;
+
+        let path = format!("Playlists/{}/Items", server_id);
+        let query = format!("userId={}", self.user_id());
+        let song_ids = self
+            .get_all_items(&path, &query, None)
+            .await?
+            .iter()
+            .map(|item| namespace_id(&self.library.id, &item.id))
+            .collect();
+        Ok((playlist, song_ids))
+    }
+
+    /* Audio/{id}/universal, which sends the file as it is if its format is one of the given
+     *  containers and transcodes it otherwise */
+    async fn stream_url(
+        &self,
+        song_id: &str,
+        transcode: &TranscodeSettings,
+    ) -> Result<String, anyhow::Error> {
+        let item_id = strip_namespace(&self.library.id, song_id);
+        let mut url = format!(
+            "{}&UserId={}&DeviceId={}&TranscodingProtocol=http",
+            create_jellyfin_url(&self.library, &format!("Audio/{}/universal", item_id)),
+            self.user_id(),
+            device_id(&self.library)
+        );
+        match transcode.format.as_deref() {
+            Some(format) if format != "raw" => {
+                let format = encode_query_value(format);
+                url.push_str(&format!(
+                    "&Container={}&TranscodingContainer={}&AudioCodec={}",
+                    format, format, format
+                ));
+            }
+            _ => url.push_str(&format!(
+                "&Container={}&TranscodingContainer=mp3&AudioCodec=mp3",
+                DIRECT_PLAY_CONTAINERS
+            )),
+        }
+        if let Some(max_bit_rate) = transcode
+            .max_bit_rate
+            .filter(|max_bit_rate| *max_bit_rate > 0)
+        {
+            url.push_str(&format!("&MaxStreamingBitrate={}", max_bit_rate * 1000));
+        }
+        Ok(url)
+    }
+}
+
+impl JellyfinSource {
+    fn to_artist(&self, item: &JellyfinItem) -> Artist {
+        Artist {
+            id: namespace_id(&self.library.id, &item.id),
+            name: item.name.clone(),
+            library_id: self.library.id.clone(),
+            starred: starred(item),
+            rating: 0,
+        }
+    }
+
+    fn to_album(&self, item: &JellyfinItem) -> Album {
+        let library_id = &self.library.id;
+        let artist = item.album_artists.first();
+        Album {
+            id: namespace_id(library_id, &item.id),
+            name: item.name.clone(),
+            artist_id: namespace_id(library_id, artist.map_or("", |artist| artist.id.as_str())),
+            artist_name: item
+                .album_artist
+                .clone()
+                .or_else(|| artist.map(|artist| artist.name.clone()))
+                .unwrap_or_default(),
+            library_id: library_id.clone(),
+            cover_art: if item.image_tags.contains_key("Primary") {
+                cover_art_key(library_id, &item.id)
+            } else {
+                String::new()
+            },
+            year: item.production_year,
+            duration: seconds(item.run_time_ticks).unwrap_or(0),
+            starred: starred(item),
+            rating: 0,
+        }
+    }
+
+    fn to_song(&self, item: &JellyfinItem) -> Song {
+        let library_id = &self.library.id;
+        let album_id = item.album_id.as_deref().unwrap_or("");
+        //Only album artists are kept as artists by a sync, so songs link to their album artist
+        //(or else their first artist) while the name lists every artist of the song
+        let artist = item.album_artists.first().or(item.artist_items.first());
+        let artist_names: Vec<&str> = item
+            .artist_items
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect();
+        Song {
+            id: namespace_id(library_id, &item.id),
+            title: item.name.clone(),
+            artist_id: namespace_id(library_id, artist.map_or("", |artist| artist.id.as_str())),
+            artist_name: if artist_names.is_empty() {
+                item.album_artist.clone().unwrap_or_default()
+            } else {
+                artist_names.join(", ")
+            },
+            album_id: namespace_id(library_id, album_id),
+            album_name: item.album.clone().unwrap_or_default(),
+            library_id: library_id.clone(),
+            track: item.index_number,
+            duration: seconds(item.run_time_ticks),
+            disc_number: item.parent_index_number.unwrap_or(1),
+            year: item.production_year,
+            content_type: content_type(item.container.as_deref().unwrap_or("")),
+            //Songs share their album's art
+            cover_art: match item.album_primary_image_tag {
+                Some(_) => cover_art_key(library_id, album_id),
+                None => String::new(),
+            },
+            starred: starred(item),
+            rating: 0,
+            replay_gain_track: item.normalization_gain,
+            replay_gain_album: None,
+            replay_gain_track_peak: None,
+            replay_gain_album_peak: None,
+        }
+    }
+
+    fn to_playlist(&self, item: &JellyfinItem) -> Playlist {
+        Playlist {
+            id: namespace_id(&self.library.id, &item.id),
+            library_id: self.library.id.clone(),
+            name: item.name.clone(),
+            owner: self.library.username.clone(),
+            created: item.date_created.clone().unwrap_or_default(),
+            //Jellyfin doesn't say when a playlist last changed, but its Etag changes with it
+            modified: item.etag.clone().unwrap_or_default(),
+            song_count: item.child_count.unwrap_or(0),
+            duration: seconds(item.run_time_ticks).unwrap_or(0),
+        }
+    }
+}
+
+fn seconds(ticks: Option<i64>) -> Option<u32> {
+    ticks.map(|ticks| (ticks / TICKS_PER_SECOND) as u32)
+}
+
+/* Favourites have no date, so they are starred as of when the item was added */
+fn starred(item: &JellyfinItem) -> Option<String> {
+    let is_favorite = item
+        .user_data
+        .as_ref()
+        .is_some_and(|user_data| user_data.is_favorite);
+    if is_favorite {
+        Some(item.date_created.clone().unwrap_or_default())
+    } else {
+        None
+    }
+}
+
+/* Content type of a song from its container (which can be a list, e.g. "mov,mp4,m4a") */
+fn content_type(container: &str) -> String {
+    let content_type = match container.split(',').next().unwrap_or("") {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "mov" | "mp4" | "m4a" | "m4b" | "aac" => "audio/mp4",
+        "wav" => "audio/wav",
+        "webm" | "webma" => "audio/webm",
+        _ => "application/octet-stream",
+    };
+    content_type.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{Matcher, Server};
+
+    use super::*;
+    use crate::client::ServerClientConfig;
+    use crate::jellyfin::authenticate;
+    use crate::models::LibraryKind;
+
+    const USER_ID: &str = "5f3c9a2b7e1d4c8a9b0e6f2d1a3c5b7e";
+    const ACCESS_TOKEN: &str = "0d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a";
+
+    /* Responses as a Jellyfin 10.9 server sends them, trimmed to a few items */
+    const AUTHENTICATE_BY_NAME: &str = include_str!("fixtures/authenticate_by_name.json");
+    const ARTISTS: &str = include_str!("fixtures/artists.json");
+    const ALBUMS: &str = include_str!("fixtures/albums.json");
+    const SONGS_PAGE_1: &str = include_str!("fixtures/songs_page_1.json");
+    const SONGS_PAGE_2: &str = include_str!("fixtures/songs_page_2.json");
+
+    fn library(host: String) -> Library {
+        Library {
+            id: String::from("jf"),
+            name: String::from("Jellyfin"),
+            host,
+            port: None,
+            username: String::from("alice"),
+            hashed_password: ACCESS_TOKEN.to_string(),
+            salt: String::new(),
+            last_scanned: String::new(),
+            kind: LibraryKind::Jellyfin,
+            paths: vec![],
+            user_id: USER_ID.to_string(),
+        }
+    }
+
+    fn source(server: &Server) -> JellyfinSource {
+        let client = ServerClient::new(ServerClientConfig::default());
+        JellyfinSource::new(library(server.url()), client)
+    }
+
+    fn items_query(item_type: &str, start_index: u32) -> Matcher {
+        Matcher::AllOf(vec![
+            Matcher::UrlEncoded("api_key".into(), ACCESS_TOKEN.into()),
+            Matcher::UrlEncoded("IncludeItemTypes".into(), item_type.into()),
+            Matcher::UrlEncoded("StartIndex".into(), start_index.to_string()),
+            Matcher::UrlEncoded("Limit".into(), PAGE_SIZE.to_string()),
+        ])
+    }
+
+    async fn list(source: &JellyfinSource, item_type: &str) -> Vec<JellyfinItem> {
+        let path = format!("Users/{}/Items", USER_ID);
+        let query = format!("IncludeItemTypes={}&Recursive=true", item_type);
+        source.get_all_items(&path, &query, None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn authenticate_by_name_returns_the_user_and_token() {
+        let mut server = Server::new_async().await;
+        let mock = server
+            .mock("POST", "/Users/AuthenticateByName")
+            .match_header(
+                "authorization",
+                Matcher::Regex(r#"^MediaBrowser .*DeviceId="polyphonic-jf""#.into()),
+            )
+            .match_body(Matcher::Json(serde_json::json!({
+                "Username": "alice",
+                "Pw": "secret"
+            })))
+            .with_header("content-type", "application/json")
+            .with_body(AUTHENTICATE_BY_NAME)
+            .create_async()
+            .await;
+
+        let client = ServerClient::new(ServerClientConfig::default());
+        let response = authenticate(&client, &library(server.url()), "secret")
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        assert_eq!(response.user.id, USER_ID);
+        assert_eq!(response.access_token, ACCESS_TOKEN);
+    }
+
+    #[tokio::test]
+    async fn refused_login_is_wrong_credentials() {
+        let mut server = Server::new_async().await;
+        server
+            .mock("POST", "/Users/AuthenticateByName")
+            .with_status(401)
+            .create_async()
+            .await;
+
+        let client = ServerClient::new(ServerClientConfig::default());
+        let error = authenticate(&client, &library(server.url()), "wrong")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), "wrong_credentials");
+        assert_eq!(error.code(), Some(401));
+    }
+
+    #[tokio::test]
+    async fn items_are_listed_a_page_at_a_time() {
+        let mut server = Server::new_async().await;
+        let path = format!("/Users/{}/Items", USER_ID);
+        let first_page = server
+            .mock("GET", path.as_str())
+            .match_query(items_query("Audio", 0))
+            .with_body(SONGS_PAGE_1)
+            .expect(1)
+            .create_async()
+            .await;
+        let last_page = server
+            .mock("GET", path.as_str())
+            .match_query(items_query("Audio", 2))
+            .with_body(SONGS_PAGE_2)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let items = list(&source(&server), "Audio").await;
+        first_page.assert_async().await;
+        last_page.assert_async().await;
+        let names: Vec<&str> = items.iter().map(|item| item.name.as_str()).collect();
+        assert_eq!(names, vec!["Roygbiv", "Aquarius", "Nlogax (Plaid Remix)"]);
+    }
+
+    #[tokio::test]
+    async fn items_map_into_models() {
+        let mut server = Server::new_async().await;
+        let path = format!("/Users/{}/Items", USER_ID);
+        for (item_type, start_index, body) in [
+            ("MusicArtist", 0, ARTISTS),
+            ("MusicAlbum", 0, ALBUMS),
+            ("Audio", 0, SONGS_PAGE_1),
+            ("Audio", 2, SONGS_PAGE_2),
+        ] {
+            server
+                .mock("GET", path.as_str())
+                .match_query(items_query(item_type, start_index))
+                .with_body(body)
+                .create_async()
+                .await;
+        }
+        let source = source(&server);
+
+        let artists: Vec<Artist> = list(&source, "MusicArtist")
+            .await
+            .iter()
+            .map(|item| source.to_artist(item))
+            .collect();
+        assert_eq!(artists.len(), 2);
+        assert_eq!(artists[0].id, "jf:a1b2c3d4e5f60718293a4b5c6d7e8f90");
+        assert_eq!(artists[0].name, "Boards of Canada");
+        assert_eq!(
+            artists[0].starred.as_deref(),
+            Some("2024-01-10T09:00:00.0000000Z")
+        );
+        assert_eq!(artists[1].starred, None);
+
+        let albums: Vec<Album> = list(&source, "MusicAlbum")
+            .await
+            .iter()
+            .map(|item| source.to_album(item))
+            .collect();
+        assert_eq!(albums[0].id, "jf:c3d4e5f60718293a4b5c6d7e8f90a1b2");
+        assert_eq!(albums[0].artist_id, "jf:a1b2c3d4e5f60718293a4b5c6d7e8f90");
+        assert_eq!(albums[0].artist_name, "Boards of Canada");
+        assert_eq!(albums[0].year, Some(1998));
+        assert_eq!(albums[0].duration, 4233);
+        assert_eq!(
+            albums[0].cover_art,
+            cover_art_key("jf", "c3d4e5f60718293a4b5c6d7e8f90a1b2")
+        );
+        //No AlbumArtist name and no Primary image
+        assert_eq!(albums[1].artist_name, "Various Artists");
+        assert_eq!(albums[1].cover_art, "");
+
+        let songs: Vec<Song> = list(&source, "Audio")
+            .await
+            .iter()
+            .map(|item| source.to_song(item))
+            .collect();
+        let roygbiv = &songs[0];
+        assert_eq!(roygbiv.album_id, albums[0].id);
+        assert_eq!(roygbiv.artist_id, albums[0].artist_id);
+        assert_eq!(roygbiv.track, Some(12));
+        assert_eq!(roygbiv.duration, Some(151));
+        assert_eq!(roygbiv.content_type, "audio/flac");
+        assert_eq!(roygbiv.cover_art, albums[0].cover_art);
+        assert_eq!(roygbiv.replay_gain_track, Some(-6.5));
+        assert!(roygbiv.starred.is_some());
+        //Without ParentIndexNumber, and with a list of containers
+        assert_eq!(songs[1].disc_number, 1);
+        assert_eq!(songs[1].content_type, "audio/mp4");
+        //No album artists, so the song links to its first artist
+        let remix = &songs[2];
+        assert_eq!(remix.artist_id, "jf:a1b2c3d4e5f60718293a4b5c6d7e8f90");
+        assert_eq!(remix.artist_name, "Boards of Canada, Plaid");
+        assert_eq!(remix.content_type, "audio/mpeg");
+        assert_eq!(remix.cover_art, "");
+    }
+}
@@ -1,16 +1,28 @@
 use tauri_plugin_sql::{Migration, MigrationKind};
 
+mod ampache;
+mod cache;
+mod client;
 mod commands;
 mod db;
+mod downloads;
+mod errors;
 mod formatter;
+mod jellyfin;
+mod local;
 mod models;
 mod music;
+mod playback;
 mod responses;
+mod scrobble;
+mod search;
+mod source;
+mod streaming;
 mod subsonic;
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    let migrations = vec![
+/* Schema migrations, applied in order by tauri_plugin_sql (and by the tests' in-memory databases) */
+pub(crate) fn migrations() -> Vec<Migration> {
+    vec![
         Migration {
         version: 1,
         description: "Create initial tables",
@@ -45,33 +57,220 @@ pub fn run() {
         sql: "CREATE UNIQUE INDEX IF NOT EXISTS album_tag_idx ON album_tags (album_id, tag_id);",
         kind: MigrationKind::Up,
       },
-    ];
+      Migration {
+        version: 6,
+        description: "Namespace IDs by library",
+        sql: "UPDATE album_tags SET album_id = (SELECT albums.library_id || ':' || albums.id FROM albums WHERE albums.id = album_tags.album_id) WHERE album_id IN (SELECT id FROM albums);
+        UPDATE artists SET id = library_id || ':' || id;
+        UPDATE albums SET id = library_id || ':' || id, artist_id = CASE WHEN artist_id = '' THEN artist_id ELSE library_id || ':' || artist_id END, cover_art = CASE WHEN cover_art IS NULL OR cover_art = '' THEN cover_art ELSE library_id || '/' || cover_art END;
+        UPDATE songs SET id = library_id || ':' || id, album_id = library_id || ':' || album_id, artist_id = CASE WHEN artist_id = '' THEN artist_id ELSE library_id || ':' || artist_id END, cover_art = CASE WHEN cover_art IS NULL OR cover_art = '' THEN cover_art ELSE library_id || '/' || cover_art END;
+        UPDATE playlists SET id = library_id || ':' || id;
+        CREATE INDEX IF NOT EXISTS artists_library_idx ON artists (library_id);
+        CREATE INDEX IF NOT EXISTS albums_library_idx ON albums (library_id);
+        CREATE INDEX IF NOT EXISTS songs_library_idx ON songs (library_id);
+        CREATE INDEX IF NOT EXISTS playlists_library_idx ON playlists (library_id);",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 7,
+        description: "Create scrobble_queue table",
+        sql: "CREATE TABLE IF NOT EXISTS scrobble_queue (id INTEGER PRIMARY KEY AUTOINCREMENT, library_id TEXT REFERENCES libraries(id), song_id TEXT, played_at INTEGER);",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 8,
+        description: "Add starred and rating columns",
+        sql: "ALTER TABLE artists ADD COLUMN starred TEXT;
+        ALTER TABLE artists ADD COLUMN rating INTEGER DEFAULT 0;
+        ALTER TABLE albums ADD COLUMN starred TEXT;
+        ALTER TABLE albums ADD COLUMN rating INTEGER DEFAULT 0;
+        ALTER TABLE songs ADD COLUMN starred TEXT;
+        ALTER TABLE songs ADD COLUMN rating INTEGER DEFAULT 0;",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 9,
+        description: "Create search_index full-text table",
+        sql: "CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(kind UNINDEXED, id UNINDEXED, library_id UNINDEXED, title, artist, album, tokenize = 'unicode61 remove_diacritics 2');
+        INSERT INTO search_index (kind, id, library_id, title, artist, album) SELECT 'artist', id, library_id, name, name, '' FROM artists;
+        INSERT INTO search_index (kind, id, library_id, title, artist, album) SELECT 'album', id, library_id, name, artist_name, name FROM albums;
+        INSERT INTO search_index (kind, id, library_id, title, artist, album) SELECT 'song', id, library_id, title, artist_name, album_name FROM songs;",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 10,
+        description: "Create downloads tables",
+        sql: "CREATE TABLE IF NOT EXISTS downloads (song_id TEXT PRIMARY KEY, library_id TEXT REFERENCES libraries(id), path TEXT, size INTEGER, checksum TEXT, source TEXT, content_type TEXT, downloaded_at INTEGER);
+        CREATE TABLE IF NOT EXISTS download_pins (id TEXT PRIMARY KEY, library_id TEXT REFERENCES libraries(id), kind TEXT, created_at INTEGER);
+        CREATE TABLE IF NOT EXISTS download_pin_songs (pin_id TEXT REFERENCES download_pins(id), song_id TEXT, PRIMARY KEY (pin_id, song_id));
+        CREATE INDEX IF NOT EXISTS download_pin_songs_song_idx ON download_pin_songs (song_id);",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 11,
+        description: "Create settings and cache_entries tables",
+        sql: "CREATE TABLE IF NOT EXISTS settings (key TEXT PRIMARY KEY, value TEXT);
+        CREATE TABLE IF NOT EXISTS cache_entries (kind TEXT, key TEXT, library_id TEXT, path TEXT, size INTEGER, content_type TEXT, last_accessed INTEGER, PRIMARY KEY (kind, key));
+        CREATE INDEX IF NOT EXISTS cache_entries_lru_idx ON cache_entries (kind, last_accessed);",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 12,
+        description: "Create transcode_settings table",
+        sql: "CREATE TABLE IF NOT EXISTS transcode_settings (library_id TEXT REFERENCES libraries(id), profile TEXT, format TEXT, max_bit_rate INTEGER, estimate_content_length INTEGER DEFAULT 0, PRIMARY KEY (library_id, profile));",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 13,
+        description: "Add ReplayGain columns to songs",
+        sql: "ALTER TABLE songs ADD COLUMN replay_gain_track REAL;
+        ALTER TABLE songs ADD COLUMN replay_gain_album REAL;
+        ALTER TABLE songs ADD COLUMN replay_gain_track_peak REAL;
+        ALTER TABLE songs ADD COLUMN replay_gain_album_peak REAL;
+        ALTER TABLE songs ADD COLUMN replay_gain_computed REAL;
+        ALTER TABLE songs ADD COLUMN replay_gain_computed_peak REAL;",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 14,
+        description: "Create dsp_presets table",
+        sql: "CREATE TABLE IF NOT EXISTS dsp_presets (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, library_id TEXT REFERENCES libraries(id), device TEXT, settings TEXT NOT NULL);",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 15,
+        description: "Create play queue tables",
+        sql: "CREATE TABLE IF NOT EXISTS play_queue_items (item_index INTEGER PRIMARY KEY, library_id TEXT, song_id TEXT);
+        CREATE TABLE IF NOT EXISTS play_queue_state (id INTEGER PRIMARY KEY CHECK (id = 0), current_index INTEGER, position REAL, changed INTEGER);",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 16,
+        description: "Create playlist_songs table",
+        sql: "CREATE TABLE IF NOT EXISTS playlist_songs (playlist_id TEXT REFERENCES playlists(id), position INTEGER, song_id TEXT, PRIMARY KEY (playlist_id, position));",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 17,
+        description: "Add local libraries",
+        sql: "ALTER TABLE libraries ADD COLUMN kind TEXT NOT NULL DEFAULT 'subsonic';
+        ALTER TABLE libraries ADD COLUMN paths TEXT NOT NULL DEFAULT '[]';
+        CREATE TABLE IF NOT EXISTS local_files (path TEXT PRIMARY KEY, library_id TEXT REFERENCES libraries(id), song_id TEXT, content_type TEXT, modified INTEGER, size INTEGER);
+        CREATE INDEX IF NOT EXISTS local_files_song_id ON local_files (song_id);",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 18,
+        description: "Store the tags of local files",
+        sql: "ALTER TABLE local_files ADD COLUMN track TEXT NOT NULL DEFAULT '';",
+        kind: MigrationKind::Up,
+      },
+      Migration {
+        version: 19,
+        description: "Store the user of Jellyfin libraries apart from the salt",
+        sql: "ALTER TABLE libraries ADD COLUMN user_id TEXT NOT NULL DEFAULT '';
+        UPDATE libraries SET user_id = salt, salt = '' WHERE kind = 'jellyfin';",
+        kind: MigrationKind::Up,
+      },
+    ]
+}
 
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
     #[cfg(target_os = "linux")]
-    unsafe { // Not unsafe if you don't use edition 2024
+    unsafe {
+        // Not unsafe if you don't use edition 2024
         std::env::set_var("WEBKIT_DISABLE_DMABUF_RENDERER", "1");
     }
 
     tauri::Builder::default()
+        .manage(music::SyncState::default())
+        .manage(client::ServerClient::new(
+            client::ServerClientConfig::default(),
+        ))
+        .manage(streaming::StreamState::default())
+        .manage(playback::Player::default())
+        .manage(local::LocalWatcher::default())
+        .register_asynchronous_uri_scheme_protocol(
+            streaming::STREAM_PROTOCOL,
+            streaming::handle_stream_request,
+        )
         .plugin(tauri_plugin_window_state::Builder::new().build())
         .plugin(tauri_plugin_sql::Builder::new().build())
         .plugin(tauri_plugin_store::Builder::new().build())
         .plugin(tauri_plugin_shell::init())
         .plugin(
             tauri_plugin_sql::Builder::default()
-                .add_migrations("sqlite:music.db", migrations)
+                .add_migrations("sqlite:music.db", migrations())
                 .build(),
         )
+        .setup(|app| {
+            //Art from before IDs were namespaced is moved into its library's folder
+            let app_handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = cache::relocate_legacy_cover_art(&app_handle).await {
+                    println!("Error: {}", e);
+                }
+            });
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             commands::add_server,
+            commands::add_local_library,
             commands::sync_collection,
-            commands::load_songs,
+            commands::cancel_sync,
+            commands::libraries_changed,
             commands::get_libraries,
             commands::get_recently_played,
             commands::get_recently_added,
             commands::get_songs_for_playlist,
             commands::update_library_modified,
             commands::clear_cover_art_cache,
+            commands::scrobble_now_playing,
+            commands::scrobble_submission,
+            commands::flush_scrobble_queue,
+            commands::set_starred,
+            commands::set_rating,
+            commands::get_favourites,
+            commands::search_library,
+            commands::pin_album,
+            commands::pin_playlist,
+            commands::unpin,
+            commands::get_downloads,
+            commands::get_download_pins,
+            commands::get_cache_stats,
+            commands::get_cache_settings,
+            commands::set_cache_settings,
+            commands::touch_cover_art,
+            commands::fetch_cover_art,
+            commands::get_network_profile,
+            commands::set_network_profile,
+            commands::get_transcode_settings,
+            commands::set_transcode_settings,
+            commands::play_queue,
+            commands::update_queue,
+            commands::play,
+            commands::pause,
+            commands::seek,
+            commands::next_track,
+            commands::previous_track,
+            commands::set_volume,
+            commands::get_playback_settings,
+            commands::set_playback_settings,
+            commands::get_dsp_presets,
+            commands::save_dsp_preset,
+            commands::delete_dsp_preset,
+            commands::get_active_dsp_preset,
+            commands::set_active_dsp_preset,
+            commands::get_dsp_response,
+            commands::get_output_devices,
+            commands::get_saved_queue,
+            commands::save_server_play_queue,
+            commands::get_server_play_queue,
+            commands::create_playlist,
+            commands::update_playlist,
+            commands::reorder_playlist,
+            commands::delete_playlist,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
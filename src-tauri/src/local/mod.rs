@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use glob::glob;
+use image::{ImageFormat, ImageReader};
+
+use crate::{
+    formatter::namespace_id,
+    models::{Album, Artist, LocalFile, Song},
+    music::cover_art_key,
+};
+
+mod source;
+mod tags;
+mod watcher;
+pub use source::LocalSource;
+pub use tags::{file_stamp, partial_hash, read_embedded_art, read_track, LocalTrack};
+pub use watcher::{watch_library, LocalWatcher};
+
+const AUDIO_EXTENSIONS: [&str; 7] = ["mp3", "flac", "ogg", "oga", "opus", "m4a", "mp4"];
+
+//Image files (by name, without extension) used as an album's art when its songs have none embedded
+const FOLDER_ART_NAMES: [&str; 4] = ["folder", "cover", "front", "album"];
+const FOLDER_ART_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/* Artists, albums and songs built from the tags of a local library's files */
+pub struct LocalCollection {
+    pub artists: Vec<Artist>,
+    pub albums: Vec<Album>,
+    pub songs: Vec<Song>,
+    pub files: Vec<LocalFile>,
+    //Files of each album (by album ID), to find its art in
+    pub album_paths: HashMap<String, Vec<PathBuf>>,
+}
+
+/* Every audio file under the given folders, by extension */
+pub fn find_audio_files(paths: &[String]) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = vec![];
+    let mut dirs: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("Error: Failed to read {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => dirs.push(path),
+                Ok(_) if is_audio_file(&path) => files.push(path),
+                _ => {}
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| AUDIO_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/* Group tracks into albums (by album artist and album name) and album artists. IDs are derived
+*  from names and paths, so they stay the same from one scan to the next. */
+pub fn build_collection(library_id: &str, tracks: Vec<LocalTrack>) -> LocalCollection {
+    let mut collection = LocalCollection {
+        artists: vec![],
+        albums: vec![],
+        songs: vec![],
+        files: vec![],
+        album_paths: HashMap::new(),
+    };
+    let mut album_indexes: HashMap<String, usize> = HashMap::new();
+    for track in tracks {
+        let stored_track = serde_json::to_string(&track).unwrap_or_default();
+        let artist_id = namespace_id(library_id, &artist_local_id(&track.album_artist));
+        let local_album_id = album_local_id(&track.album_artist, &track.album);
+        let album_id = namespace_id(library_id, &local_album_id);
+        let cover_art = cover_art_key(library_id, &local_album_id);
+
+        let album_index = *album_indexes.entry(album_id.clone()).or_insert_with(|| {
+            if !collection
+                .artists
+                .iter()
+                .any(|artist| artist.id == artist_id)
+            {
+                collection.artists.push(Artist {
+                    id: artist_id.clone(),
+                    name: track.album_artist.clone(),
+                    library_id: library_id.to_string(),
+                    starred: None,
+                    rating: 0,
+                });
+            }
+            collection.albums.push(Album {
+                id: album_id.clone(),
+                name: track.album.clone(),
+                artist_id: artist_id.clone(),
+                artist_name: track.album_artist.clone(),
+                library_id: library_id.to_string(),
+                cover_art: cover_art.clone(),
+                year: None,
+                duration: 0,
+                starred: None,
+                rating: 0,
+            });
+            collection.albums.len() - 1
+        });
+        let album = &mut collection.albums[album_index];
+        album.duration += track.duration.unwrap_or(0);
+        //The earliest year of an album's songs, for reissues that only retag some of them
+        album.year = match (album.year, track.year) {
+            (Some(year), Some(track_year)) => Some(year.min(track_year)),
+            (year, track_year) => year.or(track_year),
+        };
+
+        let path = track.path.to_string_lossy().to_string();
+        let song_id = namespace_id(library_id, &song_local_id(&path));
+        collection.songs.push(Song {
+            id: song_id.clone(),
+            title: track.title,
+            artist_id: namespace_id(library_id, &artist_local_id(&track.artists[0])),
+            artist_name: track.artists.join(", "),
+            album_id: album_id.clone(),
+            album_name: track.album,
+            library_id: library_id.to_string(),
+            track: track.track,
+            duration: track.duration,
+            disc_number: track.disc_number.unwrap_or(1),
+            year: track.year,
+            content_type: track.content_type.clone(),
+            cover_art,
+            starred: None,
+            rating: 0,
+            replay_gain_track: track.replay_gain.track_gain,
+            replay_gain_album: track.replay_gain.album_gain,
+            replay_gain_track_peak: track.replay_gain.track_peak,
+            replay_gain_album_peak: track.replay_gain.album_peak,
+        });
+        collection.files.push(LocalFile {
+            path,
+            library_id: library_id.to_string(),
+            song_id,
+            content_type: track.content_type,
+            modified: track.modified,
+            size: track.size,
+            track: stored_track,
+        });
+        collection
+            .album_paths
+            .entry(album_id)
+            .or_default()
+            .push(track.path);
+    }
+    collection
+}
+
+/* Write the art of an album to cover_art/<key>.<ext>, from an image in its folder or else the
+*  first of its songs with art embedded. Returns the file name relative to cover_art. */
+pub fn save_album_art(
+    cover_art_root: &Path,
+    key: &String,
+    paths: &Vec<PathBuf>,
+) -> Result<Option<String>, anyhow::Error> {
+    let art = match find_folder_art(paths) {
+        Some(art) => Some(art),
+        None => {
+            let mut embedded = None;
+            for path in paths {
+                if let Some(art) = read_embedded_art(path)? {
+                    embedded = Some(art);
+                    break;
+                }
+            }
+            embedded
+        }
+    };
+    let art = match art {
+        Some(art) => art,
+        None => return Ok(None),
+    };
+
+    let reader = ImageReader::new(Cursor::new(&art)).with_guessed_format()?;
+    let file_extension = match reader.format() {
+        Some(ImageFormat::Jpeg) => "jpg",
+        Some(ImageFormat::Gif) => "gif",
+        Some(ImageFormat::WebP) => "webp",
+        //Default to png
+        _ => "png",
+    };
+    let file_name = format!("{}.{}", key, file_extension);
+    let file_path = cover_art_root.join(&file_name);
+
+    //Art that hasn't changed is left alone, and art that changed format replaces the old file
+    for entry in glob(&format!("{}.*", cover_art_root.join(key).to_string_lossy()))?.flatten() {
+        if entry == file_path {
+            if fs::metadata(&entry).is_ok_and(|metadata| metadata.len() == art.len() as u64) {
+                return Ok(Some(file_name));
+            }
+        } else {
+            fs::remove_file(&entry)?;
+        }
+    }
+    fs::write(&file_path, &art)?;
+    Ok(Some(file_name))
+}
+
+/* The file already saved for a cover art key, relative to cover_art */
+pub fn saved_album_art(cover_art_root: &Path, key: &String) -> Option<String> {
+    let pattern = format!("{}.*", cover_art_root.join(key).to_string_lossy());
+    let saved = glob(&pattern).ok()?.flatten().next()?;
+    let file_name = saved.strip_prefix(cover_art_root).ok()?;
+    Some(file_name.to_string_lossy().to_string())
+}
+
+fn find_folder_art(paths: &[PathBuf]) -> Option<Vec<u8>> {
+    let mut folders: Vec<&Path> = paths.iter().filter_map(|path| path.parent()).collect();
+    folders.dedup();
+    for folder in folders {
+        let entries = match fs::read_dir(folder) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let extension = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            if FOLDER_ART_NAMES.contains(&stem.as_str())
+                && FOLDER_ART_EXTENSIONS.contains(&extension.as_str())
+            {
+                if let Ok(art) = fs::read(&path) {
+                    return Some(art);
+                }
+            }
+        }
+    }
+    None
+}
+
+/* IDs are hashes, so they are safe to use as cover art file names */
+fn local_id(kind: &str, name: &str) -> String {
+    use md5::{Digest, Md5};
+    let mut hasher = Md5::new();
+    hasher.update(format!("{}\u{1f}{}", kind, name));
+    hex::encode(hasher.finalize())
+}
+
+/* Artists and albums are matched case-insensitively, so "The Band" and "The band" are one artist */
+fn artist_local_id(name: &str) -> String {
+    local_id("artist", &name.to_lowercase())
+}
+
+fn album_local_id(album_artist: &str, album: &str) -> String {
+    local_id(
+        "album",
+        &format!(
+            "{}\u{1f}{}",
+            album_artist.to_lowercase(),
+            album.to_lowercase()
+        ),
+    )
+}
+
+pub fn song_local_id(path: &str) -> String {
+    local_id("song", path)
+}
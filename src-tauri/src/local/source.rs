@@ -0,0 +1,408 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::{
+    db::{get_local_files, replace_local_files},
+    models::{Album, Artist, Library, Playlist, Song, TranscodeSettings},
+    music::SyncProgressReporter,
+    source::{MusicSource, SourceAlbum},
+};
+
+use super::{
+    build_collection, file_stamp, find_audio_files, is_audio_file, partial_hash, read_track,
+    save_album_art, saved_album_art, LocalCollection, LocalTrack,
+};
+
+//Files are read on blocking threads, this many at a time
+const MAX_CONCURRENT_READS: usize = 4;
+
+/* Folders on this machine. The files are scanned when artists are listed, and the rest of the
+*  listing comes from that scan. Only files whose size or mtime changed since the last scan are
+*  read again. */
+pub struct LocalSource {
+    library: Library,
+    //Set for rescans after files changed: only these paths (files or folders) are looked at
+    changed_paths: Option<Vec<PathBuf>>,
+    //Tracks stored by the last scan, by path
+    index: Mutex<HashMap<PathBuf, LocalTrack>>,
+    //Files read by this scan rather than taken from the index
+    read_paths: Mutex<HashSet<PathBuf>>,
+    collection: Mutex<Option<LocalCollection>>,
+}
+
+impl LocalSource {
+    pub fn new(library: Library) -> Self {
+        LocalSource {
+            library,
+            changed_paths: None,
+            index: Mutex::new(HashMap::new()),
+            read_paths: Mutex::new(HashSet::new()),
+            collection: Mutex::new(None),
+        }
+    }
+
+    /* A rescan of the given paths, keeping everything else in the index as it is */
+    pub fn with_changes(library: Library, changed_paths: Vec<PathBuf>) -> Self {
+        LocalSource {
+            changed_paths: Some(changed_paths),
+            ..LocalSource::new(library)
+        }
+    }
+
+    fn scanned<T>(&self, read: impl FnOnce(&LocalCollection) -> T) -> Result<T, anyhow::Error> {
+        match self.collection.lock().unwrap().as_ref() {
+            Some(collection) => Ok(read(collection)),
+            None => Err(anyhow::anyhow!(
+                "Library {} hasn't been scanned",
+                self.library.id
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl MusicSource for LocalSource {
+    fn library(&self) -> &Library {
+        &self.library
+    }
+
+    async fn ping(&self) -> Result<(), anyhow::Error> {
+        for path in &self.library.paths {
+            if !Path::new(path).is_dir() {
+                return Err(anyhow::anyhow!("{} is not a folder", path));
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_index(&self, pool: &Pool<Sqlite>) -> Result<(), anyhow::Error> {
+        let mut index = HashMap::new();
+        for file in get_local_files(pool, &self.library.id).await? {
+            //Files indexed before tags were stored are read again
+            if let Ok(track) = serde_json::from_str::<LocalTrack>(&file.track) {
+                index.insert(track.path.clone(), track);
+            }
+        }
+        *self.index.lock().unwrap() = index;
+        Ok(())
+    }
+
+    /* Find the library's audio files and read the tags of those that are new or changed */
+    async fn list_artists(
+        &self,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Artist>, anyhow::Error> {
+        self.ping().await?;
+        let index = self.index.lock().unwrap().clone();
+        let roots = self.library.paths.clone();
+        let changed_paths = self.changed_paths.clone();
+        let (mut tracks, to_read) =
+            tauri::async_runtime::spawn_blocking(move || match changed_paths {
+                None => match_index(find_audio_files(&roots), &index),
+                Some(changed_paths) => {
+                    //Indexed files under the changed paths are checked again, the rest are kept
+                    let (touched, kept): (HashMap<_, _>, HashMap<_, _>) =
+                        index.into_iter().partition(|(path, _)| {
+                            changed_paths
+                                .iter()
+                                .any(|changed| path.starts_with(changed))
+                        });
+                    let (mut tracks, to_read) =
+                        match_index(find_changed_files(&changed_paths), &touched);
+                    tracks.extend(kept.into_values());
+                    (tracks, to_read)
+                }
+            })
+            .await?;
+
+        progress.start(to_read.len());
+        let mut read_paths = HashSet::new();
+        let mut reads = futures::stream::iter(to_read)
+            .map(|path| tauri::async_runtime::spawn_blocking(move || (read_track(&path), path)))
+            .buffered(MAX_CONCURRENT_READS);
+        while let Some(read) = reads.next().await {
+            progress.advance();
+            match read? {
+                (Ok(track), path) => {
+                    tracks.push(track);
+                    read_paths.insert(path);
+                }
+                (Err(e), path) => {
+                    println!("Error: Failed to read {}: {}", path.display(), e);
+                    //A file that can't be read right now (e.g. one still being written) keeps its
+                    //indexed tags. Only files that are gone are removed from the library.
+                    if path.exists() {
+                        if let Some(track) = self.index.lock().unwrap().get(&path) {
+                            tracks.push(track.clone());
+                        }
+                    }
+                }
+            }
+        }
+        //Same order as a full scan, whether tracks came from the index or were read
+        tracks.sort_by(|a, b| a.path.cmp(&b.path));
+        *self.read_paths.lock().unwrap() = read_paths;
+        let collection = build_collection(&self.library.id, tracks);
+        let artists = collection.artists.clone();
+        *self.collection.lock().unwrap() = Some(collection);
+        Ok(artists)
+    }
+
+    async fn list_albums(
+        &self,
+        _artists: &[Artist],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<SourceAlbum>, anyhow::Error> {
+        progress.start(1);
+        let albums = self.scanned(|collection| {
+            collection
+                .albums
+                .iter()
+                .map(|album| SourceAlbum {
+                    album: album.clone(),
+                    changed: None,
+                })
+                .collect()
+        })?;
+        progress.advance();
+        Ok(albums)
+    }
+
+    async fn list_songs(
+        &self,
+        albums: &[Album],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Song>, anyhow::Error> {
+        progress.start(1);
+        let album_ids: HashSet<&String> = albums.iter().map(|album| &album.id).collect();
+        let songs = self.scanned(|collection| {
+            collection
+                .songs
+                .iter()
+                .filter(|song| album_ids.contains(&song.album_id))
+                .cloned()
+                .collect()
+        })?;
+        progress.advance();
+        Ok(songs)
+    }
+
+    /* Art from each album's folder or embedded in its songs. Albums with none of their files read
+     *  by this scan keep the art already saved. */
+    async fn fetch_art(
+        &self,
+        albums: &[Album],
+        cover_art_dir: &Path,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<HashMap<String, String>, anyhow::Error> {
+        std::fs::create_dir_all(cover_art_dir.join(&self.library.id))?;
+        //Art fetched again on its own (after it was evicted) has no scan, only the stored index
+        let album_paths = match self.scanned(|collection| collection.album_paths.clone()) {
+            Ok(album_paths) => album_paths,
+            Err(_) => {
+                let tracks = self.index.lock().unwrap().values().cloned().collect();
+                build_collection(&self.library.id, tracks).album_paths
+            }
+        };
+        let read_paths = self.read_paths.lock().unwrap().clone();
+
+        let mut cover_art_map: HashMap<String, String> = HashMap::new();
+        progress.start(albums.len());
+        for album in albums {
+            let paths = album_paths.get(&album.id).cloned().unwrap_or_default();
+            if !paths.iter().any(|path| read_paths.contains(path)) {
+                if let Some(file_name) = saved_album_art(cover_art_dir, &album.cover_art) {
+                    progress.advance();
+                    cover_art_map.insert(album.cover_art.clone(), file_name);
+                    continue;
+                }
+            }
+            let (root, key) = (cover_art_dir.to_path_buf(), album.cover_art.clone());
+            let saved =
+                tauri::async_runtime::spawn_blocking(move || save_album_art(&root, &key, &paths))
+                    .await?;
+            progress.advance();
+            match saved {
+                Ok(Some(file_name)) => {
+                    cover_art_map.insert(album.cover_art.clone(), file_name);
+                }
+                Ok(None) => {}
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Ok(cover_art_map)
+    }
+
+    async fn playlists(&self) -> Result<Vec<Playlist>, anyhow::Error> {
+        Ok(vec![])
+    }
+
+    async fn playlist(&self, playlist_id: &str) -> Result<(Playlist, Vec<String>), anyhow::Error> {
+        Err(anyhow::anyhow!("Unknown playlist {}", playlist_id))
+    }
+
+    /* Local songs are read straight from their files (see streaming::open_local_file) */
+    async fn stream_url(
+        &self,
+        song_id: &str,
+        _transcode: &TranscodeSettings,
+    ) -> Result<String, anyhow::Error> {
+        Err(anyhow::anyhow!("{} is a local file, not a stream", song_id))
+    }
+
+    /* The file index, so songs can be found on disk */
+    async fn write_index(&self, conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+        let files = self.scanned(|collection| collection.files.clone())?;
+        replace_local_files(conn, &self.library.id, &files).await
+    }
+}
+
+/* Split files into tracks that can be taken from the index and files that need reading. A file
+*  that isn't indexed but has the size, mtime and partial hash of an indexed one that is gone was
+*  renamed or moved, and keeps its tags. */
+fn match_index(
+    files: Vec<PathBuf>,
+    index: &HashMap<PathBuf, LocalTrack>,
+) -> (Vec<LocalTrack>, Vec<PathBuf>) {
+    let found: HashSet<&PathBuf> = files.iter().collect();
+    let mut moved: HashMap<(i64, i64), Vec<&LocalTrack>> = HashMap::new();
+    for track in index.values().filter(|track| !found.contains(&track.path)) {
+        moved
+            .entry((track.modified, track.size))
+            .or_default()
+            .push(track);
+    }
+
+    let mut tracks: Vec<LocalTrack> = vec![];
+    let mut to_read: Vec<PathBuf> = vec![];
+    for path in &files {
+        let stamp = match file_stamp(path) {
+            Ok(stamp) => stamp,
+            Err(_) => {
+                to_read.push(path.clone());
+                continue;
+            }
+        };
+        let indexed = match index.get(path) {
+            Some(track) => Some(track).filter(|track| (track.modified, track.size) == stamp),
+            None => take_moved(&mut moved, stamp, path),
+        };
+        match indexed {
+            Some(track) => tracks.push(LocalTrack {
+                path: path.clone(),
+                ..track.clone()
+            }),
+            None => to_read.push(path.clone()),
+        }
+    }
+    (tracks, to_read)
+}
+
+/* The gone track a new file was renamed or moved from. Size and mtime only make it a candidate,
+*  as copies and re-encodes can share them; the content has to match too. */
+fn take_moved<'a>(
+    moved: &mut HashMap<(i64, i64), Vec<&'a LocalTrack>>,
+    stamp: (i64, i64),
+    path: &Path,
+) -> Option<&'a LocalTrack> {
+    let candidates = moved.get_mut(&stamp)?;
+    let hash = partial_hash(path).ok()?;
+    let position = candidates
+        .iter()
+        .position(|track| !track.partial_hash.is_empty() && track.partial_hash == hash)?;
+    Some(candidates.swap_remove(position))
+}
+
+/* Audio files at or under the given paths. Paths that no longer exist are skipped, their files
+*  having been removed. */
+fn find_changed_files(changed_paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = vec![];
+    for path in changed_paths {
+        if path.is_dir() {
+            files.extend(find_audio_files(&[path.to_string_lossy().to_string()]));
+        } else if path.is_file() && is_audio_file(path) {
+            files.push(path.clone());
+        }
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::models::ReplayGain;
+
+    fn indexed_track(
+        path: PathBuf,
+        (modified, size): (i64, i64),
+        partial_hash: String,
+    ) -> LocalTrack {
+        LocalTrack {
+            path,
+            title: String::from("Song"),
+            artists: vec![String::from("Artist")],
+            album: String::from("Album"),
+            album_artist: String::from("Artist"),
+            track: Some(1),
+            disc_number: None,
+            year: None,
+            duration: Some(180),
+            content_type: String::from("audio/flac"),
+            replay_gain: ReplayGain::default(),
+            modified,
+            size,
+            partial_hash,
+        }
+    }
+
+    /* A file with the given content and a fixed mtime, so files can share a stamp */
+    fn write_file(path: &Path, content: &[u8]) {
+        fs::write(path, content).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn renames_are_confirmed_by_content() {
+        let dir =
+            std::env::temp_dir().join(format!("polyphonic-match-index-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        //The indexed file is gone. One new file is it renamed, the other only shares its stamp.
+        let (old, renamed, other) = (
+            dir.join("old.flac"),
+            dir.join("renamed.flac"),
+            dir.join("other.flac"),
+        );
+        write_file(&renamed, b"the same audio");
+        write_file(&other, b"other audio!!!");
+        let stamp = file_stamp(&renamed).unwrap();
+        assert_eq!(stamp, file_stamp(&other).unwrap());
+        let index = HashMap::from([(
+            old.clone(),
+            indexed_track(old, stamp, partial_hash(&renamed).unwrap()),
+        )]);
+
+        let (tracks, to_read) = match_index(vec![other.clone(), renamed.clone()], &index);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].path, renamed);
+        assert_eq!(to_read, vec![other]);
+    }
+}
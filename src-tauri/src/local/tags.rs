@@ -0,0 +1,246 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::codecs::{
+    CodecType, CODEC_TYPE_AAC, CODEC_TYPE_ALAC, CODEC_TYPE_FLAC, CODEC_TYPE_MP3, CODEC_TYPE_NULL,
+    CODEC_TYPE_OPUS, CODEC_TYPE_VORBIS,
+};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, StandardVisualKey, Tag, Visual};
+use symphonia::core::probe::{Hint, ProbeResult};
+
+use crate::{models::ReplayGain, playback::replay_gain_tags};
+
+/* What a local library needs from an audio file. Missing tags are filled in from the file and
+*  folder names. */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocalTrack {
+    pub path: PathBuf,
+    pub title: String,
+    //In tag order. The first is used as the song's artist.
+    pub artists: Vec<String>,
+    pub album: String,
+    pub album_artist: String,
+    pub track: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    //Seconds
+    pub duration: Option<u32>,
+    pub content_type: String,
+    pub replay_gain: ReplayGain,
+    //Milliseconds since the epoch
+    pub modified: i64,
+    pub size: i64,
+    //See partial_hash. Empty for tracks indexed before it was stored.
+    #[serde(default)]
+    pub partial_hash: String,
+}
+
+/* Read the tags, duration and codec of an audio file. Pictures aren't decoded here (see
+*  read_embedded_art), so reading a whole library doesn't hold every cover in memory. */
+pub fn read_track(path: &Path) -> Result<LocalTrack, anyhow::Error> {
+    let (modified, size) = file_stamp(path)?;
+    let probed = probe(path)?;
+    let mut format = probed.format;
+    let mut probed_metadata = probed.metadata;
+    //Tags can be in the container (e.g. FLAC, MP4) or ahead of it (e.g. ID3v2 in MP3s)
+    let mut tags: Vec<Tag> = vec![];
+    if let Some(revision) = probed_metadata
+        .get()
+        .as_ref()
+        .and_then(|metadata| metadata.current())
+    {
+        tags.extend_from_slice(revision.tags());
+    }
+    if let Some(revision) = format.metadata().current() {
+        tags.extend_from_slice(revision.tags());
+    }
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow::anyhow!("No audio track"))?;
+    let codec_params = &track.codec_params;
+    let duration = match (codec_params.n_frames, codec_params.sample_rate) {
+        (Some(frames), Some(sample_rate)) if sample_rate > 0 => {
+            Some((frames as f64 / sample_rate as f64).round() as u32)
+        }
+        _ => None,
+    };
+
+    let mut artists: Vec<String> = vec![];
+    for tag in tags
+        .iter()
+        .filter(|tag| tag.std_key == Some(StandardTagKey::Artist))
+    {
+        let artist = tag.value.to_string().trim().to_string();
+        if !artist.is_empty() && !artists.contains(&artist) {
+            artists.push(artist);
+        }
+    }
+    let title = tag_text(&tags, StandardTagKey::TrackTitle).unwrap_or_else(|| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    });
+    let album = tag_text(&tags, StandardTagKey::Album).unwrap_or_else(|| {
+        path.parent()
+            .and_then(|parent| parent.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "Unknown Album".to_string())
+    });
+    let album_artist = tag_text(&tags, StandardTagKey::AlbumArtist)
+        .or_else(|| artists.first().cloned())
+        .unwrap_or_else(|| "Unknown Artist".to_string());
+    if artists.is_empty() {
+        artists.push(album_artist.clone());
+    }
+
+    Ok(LocalTrack {
+        path: path.to_path_buf(),
+        title,
+        artists,
+        album,
+        album_artist,
+        track: tag_number(&tags, StandardTagKey::TrackNumber),
+        disc_number: tag_number(&tags, StandardTagKey::DiscNumber),
+        year: tag_year(&tags),
+        duration,
+        content_type: content_type(codec_params.codec, path),
+        replay_gain: replay_gain_tags(&tags),
+        modified,
+        size,
+        partial_hash: partial_hash(path)?,
+    })
+}
+
+/* The front cover embedded in a file (or its first picture, if none is marked as the front) */
+pub fn read_embedded_art(path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let probed = probe(path)?;
+    let mut format = probed.format;
+    let mut probed_metadata = probed.metadata;
+    let mut visuals: Vec<Visual> = vec![];
+    if let Some(revision) = probed_metadata
+        .get()
+        .as_ref()
+        .and_then(|metadata| metadata.current())
+    {
+        visuals.extend_from_slice(revision.visuals());
+    }
+    if let Some(revision) = format.metadata().current() {
+        visuals.extend_from_slice(revision.visuals());
+    }
+    let visual = visuals
+        .iter()
+        .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+        .or(visuals.first());
+    Ok(visual.map(|visual| visual.data.to_vec()))
+}
+
+/* Size and modification time of a file, used to tell whether it changed since it was read */
+pub fn file_stamp(path: &Path) -> Result<(i64, i64), anyhow::Error> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |modified| modified.as_millis() as i64);
+    Ok((modified, metadata.len() as i64))
+}
+
+fn probe(path: &Path) -> Result<ProbeResult, anyhow::Error> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension() {
+        hint.with_extension(&extension.to_string_lossy());
+    }
+    Ok(symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?)
+}
+
+fn tag_text(tags: &[Tag], key: StandardTagKey) -> Option<String> {
+    tags.iter()
+        .filter(|tag| tag.std_key == Some(key))
+        .map(|tag| tag.value.to_string().trim().to_string())
+        .find(|value| !value.is_empty())
+}
+
+/* Track and disc numbers, written as e.g. "3" or "3/12" */
+fn tag_number(tags: &[Tag], key: StandardTagKey) -> Option<u32> {
+    let value = tag_text(tags, key)?;
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/* The year of the release date, written as e.g. "2004" or "2004-05-17" */
+fn tag_year(tags: &[Tag]) -> Option<u32> {
+    let value = tag_text(tags, StandardTagKey::Date)
+        .or_else(|| tag_text(tags, StandardTagKey::OriginalDate))?;
+    let year: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    match year.len() {
+        4 => year.parse().ok(),
+        _ => None,
+    }
+}
+
+/* Content type of a file, from its codec (an M4A can hold AAC or ALAC, an Ogg Vorbis or Opus) */
+fn content_type(codec: CodecType, path: &Path) -> String {
+    let content_type = match codec {
+        CODEC_TYPE_MP3 => "audio/mpeg",
+        CODEC_TYPE_FLAC => "audio/flac",
+        CODEC_TYPE_VORBIS => "audio/ogg",
+        CODEC_TYPE_OPUS => "audio/ogg",
+        CODEC_TYPE_AAC | CODEC_TYPE_ALAC => "audio/mp4",
+        _ => {
+            let extension = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            match extension.as_str() {
+                "mp3" => "audio/mpeg",
+                "flac" => "audio/flac",
+                "ogg" | "oga" | "opus" => "audio/ogg",
+                "m4a" | "mp4" => "audio/mp4",
+                _ => "application/octet-stream",
+            }
+        }
+    };
+    content_type.to_string()
+}
+
+//Bytes hashed from each end of a file
+const PARTIAL_HASH_BYTES: u64 = 64 * 1024;
+
+/* sha256 of the start and end of a file, which tells a renamed file from a different one with the
+*  same size and mtime without reading all of it */
+pub fn partial_hash(path: &Path) -> Result<String, anyhow::Error> {
+    use sha2::{Digest, Sha256};
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![];
+    file.by_ref()
+        .take(PARTIAL_HASH_BYTES)
+        .read_to_end(&mut buffer)?;
+    hasher.update(&buffer);
+    if size > PARTIAL_HASH_BYTES {
+        buffer.clear();
+        file.seek(SeekFrom::Start(
+            size.saturating_sub(PARTIAL_HASH_BYTES)
+                .max(PARTIAL_HASH_BYTES),
+        ))?;
+        file.read_to_end(&mut buffer)?;
+        hasher.update(&buffer);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    db::{db_connect, get_library},
+    models::{Library, LibraryKind},
+    music::{sync_library, SyncState},
+};
+
+use super::{is_audio_file, LocalSource};
+
+//Changes are applied once a library's files have been quiet for this long, so copying in a whole
+//album is one rescan rather than one per file
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+//Sent with the SyncDiff of a rescan, so the collection can be refreshed
+pub const LIBRARY_CHANGED_EVENT: &str = "library-changed";
+
+//Changed paths of a library, by library ID
+type LibraryChanges = (String, Vec<PathBuf>);
+
+#[derive(Default)]
+pub struct LocalWatcher {
+    //Watched folders of each local library, with the watcher that has to be kept alive for them
+    watchers: Mutex<HashMap<String, (Vec<String>, RecommendedWatcher)>>,
+    //Changed paths of every library go through one channel, to the task that rescans them
+    sender: Mutex<Option<UnboundedSender<LibraryChanges>>>,
+}
+
+/* Watch the folders of a local library, rescanning the files that change in them. Libraries that
+*  are already watched are left alone unless their folders changed. */
+pub fn watch_library(app_handle: &AppHandle, library: &Library) -> Result<(), anyhow::Error> {
+    let state = app_handle.state::<LocalWatcher>();
+    if let Some((paths, _)) = state.watchers.lock().unwrap().get(&library.id) {
+        if paths == &library.paths {
+            return Ok(());
+        }
+    }
+    let sender = state
+        .sender
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| {
+            let (sender, receiver) = unbounded_channel();
+            tauri::async_runtime::spawn(apply_changes(app_handle.clone(), receiver));
+            sender
+        })
+        .clone();
+
+    let library_id = library.id.clone();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+        let event = match result {
+            Ok(event) => event,
+            Err(e) => {
+                println!("Error: {}", e);
+                return;
+            }
+        };
+        if let EventKind::Access(_) = event.kind {
+            return;
+        }
+        let paths: Vec<PathBuf> = event
+            .paths
+            .into_iter()
+            .filter(|path| is_relevant(path))
+            .collect();
+        if !paths.is_empty() {
+            let _ = sender.send((library_id.clone(), paths));
+        }
+    })?;
+    for path in &library.paths {
+        watcher.watch(Path::new(path), RecursiveMode::Recursive)?;
+    }
+    state
+        .watchers
+        .lock()
+        .unwrap()
+        .insert(library.id.clone(), (library.paths.clone(), watcher));
+    Ok(())
+}
+
+/* Audio files and folders. A removed folder can't be told apart from a removed file, so anything
+*  without an extension counts too. */
+fn is_relevant(path: &Path) -> bool {
+    is_audio_file(path) || path.is_dir() || path.extension().is_none()
+}
+
+/* Gather changed paths until the files have been quiet for DEBOUNCE, then rescan them */
+async fn apply_changes(app_handle: AppHandle, mut receiver: UnboundedReceiver<LibraryChanges>) {
+    while let Some((library_id, paths)) = receiver.recv().await {
+        let mut changes: HashMap<String, HashSet<PathBuf>> = HashMap::new();
+        changes.entry(library_id).or_default().extend(paths);
+        while let Ok(Some((library_id, paths))) =
+            tokio::time::timeout(DEBOUNCE, receiver.recv()).await
+        {
+            changes.entry(library_id).or_default().extend(paths);
+        }
+        for (library_id, paths) in changes {
+            if let Err(e) =
+                rescan_library(&app_handle, &library_id, paths.into_iter().collect()).await
+            {
+                println!("Error: Failed to rescan {}: {}", library_id, e);
+            }
+        }
+    }
+}
+
+async fn rescan_library(
+    app_handle: &AppHandle,
+    library_id: &String,
+    changed_paths: Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    let pool = db_connect(app_handle).await?;
+    let library = match get_library(&pool, library_id).await? {
+        Some(library) if library.kind == LibraryKind::Local => library,
+        //The library was removed, so there is nothing left to watch
+        _ => {
+            let state = app_handle.state::<LocalWatcher>();
+            state.watchers.lock().unwrap().remove(library_id);
+            return Ok(());
+        }
+    };
+    let source = LocalSource::with_changes(library, changed_paths);
+    //Never alongside a manual sync of the same library, and stopped by cancel_sync like one
+    let sync_state = app_handle.state::<SyncState>();
+    let _lock = sync_state.lock_library(library_id).await;
+    let diff = sync_library(&source, app_handle, sync_state.register_rescan(library_id)).await?;
+    app_handle.emit(LIBRARY_CHANGED_EVENT, diff)?;
+    Ok(())
+}
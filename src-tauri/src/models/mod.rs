@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
+use crate::errors::CommandError;
+
 #[derive(Serialize, Deserialize)]
 pub struct LibraryConfig {
     pub id: String,
@@ -9,6 +11,47 @@ pub struct LibraryConfig {
     pub port: Option<i16>,
     pub username: String,
     pub password: String,
+    //Subsonic unless given
+    #[serde(default)]
+    pub kind: LibraryKind,
+}
+
+/* A library of folders on this machine */
+#[derive(Serialize, Deserialize)]
+pub struct LocalLibraryConfig {
+    pub id: String,
+    pub name: String,
+    pub paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LibraryKind {
+    #[default]
+    Subsonic,
+    Local,
+    Jellyfin,
+    Ampache,
+}
+
+impl LibraryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LibraryKind::Subsonic => "subsonic",
+            LibraryKind::Local => "local",
+            LibraryKind::Jellyfin => "jellyfin",
+            LibraryKind::Ampache => "ampache",
+        }
+    }
+
+    pub fn from_str(kind: &str) -> LibraryKind {
+        match kind {
+            "local" => LibraryKind::Local,
+            "jellyfin" => LibraryKind::Jellyfin,
+            "ampache" => LibraryKind::Ampache,
+            _ => LibraryKind::Subsonic,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, FromRow)]
@@ -20,9 +63,15 @@ pub struct DBLibrary {
     pub username: String,
     pub salt: String,
     pub last_scanned: String,
+    pub kind: String,
+    pub user_id: String,
+    // JSON array of folders (local libraries)
+    pub paths: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/* Host and username are only used by server libraries, paths only by local ones. The salt is only
+*  used by Subsonic libraries, and the user ID (of the logged in user) only by Jellyfin ones. */
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Library {
     pub id: String,
     pub name: String,
@@ -32,16 +81,24 @@ pub struct Library {
     pub hashed_password: String,
     pub salt: String,
     pub last_scanned: String,
+    #[serde(default)]
+    pub kind: LibraryKind,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub user_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct Artist {
     pub id: String,
     pub name: String,
     pub library_id: String,
+    pub starred: Option<String>,
+    pub rating: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct Album {
     pub id: String,
     pub name: String,
@@ -51,9 +108,11 @@ pub struct Album {
     pub cover_art: String,
     pub year: Option<u32>,
     pub duration: u32,
+    pub starred: Option<String>,
+    pub rating: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct Song {
     pub id: String,
     pub title: String,
@@ -65,8 +124,15 @@ pub struct Song {
     pub track: Option<u32>,
     pub duration: Option<u32>,
     pub disc_number: u32,
+    pub year: Option<u32>,
     pub content_type: String,
     pub cover_art: String,
+    pub starred: Option<String>,
+    pub rating: u32,
+    pub replay_gain_track: Option<f64>,
+    pub replay_gain_album: Option<f64>,
+    pub replay_gain_track_peak: Option<f64>,
+    pub replay_gain_album_peak: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, FromRow)]
@@ -81,6 +147,7 @@ pub struct Playlist {
     pub duration: u32,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Tag {
     pub id: String,
@@ -88,9 +155,472 @@ pub struct Tag {
     pub description: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AlbumTag {
     pub id: String,
     pub tag_id: String,
     pub album_id: String,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    Artists,
+    Albums,
+    Songs,
+    CoverArt,
+    Playlists,
+}
+
+/* Payload of the `sync-progress` event */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncProgress {
+    pub library_id: String,
+    pub phase: SyncPhase,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TableDiff {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/* What a single library sync changed locally (returned by `sync_collection`) */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncDiff {
+    pub library_id: String,
+    pub artists: TableDiff,
+    pub albums: TableDiff,
+    pub songs: TableDiff,
+    pub playlists: TableDiff,
+}
+
+/* The outcome of syncing one library (returned by `sync_collection`). A library that fails
+*  doesn't stop the ones after it from syncing. */
+#[derive(Serialize, Debug)]
+pub struct SyncResult {
+    pub library_id: String,
+    pub diff: Option<SyncDiff>,
+    pub error: Option<CommandError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, FromRow)]
+pub struct QueuedScrobble {
+    pub id: i64,
+    pub library_id: String,
+    pub song_id: String,
+    pub played_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrobbleStatus {
+    Submitted,
+    // Server unreachable or refusing it for now; kept in scrobble_queue until it can be submitted
+    Queued,
+    // Not enough of the track has been played yet
+    BelowThreshold,
+}
+
+/* Something that can be starred or rated */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RatingTarget {
+    Song,
+    Album,
+    Artist,
+}
+
+impl RatingTarget {
+    pub fn table(&self) -> &'static str {
+        match self {
+            RatingTarget::Song => "songs",
+            RatingTarget::Album => "albums",
+            RatingTarget::Artist => "artists",
+        }
+    }
+}
+
+/* Namespaced IDs of everything starred on a server */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Favourites {
+    pub library_id: String,
+    pub artists: Vec<String>,
+    pub albums: Vec<String>,
+    pub songs: Vec<String>,
+}
+
+/* Page size and offset for each kind of search result */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct SearchPage {
+    pub artist_count: u32,
+    pub artist_offset: u32,
+    pub album_count: u32,
+    pub album_offset: u32,
+    pub song_count: u32,
+    pub song_offset: u32,
+}
+
+impl Default for SearchPage {
+    fn default() -> Self {
+        SearchPage {
+            artist_count: 20,
+            artist_offset: 0,
+            album_count: 20,
+            album_offset: 0,
+            song_count: 20,
+            song_offset: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchSource {
+    Local,
+    Server,
+}
+
+/* Namespaced IDs of matching items, best match first */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchResults {
+    pub source: SearchSource,
+    pub artists: Vec<String>,
+    pub albums: Vec<String>,
+    pub songs: Vec<String>,
+}
+
+/* A song stored on disk for offline playback */
+#[derive(Serialize, Deserialize, Debug, FromRow)]
+pub struct Download {
+    pub song_id: String,
+    pub library_id: String,
+    pub path: String,
+    pub size: i64,
+    // MD5 of the file, hex encoded
+    pub checksum: String,
+    // "download" (original file) or "stream" (format=raw stream, when downloads aren't allowed)
+    pub source: String,
+    pub content_type: String,
+    pub downloaded_at: i64,
+}
+
+/* An album or playlist kept available offline. Its songs stay downloaded while any pin covers them. */
+#[derive(Serialize, Deserialize, Debug, FromRow)]
+pub struct DownloadPin {
+    pub id: String,
+    pub library_id: String,
+    // "album" or "playlist"
+    pub kind: String,
+    pub created_at: i64,
+}
+
+/* Payload of the `download-progress` event */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DownloadProgress {
+    pub pin_id: String,
+    pub done: usize,
+    pub total: usize,
+    pub failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheKind {
+    Art,
+    Audio,
+}
+
+impl CacheKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheKind::Art => "art",
+            CacheKind::Audio => "audio",
+        }
+    }
+}
+
+/* A file in one of the evictable caches. Art is keyed by its cover_art value ("<library_id>/<file>"),
+*  audio by song ID and transcode (see TranscodeSettings::cache_key). */
+#[derive(Serialize, Deserialize, Debug, FromRow)]
+pub struct CacheEntry {
+    pub kind: String,
+    pub key: String,
+    pub library_id: String,
+    pub path: String,
+    pub size: i64,
+    pub content_type: Option<String>,
+    pub last_accessed: i64,
+}
+
+/* Byte quotas for each cache. Least recently used files are evicted past them. */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheSettings {
+    pub art_quota_bytes: i64,
+    // 0 turns audio caching off
+    pub audio_quota_bytes: i64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            art_quota_bytes: 512 * 1024 * 1024,
+            audio_quota_bytes: 2 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/* Disk usage of one library. Downloads are pinned, so they don't count against the quotas. */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CacheStats {
+    pub library_id: String,
+    pub art_bytes: i64,
+    pub art_files: i64,
+    pub audio_bytes: i64,
+    pub audio_files: i64,
+    pub download_bytes: i64,
+    pub download_files: i64,
+}
+
+/* Which set of transcode settings applies. Chosen by the user, as the webview can't tell what
+*  kind of network it is on. */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkProfile {
+    #[default]
+    Unmetered,
+    Metered,
+}
+
+impl NetworkProfile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetworkProfile::Unmetered => "unmetered",
+            NetworkProfile::Metered => "metered",
+        }
+    }
+}
+
+/* Parameters for stream requests of a library. The default asks for the original file. */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, FromRow)]
+#[serde(default)]
+pub struct TranscodeSettings {
+    //e.g. "mp3" or "opus", "raw" turns transcoding off. None leaves it to the server.
+    pub format: Option<String>,
+    //In kbps, None or 0 for no limit
+    pub max_bit_rate: Option<u32>,
+    //Have the server send a Content-Length for transcoded songs
+    pub estimate_content_length: bool,
+}
+
+impl TranscodeSettings {
+    pub fn is_original(&self) -> bool {
+        match self.format.as_deref() {
+            Some("raw") => true,
+            Some(_) => false,
+            None => self.max_bit_rate.unwrap_or(0) == 0,
+        }
+    }
+
+    /* Key for the audio cache. Transcoded copies get their own, so they are never served in place
+     *  of the original (or of a copy at another bitrate). */
+    pub fn cache_key(&self, song_id: &str) -> String {
+        if self.is_original() {
+            return song_id.to_string();
+        }
+        format!(
+            "{}@{}-{}",
+            song_id,
+            self.format.as_deref().unwrap_or("default"),
+            self.max_bit_rate.unwrap_or(0)
+        )
+    }
+}
+
+/* A song in the playback engine's queue */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaybackItem {
+    pub library_id: String,
+    pub song_id: String,
+}
+
+/* The play queue as it was last left, locally or on a server */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SavedQueue {
+    pub items: Vec<PlaybackItem>,
+    pub index: Option<usize>,
+    //Seconds into the current song
+    pub position: f64,
+    //Milliseconds since the epoch
+    pub changed: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    Stopped,
+    Loading,
+}
+
+/* Payload of the `playback-state` event, sent when the state or the playing queue item changes */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaybackStatus {
+    pub state: PlaybackState,
+    pub index: Option<usize>,
+}
+
+/* Payload of the `playback-position` event, sent a few times a second during playback */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaybackPosition {
+    pub index: usize,
+    //Seconds
+    pub position: f64,
+    //Seconds, if the file reports it
+    pub duration: Option<f64>,
+}
+
+/* Payload of the `playback-error` event. The engine moves on to the next song after one. */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaybackError {
+    pub index: usize,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    //Keeps the differences between songs of an album, falls back to track gain
+    Album,
+}
+
+/* Shape of the volume ramps. Equal power keeps the overall loudness steady through the fade. */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossfadeCurve {
+    Linear,
+    #[default]
+    EqualPower,
+    SCurve,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PlaybackSettings {
+    //0 plays songs back to back (gapless)
+    pub crossfade_seconds: f32,
+    pub crossfade_curve: CrossfadeCurve,
+    pub replay_gain_mode: ReplayGainMode,
+    //dB added to every ReplayGain adjustment
+    pub replay_gain_preamp_db: f32,
+    //Lower the gain of songs whose peaks would otherwise clip
+    pub prevent_clipping: bool,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        PlaybackSettings {
+            crossfade_seconds: 0.0,
+            crossfade_curve: CrossfadeCurve::default(),
+            replay_gain_mode: ReplayGainMode::default(),
+            replay_gain_preamp_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+/* ReplayGain values of a song, in dB (peaks as a fraction of full scale). From the server, the
+*  file's tags, or measured locally the last time it was played through. */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, FromRow)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_peak: Option<f64>,
+    pub computed_gain: Option<f64>,
+    pub computed_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    /* Fill in values this is missing from another source */
+    pub fn or(self, other: ReplayGain) -> ReplayGain {
+        ReplayGain {
+            track_gain: self.track_gain.or(other.track_gain),
+            album_gain: self.album_gain.or(other.album_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_peak: self.album_peak.or(other.album_peak),
+            computed_gain: self.computed_gain.or(other.computed_gain),
+            computed_peak: self.computed_peak.or(other.computed_peak),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EqFilter {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+/* One filter of the equalizer. A 10-band graphic EQ is ten peaking bands an octave apart. */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EqBand {
+    pub filter: EqFilter,
+    //Hz. Centre frequency, or the corner frequency of shelves and passes.
+    pub frequency: f32,
+    //dB, unused by passes
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+/* The DSP chain, applied in order: preamp, EQ bands, mono downmix, balance */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct DspSettings {
+    pub preamp_db: f32,
+    pub bands: Vec<EqBand>,
+    pub mono: bool,
+    //-1 (left only) to 1 (right only)
+    pub balance: f32,
+}
+
+/* Saved DSP settings. A preset bound to a library is used for its songs, and one bound to an
+*  output device whenever it is playing, ahead of the active preset. */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DspPreset {
+    //None for a preset not saved yet
+    pub id: Option<i64>,
+    pub name: String,
+    pub library_id: Option<String>,
+    pub device: Option<String>,
+    pub settings: DspSettings,
+}
+
+/* An audio file of a local library, with the size and modification time it had when its tags
+*  were read */
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
+pub struct LocalFile {
+    pub path: String,
+    pub library_id: String,
+    pub song_id: String,
+    pub content_type: String,
+    //Milliseconds since the epoch
+    pub modified: i64,
+    pub size: i64,
+    //The tags read from it (a LocalTrack as JSON), so an unchanged file isn't read again
+    #[sqlx(default)]
+    pub track: String,
+}
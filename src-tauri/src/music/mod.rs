@@ -1,264 +1,535 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use sqlx::{Pool, Sqlite};
 
 use crate::{
+    cache::{cover_art_dir, track_cover_art},
     db::{
-        db_connect, delete_unused_artists, insert_albums, insert_artists, insert_library, insert_playlists, insert_songs, update_last_scanned
-    },
-    formatter::create_connection_string,
-    models::{Album, Artist, Library, Playlist, Song},
-    responses::{
-        SubsonicAlbumID3, SubsonicChild, SubsonicGetArtistsResponse, SubsonicPlaylist,
-        SubsonicResponse,
-    },
-    subsonic::{
-        get_album_art, get_albums_for_artist, get_artists, get_playlists, get_songs_for_album,
+        db_connect, delete_missing_artists, delete_orphaned_playlist_songs, delete_unused_artists,
+        get_last_scanned, get_song_album_ids, get_stored_albums, get_stored_playlist_versions,
+        insert_albums, insert_artists, insert_library, insert_playlists, insert_songs,
+        rebuild_search_index, replace_cover_art, replace_playlist_songs, update_last_scanned,
+        upsert_playlist,
     },
+    models::{Album, Artist, Playlist, Song, SyncDiff, SyncPhase, SyncProgress},
+    source::MusicSource,
 };
-use futures::{future::join_all, StreamExt};
-use tauri::{AppHandle, Manager};
-
-pub async fn sync_library(library: &Library, app_handle: &AppHandle) -> Result<(), anyhow::Error> {
-    println!("Get Artists");
-    let artists: SubsonicResponse<SubsonicGetArtistsResponse> = get_artists(library).await?;
-    println!("Get Albums");
-    let albums: Vec<SubsonicAlbumID3> = get_albums(&artists, library).await?;
-    println!("Get Songs");
-    let songs: Vec<SubsonicChild> = get_songs(&albums, library).await?;
-    println!("Get Cover Art");
-    let cover_art_map = get_cover_art(&albums, library, app_handle).await?;
-    println!("Get Playlists");
-    let playlists: Vec<SubsonicPlaylist> = get_playlists(library).await?;
-
-    println!("Insert Library");
-    let pool = db_connect(app_handle).await?;
-    match insert_library(&pool, library).await {
-        Ok(_) => println!("Library inserted"),
-        Err(e) => println!("Error: {}", e),
+use futures::future::{AbortHandle, AbortRegistration, Abortable, Aborted};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::OwnedMutexGuard;
+
+//How long incremental syncs can go on before a full one (7 days)
+const FULL_SYNC_INTERVAL_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug, PartialEq)]
+enum SyncMode {
+    // Crawl every artist, album and song
+    Full,
+    // Only fetch songs for albums added or changed since the given time (in ms)
+    Incremental(i64),
+    // Source reports nothing changed since the last scan
+    Unchanged,
+}
+
+struct AlbumSyncPlan {
+    albums: Vec<Album>,
+    albums_to_fetch: Vec<Album>,
+    kept_song_ids: Vec<String>,
+}
+
+/* Tracks in-flight syncs so that they can be stopped by the `cancel_sync` command, and keeps two
+*  syncs of the same library (a manual one and a rescan after its files changed) from overlapping */
+#[derive(Default)]
+pub struct SyncState {
+    cancelled: AtomicBool,
+    //Abort handle of the latest sync of each library
+    abort_handles: Mutex<HashMap<String, AbortHandle>>,
+    library_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl SyncState {
+    pub fn begin(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
     }
 
-    //Transform data (perhaps not necessary for Subsonic-only, but for comaptibility with other future sources)
-    let mut transformed_artists: Vec<Artist> = vec![];
-    let mut transformed_albums: Vec<Album> = vec![];
-    let mut transformed_songs: Vec<Song> = vec![];
-    let mut transformed_playlists: Vec<Playlist> = vec![];
-
-    let mut album_ids: Vec<String> = vec![];
-    let mut song_ids: Vec<String> = vec![];
-    let mut artist_ids: Vec<String> = vec![];
-    let mut playlist_ids: Vec<String> = vec![];
-
-    let mut album_artist_ids: Vec<String> = vec![];
-
-    println!("Transform");
-    for artist in &artists.data.artists.index {
-        for artist_detail in &artist.artist {
-            let artist = Artist {
-                id: artist_detail.id.clone(),
-                name: artist_detail.name.clone(),
-                library_id: library.id.clone(),
-            };
-            artist_ids.push(artist.id.clone());
-            transformed_artists.push(artist);
-        }
+    /* Wait for any other sync of the library to finish. It stays locked until the guard is dropped. */
+    pub async fn lock_library(&self, library_id: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .library_locks
+            .lock()
+            .unwrap()
+            .entry(library_id.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
     }
 
-    for album in &albums {
-        let album = Album {
-            id: album.id.clone(),
-            name: album.name.clone(),
-            artist_id: album.artist_id.clone().unwrap_or("".to_string()),
-            artist_name: album.artist.clone(),
-            library_id: library.id.clone(),
-            cover_art: album.cover_art.clone(),
-            year: album.year,
-            duration: album.duration,
-        };
-        album_ids.push(album.id.clone());
-        album_artist_ids.push(album.artist_id.clone());
-        transformed_albums.push(album);
+    /* A manual sync of one library, cancelled along with the rest of `sync_collection` */
+    pub fn register(&self, library_id: &str) -> AbortRegistration {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        //Cancel may have landed between the last is_cancelled() check and now
+        if self.is_cancelled() {
+            abort_handle.abort();
+        }
+        self.track(library_id, abort_handle);
+        abort_registration
     }
 
-    //Remove artists that aren't album artists (we prefer to use the first artist listed)
-    transformed_artists.retain(|artist| album_artist_ids.contains(&artist.id));
-
-    for song in &songs {
-        let song = Song {
-            id: song.id.clone(),
-            title: song.title.clone(),
-            artist_id: song.artist_id.clone().unwrap_or("".to_string()),
-            artist_name: song.artist.clone(),
-            album_id: song.album_id.clone(),
-            album_name: song.album.clone(),
-            library_id: library.id.clone(),
-            track: song.track,
-            duration: song.duration,
-            disc_number: song.disc_number.unwrap_or(1),
-            content_type: song.content_type.clone(),
-            cover_art: song.cover_art.clone().unwrap_or("".to_string()),
-        };
-        song_ids.push(song.id.clone());
-        transformed_songs.push(song);
+    /* A rescan started by the file watcher. A cancel before it started doesn't apply to it. */
+    pub fn register_rescan(&self, library_id: &str) -> AbortRegistration {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        self.track(library_id, abort_handle);
+        abort_registration
     }
 
-    for playlist in &playlists {
-        let playlist = Playlist {
-            id: playlist.id.clone(),
-            library_id: library.id.clone(),
-            name: playlist.name.clone(),
-            owner: playlist.owner.clone(),
-            created: playlist.created.clone(),
-            modified: playlist.changed.clone(),
-            song_count: playlist.song_count.unwrap_or(0),
-            duration: playlist.duration.unwrap_or(0),
-        };
-        playlist_ids.push(playlist.id.clone());
-        transformed_playlists.push(playlist);
+    fn track(&self, library_id: &str, abort_handle: AbortHandle) {
+        self.abort_handles
+            .lock()
+            .unwrap()
+            .insert(library_id.to_string(), abort_handle);
     }
 
-    //Write to DB
-    println!("Insert Artists");
-    match insert_artists(&pool, &transformed_artists, &artist_ids).await {
-        Ok(_) => println!("Artists inserted"),
-        Err(e) => println!("Error: {}", e),
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
     }
-    println!("Insert Albums");
-    match insert_albums(&pool, &transformed_albums, &album_ids, &cover_art_map).await {
-        Ok(_) => println!("Albums inserted"),
-        Err(e) => println!("Error: {}", e),
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        for (_, abort_handle) in self.abort_handles.lock().unwrap().drain() {
+            abort_handle.abort();
+        }
     }
-    println!("Insert Songs");
-    match insert_songs(&pool, &transformed_songs, &song_ids, &cover_art_map).await {
-        Ok(_) => println!("Songs inserted"),
-        Err(e) => println!("Error: {}", e),
+}
+
+/* Emits `sync-progress` events for a single phase of a library sync */
+pub struct SyncProgressReporter<'a> {
+    app_handle: &'a AppHandle,
+    library_id: &'a str,
+    phase: SyncPhase,
+    done: usize,
+    total: usize,
+}
+
+impl<'a> SyncProgressReporter<'a> {
+    pub fn new(app_handle: &'a AppHandle, library_id: &'a str, phase: SyncPhase) -> Self {
+        SyncProgressReporter {
+            app_handle,
+            library_id,
+            phase,
+            done: 0,
+            total: 0,
+        }
     }
-    println!("Insert Playlists");
-    match insert_playlists(&pool, &transformed_playlists, &playlist_ids).await {
-        Ok(_) => println!("Playlists inserted"),
-        Err(e) => println!("Error: {}", e),
+
+    pub fn start(&mut self, total: usize) {
+        self.done = 0;
+        self.total = total;
+        self.emit();
     }
-    println!("Delete unused artists");
-    match delete_unused_artists(&pool).await {
-        Ok(_) => println!("Unused artists deleted"),
-        Err(e) => println!("Error: {}", e),
+
+    pub fn advance(&mut self) {
+        self.done += 1;
+        self.emit();
     }
-    println!("Update last scanned");
-    match update_last_scanned(&pool, &library.id).await {
-        Ok(_) => println!("Last scanned updated"),
-        Err(e) => println!("Error: {}", e),
+
+    fn emit(&self) {
+        let progress = SyncProgress {
+            library_id: self.library_id.to_string(),
+            phase: self.phase,
+            done: self.done,
+            total: self.total,
+        };
+        if let Err(e) = self.app_handle.emit("sync-progress", progress) {
+            println!("Error: {}", e);
+        }
     }
+}
 
-    Ok(())
+/* Everything pulled from the source for one sync, gathered before anything is written */
+struct LibraryFetch {
+    sync_mode: SyncMode,
+    artists: Vec<Artist>,
+    plan: AlbumSyncPlan,
+    songs: Vec<Song>,
+    cover_art_map: HashMap<String, String>,
+    playlists: Vec<Playlist>,
+    //Song IDs of the playlists changed since their entries were last stored, by playlist ID
+    playlist_songs: HashMap<String, Vec<String>>,
 }
 
-/* Retrieve each album per artist */
-pub async fn get_albums(
-    artists: &SubsonicResponse<SubsonicGetArtistsResponse>,
-    library: &Library,
-) -> Result<Vec<SubsonicAlbumID3>, anyhow::Error> {
-    let mut albums: Vec<SubsonicAlbumID3> = vec![];
-    let base_url = create_connection_string(library, "getArtist");
-    let client = reqwest::Client::new();
-    let mut futures = vec![];
-    for index in &artists.data.artists.index {
-        for artist in &index.artist {
-            let url = format!("{}&id={}", base_url, artist.id);
-            futures.push(get_albums_for_artist(url, client.clone()));
-        }
+pub async fn sync_library(
+    source: &dyn MusicSource,
+    app_handle: &AppHandle,
+    abort_registration: AbortRegistration,
+) -> Result<SyncDiff, anyhow::Error> {
+    let library = source.library();
+    let pool = db_connect(app_handle).await?;
+
+    //Fetching can be cancelled at any await point, since nothing has been written to the DB yet
+    let fetch = Abortable::new(fetch_library(&pool, source, app_handle), abort_registration);
+    let LibraryFetch {
+        sync_mode,
+        mut artists,
+        plan,
+        songs,
+        cover_art_map,
+        playlists,
+        playlist_songs,
+    } = match fetch.await {
+        Ok(fetched) => fetched?,
+        Err(Aborted) => return Err(anyhow::anyhow!("Sync cancelled")),
+    };
+
+    let artist_ids: Vec<String> = artists.iter().map(|artist| artist.id.clone()).collect();
+    let album_ids: Vec<String> = plan.albums.iter().map(|album| album.id.clone()).collect();
+    let mut song_ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+    song_ids.extend(plan.kept_song_ids.iter().cloned());
+    let playlist_ids: Vec<String> = playlists
+        .iter()
+        .map(|playlist| playlist.id.clone())
+        .collect();
+
+    //Remove artists that aren't album artists (we prefer to use the first artist listed)
+    let album_artist_ids: HashSet<&String> =
+        plan.albums.iter().map(|album| &album.artist_id).collect();
+    artists.retain(|artist| album_artist_ids.contains(&artist.id));
+
+    //Write to DB. Everything happens in a single transaction, so a failure at any step rolls the
+    //library back to its state before the sync instead of leaving it half-written.
+    let mut diff = SyncDiff {
+        library_id: library.id.clone(),
+        ..Default::default()
+    };
+    let mut tx = pool.begin().await?;
+    insert_library(&mut tx, library).await?;
+    //Artists, albums and songs are left alone if the source reports no changes
+    if sync_mode != SyncMode::Unchanged {
+        diff.artists = insert_artists(&mut tx, &library.id, &artists).await?;
+        //Unchanged albums (incremental sync) only need their IDs kept, not an upsert
+        diff.albums = insert_albums(
+            &mut tx,
+            &library.id,
+            &plan.albums_to_fetch,
+            &album_ids,
+            &cover_art_map,
+        )
+        .await?;
+        diff.songs = insert_songs(&mut tx, &library.id, &songs, &song_ids, &cover_art_map).await?;
+        diff.artists.removed = delete_missing_artists(&mut tx, &library.id, &artist_ids).await?;
+        source.write_index(&mut tx).await?;
+    }
+    diff.playlists = insert_playlists(&mut tx, &library.id, &playlists, &playlist_ids).await?;
+    for (playlist_id, song_ids) in &playlist_songs {
+        replace_playlist_songs(&mut tx, playlist_id, song_ids).await?;
+    }
+    delete_orphaned_playlist_songs(&mut tx).await?;
+    diff.artists
+        .removed
+        .extend(delete_unused_artists(&mut tx).await?);
+    if sync_mode != SyncMode::Unchanged {
+        rebuild_search_index(&mut tx, &library.id).await?;
     }
-    let album_calls = join_all(futures).await;
-    for album_call in album_calls {
-        match album_call {
-            Ok(album_response) => {
-                for album in &album_response.data.artist.album {
-                    albums.push(album.clone());
+    update_last_scanned(&mut tx, &library.id).await?;
+    tx.commit().await?;
+
+    //Cache bookkeeping only, so a failure here doesn't fail the sync
+    if let Err(e) = track_cover_art(&pool, app_handle, &library.id, &cover_art_map).await {
+        println!("Error: {}", e);
+    }
+
+    Ok(diff)
+}
+
+async fn fetch_library(
+    pool: &Pool<Sqlite>,
+    source: &dyn MusicSource,
+    app_handle: &AppHandle,
+) -> Result<LibraryFetch, anyhow::Error> {
+    let library = source.library();
+    source.read_index(pool).await?;
+    let sync_mode = get_sync_mode(source, pool).await;
+
+    let mut artists: Vec<Artist> = vec![];
+    let mut plan = AlbumSyncPlan {
+        albums: vec![],
+        albums_to_fetch: vec![],
+        kept_song_ids: vec![],
+    };
+    if sync_mode != SyncMode::Unchanged {
+        let mut progress = SyncProgressReporter::new(app_handle, &library.id, SyncPhase::Artists);
+        artists = source.list_artists(&mut progress).await?;
+
+        let mut progress = SyncProgressReporter::new(app_handle, &library.id, SyncPhase::Albums);
+        plan = match sync_mode {
+            SyncMode::Incremental(since) => {
+                match get_changed_albums(source, pool, since, &mut progress).await {
+                    Ok(plan) => plan,
+                    Err(e) => {
+                        println!("Error: {} (falling back to full sync)", e);
+                        get_all_albums(source, &artists, &mut progress).await?
+                    }
                 }
             }
-            Err(e) => {
-                println!("Error: {}", e);
-            }
-        }
+            _ => get_all_albums(source, &artists, &mut progress).await?,
+        };
     }
-    Ok(albums)
+    let mut progress = SyncProgressReporter::new(app_handle, &library.id, SyncPhase::Songs);
+    let songs = source
+        .list_songs(&plan.albums_to_fetch, &mut progress)
+        .await?;
+    let mut progress = SyncProgressReporter::new(app_handle, &library.id, SyncPhase::CoverArt);
+    let cover_art_map = source
+        .fetch_art(
+            &plan.albums_to_fetch,
+            &cover_art_dir(app_handle)?,
+            &mut progress,
+        )
+        .await?;
+    let mut progress = SyncProgressReporter::new(app_handle, &library.id, SyncPhase::Playlists);
+    progress.start(1);
+    let playlists = source.playlists().await?;
+    let playlist_songs =
+        get_changed_playlist_songs(source, pool, &playlists, &mut progress).await?;
+
+    Ok(LibraryFetch {
+        sync_mode,
+        artists,
+        plan,
+        songs,
+        cover_art_map,
+        playlists,
+        playlist_songs,
+    })
 }
 
-/* Similarly, get songs for each album */
-pub async fn get_songs(
-    albums: &Vec<SubsonicAlbumID3>,
-    library: &Library,
-) -> Result<Vec<SubsonicChild>, anyhow::Error> {
-    let mut songs: Vec<SubsonicChild> = vec![];
-    let base_url = create_connection_string(library, "getAlbum");
-    let client = reqwest::Client::new();
-    let mut futures = vec![];
-    for album in albums {
-        let url = format!("{}&id={}", base_url, album.id);
-        futures.push(get_songs_for_album(url, client.clone()));
+/* Entries of the playlists whose modified time has moved since they were stored */
+async fn get_changed_playlist_songs(
+    source: &dyn MusicSource,
+    pool: &Pool<Sqlite>,
+    playlists: &[Playlist],
+    progress: &mut SyncProgressReporter<'_>,
+) -> Result<HashMap<String, Vec<String>>, anyhow::Error> {
+    let stored = get_stored_playlist_versions(pool, &source.library().id).await?;
+    let changed: Vec<&Playlist> = playlists
+        .iter()
+        .filter(|playlist| stored.get(&playlist.id) != Some(&playlist.modified))
+        .collect();
+    progress.start(1 + changed.len());
+    progress.advance();
+    let mut playlist_songs = HashMap::new();
+    for playlist in changed {
+        let (_, song_ids) = source.playlist(&playlist.id).await?;
+        playlist_songs.insert(playlist.id.clone(), song_ids);
+        progress.advance();
     }
-    let album_calls = join_all(futures).await;
-    for album_call in album_calls {
-        match album_call {
-            Ok(album_response) => {
-                let cover_art_clone = album_response.data.album.cover_art.clone();
-                for song in &album_response.data.album.song {
-                    let mut song_clone = song.clone();
-                    song_clone.cover_art = Some(cover_art_clone.clone());
-                    songs.push(song_clone);
-                }
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-            }
+    Ok(playlist_songs)
+}
+
+/* Whether a sync of the library would find anything to fetch */
+pub async fn library_changed(source: &dyn MusicSource, pool: &Pool<Sqlite>) -> bool {
+    get_sync_mode(source, pool).await != SyncMode::Unchanged
+}
+
+/* Use the stored last_scanned time + the source's change check to decide how much of the library
+*  needs crawling */
+async fn get_sync_mode(source: &dyn MusicSource, pool: &Pool<Sqlite>) -> SyncMode {
+    let last_scanned = match get_last_scanned(pool, &source.library().id).await {
+        Ok(Some(last_scanned)) => last_scanned,
+        _ => return SyncMode::Full,
+    };
+    let since: i64 = match last_scanned.parse() {
+        Ok(since) => since,
+        Err(_) => return SyncMode::Full,
+    };
+    //Edits that change nothing a listing reports (e.g. a song's title) are only picked up by a
+    //full sync, so one is forced every so often
+    if chrono::Utc::now().timestamp_millis() - since > FULL_SYNC_INTERVAL_MS {
+        return SyncMode::Full;
+    }
+    match source.changed_since(since).await {
+        Ok(Some(true)) => SyncMode::Incremental(since),
+        Ok(Some(false)) => SyncMode::Unchanged,
+        Ok(None) => SyncMode::Full,
+        Err(e) => {
+            println!("Error: {}", e);
+            SyncMode::Full
         }
     }
-    Ok(songs)
 }
 
-/* Sync album art */
-async fn get_cover_art(
-    albums: &Vec<SubsonicAlbumID3>,
-    library: &Library,
+/* Full sync: every album gets its songs re-fetched */
+async fn get_all_albums(
+    source: &dyn MusicSource,
+    artists: &[Artist],
+    progress: &mut SyncProgressReporter<'_>,
+) -> Result<AlbumSyncPlan, anyhow::Error> {
+    let albums: Vec<Album> = source
+        .list_albums(artists, progress)
+        .await?
+        .into_iter()
+        .map(|listed| listed.album)
+        .collect();
+    Ok(AlbumSyncPlan {
+        albums_to_fetch: albums.clone(),
+        albums,
+        kept_song_ids: vec![],
+    })
+}
+
+/* Incremental sync: list every album in one go, then only fetch songs for albums that are new or
+*  have changed */
+async fn get_changed_albums(
+    source: &dyn MusicSource,
+    pool: &Pool<Sqlite>,
+    since: i64,
+    progress: &mut SyncProgressReporter<'_>,
+) -> Result<AlbumSyncPlan, anyhow::Error> {
+    let library_id = &source.library().id;
+    let listed = source.list_all_albums(progress).await?;
+    let stored_albums: HashMap<String, Album> = get_stored_albums(pool, library_id)
+        .await?
+        .into_iter()
+        .map(|album| (album.id.clone(), album))
+        .collect();
+
+    //Not every source reports edits in its timestamps (Subsonic albums only have created), so an
+    //album whose listing no longer matches what was stored is fetched again too
+    let albums_to_fetch: Vec<Album> = listed
+        .iter()
+        .filter(|listed| match stored_albums.get(&listed.album.id) {
+            Some(stored) => {
+                listed.changed.is_none_or(|changed| changed > since)
+                    || album_listing_changed(stored, &listed.album)
+            }
+            None => true,
+        })
+        .map(|listed| listed.album.clone())
+        .collect();
+    let albums: Vec<Album> = listed.into_iter().map(|listed| listed.album).collect();
+
+    //Songs belonging to albums that are still present and unchanged are kept as-is
+    let listed_album_ids: HashSet<&String> = albums.iter().map(|album| &album.id).collect();
+    let fetched_album_ids: HashSet<&String> =
+        albums_to_fetch.iter().map(|album| &album.id).collect();
+    let kept_song_ids = get_song_album_ids(pool, library_id)
+        .await?
+        .into_iter()
+        .filter(|(_, album_id)| {
+            listed_album_ids.contains(album_id) && !fetched_album_ids.contains(album_id)
+        })
+        .map(|(song_id, _)| song_id)
+        .collect();
+
+    Ok(AlbumSyncPlan {
+        albums,
+        albums_to_fetch,
+        kept_song_ids,
+    })
+}
+
+/* Fields a listing reports that are also stored. Year is stored as 9999 when unknown. */
+fn album_listing_changed(stored: &Album, listed: &Album) -> bool {
+    stored.name != listed.name
+        || stored.artist_id != listed.artist_id
+        || stored.artist_name != listed.artist_name
+        || stored.duration != listed.duration
+        || stored.year != Some(listed.year.unwrap_or(9999))
+}
+
+/* Re-read a playlist and its entries from the source (after changing it, or when its entries
+*  haven't been stored yet), so the local DB matches without a sync */
+pub async fn refresh_playlist(
+    source: &dyn MusicSource,
+    pool: &Pool<Sqlite>,
+    playlist_id: &str,
+) -> Result<Playlist, anyhow::Error> {
+    let (playlist, song_ids) = source.playlist(playlist_id).await?;
+    upsert_playlist(pool, &playlist, &song_ids).await?;
+    Ok(playlist)
+}
+
+/* Fetch the art of an album again once its file has been evicted from the cache (or cleared).
+*  Returns the file it was saved as, which the album's songs are pointed at too if the source now
+*  sends another format. */
+pub async fn refetch_cover_art(
+    source: &dyn MusicSource,
+    pool: &Pool<Sqlite>,
     app_handle: &AppHandle,
-) -> Result<HashMap<String, String>, anyhow::Error> {
-    let binding = app_handle.path().app_config_dir().unwrap();
-    let app_data_dir = binding.to_str().unwrap();
-
-    //Create cover_art folder if it doesn't exist
-    let cover_art_dir = format!("{}/cover_art", app_data_dir);
-    if !Path::new(&cover_art_dir).exists() {
-        fs::create_dir(&cover_art_dir).unwrap();
+    mut album: Album,
+) -> Result<Option<String>, anyhow::Error> {
+    let library_id = &source.library().id;
+    let stored_file = album.cover_art.clone();
+    //Stored as the file name, but fetched by the key it was saved under
+    album.cover_art = Path::new(&stored_file)
+        .with_extension("")
+        .to_string_lossy()
+        .to_string();
+    source.read_index(pool).await?;
+    let mut progress = SyncProgressReporter::new(app_handle, library_id, SyncPhase::CoverArt);
+    let cover_art_map = source
+        .fetch_art(
+            std::slice::from_ref(&album),
+            &cover_art_dir(app_handle)?,
+            &mut progress,
+        )
+        .await?;
+    let file_name = match cover_art_map.get(&album.cover_art) {
+        Some(file_name) => file_name.clone(),
+        None => return Ok(None),
+    };
+    if file_name != stored_file {
+        replace_cover_art(pool, &stored_file, &file_name).await?;
     }
+    track_cover_art(pool, app_handle, library_id, &cover_art_map).await?;
+    Ok(Some(file_name))
+}
 
-    let data_dir_string = app_data_dir.to_string();
-    let base_url = create_connection_string(library, "getCoverArt");
-    let client = reqwest::Client::new();
-    let mut futures = vec![];
-    for album in albums {
-        let url = format!("{}&id={}", base_url, &album.cover_art);
-        futures.push(get_album_art(
-            url,
-            album.cover_art.clone(),
-            client.clone(),
-            &data_dir_string,
-        ));
+/* Cover art is stored per library (cover_art/<library_id>/<cover_id>.<ext>), so the key kept in
+*  the DB includes the library folder. */
+pub fn cover_art_key(library_id: &str, cover_id: &str) -> String {
+    if cover_id.is_empty() {
+        return String::new();
     }
-    //join_all(futures).await;
-
-    let mut cover_art_map: HashMap<String, String> = HashMap::new();
-    let stream = futures::stream::iter(futures).buffer_unordered(10);
-    let results = stream.collect::<Vec<_>>().await;
-    for result in results {
-        match result {
-            Ok(file_name) => {
-                if !file_name.is_empty() {
-                    cover_art_map.insert(Path::new(&file_name)
-                    .with_extension("")
-                    .to_string_lossy()
-                    .to_string(), file_name);
-                }
-            },
-            Err(e) => println!("Error: {}", e),
-        }
+    format!("{}/{}", library_id, cover_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::pending;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cancel_stops_a_rescan_but_an_earlier_cancel_does_not() {
+        let sync_state = SyncState::default();
+        sync_state.cancel();
+        let rescan = Abortable::new(async {}, sync_state.register_rescan("local"));
+        assert!(rescan.await.is_ok());
+
+        let rescan = Abortable::new(pending::<()>(), sync_state.register_rescan("local"));
+        sync_state.cancel();
+        assert!(rescan.await.is_err());
     }
 
-    Ok(cover_art_map)
+    #[tokio::test]
+    async fn syncs_of_one_library_wait_for_each_other() {
+        let sync_state = SyncState::default();
+        let lock = sync_state.lock_library("local").await;
+        //Another library isn't held up
+        drop(sync_state.lock_library("server").await);
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(50), sync_state.lock_library("local"));
+        assert!(waiting.await.is_err());
+        drop(lock);
+        drop(sync_state.lock_library("local").await);
+    }
 }
@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use crate::models::CrossfadeCurve;
+
+/* Volumes of the incoming and outgoing songs, `progress` (0 to 1) through a crossfade */
+pub fn curve_gains(curve: CrossfadeCurve, progress: f32) -> (f32, f32) {
+    let progress = progress.clamp(0.0, 1.0);
+    let fade_in = |progress: f32| match curve {
+        CrossfadeCurve::Linear => progress,
+        CrossfadeCurve::EqualPower => (progress * PI / 2.0).sin(),
+        CrossfadeCurve::SCurve => (1.0 - (progress * PI).cos()) / 2.0,
+    };
+    (fade_in(progress), fade_in(1.0 - progress))
+}
+
+/* The end of a song being mixed into the start of the next. The outgoing song's samples are
+*  decoded by the engine and handed over with `feed`. */
+pub struct Crossfade {
+    curve: CrossfadeCurve,
+    channels: usize,
+    //Samples in the whole fade
+    length: u64,
+    mixed: u64,
+    //Outgoing samples decoded but not mixed in yet
+    pending: VecDeque<f32>,
+}
+
+impl Crossfade {
+    pub fn new(curve: CrossfadeCurve, channels: usize, length: u64) -> Self {
+        Crossfade {
+            curve,
+            channels,
+            //Whole frames, so channels stay in step
+            length: length - length % channels as u64,
+            mixed: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /* Outgoing samples still needed to mix the next `incoming` samples */
+    pub fn needs(&self, incoming: usize) -> usize {
+        let remaining = (self.length - self.mixed) as usize;
+        incoming.min(remaining).saturating_sub(self.pending.len())
+    }
+
+    pub fn feed(&mut self, outgoing: &[f32]) {
+        self.pending.extend(outgoing);
+    }
+
+    /* Mix pending outgoing samples into incoming ones (silence once the outgoing song has run
+     *  out). Returns true once the fade is complete. */
+    pub fn mix(&mut self, incoming: &mut [f32]) -> bool {
+        for frame in incoming.chunks_exact_mut(self.channels) {
+            if self.mixed >= self.length {
+                break;
+            }
+            let progress = self.mixed as f32 / self.length as f32;
+            let (fade_in, fade_out) = curve_gains(self.curve, progress);
+            for sample in frame.iter_mut() {
+                let outgoing = self.pending.pop_front().unwrap_or(0.0);
+                *sample = *sample * fade_in + outgoing * fade_out;
+            }
+            self.mixed += self.channels as u64;
+        }
+        self.mixed >= self.length
+    }
+}
@@ -0,0 +1,269 @@
+use std::io::ErrorKind;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use crate::models::ReplayGain;
+use crate::streaming::{SongData, SongFile};
+
+/* A song being decoded, converted to the output's sample rate and channel count */
+pub struct Track {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    sample_rate: u32,
+    time_base: Option<TimeBase>,
+    //Seconds, if the file reports it
+    pub duration: Option<f64>,
+    //ReplayGain tags of the file
+    pub tags: ReplayGain,
+    //Frames still to be dropped to land exactly on a seek target
+    skip_frames: u64,
+    output_channels: usize,
+    resampler: Resampler,
+}
+
+impl Track {
+    pub fn open(
+        song: SongFile,
+        output_rate: u32,
+        output_channels: usize,
+    ) -> Result<Track, anyhow::Error> {
+        let source: Box<dyn MediaSource> = match song.data {
+            SongData::File(file) => Box::new(file),
+            SongData::Remote(stream) => Box::new(stream),
+        };
+        let stream = MediaSourceStream::new(source, Default::default());
+        let mut hint = Hint::new();
+        if let Some(content_type) = &song.content_type {
+            hint.mime_type(content_type);
+        }
+        //Trims encoder delay and padding, so consecutive songs join without silence
+        let format_options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe().format(
+            &hint,
+            stream,
+            &format_options,
+            &MetadataOptions::default(),
+        )?;
+
+        let mut format = probed.format;
+        let mut probed_metadata = probed.metadata;
+        //Tags can be in the container (e.g. FLAC, MP4) or ahead of it (e.g. ID3v2 in MP3s)
+        let mut tags: Vec<Tag> = vec![];
+        if let Some(revision) = probed_metadata
+            .get()
+            .as_ref()
+            .and_then(|metadata| metadata.current())
+        {
+            tags.extend_from_slice(revision.tags());
+        }
+        if let Some(revision) = format.metadata().current() {
+            tags.extend_from_slice(revision.tags());
+        }
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| anyhow::anyhow!("No audio track"))?;
+        let codec_params = track.codec_params.clone();
+        let track_id = track.id;
+        let sample_rate = codec_params
+            .sample_rate
+            .ok_or_else(|| anyhow::anyhow!("Unknown sample rate"))?;
+        let decoder =
+            symphonia::default::get_codecs().make(&codec_params, &DecoderOptions::default())?;
+
+        Ok(Track {
+            format,
+            decoder,
+            track_id,
+            sample_rate,
+            time_base: codec_params.time_base,
+            duration: codec_params
+                .n_frames
+                .map(|frames| frames as f64 / sample_rate as f64),
+            tags: replay_gain_tags(&tags),
+            skip_frames: 0,
+            output_channels,
+            resampler: Resampler::new(sample_rate, output_rate, output_channels),
+        })
+    }
+
+    /* Decode the next packet. Returns None at the end of the song. */
+    pub fn next_samples(&mut self) -> Result<Option<Vec<f32>>, anyhow::Error> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                //A corrupt packet is skipped rather than ending the song
+                Err(SymphoniaError::DecodeError(e)) => {
+                    println!("Error: Skipping packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let spec = *decoded.spec();
+            let channels = spec.channels.count();
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            buffer.copy_interleaved_ref(decoded);
+
+            let mut samples = buffer.samples();
+            if self.skip_frames > 0 {
+                let skipped = self.skip_frames.min((samples.len() / channels) as u64);
+                self.skip_frames -= skipped;
+                samples = &samples[skipped as usize * channels..];
+            }
+            if samples.is_empty() {
+                continue;
+            }
+            let samples = map_channels(samples, channels, self.output_channels);
+            return Ok(Some(self.resampler.process(&samples)));
+        }
+    }
+
+    /* Seek to a position in seconds */
+    pub fn seek(&mut self, position: f64) -> Result<(), anyhow::Error> {
+        let seeked = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(position.max(0.0)),
+                track_id: Some(self.track_id),
+            },
+        )?;
+        self.decoder.reset();
+        self.resampler.reset();
+        //Seeks land on the packet containing the target, the frames before it are decoded and dropped
+        let behind = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        self.skip_frames = match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(behind);
+                ((time.seconds as f64 + time.frac) * self.sample_rate as f64) as u64
+            }
+            None => behind,
+        };
+        Ok(())
+    }
+}
+
+/* REPLAYGAIN_* tags, written as e.g. "-6.20 dB" and "0.988235" */
+pub fn replay_gain_tags(tags: &[Tag]) -> ReplayGain {
+    let mut replay_gain = ReplayGain::default();
+    for tag in tags {
+        let field = match tag.std_key {
+            Some(StandardTagKey::ReplayGainTrackGain) => &mut replay_gain.track_gain,
+            Some(StandardTagKey::ReplayGainAlbumGain) => &mut replay_gain.album_gain,
+            Some(StandardTagKey::ReplayGainTrackPeak) => &mut replay_gain.track_peak,
+            Some(StandardTagKey::ReplayGainAlbumPeak) => &mut replay_gain.album_peak,
+            _ => continue,
+        };
+        let value = tag.value.to_string();
+        let value = value
+            .trim()
+            .trim_end_matches(|c: char| c.is_ascii_alphabetic() || c.is_whitespace());
+        if let Ok(value) = value.parse::<f64>() {
+            *field = field.or(Some(value));
+        }
+    }
+    replay_gain
+}
+
+/* Fit interleaved samples to the output's channel count. Mono is copied to every channel, anything
+*  mixed down to mono is averaged, and other extra channels are dropped. */
+fn map_channels(samples: &[f32], channels: usize, output_channels: usize) -> Vec<f32> {
+    if channels == output_channels {
+        return samples.to_vec();
+    }
+    let mut mapped = Vec::with_capacity(samples.len() / channels * output_channels);
+    for frame in samples.chunks_exact(channels) {
+        if output_channels == 1 {
+            mapped.push(frame.iter().sum::<f32>() / channels as f32);
+            continue;
+        }
+        for channel in 0..output_channels {
+            let sample = match channels {
+                1 => frame[0],
+                _ => frame.get(channel).copied().unwrap_or(0.0),
+            };
+            mapped.push(sample);
+        }
+    }
+    mapped
+}
+
+/* Linear interpolation between the song's sample rate and the output's. Only used when they
+*  differ, as devices are opened once at their own rate to keep playback gapless. */
+struct Resampler {
+    //Input frames per output frame
+    ratio: f64,
+    channels: usize,
+    //Position of the next output frame, in frames from the start of `previous`
+    position: f64,
+    //Last frame of the previous block, interpolated from at the start of the next
+    previous: Vec<f32>,
+}
+
+impl Resampler {
+    fn new(input_rate: u32, output_rate: u32, channels: usize) -> Self {
+        Resampler {
+            ratio: input_rate as f64 / output_rate as f64,
+            channels,
+            position: 0.0,
+            previous: vec![],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = 0.0;
+        self.previous.clear();
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.ratio == 1.0 {
+            return input.to_vec();
+        }
+        let mut frames = Vec::with_capacity(self.previous.len() + input.len());
+        frames.extend_from_slice(&self.previous);
+        frames.extend_from_slice(input);
+        let frame_count = frames.len() / self.channels;
+        if frame_count == 0 {
+            return vec![];
+        }
+
+        let mut output =
+            Vec::with_capacity((input.len() as f64 / self.ratio) as usize + self.channels);
+        while self.position + 1.0 < frame_count as f64 {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+            for channel in 0..self.channels {
+                let a = frames[index * self.channels + channel];
+                let b = frames[(index + 1) * self.channels + channel];
+                output.push(a + (b - a) * fraction);
+            }
+            self.position += self.ratio;
+        }
+        let last = frame_count - 1;
+        self.position -= last as f64;
+        self.previous = frames[last * self.channels..].to_vec();
+        output
+    }
+}
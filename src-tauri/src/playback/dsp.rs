@@ -0,0 +1,343 @@
+use std::f64::consts::PI;
+
+use crate::models::{DspSettings, EqBand, EqFilter};
+
+/* The user's DSP chain for one output format. Takes interleaved samples and processes them in
+*  place, keeping each channel's filter state between calls. */
+pub struct Dsp {
+    channels: usize,
+    sample_rate: u32,
+    preamp: f32,
+    //One set of band filters per channel
+    filters: Vec<Vec<Biquad>>,
+    mono: bool,
+    //Gains of the first two channels
+    balance: (f32, f32),
+}
+
+impl Dsp {
+    pub fn new(settings: &DspSettings, sample_rate: u32, channels: usize) -> Self {
+        let balance = settings.balance.clamp(-1.0, 1.0);
+        Dsp {
+            channels,
+            sample_rate,
+            preamp: 10f32.powf(settings.preamp_db / 20.0),
+            filters: (0..channels)
+                .map(|_| {
+                    settings
+                        .bands
+                        .iter()
+                        .map(|band| Biquad::eq_band(band, sample_rate))
+                        .collect()
+                })
+                .collect(),
+            mono: settings.mono,
+            balance: ((1.0 - balance).min(1.0), (1.0 + balance).min(1.0)),
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (sample, filters) in frame.iter_mut().zip(self.filters.iter_mut()) {
+                let mut value = (*sample * self.preamp) as f64;
+                for filter in filters.iter_mut() {
+                    value = filter.process(value);
+                }
+                *sample = value as f32;
+            }
+            if self.mono && self.channels > 1 {
+                let mixed = frame.iter().sum::<f32>() / self.channels as f32;
+                frame.fill(mixed);
+            }
+            if self.channels > 1 {
+                frame[0] *= self.balance.0;
+                frame[1] *= self.balance.1;
+            }
+        }
+    }
+
+    /* Gain of the preamp and EQ at a frequency, in dB. For drawing the EQ curve, and checking it
+     *  against what the chain does to real audio. */
+    pub fn response_db(&self, frequency: f64) -> f64 {
+        let preamp = 20.0 * (self.preamp as f64).log10();
+        let bands: f64 = match self.filters.first() {
+            Some(filters) => filters
+                .iter()
+                .map(|filter| 20.0 * filter.magnitude(frequency, self.sample_rate).log10())
+                .sum(),
+            None => 0.0,
+        };
+        preamp + bands
+    }
+}
+
+/* Transposed direct form II, with a0 normalised to 1 */
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /* Coefficients from the Audio EQ Cookbook (R. Bristow-Johnson) */
+    fn eq_band(band: &EqBand, sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+        //Kept below Nyquist, where the formulas break down
+        let frequency = (band.frequency as f64).clamp(1.0, rate * 0.49);
+        let w0 = 2.0 * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * (band.q as f64).max(0.01));
+        let a = 10f64.powf(band.gain_db as f64 / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b, a) = match band.filter {
+            EqFilter::Peaking => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            EqFilter::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - shelf,
+                ],
+            ),
+            EqFilter::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - shelf,
+                ],
+            ),
+            EqFilter::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            EqFilter::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+        };
+        let a0 = a[0];
+        Biquad::new([b[0] / a0, b[1] / a0, b[2] / a0], [a[1] / a0, a[2] / a0])
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z1;
+        self.z1 = self.b[1] * x - self.a[0] * y + self.z2;
+        self.z2 = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    /* |H(e^jw)| at a frequency */
+    fn magnitude(&self, frequency: f64, sample_rate: u32) -> f64 {
+        let w = 2.0 * PI * frequency / sample_rate as f64;
+        //z^-1 and z^-2 as (real, imaginary)
+        let (z1, z2) = ((w.cos(), -w.sin()), ((2.0 * w).cos(), -(2.0 * w).sin()));
+        let numerator = (
+            self.b[0] + self.b[1] * z1.0 + self.b[2] * z2.0,
+            self.b[1] * z1.1 + self.b[2] * z2.1,
+        );
+        let denominator = (
+            1.0 + self.a[0] * z1.0 + self.a[1] * z2.0,
+            self.a[0] * z1.1 + self.a[1] * z2.1,
+        );
+        numerator.0.hypot(numerator.1) / denominator.0.hypot(denominator.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::playback::decoder::Track;
+    use crate::streaming::{SongData, SongFile};
+
+    const SAMPLE_RATE: u32 = 48000;
+    //Rate of fixtures/tones.wav, which is decoded without resampling
+    const FIXTURE_RATE: u32 = 44100;
+
+    fn peaking(frequency: f32, gain_db: f32) -> EqBand {
+        EqBand {
+            filter: EqFilter::Peaking,
+            frequency,
+            gain_db,
+            q: 2.0,
+        }
+    }
+
+    /* Gain in dB that the chain applies to a stereo sine at a frequency, measured on the left
+     *  channel once the filters have settled */
+    fn measured_gain_db(settings: &DspSettings, frequency: f64) -> f64 {
+        let mut dsp = Dsp::new(settings, SAMPLE_RATE, 2);
+        //A second of audio, the first half of which lets the filters settle
+        let frames = SAMPLE_RATE as usize;
+        let input: Vec<f32> = (0..frames)
+            .flat_map(|i| {
+                let sample = 0.25 * (2.0 * PI * frequency * i as f64 / SAMPLE_RATE as f64).sin();
+                [sample as f32; 2]
+            })
+            .collect();
+        let mut output = input.clone();
+        dsp.process(&mut output);
+
+        //Half a second is a whole number of cycles at every frequency tested
+        let rms = |samples: &[f32]| {
+            let settled = samples.iter().skip(frames).step_by(2);
+            (settled.map(|s| (*s as f64).powi(2)).sum::<f64>() / (frames / 2) as f64).sqrt()
+        };
+        20.0 * (rms(&output) / rms(&input)).log10()
+    }
+
+    #[test]
+    fn eq_applies_band_gains_at_their_centre_frequencies() {
+        let settings = DspSettings {
+            bands: vec![
+                peaking(100.0, 6.0),
+                peaking(1000.0, -9.0),
+                peaking(8000.0, 3.0),
+            ],
+            ..Default::default()
+        };
+        let dsp = Dsp::new(&settings, SAMPLE_RATE, 2);
+        for (frequency, gain_db) in [(100.0, 6.0), (1000.0, -9.0), (8000.0, 3.0)] {
+            let measured = measured_gain_db(&settings, frequency);
+            //Neighbouring bands add a little at each centre
+            assert!(
+                (measured - gain_db).abs() < 0.5,
+                "{} Hz: measured {:.2} dB, expected {} dB",
+                frequency,
+                measured,
+                gain_db
+            );
+            //The curve drawn for the user matches what happens to audio
+            assert!(
+                (measured - dsp.response_db(frequency)).abs() < 0.05,
+                "{} Hz: measured {:.2} dB, response {:.2} dB",
+                frequency,
+                measured,
+                dsp.response_db(frequency)
+            );
+        }
+    }
+
+    /* fixtures/tones.wav decoded to stereo: 0.75s of 100 Hz, 1 kHz and 8 kHz tones mixed at 0.2
+     *  each, as 16-bit mono */
+    fn decode_fixture() -> Vec<f32> {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/playback/fixtures/tones.wav"
+        );
+        let song = SongFile {
+            content_type: Some(String::from("audio/wav")),
+            data: SongData::File(File::open(path).unwrap()),
+        };
+        let mut track = Track::open(song, FIXTURE_RATE, 2).unwrap();
+        let mut samples = vec![];
+        while let Some(decoded) = track.next_samples().unwrap() {
+            samples.extend(decoded);
+        }
+        samples
+    }
+
+    /* Amplitude of one of the fixture's tones on the left channel (Goertzel), skipping the first
+     *  quarter second to let the filters settle. The half second left is a whole number of cycles
+     *  of every tone, so they don't leak into each other. */
+    fn tone_amplitude(samples: &[f32], frequency: f64) -> f64 {
+        let settled: Vec<f64> = samples
+            .iter()
+            .step_by(2)
+            .skip(FIXTURE_RATE as usize / 4)
+            .map(|s| *s as f64)
+            .collect();
+        let coefficient = 2.0 * (2.0 * PI * frequency / FIXTURE_RATE as f64).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for sample in &settled {
+            let s0 = sample + coefficient * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+        2.0 * power.sqrt() / settled.len() as f64
+    }
+
+    #[test]
+    fn eq_over_decoded_fixture_matches_its_response() {
+        let settings = DspSettings {
+            bands: vec![
+                peaking(100.0, 6.0),
+                peaking(1000.0, -9.0),
+                peaking(8000.0, 3.0),
+            ],
+            ..Default::default()
+        };
+        let input = decode_fixture();
+        let mut output = input.clone();
+        let mut dsp = Dsp::new(&settings, FIXTURE_RATE, 2);
+        dsp.process(&mut output);
+
+        for (frequency, gain_db) in [(100.0, 6.0), (1000.0, -9.0), (8000.0, 3.0)] {
+            let input_amplitude = tone_amplitude(&input, frequency);
+            assert!(
+                (input_amplitude - 0.2).abs() < 0.001,
+                "{} Hz: decoded amplitude {:.4}",
+                frequency,
+                input_amplitude
+            );
+            let measured = 20.0 * (tone_amplitude(&output, frequency) / input_amplitude).log10();
+            assert!(
+                (measured - gain_db).abs() < 0.5,
+                "{} Hz: measured {:.2} dB, expected {} dB",
+                frequency,
+                measured,
+                gain_db
+            );
+            assert!(
+                (measured - dsp.response_db(frequency)).abs() < 0.05,
+                "{} Hz: measured {:.2} dB, response {:.2} dB",
+                frequency,
+                measured,
+                dsp.response_db(frequency)
+            );
+        }
+    }
+
+    #[test]
+    fn preamp_and_flat_eq_leave_only_the_preamp_gain() {
+        let settings = DspSettings {
+            preamp_db: -6.0,
+            bands: vec![peaking(1000.0, 0.0)],
+            ..Default::default()
+        };
+        for frequency in [100.0, 1000.0, 8000.0] {
+            let measured = measured_gain_db(&settings, frequency);
+            assert!(
+                (measured + 6.0).abs() < 0.01,
+                "{} Hz: {:.3} dB",
+                frequency,
+                measured
+            );
+        }
+    }
+}
@@ -0,0 +1,854 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+
+use super::crossfade::Crossfade;
+use super::decoder::Track;
+use super::dsp::Dsp;
+use super::loudness::{gain_factor, Loudness};
+use super::output::Output;
+use super::queue_store::QueueStore;
+use super::{load_dsp_presets, DspPresets, PlayerCommand, PLAYBACK_SETTINGS_KEY};
+use crate::db::{get_replay_gain, get_setting, set_computed_replay_gain};
+use crate::models::{
+    PlaybackError, PlaybackItem, PlaybackPosition, PlaybackSettings, PlaybackState, PlaybackStatus,
+    ReplayGain,
+};
+use crate::streaming::{fetch_song, get_stream_pool};
+
+//How often the engine wakes up to decode more audio when there are no commands
+const TICK: Duration = Duration::from_millis(10);
+//Decoded audio kept ahead of the device. Also how long a seek or skip takes to be heard at most.
+const BUFFER_SECONDS: f64 = 0.5;
+const POSITION_INTERVAL: Duration = Duration::from_millis(250);
+//How often the play position is saved during playback
+const QUEUE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+//Previous restarts the song instead once this far in
+const RESTART_THRESHOLD_SECONDS: f64 = 3.0;
+
+/* A queue item, opened and ready to decode */
+pub struct LoadedSong {
+    item: PlaybackItem,
+    track: Track,
+    replay_gain: ReplayGain,
+}
+
+/* A queue item with its open track */
+struct Decoding {
+    index: usize,
+    item: PlaybackItem,
+    track: Track,
+    replay_gain: ReplayGain,
+    //Multiplier from the ReplayGain settings
+    gain: f32,
+    //Measured for songs without a track gain. Dropped on seeking, as only whole songs are measured.
+    loudness: Option<Loudness>,
+}
+
+impl Decoding {
+    /* Next decoded samples, with the ReplayGain adjustment applied */
+    fn next_samples(&mut self) -> Result<Option<Vec<f32>>, anyhow::Error> {
+        let mut samples = match self.track.next_samples()? {
+            Some(samples) => samples,
+            None => return Ok(None),
+        };
+        if let Some(loudness) = &mut self.loudness {
+            loudness.push(&samples);
+        }
+        if self.gain != 1.0 {
+            for sample in samples.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+        Ok(Some(samples))
+    }
+
+    fn seek(&mut self, position: f64) -> Result<(), anyhow::Error> {
+        self.loudness = None;
+        self.track.seek(position)
+    }
+}
+
+/* The end of a song fading out under the start of the next */
+struct Fade {
+    mixer: Crossfade,
+    outgoing: Decoding,
+    //The outgoing song has no samples left, the rest of the fade is the next song fading in
+    ended: bool,
+}
+
+/* Where a queue item starts among the samples written to the output */
+struct Segment {
+    index: usize,
+    //Written sample count at its first sample
+    start: u64,
+    //Song position of its first sample, in seconds (non-zero after a seek)
+    offset: f64,
+    duration: Option<f64>,
+}
+
+struct Load {
+    id: u64,
+    index: usize,
+    //Whether playback is waiting on it, as opposed to opening the next song ahead of time
+    play: bool,
+}
+
+struct Engine {
+    app_handle: AppHandle,
+    //For load tasks to report back on
+    commands: Sender<PlayerCommand>,
+    output: Option<Output>,
+    volume: f32,
+    settings: PlaybackSettings,
+    dsp_presets: DspPresets,
+    //The DSP chain of the song being decoded, and the preset it was made from (None if no preset applies)
+    dsp: Option<(i64, Dsp)>,
+    queue: Vec<PlaybackItem>,
+    queue_store: QueueStore,
+    last_queue_save: Instant,
+    //Position to seek the next song started to, when resuming a saved queue
+    resume_at: Option<f64>,
+    state: PlaybackState,
+    //Queue item being heard
+    current: Option<usize>,
+    //Queue item being decoded. Runs ahead of `current` at the end of a song.
+    decoding: Option<Decoding>,
+    //The previously decoded song, kept until it has finished playing so it can still be seeked
+    finished: Option<Decoding>,
+    //The next queue item, opened ahead of time so it follows without a gap
+    preloaded: Option<Decoding>,
+    //The previous song, while it fades out under `decoding`
+    fade: Option<Fade>,
+    loading: Option<Load>,
+    segments: VecDeque<Segment>,
+    //Samples written to the output since it was opened, less any dropped before being played
+    written: u64,
+    next_load_id: u64,
+    last_position_event: Instant,
+}
+
+pub fn run(
+    app_handle: AppHandle,
+    commands: Sender<PlayerCommand>,
+    receiver: Receiver<PlayerCommand>,
+) {
+    let (settings, dsp_presets) = match tauri::async_runtime::block_on(load_settings(&app_handle)) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("Error: {}", e);
+            (PlaybackSettings::default(), DspPresets::default())
+        }
+    };
+    let queue_store = QueueStore::new(app_handle.clone());
+    let mut engine = Engine {
+        app_handle,
+        commands,
+        output: None,
+        volume: 1.0,
+        settings,
+        dsp_presets,
+        dsp: None,
+        queue: vec![],
+        queue_store,
+        last_queue_save: Instant::now(),
+        resume_at: None,
+        state: PlaybackState::Stopped,
+        current: None,
+        decoding: None,
+        finished: None,
+        preloaded: None,
+        fade: None,
+        loading: None,
+        segments: VecDeque::new(),
+        written: 0,
+        next_load_id: 0,
+        last_position_event: Instant::now(),
+    };
+    loop {
+        match receiver.recv_timeout(TICK) {
+            Ok(command) => engine.handle(command),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        engine.fill();
+        engine.update_position();
+    }
+}
+
+impl Engine {
+    fn handle(&mut self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::PlayQueue {
+                items,
+                index,
+                position,
+            } => {
+                self.queue = items;
+                self.queue_store.save_items(&self.queue);
+                self.preloaded = None;
+                self.start_at(index, position);
+            }
+            PlayerCommand::UpdateQueue { items, index } => {
+                self.update_queue(items, index);
+                self.queue_store.save_items(&self.queue);
+                self.save_queue_state();
+            }
+            PlayerCommand::Play => match self.state {
+                PlaybackState::Paused => {
+                    if let Some(output) = &self.output {
+                        output.play();
+                    }
+                    self.set_state(PlaybackState::Playing);
+                }
+                PlaybackState::Stopped if !self.queue.is_empty() => self.start(0),
+                _ => {}
+            },
+            PlayerCommand::Pause => {
+                if self.state == PlaybackState::Playing {
+                    if let Some(output) = &self.output {
+                        output.pause();
+                    }
+                    self.set_state(PlaybackState::Paused);
+                }
+            }
+            PlayerCommand::Seek(position) => self.seek(position),
+            PlayerCommand::Next => match self.current {
+                Some(current) if current + 1 < self.queue.len() => self.start(current + 1),
+                _ => self.stop(),
+            },
+            PlayerCommand::Previous => match self.current {
+                Some(current) if current > 0 && self.position() < RESTART_THRESHOLD_SECONDS => {
+                    self.start(current - 1)
+                }
+                Some(_) => self.seek(0.0),
+                None => {}
+            },
+            PlayerCommand::SetVolume(volume) => {
+                self.volume = volume.clamp(0.0, 1.0);
+                if let Some(output) = &self.output {
+                    output.queue.set_volume(self.volume);
+                }
+            }
+            PlayerCommand::SetSettings(settings) => {
+                self.settings = settings;
+                //Applies from the next decoded samples, a moment after what is already buffered
+                let fading = self.fade.as_mut().map(|fade| &mut fade.outgoing);
+                for decoding in [
+                    self.decoding.as_mut(),
+                    self.finished.as_mut(),
+                    self.preloaded.as_mut(),
+                    fading,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    decoding.gain = gain_factor(&self.settings, &decoding.replay_gain);
+                }
+            }
+            PlayerCommand::SetDspPresets(presets) => {
+                self.dsp_presets = presets;
+                self.dsp = None;
+                if let Some(decoding) = &self.decoding {
+                    let library_id = decoding.item.library_id.clone();
+                    self.select_dsp(&library_id);
+                }
+            }
+            PlayerCommand::Loaded { load_id, result } => self.loaded(load_id, result),
+        }
+    }
+
+    /* Play a queue item from its start, dropping whatever is buffered */
+    fn start(&mut self, index: usize) {
+        self.start_at(index, 0.0);
+    }
+
+    fn start_at(&mut self, index: usize, position: f64) {
+        self.resume_at = (position > 0.0).then_some(position);
+        if self.output.is_none() {
+            match Output::open(self.volume) {
+                Ok(output) => self.output = Some(output),
+                Err(e) => {
+                    self.emit_error(index, e.to_string());
+                    self.stop();
+                    return;
+                }
+            }
+        }
+        self.clear_buffer();
+        self.decoding = None;
+        self.finished = None;
+        self.fade = None;
+        self.current = Some(index);
+        match self.preloaded.take() {
+            Some(preloaded) if preloaded.index == index => {
+                self.set_state(PlaybackState::Loading);
+                self.begin_decoding(preloaded);
+            }
+            _ => {
+                self.load(index, true);
+                self.set_state(PlaybackState::Loading);
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.clear_buffer();
+        self.decoding = None;
+        self.finished = None;
+        self.fade = None;
+        self.loading = None;
+        if let Some(output) = &self.output {
+            output.pause();
+        }
+        self.set_state(PlaybackState::Stopped);
+    }
+
+    fn seek(&mut self, position: f64) {
+        let current = match self.current {
+            Some(current) => current,
+            None => return,
+        };
+        //Past the end of the current song's decoding, so it was moved to `finished` (or is fading out)
+        if self
+            .decoding
+            .as_ref()
+            .is_none_or(|decoding| decoding.index != current)
+        {
+            let fading = self.fade.take().map(|fade| fade.outgoing);
+            let finished = match self.finished.take().or(fading) {
+                Some(finished) if finished.index == current => finished,
+                _ => return,
+            };
+            if let Some(mut next) = self.decoding.replace(finished) {
+                match next.seek(0.0) {
+                    Ok(_) => self.preloaded = Some(next),
+                    Err(e) => println!("Error: {}", e),
+                }
+            }
+            //A next song still being fetched is no longer needed right away
+            if let Some(load) = &mut self.loading {
+                load.play = false;
+            }
+        }
+        self.fade = None;
+        let decoding = self.decoding.as_mut().unwrap();
+        if let Err(e) = decoding.seek(position) {
+            self.emit_error(current, e.to_string());
+            return;
+        }
+        let duration = decoding.track.duration;
+        self.clear_buffer();
+        self.segments.push_back(Segment {
+            index: current,
+            start: self.written,
+            offset: position,
+            duration,
+        });
+        self.emit_position();
+        self.save_queue_state();
+    }
+
+    /* Swap in a new queue without interrupting playback. `index` is where the current song is in
+     *  it. Anything opened ahead of time for a song that is no longer next is dropped. */
+    fn update_queue(&mut self, items: Vec<PlaybackItem>, index: Option<usize>) {
+        self.queue = items;
+        let shift = match (self.current, index) {
+            (Some(current), Some(index)) => index as i64 - current as i64,
+            _ => 0,
+        };
+        let moved = |index: usize| (index as i64 + shift).max(0) as usize;
+        self.current = self.current.map(moved);
+        for segment in self.segments.iter_mut() {
+            segment.index = moved(segment.index);
+        }
+        for decoding in [&mut self.decoding, &mut self.finished, &mut self.preloaded]
+            .into_iter()
+            .flatten()
+        {
+            decoding.index = moved(decoding.index);
+        }
+        //Only a load of the current song is still wanted, anything after it is reloaded below
+        let current = self.current;
+        self.loading = self
+            .loading
+            .take()
+            .filter(|load| Some(moved(load.index)) == current);
+        if let Some(load) = &mut self.loading {
+            load.index = moved(load.index);
+        }
+
+        let queue = &self.queue;
+        let still_queued = |decoding: &Decoding| queue.get(decoding.index) == Some(&decoding.item);
+        self.preloaded = self.preloaded.take().filter(still_queued);
+        //Already decoding the next song into the buffer, which has to be cut back to where it starts
+        let ahead = self.decoding.as_ref().is_some_and(|decoding| {
+            Some(decoding.index) != self.current && !still_queued(decoding)
+        });
+        if ahead {
+            if let (Some(output), Some(segment)) = (&self.output, self.segments.pop_back()) {
+                let keep = segment.start.saturating_sub(output.queue.consumed());
+                self.written -= output.queue.truncate(keep as usize) as u64;
+            }
+            //Mid-crossfade, the current song carries on without it (less what was already mixed)
+            self.decoding = self.fade.take().map(|fade| fade.outgoing);
+        }
+        match (&self.decoding, &self.finished) {
+            (None, Some(finished)) => self.queue_next(finished.index + 1),
+            _ => self.preload_next(),
+        }
+    }
+
+    fn begin_decoding(&mut self, mut decoding: Decoding) {
+        let mut offset = 0.0;
+        if let Some(position) = self.resume_at.take() {
+            match decoding.seek(position) {
+                Ok(_) => offset = position,
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        self.segments.push_back(Segment {
+            index: decoding.index,
+            start: self.written,
+            offset,
+            duration: decoding.track.duration,
+        });
+        self.select_dsp(&decoding.item.library_id);
+        self.decoding = Some(decoding);
+        if self.state == PlaybackState::Loading {
+            if let Some(output) = &self.output {
+                output.play();
+            }
+            self.set_state(PlaybackState::Playing);
+        }
+        self.preload_next();
+    }
+
+    /* The decoded song has ended (or failed, if not `complete`), continue straight on with the next one */
+    fn finish_decoding(&mut self, complete: bool) {
+        self.finished = self.decoding.take();
+        if let Some(finished) = &self.finished {
+            if complete {
+                save_measured_gain(&self.app_handle, finished);
+            }
+            self.queue_next(finished.index + 1);
+        }
+    }
+
+    /* Start fading into the next song once the current one is within the crossfade length of its
+     *  end. Needs the next song opened already and the current song's duration. */
+    fn start_crossfade_if_due(&mut self) {
+        if self.fade.is_some() || self.settings.crossfade_seconds <= 0.0 {
+            return;
+        }
+        let (samples_per_second, channels) = match &self.output {
+            Some(output) => (output.samples_per_second(), output.channels),
+            None => return,
+        };
+        let (decoding, segment) = match (&self.decoding, self.segments.back()) {
+            (Some(decoding), Some(segment)) if segment.index == decoding.index => {
+                (decoding, segment)
+            }
+            _ => return,
+        };
+        let next = match &self.preloaded {
+            Some(next) if next.index == decoding.index + 1 => next,
+            _ => return,
+        };
+        let duration = match decoding.track.duration {
+            Some(duration) => duration,
+            None => return,
+        };
+        let decoded = segment.offset + (self.written - segment.start) as f64 / samples_per_second;
+        let remaining = (duration - decoded).max(0.0);
+        //Short songs get a shorter fade, at most half of either song
+        let length = (self.settings.crossfade_seconds as f64)
+            .min(duration / 2.0)
+            .min(
+                next.track
+                    .duration
+                    .map_or(f64::MAX, |duration| duration / 2.0),
+            );
+        if remaining > length {
+            return;
+        }
+
+        let mixer = Crossfade::new(
+            self.settings.crossfade_curve,
+            channels,
+            (remaining * samples_per_second) as u64,
+        );
+        let outgoing = self.decoding.take().unwrap();
+        let incoming = self.preloaded.take().unwrap();
+        self.fade = Some(Fade {
+            mixer,
+            outgoing,
+            ended: false,
+        });
+        self.begin_decoding(incoming);
+    }
+
+    /* Mix the song fading out into samples of the next one */
+    fn mix_crossfade(&mut self, samples: &mut [f32]) {
+        let fade = match &mut self.fade {
+            Some(fade) => fade,
+            None => return,
+        };
+        while !fade.ended && fade.mixer.needs(samples.len()) > 0 {
+            match fade.outgoing.next_samples() {
+                Ok(Some(outgoing)) => fade.mixer.feed(&outgoing),
+                Ok(None) => {
+                    fade.ended = true;
+                    save_measured_gain(&self.app_handle, &fade.outgoing);
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    fade.ended = true;
+                }
+            }
+        }
+        if fade.mixer.mix(samples) {
+            self.fade = None;
+        }
+    }
+
+    /* Switch to the DSP chain for a song from `library_id`, if it differs from the one in use */
+    fn select_dsp(&mut self, library_id: &String) {
+        let output = match &self.output {
+            Some(output) => output,
+            None => return,
+        };
+        let presets = &self.dsp_presets.presets;
+        let preset = presets
+            .iter()
+            .find(|preset| preset.library_id.as_ref() == Some(library_id))
+            .or_else(|| {
+                presets
+                    .iter()
+                    .find(|preset| preset.device.as_ref() == Some(&output.device))
+            })
+            .or_else(|| {
+                presets
+                    .iter()
+                    .find(|preset| preset.id.is_some() && preset.id == self.dsp_presets.active)
+            });
+        let preset = match preset {
+            Some(preset) => preset,
+            None => {
+                self.dsp = None;
+                return;
+            }
+        };
+        let id = preset.id.unwrap_or_default();
+        if self.dsp.as_ref().is_none_or(|(current, _)| *current != id) {
+            let dsp = Dsp::new(&preset.settings, output.sample_rate, output.channels);
+            self.dsp = Some((id, dsp));
+        }
+    }
+
+    /* Decode queue item `next` once the buffer runs out of the current one */
+    fn queue_next(&mut self, next: usize) {
+        match self.preloaded.take() {
+            Some(preloaded) if preloaded.index == next => self.begin_decoding(preloaded),
+            //Not opened in time (or at all), so there will be a gap
+            _ if next < self.queue.len() => {
+                if self.loading.as_ref().is_none_or(|load| load.index != next) {
+                    self.load(next, true);
+                }
+                if let Some(load) = &mut self.loading {
+                    load.play = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn preload_next(&mut self) {
+        let next = match &self.decoding {
+            Some(decoding) => decoding.index + 1,
+            None => return,
+        };
+        let already_loaded = self
+            .preloaded
+            .as_ref()
+            .is_some_and(|preloaded| preloaded.index == next)
+            || self.loading.as_ref().is_some_and(|load| load.index == next);
+        if next < self.queue.len() && !already_loaded {
+            self.load(next, false);
+        }
+    }
+
+    /* Fetch and open a queue item in the background. Replaces any load in progress. */
+    fn load(&mut self, index: usize, play: bool) {
+        let (sample_rate, channels) = match &self.output {
+            Some(output) => (output.sample_rate, output.channels),
+            None => return,
+        };
+        let item = self.queue[index].clone();
+        let load_id = self.next_load_id;
+        self.next_load_id += 1;
+        self.loading = Some(Load {
+            id: load_id,
+            index,
+            play,
+        });
+
+        let app_handle = self.app_handle.clone();
+        let commands = self.commands.clone();
+        tauri::async_runtime::spawn(async move {
+            let result = load_song(&app_handle, item, sample_rate, channels)
+                .await
+                .map(Box::new)
+                .map_err(|e| e.to_string());
+            let _ = commands.send(PlayerCommand::Loaded { load_id, result });
+        });
+    }
+
+    fn loaded(&mut self, load_id: u64, result: Result<Box<LoadedSong>, String>) {
+        let load = match self.loading.take() {
+            Some(load) if load.id == load_id => load,
+            //Superseded by a later load
+            load => {
+                self.loading = load;
+                return;
+            }
+        };
+        let index = load.index;
+        match result {
+            Ok(song) => {
+                let (sample_rate, channels) = match &self.output {
+                    Some(output) => (output.sample_rate, output.channels),
+                    None => return,
+                };
+                let measure = song.replay_gain.track_gain.is_none()
+                    && song.replay_gain.computed_gain.is_none();
+                let decoding = Decoding {
+                    index,
+                    gain: gain_factor(&self.settings, &song.replay_gain),
+                    loudness: measure.then(|| Loudness::new(sample_rate, channels)),
+                    item: song.item,
+                    track: song.track,
+                    replay_gain: song.replay_gain,
+                };
+                if load.play {
+                    self.begin_decoding(decoding);
+                } else {
+                    self.preloaded = Some(decoding);
+                }
+            }
+            Err(message) => {
+                self.emit_error(index, message);
+                //Skip songs that can't be played, but only once playback reaches them
+                if !load.play {
+                    return;
+                }
+                if self.state == PlaybackState::Loading {
+                    match index + 1 < self.queue.len() {
+                        true => self.start(index + 1),
+                        false => self.stop(),
+                    }
+                } else {
+                    //Between songs, the one before is still playing out of the buffer
+                    self.queue_next(index + 1);
+                }
+            }
+        }
+    }
+
+    /* Decode until the buffer holds BUFFER_SECONDS of audio */
+    fn fill(&mut self) {
+        let (queue, target) = match &self.output {
+            Some(output) => (
+                output.queue.clone(),
+                (output.samples_per_second() * BUFFER_SECONDS) as usize,
+            ),
+            None => return,
+        };
+        while queue.len() < target {
+            self.start_crossfade_if_due();
+            let decoding = match self.decoding.as_mut() {
+                Some(decoding) => decoding,
+                None => break,
+            };
+            match decoding.next_samples() {
+                Ok(Some(mut samples)) => {
+                    self.mix_crossfade(&mut samples);
+                    if let Some((_, dsp)) = &mut self.dsp {
+                        dsp.process(&mut samples);
+                    }
+                    queue.push(&samples);
+                    self.written += samples.len() as u64;
+                }
+                Ok(None) => {
+                    //Ended before the fade into it did, so the rest of the fade is dropped
+                    self.fade = None;
+                    self.finish_decoding(true);
+                }
+                //Treated as the end of the song, so playback carries on with the next
+                Err(e) => {
+                    let index = decoding.index;
+                    self.emit_error(index, e.to_string());
+                    self.fade = None;
+                    self.finish_decoding(false);
+                }
+            }
+        }
+    }
+
+    /* Follow playback into the next segment once the device reaches it, and report the position */
+    fn update_position(&mut self) {
+        let consumed = match &self.output {
+            Some(output) => output.queue.consumed(),
+            None => return,
+        };
+        let mut changed = false;
+        while self.segments.len() > 1 && self.segments[1].start <= consumed {
+            self.segments.pop_front();
+            self.finished = None;
+            changed = true;
+        }
+        if changed {
+            self.current = self.segments.front().map(|segment| segment.index);
+            let state = self.state;
+            self.set_state(state);
+        }
+
+        let waiting =
+            self.decoding.is_some() || self.loading.as_ref().is_some_and(|load| load.play);
+        if self.state == PlaybackState::Playing && !waiting && consumed >= self.written {
+            //End of the queue
+            self.stop();
+            return;
+        }
+        if self.state == PlaybackState::Playing
+            && self.last_position_event.elapsed() >= POSITION_INTERVAL
+        {
+            self.emit_position();
+        }
+        if self.state == PlaybackState::Playing
+            && self.last_queue_save.elapsed() >= QUEUE_SAVE_INTERVAL
+        {
+            self.save_queue_state();
+        }
+    }
+
+    /* Seconds into the song being heard */
+    fn position(&self) -> f64 {
+        match (&self.output, self.segments.front()) {
+            (Some(output), Some(segment)) => {
+                let played = output.queue.consumed().saturating_sub(segment.start);
+                segment.offset + played as f64 / output.samples_per_second()
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn clear_buffer(&mut self) {
+        if let Some(output) = &self.output {
+            self.written -= output.queue.truncate(0) as u64;
+        }
+        self.segments.clear();
+    }
+
+    fn save_queue_state(&mut self) {
+        self.last_queue_save = Instant::now();
+        //Still loading a resumed song, which keeps its saved position
+        let position = match self.resume_at {
+            Some(position) if self.segments.is_empty() => position,
+            _ => self.position(),
+        };
+        self.queue_store.save_state(self.current, position);
+    }
+
+    fn set_state(&mut self, state: PlaybackState) {
+        self.state = state;
+        self.save_queue_state();
+        let status = PlaybackStatus {
+            state,
+            index: self.current,
+        };
+        self.emit("playback-state", status);
+    }
+
+    fn emit_position(&mut self) {
+        self.last_position_event = Instant::now();
+        if let Some(segment) = self.segments.front() {
+            let position = PlaybackPosition {
+                index: segment.index,
+                position: self.position(),
+                duration: segment.duration,
+            };
+            self.emit("playback-position", position);
+        }
+    }
+
+    fn emit_error(&self, index: usize, message: String) {
+        println!("Error: Playback of queue item {}: {}", index, message);
+        self.emit("playback-error", PlaybackError { index, message });
+    }
+
+    fn emit<T: Serialize + Clone>(&self, event: &str, payload: T) {
+        if let Err(e) = self.app_handle.emit(event, payload) {
+            println!("Error: {}", e);
+        }
+    }
+}
+
+async fn load_settings(
+    app_handle: &AppHandle,
+) -> Result<(PlaybackSettings, DspPresets), anyhow::Error> {
+    let pool = get_stream_pool(app_handle).await?;
+    let settings = get_setting::<PlaybackSettings>(pool, PLAYBACK_SETTINGS_KEY).await?;
+    let dsp_presets = load_dsp_presets(pool).await?;
+    Ok((settings.unwrap_or_default(), dsp_presets))
+}
+
+async fn load_song(
+    app_handle: &AppHandle,
+    item: PlaybackItem,
+    sample_rate: u32,
+    channels: usize,
+) -> Result<LoadedSong, anyhow::Error> {
+    let song = fetch_song(app_handle, &item.library_id, &item.song_id).await?;
+    //Probing reads the start of the song, which blocks until it arrives when streamed
+    let track =
+        tauri::async_runtime::spawn_blocking(move || Track::open(song, sample_rate, channels))
+            .await??;
+    let pool = get_stream_pool(app_handle).await?;
+    //The server's values come first, then the file's tags
+    let replay_gain = get_replay_gain(pool, &item.song_id)
+        .await?
+        .unwrap_or_default()
+        .or(track.tags.clone());
+    Ok(LoadedSong {
+        item,
+        track,
+        replay_gain,
+    })
+}
+
+/* Store the loudness measured over a whole song, used as its gain from then on */
+fn save_measured_gain(app_handle: &AppHandle, decoding: &Decoding) {
+    let (gain, peak) = match decoding
+        .loudness
+        .as_ref()
+        .and_then(|loudness| loudness.replay_gain())
+    {
+        Some(measured) => measured,
+        None => return,
+    };
+    let app_handle = app_handle.clone();
+    let song_id = decoding.item.song_id.clone();
+    tauri::async_runtime::spawn(async move {
+        let result = match get_stream_pool(&app_handle).await {
+            Ok(pool) => set_computed_replay_gain(pool, &song_id, gain, peak).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            println!("Error: {}", e);
+        }
+    });
+}
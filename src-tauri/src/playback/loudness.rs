@@ -0,0 +1,156 @@
+use std::f64::consts::PI;
+
+use super::dsp::Biquad;
+use crate::models::{PlaybackSettings, ReplayGain, ReplayGainMode};
+
+//ReplayGain 2.0 reference level, in LUFS
+const REFERENCE_LOUDNESS: f64 = -18.0;
+const ABSOLUTE_GATE: f64 = -70.0;
+//Relative to the loudness of the blocks above the absolute gate
+const RELATIVE_GATE: f64 = -10.0;
+//Gating blocks are 400ms, overlapping by 75%, so they are made of four 100ms steps
+const STEPS_PER_BLOCK: usize = 4;
+
+/* The factor to multiply a song's samples by for the ReplayGain settings. Songs with no gain
+*  information from anywhere are left as they are (apart from the preamp). */
+pub fn gain_factor(settings: &PlaybackSettings, replay_gain: &ReplayGain) -> f32 {
+    let track_gain = replay_gain.track_gain.or(replay_gain.computed_gain);
+    let track_peak = replay_gain.track_peak.or(replay_gain.computed_peak);
+    let (gain, peak) = match settings.replay_gain_mode {
+        ReplayGainMode::Off => return 1.0,
+        ReplayGainMode::Track => (track_gain, track_peak),
+        ReplayGainMode::Album => (
+            replay_gain.album_gain.or(track_gain),
+            replay_gain.album_peak.or(track_peak),
+        ),
+    };
+    let gain_db = gain.unwrap_or(0.0) + settings.replay_gain_preamp_db as f64;
+    let mut factor = 10f64.powf(gain_db / 20.0);
+    if settings.prevent_clipping {
+        //Without a peak, boosting at all could clip
+        let limit = match peak {
+            Some(peak) if peak > 0.0 => 1.0 / peak,
+            _ => 1.0,
+        };
+        factor = factor.min(limit);
+    }
+    factor as f32
+}
+
+/* Integrated loudness (ITU-R BS.1770 / EBU R128) of a song, measured as it is decoded. Gives
+*  songs without ReplayGain information a gain for the next time they are played. */
+pub struct Loudness {
+    channels: usize,
+    filters: Vec<KWeighting>,
+    step_frames: usize,
+    //Frames and sum of squares of the step in progress
+    frames: usize,
+    sum: f64,
+    //Mean square of each completed 100ms step
+    steps: Vec<f64>,
+    peak: f32,
+}
+
+impl Loudness {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Loudness {
+            channels,
+            filters: (0..channels)
+                .map(|_| KWeighting::new(sample_rate))
+                .collect(),
+            step_frames: (sample_rate / 10) as usize,
+            frames: 0,
+            sum: 0.0,
+            steps: vec![],
+            peak: 0.0,
+        }
+    }
+
+    /* Interleaved samples, before any gain is applied */
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (sample, filter) in frame.iter().zip(self.filters.iter_mut()) {
+                self.peak = self.peak.max(sample.abs());
+                let weighted = filter.process(*sample as f64);
+                self.sum += weighted * weighted;
+            }
+            self.frames += 1;
+            if self.frames == self.step_frames {
+                self.steps.push(self.sum / self.step_frames as f64);
+                self.frames = 0;
+                self.sum = 0.0;
+            }
+        }
+    }
+
+    /* (ReplayGain in dB, sample peak). None for songs too short or too quiet to measure. */
+    pub fn replay_gain(&self) -> Option<(f64, f64)> {
+        let blocks: Vec<f64> = self
+            .steps
+            .windows(STEPS_PER_BLOCK)
+            .map(|steps| steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64)
+            .filter(|block| loudness(*block) > ABSOLUTE_GATE)
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+        let gate = loudness(mean(&blocks)) + RELATIVE_GATE;
+        let gated: Vec<f64> = blocks
+            .into_iter()
+            .filter(|block| loudness(*block) > gate)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        let integrated = loudness(mean(&gated));
+        Some((REFERENCE_LOUDNESS - integrated, self.peak as f64))
+    }
+}
+
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/* The BS.1770 K-weighting filter (a high shelf, then a high pass), for any sample rate */
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        KWeighting { shelf, high_pass }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
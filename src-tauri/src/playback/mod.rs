@@ -0,0 +1,111 @@
+mod crossfade;
+mod decoder;
+mod dsp;
+mod engine;
+mod loudness;
+mod output;
+mod queue_store;
+
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use sqlx::{Pool, Sqlite};
+use tauri::AppHandle;
+
+use crate::db::{get_dsp_presets, get_setting};
+use crate::models::{DspPreset, DspSettings, PlaybackItem, PlaybackSettings};
+pub use decoder::replay_gain_tags;
+use dsp::Dsp;
+use engine::LoadedSong;
+pub use output::output_devices;
+
+pub const PLAYBACK_SETTINGS_KEY: &str = "playback_settings";
+//ID of the preset used when none is bound to the library or output device
+pub const DSP_PRESET_KEY: &str = "dsp_preset";
+
+/* Native playback: songs are decoded with symphonia and played through cpal, so playback
+*  doesn't depend on the webview's codecs. The next queue item is opened ahead of time and its
+*  samples follow the current song's in the same output stream, so albums play without gaps.
+*  State changes and the play position are sent to the webview as `playback-state`,
+*  `playback-position` and `playback-error` events. Crossfading and ReplayGain are applied to the
+*  decoded samples, then the DSP chain, before the volume. The queue and position are saved as
+*  they change, to be picked up again after a restart. */
+pub enum PlayerCommand {
+    //Replace the queue and play from `index`, `position` seconds in
+    PlayQueue {
+        items: Vec<PlaybackItem>,
+        index: usize,
+        position: f64,
+    },
+    //Replace the queue without interrupting the current song, which is now at `index`
+    UpdateQueue {
+        items: Vec<PlaybackItem>,
+        index: Option<usize>,
+    },
+    Play,
+    Pause,
+    //Seconds into the current song
+    Seek(f64),
+    Next,
+    Previous,
+    //0 to 1
+    SetVolume(f32),
+    SetSettings(PlaybackSettings),
+    SetDspPresets(DspPresets),
+    //Sent by the engine's own background loads
+    Loaded {
+        load_id: u64,
+        //Boxed, as a loaded song is much larger than any other command
+        result: Result<Box<LoadedSong>, String>,
+    },
+}
+
+#[derive(Default)]
+pub struct DspPresets {
+    pub presets: Vec<DspPreset>,
+    pub active: Option<i64>,
+}
+
+pub async fn load_dsp_presets(pool: &Pool<Sqlite>) -> Result<DspPresets, anyhow::Error> {
+    Ok(DspPresets {
+        presets: get_dsp_presets(pool).await?,
+        active: get_setting::<Option<i64>>(pool, DSP_PRESET_KEY)
+            .await?
+            .flatten(),
+    })
+}
+
+/* Gain of DSP settings' preamp and EQ at each frequency, in dB */
+pub fn dsp_response(settings: &DspSettings, frequencies: &[f64]) -> Vec<f64> {
+    let dsp = Dsp::new(settings, 48000, 1);
+    frequencies
+        .iter()
+        .map(|frequency| dsp.response_db(*frequency))
+        .collect()
+}
+
+/* Handle to the playback engine. The engine runs on a thread of its own, as audio output streams
+*  can't move between threads on every platform, and is started by the first command. */
+#[derive(Default)]
+pub struct Player {
+    commands: Mutex<Option<Sender<PlayerCommand>>>,
+}
+
+impl Player {
+    pub fn send(&self, app_handle: &AppHandle, command: PlayerCommand) {
+        let mut commands = self.commands.lock().unwrap();
+        let sender = commands.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            let engine_sender = sender.clone();
+            let app_handle = app_handle.clone();
+            thread::spawn(move || engine::run(app_handle, engine_sender, receiver));
+            sender
+        });
+        if let Err(e) = sender.send(command) {
+            //The engine thread panicked. The next command starts a new one.
+            println!("Error: Playback engine stopped: {}", e);
+            *commands = None;
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Device, FromSample, Sample, SampleFormat, SizedSample, Stream, StreamConfig};
+
+/* Decoded samples waiting to be played, shared between the engine and the audio callback.
+*  Samples are interleaved f32 at the output's sample rate and channel count. */
+pub struct SampleQueue {
+    samples: Mutex<VecDeque<f32>>,
+    //Samples handed to the device since the output was opened, for working out the play position
+    consumed: AtomicU64,
+    //f32 bits
+    volume: AtomicU32,
+}
+
+impl SampleQueue {
+    fn new(volume: f32) -> Self {
+        SampleQueue {
+            samples: Mutex::new(VecDeque::new()),
+            consumed: AtomicU64::new(0),
+            volume: AtomicU32::new(volume.to_bits()),
+        }
+    }
+
+    pub fn push(&self, samples: &[f32]) {
+        self.samples.lock().unwrap().extend(samples);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.lock().unwrap().len()
+    }
+
+    /* Drop everything after the first `keep` samples still to be played. Returns how many were dropped. */
+    pub fn truncate(&self, keep: usize) -> usize {
+        let mut samples = self.samples.lock().unwrap();
+        let dropped = samples.len().saturating_sub(keep);
+        samples.truncate(keep);
+        dropped
+    }
+
+    pub fn consumed(&self) -> u64 {
+        self.consumed.load(Ordering::Acquire)
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    fn fill<T: Sample + FromSample<f32>>(&self, out: &mut [T]) {
+        let volume = f32::from_bits(self.volume.load(Ordering::Relaxed));
+        let mut samples = self.samples.lock().unwrap();
+        let available = samples.len().min(out.len());
+        for (slot, sample) in out.iter_mut().zip(samples.drain(..available)) {
+            *slot = T::from_sample(sample * volume);
+        }
+        //Underrun, play silence until the engine catches up
+        for slot in &mut out[available..] {
+            *slot = T::EQUILIBRIUM;
+        }
+        //Counted while still locked, so it always agrees with what truncate sees
+        self.consumed.fetch_add(available as u64, Ordering::Release);
+    }
+}
+
+/* The default output device, opened at its preferred format. Songs are converted to it as
+*  they are decoded, so one stream plays the whole queue without gaps. */
+pub struct Output {
+    //Not Send on every platform, which is why the engine owns the output on its own thread
+    stream: Stream,
+    pub queue: Arc<SampleQueue>,
+    pub sample_rate: u32,
+    pub channels: usize,
+    //Name of the device, for finding the DSP preset bound to it
+    pub device: String,
+}
+
+impl Output {
+    pub fn open(volume: f32) -> Result<Output, anyhow::Error> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| anyhow::anyhow!("No audio output device"))?;
+        let supported = device.default_output_config()?;
+        let sample_format = supported.sample_format();
+        let config: StreamConfig = supported.into();
+
+        let queue = Arc::new(SampleQueue::new(volume));
+        let stream = match sample_format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone())?,
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone())?,
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone())?,
+            SampleFormat::I32 => build_stream::<i32>(&device, &config, queue.clone())?,
+            format => return Err(anyhow::anyhow!("Unsupported output format {:?}", format)),
+        };
+        stream.play()?;
+        Ok(Output {
+            stream,
+            queue,
+            sample_rate: config.sample_rate.0,
+            channels: config.channels as usize,
+            device: device.name().unwrap_or_default(),
+        })
+    }
+
+    pub fn play(&self) {
+        if let Err(e) = self.stream.play() {
+            println!("Error: {}", e);
+        }
+    }
+
+    pub fn pause(&self) {
+        if let Err(e) = self.stream.pause() {
+            println!("Error: {}", e);
+        }
+    }
+
+    /* Samples per second of playback */
+    pub fn samples_per_second(&self) -> f64 {
+        self.sample_rate as f64 * self.channels as f64
+    }
+}
+
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &Device,
+    config: &StreamConfig,
+    queue: Arc<SampleQueue>,
+) -> Result<Stream, anyhow::Error> {
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _| queue.fill(data),
+        |e| println!("Error: Audio output: {}", e),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/* Names of the output devices available, for binding DSP presets to */
+pub fn output_devices() -> Result<Vec<String>, anyhow::Error> {
+    let devices = cpal::default_host().output_devices()?;
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
@@ -0,0 +1,50 @@
+use tauri::AppHandle;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::db::{save_queue_items, save_queue_state};
+use crate::downloads::now_millis;
+use crate::models::PlaybackItem;
+use crate::streaming::get_stream_pool;
+
+enum QueueSave {
+    Items(Vec<PlaybackItem>),
+    State { index: Option<usize>, position: f64 },
+}
+
+/* Writes the play queue to the database in the background, one change at a time so a slow
+*  write can't land after a newer one */
+pub struct QueueStore {
+    sender: UnboundedSender<QueueSave>,
+}
+
+impl QueueStore {
+    pub fn new(app_handle: AppHandle) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tauri::async_runtime::spawn(async move {
+            while let Some(save) = receiver.recv().await {
+                if let Err(e) = write(&app_handle, save).await {
+                    println!("Error: Saving play queue: {}", e);
+                }
+            }
+        });
+        QueueStore { sender }
+    }
+
+    pub fn save_items(&self, items: &[PlaybackItem]) {
+        let _ = self.sender.send(QueueSave::Items(items.to_vec()));
+    }
+
+    pub fn save_state(&self, index: Option<usize>, position: f64) {
+        let _ = self.sender.send(QueueSave::State { index, position });
+    }
+}
+
+async fn write(app_handle: &AppHandle, save: QueueSave) -> Result<(), anyhow::Error> {
+    let pool = get_stream_pool(app_handle).await?;
+    match save {
+        QueueSave::Items(items) => save_queue_items(pool, &items).await,
+        QueueSave::State { index, position } => {
+            save_queue_state(pool, index, position, now_millis()).await
+        }
+    }
+}
@@ -16,6 +16,13 @@ pub struct SubsonicBaseResponse {
     pub server_version: String,
 }
 
+/* Just enough of a response to tell whether the call failed */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubsonicStatus {
+    pub status: String,
+    pub error: Option<SubsonicError>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SubsonicError {
     pub code: u32,
@@ -50,6 +57,26 @@ pub struct SubsonicIndexID3 {
 pub struct SubsonicArtistID3 {
     pub id: String,
     pub name: String,
+    pub starred: Option<String>,
+    pub user_rating: Option<u32>,
+}
+
+/*******************************************************************************
+ * Indexes
+ ******************************************************************************/
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubsonicGetIndexesResponse {
+    #[serde(flatten)]
+    pub base: SubsonicBaseResponse,
+    pub indexes: SubsonicIndexes,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicIndexes {
+    pub last_modified: u64,
+    // Omitted by the server when nothing changed since `ifModifiedSince`
+    pub index: Option<Vec<SubsonicIndexID3>>,
 }
 
 /*******************************************************************************
@@ -80,6 +107,10 @@ pub struct SubsonicAlbumID3 {
     pub cover_art: String,
     pub duration: u32,
     pub year: Option<u32>,
+    pub created: Option<String>,
+    pub changed: Option<String>,
+    pub starred: Option<String>,
+    pub user_rating: Option<u32>,
 }
 
 /*******************************************************************************
@@ -114,9 +145,27 @@ pub struct SubsonicChild {
     pub artist: String,
     pub track: Option<u32>,
     pub disc_number: Option<u32>,
+    pub year: Option<u32>,
     pub duration: Option<u32>,
     pub content_type: String,
     pub cover_art: Option<String>,
+    pub starred: Option<String>,
+    pub user_rating: Option<u32>,
+    //OpenSubsonic only
+    pub replay_gain: Option<SubsonicReplayGain>,
+}
+
+/* https://opensubsonic.netlify.app/docs/responses/replaygain/ (gains in dB) */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicReplayGain {
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_peak: Option<f64>,
+    pub base_gain: Option<f64>,
+    //For songs the server has no gain for
+    pub fallback_gain: Option<f64>,
 }
 
 /*******************************************************************************
@@ -133,7 +182,52 @@ pub struct SubsonicGetAlbumList2Response {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SubsonicAlbumList {
+    #[serde(default)]
+    pub album: Vec<SubsonicAlbumID3>,
+}
+
+/*******************************************************************************
+ * Starred2
+ ******************************************************************************/
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicGetStarred2Response {
+    #[serde(flatten)]
+    pub base: SubsonicBaseResponse,
+    pub starred_2: SubsonicStarred2,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicStarred2 {
+    #[serde(default)]
+    pub artist: Vec<SubsonicArtistID3>,
+    #[serde(default)]
+    pub album: Vec<SubsonicAlbumID3>,
+    #[serde(default)]
+    pub song: Vec<SubsonicChild>,
+}
+
+/*******************************************************************************
+ * Search3
+ ******************************************************************************/
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicSearch3Response {
+    #[serde(flatten)]
+    pub base: SubsonicBaseResponse,
+    pub search_result_3: SubsonicSearchResult3,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicSearchResult3 {
+    #[serde(default)]
+    pub artist: Vec<SubsonicArtistID3>,
+    #[serde(default)]
     pub album: Vec<SubsonicAlbumID3>,
+    #[serde(default)]
+    pub song: Vec<SubsonicChild>,
 }
 
 /*******************************************************************************
@@ -150,6 +244,7 @@ pub struct SubsonicGetPlaylistsResponse {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SubsonicPlaylistContainer {
+    #[serde(default)]
     pub playlist: Vec<SubsonicPlaylist>,
 }
 
@@ -176,6 +271,41 @@ pub struct SubsonicGetPlaylistResponse {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SubsonicPlaylistDetails {
-    pub id: String,
+    #[serde(flatten)]
+    pub info: SubsonicPlaylist,
+    #[serde(default)]
+    pub entry: Vec<SubsonicChild>,
+}
+
+/* createPlaylist only returns the playlist from Subsonic 1.14 on */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicCreatePlaylistResponse {
+    #[serde(flatten)]
+    pub base: SubsonicBaseResponse,
+    pub playlist: Option<SubsonicPlaylistDetails>,
+}
+
+/*******************************************************************************
+ * Play Queue
+ ******************************************************************************/
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicGetPlayQueueResponse {
+    #[serde(flatten)]
+    pub base: SubsonicBaseResponse,
+    //Missing when nothing has been saved
+    pub play_queue: Option<SubsonicPlayQueue>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SubsonicPlayQueue {
+    pub current: Option<String>,
+    //Milliseconds into the current song
+    pub position: Option<i64>,
+    pub changed: Option<String>,
+    pub changed_by: Option<String>,
+    #[serde(default)]
     pub entry: Vec<SubsonicChild>,
 }
@@ -0,0 +1,232 @@
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    client::ServerClient,
+    db::{delete_queued_scrobble, enqueue_scrobble, get_queued_scrobbles},
+    errors::ApiError,
+    formatter::strip_namespace,
+    models::{Library, ScrobbleStatus},
+    subsonic::scrobble,
+};
+
+//A play counts once half the track (or 4 minutes of it, for long tracks) has been heard
+const SCROBBLE_MAX_THRESHOLD_SECONDS: u32 = 240;
+
+pub fn reached_scrobble_threshold(played_seconds: u32, duration: u32) -> bool {
+    let threshold = match duration {
+        //Unknown duration, fall back to the fixed threshold
+        0 => SCROBBLE_MAX_THRESHOLD_SECONDS,
+        duration => (duration / 2).min(SCROBBLE_MAX_THRESHOLD_SECONDS),
+    };
+    played_seconds >= threshold
+}
+
+/* Submit a play. If the server can't take it right now it is queued (with its original play
+*  time) and submitted by the next flush_scrobble_queue. */
+pub async fn submit_scrobble(
+    client: &ServerClient,
+    pool: &Pool<Sqlite>,
+    library: &Library,
+    song_id: &String,
+    played_at: i64,
+) -> Result<ScrobbleStatus, anyhow::Error> {
+    match scrobble(
+        client,
+        library,
+        strip_namespace(&library.id, song_id),
+        Some(played_at),
+        true,
+    )
+    .await
+    {
+        Ok(_) => {
+            //Server is reachable again, so send anything queued while it wasn't
+            flush_scrobble_queue(client, pool, library).await?;
+            Ok(ScrobbleStatus::Submitted)
+        }
+        Err(e) if is_retryable(&e) => {
+            println!("Error: {}. Queueing scrobble", e);
+            enqueue_scrobble(pool, &library.id, song_id, played_at).await?;
+            Ok(ScrobbleStatus::Queued)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/* Submit queued scrobbles for a library in play order. Returns how many were accepted. */
+pub async fn flush_scrobble_queue(
+    client: &ServerClient,
+    pool: &Pool<Sqlite>,
+    library: &Library,
+) -> Result<u32, anyhow::Error> {
+    let mut submitted = 0;
+    for queued in get_queued_scrobbles(pool, &library.id).await? {
+        match scrobble(
+            client,
+            library,
+            strip_namespace(&library.id, &queued.song_id),
+            Some(queued.played_at),
+            true,
+        )
+        .await
+        {
+            Ok(_) => {
+                delete_queued_scrobble(pool, queued.id).await?;
+                submitted += 1;
+            }
+            //Rejected by the server (song no longer exists or bad request), retrying won't help
+            Err(e @ (ApiError::NotFound(_) | ApiError::MissingParameter(_))) => {
+                println!("Error: {}. Dropping queued scrobble", e);
+                delete_queued_scrobble(pool, queued.id).await?;
+            }
+            //Anything else may clear up, keep the rest for later
+            Err(e) => {
+                println!("Error: {}. Keeping queued scrobbles", e);
+                break;
+            }
+        }
+    }
+    Ok(submitted)
+}
+
+/* Failures worth retrying later: the server is offline, restarting (5xx from a proxy) or rate
+*  limiting, or the credentials are being changed */
+fn is_retryable(e: &ApiError) -> bool {
+    match e {
+        ApiError::Transport(_)
+        | ApiError::WrongCredentials(_)
+        | ApiError::NotAuthorized(_)
+        | ApiError::InvalidApiKey(_) => true,
+        ApiError::Http { status, .. } => matches!(status, 401 | 403 | 429 | 500..),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{Matcher, Server, ServerGuard};
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+    use crate::client::ServerClientConfig;
+    use crate::db::insert_library;
+    use crate::formatter::namespace_id;
+    use crate::models::LibraryKind;
+
+    const LIBRARY_ID: &str = "sub";
+
+    //In-memory database holding the library the queued scrobbles belong to
+    async fn test_pool(library: &Library) -> Pool<Sqlite> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for migration in crate::migrations() {
+            sqlx::raw_sql(migration.sql).execute(&pool).await.unwrap();
+        }
+        let mut conn = pool.acquire().await.unwrap();
+        insert_library(&mut conn, library).await.unwrap();
+        drop(conn);
+        pool
+    }
+
+    fn library(host: String) -> Library {
+        Library {
+            id: LIBRARY_ID.to_string(),
+            name: String::from("Subsonic"),
+            host,
+            port: None,
+            username: String::from("alice"),
+            hashed_password: String::new(),
+            salt: String::new(),
+            last_scanned: String::new(),
+            kind: LibraryKind::Subsonic,
+            paths: vec![],
+            user_id: String::new(),
+        }
+    }
+
+    //No retries inside the client, so each scrobble is a single request
+    fn client() -> ServerClient {
+        ServerClient::new(ServerClientConfig {
+            max_retries: 0,
+            ..Default::default()
+        })
+    }
+
+    async fn mock_scrobble(server: &mut ServerGuard, song_id: &str, status: usize, body: &str) {
+        server
+            .mock("GET", "/rest/scrobble.view")
+            .match_query(Matcher::UrlEncoded("id".into(), song_id.into()))
+            .with_status(status)
+            .with_body(body)
+            .create_async()
+            .await;
+    }
+
+    fn subsonic_error(code: u32) -> String {
+        format!(
+            r#"{{"subsonic-response":{{"status":"failed","version":"1.16.1","error":{{"code":{},"message":"error {}"}}}}}}"#,
+            code, code
+        )
+    }
+
+    async fn queued_song_ids(pool: &Pool<Sqlite>) -> Vec<String> {
+        get_queued_scrobbles(pool, &LIBRARY_ID.to_string())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|queued| queued.song_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_refused_credentials_queue_the_play() {
+        let mut server = Server::new_async().await;
+        mock_scrobble(&mut server, "restarting", 503, "").await;
+        mock_scrobble(&mut server, "limited", 429, "").await;
+        mock_scrobble(&mut server, "refused", 200, &subsonic_error(40)).await;
+
+        let library = library(server.url());
+        let pool = test_pool(&library).await;
+        for (played_at, song_id) in ["restarting", "limited", "refused"].iter().enumerate() {
+            let song_id = namespace_id(LIBRARY_ID, song_id);
+            let status = submit_scrobble(&client(), &pool, &library, &song_id, played_at as i64)
+                .await
+                .unwrap();
+            assert_eq!(status, ScrobbleStatus::Queued);
+        }
+        assert_eq!(
+            queued_song_ids(&pool).await,
+            vec!["sub:restarting", "sub:limited", "sub:refused"]
+        );
+    }
+
+    #[tokio::test]
+    async fn flush_drops_only_rejected_scrobbles() {
+        let mut server = Server::new_async().await;
+        mock_scrobble(&mut server, "gone", 200, &subsonic_error(70)).await;
+        mock_scrobble(&mut server, "bad", 200, &subsonic_error(10)).await;
+        mock_scrobble(&mut server, "restarting", 502, "").await;
+
+        let library = library(server.url());
+        let pool = test_pool(&library).await;
+        for (played_at, song_id) in ["gone", "bad", "restarting", "later"].iter().enumerate() {
+            let song_id = namespace_id(LIBRARY_ID, song_id);
+            enqueue_scrobble(&pool, &library.id, &song_id, played_at as i64)
+                .await
+                .unwrap();
+        }
+
+        let submitted = flush_scrobble_queue(&client(), &pool, &library)
+            .await
+            .unwrap();
+        assert_eq!(submitted, 0);
+        //The 502 stops the flush, so the play after it isn't sent out of order
+        assert_eq!(
+            queued_song_ids(&pool).await,
+            vec!["sub:restarting", "sub:later"]
+        );
+    }
+}
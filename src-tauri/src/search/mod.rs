@@ -0,0 +1,139 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    client::ServerClient,
+    db::{get_last_scanned, search_index},
+    errors::ApiError,
+    formatter::namespace_id,
+    models::{Library, LibraryKind, SearchPage, SearchResults, SearchSource},
+    subsonic::search3,
+};
+
+//The local index is trusted for a day after the last sync, after which the server is asked instead
+const SEARCH_INDEX_MAX_AGE_MS: u128 = 24 * 60 * 60 * 1000;
+
+/* Search a library, answering from the local index unless it is stale. A stale index is still
+*  used when the server can't be reached, and is all there is for libraries without search3. */
+pub async fn search_library(
+    client: &ServerClient,
+    pool: &Pool<Sqlite>,
+    library: &Library,
+    query: &str,
+    page: &SearchPage,
+) -> Result<SearchResults, anyhow::Error> {
+    if library.kind == LibraryKind::Subsonic && is_index_stale(pool, &library.id).await? {
+        match search_server(client, library, query, page).await {
+            Ok(results) => return Ok(results),
+            Err(ApiError::Transport(e)) => {
+                println!("Error: {}. Searching local index", e)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    search_local(pool, &library.id, query, page).await
+}
+
+pub async fn search_local(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+    query: &str,
+    page: &SearchPage,
+) -> Result<SearchResults, anyhow::Error> {
+    let mut results = SearchResults {
+        source: SearchSource::Local,
+        artists: vec![],
+        albums: vec![],
+        songs: vec![],
+    };
+    let fts_query = match to_fts_query(query) {
+        Some(fts_query) => fts_query,
+        None => return Ok(results),
+    };
+    results.artists = search_index(
+        pool,
+        library_id,
+        "artist",
+        &fts_query,
+        page.artist_count,
+        page.artist_offset,
+    )
+    .await?;
+    results.albums = search_index(
+        pool,
+        library_id,
+        "album",
+        &fts_query,
+        page.album_count,
+        page.album_offset,
+    )
+    .await?;
+    results.songs = search_index(
+        pool,
+        library_id,
+        "song",
+        &fts_query,
+        page.song_count,
+        page.song_offset,
+    )
+    .await?;
+    Ok(results)
+}
+
+pub async fn search_server(
+    client: &ServerClient,
+    library: &Library,
+    query: &str,
+    page: &SearchPage,
+) -> Result<SearchResults, ApiError> {
+    let search_result = search3(client, library, query, page)
+        .await?
+        .data
+        .search_result_3;
+    Ok(SearchResults {
+        source: SearchSource::Server,
+        artists: search_result
+            .artist
+            .iter()
+            .map(|artist| namespace_id(&library.id, &artist.id))
+            .collect(),
+        albums: search_result
+            .album
+            .iter()
+            .map(|album| namespace_id(&library.id, &album.id))
+            .collect(),
+        songs: search_result
+            .song
+            .iter()
+            .map(|song| namespace_id(&library.id, &song.id))
+            .collect(),
+    })
+}
+
+async fn is_index_stale(pool: &Pool<Sqlite>, library_id: &String) -> Result<bool, anyhow::Error> {
+    //Libraries that were never synced have no index at all
+    let last_scanned = match get_last_scanned(pool, library_id).await? {
+        Some(last_scanned) => last_scanned,
+        None => return Ok(true),
+    };
+    let last_scanned = match last_scanned.parse::<u128>() {
+        Ok(last_scanned) => last_scanned,
+        Err(_) => return Ok(true),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    Ok(now.saturating_sub(last_scanned) > SEARCH_INDEX_MAX_AGE_MS)
+}
+
+/* Turn user input into an FTS5 query: every word must match, as a prefix. Words are quoted so
+*  FTS5 syntax characters in them are matched literally. */
+fn to_fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(terms.join(" "))
+}
@@ -0,0 +1,128 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use tauri::{AppHandle, Manager};
+
+use crate::{
+    ampache::AmpacheSource,
+    client::ServerClient,
+    formatter::get_library_hash,
+    jellyfin::JellyfinSource,
+    local::LocalSource,
+    models::{Album, Artist, Library, LibraryKind, Playlist, Song, TranscodeSettings},
+    music::SyncProgressReporter,
+    subsonic::SubsonicSource,
+};
+
+/* An album as listed by a source, with when it last changed (ms since the epoch) if the source
+*  reports it. Albums without a timestamp are always treated as changed. */
+#[derive(Debug, Clone)]
+pub struct SourceAlbum {
+    pub album: Album,
+    pub changed: Option<i64>,
+}
+
+/* Where a library's music comes from (a Subsonic server, local folders, ...). Everything is
+*  listed as the models types, with IDs namespaced by library, so syncing and the DB don't depend
+*  on the source. */
+#[async_trait]
+pub trait MusicSource: Send + Sync {
+    fn library(&self) -> &Library;
+
+    /* Check that the source can be reached with the library's credentials */
+    async fn ping(&self) -> Result<(), anyhow::Error>;
+
+    /* Whether anything changed since the given time (ms). None when the source can't tell, in
+     *  which case every sync crawls the whole library. */
+    async fn changed_since(&self, _since: i64) -> Result<Option<bool>, anyhow::Error> {
+        Ok(None)
+    }
+
+    async fn list_artists(
+        &self,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Artist>, anyhow::Error>;
+
+    /* Every album of the given artists, for a full sync */
+    async fn list_albums(
+        &self,
+        artists: &[Artist],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<SourceAlbum>, anyhow::Error>;
+
+    /* Every album, for an incremental sync (which then only lists songs of changed albums).
+     *  Sources with a cheaper way to list albums than going through each artist override this. */
+    async fn list_all_albums(
+        &self,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<SourceAlbum>, anyhow::Error> {
+        let artists = self.list_artists(progress).await?;
+        self.list_albums(&artists, progress).await
+    }
+
+    async fn list_songs(
+        &self,
+        albums: &[Album],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Song>, anyhow::Error>;
+
+    /* Save the art of the given albums under cover_art_dir (cover_art/<library_id>/...). Returns
+     *  the saved file of each cover art key, relative to cover_art_dir. */
+    async fn fetch_art(
+        &self,
+        albums: &[Album],
+        cover_art_dir: &Path,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<HashMap<String, String>, anyhow::Error>;
+
+    async fn playlists(&self) -> Result<Vec<Playlist>, anyhow::Error>;
+
+    /* A playlist and its song IDs, in order */
+    async fn playlist(&self, playlist_id: &str) -> Result<(Playlist, Vec<String>), anyhow::Error>;
+
+    /* Where a song (by namespaced ID) is streamed from, with the given transcode settings. Async
+     *  so sources whose URLs carry a session can renew it first. */
+    async fn stream_url(
+        &self,
+        song_id: &str,
+        transcode: &TranscodeSettings,
+    ) -> Result<String, anyhow::Error>;
+
+    /* Read back what write_index stored, before anything is listed */
+    async fn read_index(&self, _pool: &Pool<Sqlite>) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /* Anything else the source keeps in the DB, written in the same transaction as the sync */
+    async fn write_index(&self, _conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/* The source of a library, with server credentials read from the keyring */
+pub fn open_source(
+    app_handle: &AppHandle,
+    mut library: Library,
+) -> Result<Arc<dyn MusicSource>, anyhow::Error> {
+    match library.kind {
+        LibraryKind::Local => Ok(Arc::new(LocalSource::new(library))),
+        LibraryKind::Subsonic => {
+            library.hashed_password = get_library_hash(&library)?;
+            let client = app_handle.state::<ServerClient>().inner().clone();
+            Ok(Arc::new(SubsonicSource::new(library, client)))
+        }
+        LibraryKind::Jellyfin => {
+            //The access token is kept in place of a password hash
+            library.hashed_password = get_library_hash(&library)?;
+            let client = app_handle.state::<ServerClient>().inner().clone();
+            Ok(Arc::new(JellyfinSource::new(library, client)))
+        }
+        LibraryKind::Ampache => {
+            //sha256 of the password, which every handshake is made from
+            library.hashed_password = get_library_hash(&library)?;
+            let client = app_handle.state::<ServerClient>().inner().clone();
+            Ok(Arc::new(AmpacheSource::new(library, client)))
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+use percent_encoding::percent_decode_str;
+use sqlx::{Pool, Sqlite};
+use tauri::{
+    http::{header, Request, Response, StatusCode},
+    AppHandle, Manager, UriSchemeContext, UriSchemeResponder, Wry,
+};
+use tokio::sync::OnceCell;
+
+mod remote;
+
+pub use remote::RemoteStream;
+
+use crate::{
+    cache::{cache_audio, get_cache_settings},
+    client::{ByteRangeResponse, ServerClient},
+    db::{
+        db_connect, get_cache_entry, get_download, get_library, get_local_file, get_setting,
+        get_transcode_settings, touch_cache_entries,
+    },
+    downloads::now_millis,
+    models::{CacheKind, NetworkProfile, TranscodeSettings},
+    source::{open_source, MusicSource},
+};
+
+/* Songs are played from polyphonic://stream/<library_id>/<song_id> (http://polyphonic.localhost/...
+*  on Windows and Android), so credentials never reach the webview. Downloaded and cached songs
+*  are served from disk. */
+pub const STREAM_PROTOCOL: &str = "polyphonic";
+
+pub const NETWORK_PROFILE_KEY: &str = "network_profile";
+
+//Largest range sent back per request. The webview asks for the next one as playback (or a seek) needs it.
+const MAX_CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+
+#[derive(Default)]
+pub struct StreamState {
+    //Opened on the first request and reused, as every range is a separate request
+    pool: OnceCell<Pool<Sqlite>>,
+    //Library sources with their credentials, so range requests don't each go through the keyring
+    sources: Mutex<HashMap<String, Arc<dyn MusicSource>>>,
+    //Cache keys of songs already fetched whole for the audio cache. Each song is fetched once per
+    //run, so one that can't be cached (a failure, or too big for the quota) isn't downloaded again
+    //for every range of it.
+    caching: Mutex<HashSet<String>>,
+}
+
+pub fn handle_stream_request(
+    ctx: UriSchemeContext<'_, Wry>,
+    request: Request<Vec<u8>>,
+    responder: UriSchemeResponder,
+) {
+    let app_handle = ctx.app_handle().clone();
+    tauri::async_runtime::spawn(async move {
+        let response = stream_response(&app_handle, &request).await;
+        responder.respond(response);
+    });
+}
+
+async fn stream_response(app_handle: &AppHandle, request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let (library_id, song_id) = match parse_stream_path(request.uri().path()) {
+        Some(ids) => ids,
+        None => {
+            return error_response(
+                StatusCode::NOT_FOUND,
+                "Expected stream/<library_id>/<song_id>",
+            )
+        }
+    };
+    let (start, end) = match request.headers().get(header::RANGE) {
+        Some(value) => match value.to_str().ok().and_then(parse_range) {
+            Some(range) => range,
+            None => return error_response(StatusCode::RANGE_NOT_SATISFIABLE, "Unsupported range"),
+        },
+        None => (0, None),
+    };
+    //Every response is a single chunk, requests without a range included, so no song is ever read
+    //whole to answer one
+    let last = start.saturating_add(MAX_CHUNK_SIZE - 1);
+    let range = (start, end.map_or(last, |end| end.min(last)));
+
+    let state = app_handle.state::<StreamState>();
+    let pool = match state.pool.get_or_try_init(|| db_connect(app_handle)).await {
+        Ok(pool) => pool,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    if let Some((file, content_type)) = open_local_file(pool, &song_id).await {
+        return file_response(file, &content_type, range);
+    }
+    if let Some((file, content_type)) = open_download(pool, &song_id).await {
+        return file_response(file, &content_type, range);
+    }
+
+    let transcode = match get_active_transcode_settings(pool, &library_id).await {
+        Ok(transcode) => transcode,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    let cache_key = transcode.cache_key(&song_id);
+    if let Some((file, content_type)) = open_cached_audio(pool, &cache_key).await {
+        //Counted as an access once per play, not for every range
+        if start == 0 {
+            touch_cached_audio(pool, &cache_key).await;
+        }
+        let content_type = content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        return file_response(file, content_type, range);
+    }
+
+    let source = match get_stream_source(app_handle, &library_id).await {
+        Ok(Some(source)) => source,
+        Ok(None) => return error_response(StatusCode::NOT_FOUND, "Unknown library"),
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+
+    let upstream =
+        match stream_range(app_handle, source.as_ref(), &song_id, &transcode, range).await {
+            Ok(upstream) => upstream,
+            Err(e) => return error_response(StatusCode::BAD_GATEWAY, &e.to_string()),
+        };
+    let total = upstream
+        .total
+        .map_or(String::from("*"), |total| total.to_string());
+    let mut response = Response::builder().header(header::ACCEPT_RANGES, "bytes");
+    if upstream.body.is_empty() {
+        return response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+            .body(vec![])
+            .unwrap();
+    }
+    let end = start + upstream.body.len() as u64 - 1;
+    response = response
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, total),
+        )
+        .header(header::CONTENT_LENGTH, upstream.body.len());
+    if let Some(content_type) = &upstream.content_type {
+        response = response.header(header::CONTENT_TYPE, content_type);
+    }
+    spawn_audio_caching(app_handle, source, song_id, transcode);
+    response.body(upstream.body).unwrap()
+}
+
+/* Fetch the whole song in the background, so later plays and seeks are served from the audio cache */
+fn spawn_audio_caching(
+    app_handle: &AppHandle,
+    source: Arc<dyn MusicSource>,
+    song_id: String,
+    transcode: TranscodeSettings,
+) {
+    let state = app_handle.state::<StreamState>();
+    let key = transcode.cache_key(&song_id);
+    if !state.caching.lock().unwrap().insert(key.clone()) {
+        return;
+    }
+    let app_handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = cache_whole_song(&app_handle, source.as_ref(), &song_id, &transcode).await {
+            println!("Error: Failed to cache {}: {}", key, e);
+        }
+    });
+}
+
+async fn cache_whole_song(
+    app_handle: &AppHandle,
+    source: &dyn MusicSource,
+    song_id: &str,
+    transcode: &TranscodeSettings,
+) -> Result<(), anyhow::Error> {
+    let state = app_handle.state::<StreamState>();
+    let pool = state
+        .pool
+        .get_or_try_init(|| db_connect(app_handle))
+        .await?;
+    if get_cache_settings(pool).await?.audio_quota_bytes <= 0 {
+        return Ok(());
+    }
+    let song = stream_whole(app_handle, source, song_id, transcode).await?;
+    let key = transcode.cache_key(song_id);
+    cache_audio(
+        pool,
+        app_handle,
+        &source.library().id,
+        &key,
+        song.content_type,
+        &song.body,
+    )
+    .await
+}
+
+/* A song for the playback engine */
+pub struct SongFile {
+    pub content_type: Option<String>,
+    pub data: SongData,
+}
+
+pub enum SongData {
+    File(File),
+    //Read from the server as it plays
+    Remote(RemoteStream),
+}
+
+/* Open a song from wherever it is available: its local file, its download, the audio cache, or
+*  the server (with the library's transcode settings, fetching it whole for the audio cache in the
+*  background). */
+pub async fn fetch_song(
+    app_handle: &AppHandle,
+    library_id: &String,
+    song_id: &String,
+) -> Result<SongFile, anyhow::Error> {
+    let state = app_handle.state::<StreamState>();
+    let pool = state
+        .pool
+        .get_or_try_init(|| db_connect(app_handle))
+        .await?;
+    if let Some((file, content_type)) = open_local_file(pool, song_id).await {
+        return Ok(SongFile {
+            content_type: Some(content_type),
+            data: SongData::File(file),
+        });
+    }
+    if let Some((file, content_type)) = open_download(pool, song_id).await {
+        return Ok(SongFile {
+            content_type: Some(content_type),
+            data: SongData::File(file),
+        });
+    }
+
+    let transcode = get_active_transcode_settings(pool, library_id).await?;
+    let cache_key = transcode.cache_key(song_id);
+    if let Some((file, content_type)) = open_cached_audio(pool, &cache_key).await {
+        touch_cached_audio(pool, &cache_key).await;
+        return Ok(SongFile {
+            content_type,
+            data: SongData::File(file),
+        });
+    }
+
+    let source = match get_stream_source(app_handle, library_id).await? {
+        Some(source) => source,
+        None => return Err(anyhow::anyhow!("Unknown library {}", library_id)),
+    };
+    let url = source.stream_url(song_id, &transcode).await?;
+    let client = app_handle.state::<ServerClient>().inner().clone();
+    let (stream, content_type) = RemoteStream::open(client, url).await?;
+    spawn_audio_caching(app_handle, source, song_id.clone(), transcode);
+    Ok(SongFile {
+        content_type,
+        data: SongData::Remote(stream),
+    })
+}
+
+/* The file of a song in a local library and its content type */
+async fn open_local_file(pool: &Pool<Sqlite>, song_id: &String) -> Option<(File, String)> {
+    match get_local_file(pool, song_id).await {
+        Ok(Some(local_file)) => match File::open(&local_file.path) {
+            Ok(file) => return Some((file, local_file.content_type)),
+            Err(e) => println!("Error: Failed to open {}: {}", local_file.path, e),
+        },
+        Ok(None) => {}
+        Err(e) => println!("Error: {}", e),
+    }
+    None
+}
+
+/* The downloaded file of a song and its content type. A file that changed size since it was
+*  downloaded is treated as missing. */
+async fn open_download(pool: &Pool<Sqlite>, song_id: &String) -> Option<(File, String)> {
+    match get_download(pool, song_id).await {
+        Ok(Some(download)) => match File::open(&download.path) {
+            Ok(file)
+                if file
+                    .metadata()
+                    .is_ok_and(|m| m.len() == download.size as u64) =>
+            {
+                return Some((file, download.content_type));
+            }
+            _ => println!(
+                "Download for {} is missing or incomplete, streaming instead",
+                song_id
+            ),
+        },
+        Ok(None) => {}
+        Err(e) => println!("Error: {}", e),
+    }
+    None
+}
+
+async fn open_cached_audio(
+    pool: &Pool<Sqlite>,
+    cache_key: &String,
+) -> Option<(File, Option<String>)> {
+    match get_cache_entry(pool, CacheKind::Audio, cache_key).await {
+        Ok(Some(entry)) => match File::open(&entry.path) {
+            Ok(file) if file.metadata().is_ok_and(|m| m.len() == entry.size as u64) => {
+                return Some((file, entry.content_type));
+            }
+            _ => println!(
+                "Cached audio for {} is missing, streaming instead",
+                cache_key
+            ),
+        },
+        Ok(None) => {}
+        Err(e) => println!("Error: {}", e),
+    }
+    None
+}
+
+async fn touch_cached_audio(pool: &Pool<Sqlite>, cache_key: &str) {
+    let keys = [cache_key.to_string()];
+    if let Err(e) = touch_cache_entries(pool, CacheKind::Audio, &keys, now_millis()).await {
+        println!("Error: {}", e);
+    }
+}
+
+/* The connection pool shared by stream requests, for other users on the playback path */
+pub async fn get_stream_pool(app_handle: &AppHandle) -> Result<&Pool<Sqlite>, anyhow::Error> {
+    let state = app_handle.state::<StreamState>().inner();
+    state.pool.get_or_try_init(|| db_connect(app_handle)).await
+}
+
+/* Transcode settings of a library for the network profile in use */
+pub async fn get_active_transcode_settings(
+    pool: &Pool<Sqlite>,
+    library_id: &String,
+) -> Result<TranscodeSettings, anyhow::Error> {
+    let profile = get_setting::<NetworkProfile>(pool, NETWORK_PROFILE_KEY)
+        .await?
+        .unwrap_or_default();
+    Ok(get_transcode_settings(pool, library_id, profile)
+        .await?
+        .unwrap_or_default())
+}
+
+async fn get_stream_source(
+    app_handle: &AppHandle,
+    library_id: &String,
+) -> Result<Option<Arc<dyn MusicSource>>, anyhow::Error> {
+    let state = app_handle.state::<StreamState>();
+    if let Some(source) = state.sources.lock().unwrap().get(library_id) {
+        return Ok(Some(source.clone()));
+    }
+    let pool = state
+        .pool
+        .get_or_try_init(|| db_connect(app_handle))
+        .await?;
+    let library = match get_library(pool, library_id).await? {
+        Some(library) => library,
+        None => return Ok(None),
+    };
+    let source = open_source(app_handle, library)?;
+    state
+        .sources
+        .lock()
+        .unwrap()
+        .insert(library_id.clone(), source.clone());
+    Ok(Some(source))
+}
+
+/* A range of a song from its source, with the given transcode settings */
+async fn stream_range(
+    app_handle: &AppHandle,
+    source: &dyn MusicSource,
+    song_id: &str,
+    transcode: &TranscodeSettings,
+    (start, end): (u64, u64),
+) -> Result<ByteRangeResponse, anyhow::Error> {
+    let url = source.stream_url(song_id, transcode).await?;
+    let client = app_handle.state::<ServerClient>();
+    Ok(client.get_byte_range(&url, start, end).await?)
+}
+
+/* A whole song from its source, with the given transcode settings */
+async fn stream_whole(
+    app_handle: &AppHandle,
+    source: &dyn MusicSource,
+    song_id: &str,
+    transcode: &TranscodeSettings,
+) -> Result<WholeSong, anyhow::Error> {
+    let url = source.stream_url(song_id, transcode).await?;
+    let client = app_handle.state::<ServerClient>();
+    let (content_type, body) = client.get_bytes_with_type(&url).await?;
+    Ok(WholeSong { content_type, body })
+}
+
+struct WholeSong {
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+fn file_response(mut file: File, content_type: &str, range: (u64, u64)) -> Response<Vec<u8>> {
+    let total = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    ranged_response(Some(content_type), total, range, |start, end| {
+        let mut body = vec![0; (end - start + 1) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut body)?;
+        Ok(body)
+    })
+}
+
+/* Answer a request for bytes of a payload of known size, reading bytes start..=end with `read` */
+fn ranged_response(
+    content_type: Option<&str>,
+    total: u64,
+    (start, end): (u64, u64),
+    read: impl FnOnce(u64, u64) -> std::io::Result<Vec<u8>>,
+) -> Response<Vec<u8>> {
+    let mut response = Response::builder().header(header::ACCEPT_RANGES, "bytes");
+    if let Some(content_type) = content_type {
+        response = response.header(header::CONTENT_TYPE, content_type);
+    }
+    if start >= total {
+        return response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+            .body(vec![])
+            .unwrap();
+    }
+    let end = end.min(total - 1);
+    let body = match read(start, end) {
+        Ok(body) => body,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    response
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, total),
+        )
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+/* The details are only logged. The webview just gets the status, so nothing about the server
+*  (or its credentials) ends up there. */
+fn error_response(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    println!("Stream error ({}): {}", status, message);
+    let body = status.canonical_reason().unwrap_or("Stream error");
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(body.as_bytes().to_vec())
+        .unwrap()
+}
+
+/* "/stream/<library_id>/<song_id>" (possibly percent-encoded as a whole) */
+fn parse_stream_path(path: &str) -> Option<(String, String)> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let mut parts = path.trim_start_matches('/').splitn(3, '/');
+    if parts.next()? != "stream" {
+        return None;
+    }
+    let library_id = parts.next().filter(|id| !id.is_empty())?;
+    let song_id = parts.next().filter(|id| !id.is_empty())?;
+    Some((library_id.to_string(), song_id.to_string()))
+}
+
+/* Single "bytes=start-" or "bytes=start-end" range. Suffix ranges ("bytes=-500") and multiple
+*  ranges aren't used by media elements, so they aren't supported. */
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(end.parse::<u64>().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    Some((start, end))
+}
@@ -0,0 +1,246 @@
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use symphonia::core::io::MediaSource;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::{
+    client::{ByteStream, ServerClient},
+    errors::ApiError,
+};
+
+//Chunks fetched ahead of the decoder, so playback rides out a slow network without holding the
+//whole song in memory
+const READ_AHEAD_CHUNKS: usize = 32;
+
+//Seeks forward by less than this read through what's being fetched rather than reconnecting
+const MAX_SKIP_AHEAD: u64 = 256 * 1024;
+
+type Chunks = Receiver<Result<Vec<u8>, ApiError>>;
+
+/* A song read from its server while it plays, for the playback engine. Reads block the engine
+*  thread until the bytes arrive, which are fetched on the async runtime. A seek elsewhere drops the
+*  connection, and the next read opens one at the new position with a Range request. */
+pub struct RemoteStream {
+    client: ServerClient,
+    url: String,
+    //Size of the whole song, if the server reported it (transcoded streams often don't). Without
+    //it the song can only be played through, not seeked.
+    total: Option<u64>,
+    position: u64,
+    //Chunk being read, and how far into it position is
+    chunk: Vec<u8>,
+    offset: usize,
+    //Chunks after the one being read. None after a seek, until the next read.
+    chunks: Option<Chunks>,
+}
+
+impl RemoteStream {
+    /* Connect and start fetching, so failures (e.g. refused credentials) surface while the song
+     *  loads. Returns the stream and its content type. */
+    pub async fn open(
+        client: ServerClient,
+        url: String,
+    ) -> Result<(RemoteStream, Option<String>), ApiError> {
+        let stream = client.open_stream(&url, 0).await?;
+        let content_type = stream.content_type.clone();
+        let remote = RemoteStream {
+            total: stream.total,
+            chunks: Some(spawn_fetch(client.clone(), url.clone(), 0, Some(stream))),
+            client,
+            url,
+            position: 0,
+            chunk: vec![],
+            offset: 0,
+        };
+        Ok((remote, content_type))
+    }
+
+    /* Wait for the next chunk, connecting first if a seek dropped the connection. False at the end
+     *  of the song. */
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        if self.total.is_some_and(|total| self.position >= total) {
+            return Ok(false);
+        }
+        let (client, url, position) = (&self.client, &self.url, self.position);
+        let chunks = self
+            .chunks
+            .get_or_insert_with(|| spawn_fetch(client.clone(), url.clone(), position, None));
+        match chunks.blocking_recv() {
+            Some(Ok(chunk)) => {
+                self.chunk = chunk;
+                self.offset = 0;
+                Ok(true)
+            }
+            Some(Err(e)) => {
+                //Reconnected on the next read
+                self.chunks = None;
+                Err(io::Error::other(e.to_string()))
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/* Feed a song's bytes from position on into a bounded channel, connecting first unless a stream
+*  is already open there. Stops once the channel's receiver is dropped. */
+fn spawn_fetch(
+    client: ServerClient,
+    url: String,
+    position: u64,
+    stream: Option<ByteStream>,
+) -> Chunks {
+    let (sender, receiver) = channel(READ_AHEAD_CHUNKS);
+    tauri::async_runtime::spawn(async move {
+        let stream = match stream {
+            Some(stream) => stream,
+            None => match client.open_stream(&url, position).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            },
+        };
+        fetch(stream, sender).await;
+    });
+    receiver
+}
+
+async fn fetch(mut stream: ByteStream, sender: Sender<Result<Vec<u8>, ApiError>>) {
+    loop {
+        let chunk = match stream.chunk().await {
+            Ok(Some(chunk)) => Ok(chunk),
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if sender.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+impl Read for RemoteStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.chunk.len() && !self.next_chunk()? {
+            return Ok(0);
+        }
+        let read = buf.len().min(self.chunk.len() - self.offset);
+        buf[..read].copy_from_slice(&self.chunk[self.offset..self.offset + read]);
+        self.offset += read;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for RemoteStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => match self.total {
+                Some(total) => total.checked_add_signed(offset),
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::Unsupported,
+                        "The stream's length is unknown",
+                    ))
+                }
+            },
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Seek before the start"))?;
+
+        //Within the chunk being read
+        let chunk_start = self.position - self.offset as u64;
+        if (chunk_start..=chunk_start + self.chunk.len() as u64).contains(&target) {
+            self.offset = (target - chunk_start) as usize;
+            self.position = target;
+            return Ok(target);
+        }
+        //A little way ahead on the open connection
+        if self.chunks.is_some()
+            && target > self.position
+            && target - self.position <= MAX_SKIP_AHEAD
+        {
+            let mut skipped = vec![0; (target - self.position) as usize];
+            self.read_exact(&mut skipped)?;
+            return Ok(target);
+        }
+        self.position = target;
+        self.chunk.clear();
+        self.offset = 0;
+        self.chunks = None;
+        Ok(target)
+    }
+}
+
+impl MediaSource for RemoteStream {
+    fn is_seekable(&self) -> bool {
+        self.total.is_some()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{Matcher, Server};
+
+    use super::*;
+    use crate::client::ServerClientConfig;
+
+    const SONG_SIZE: usize = 1024 * 1024;
+
+    fn song() -> Vec<u8> {
+        (0..SONG_SIZE).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_far_seek_reconnects_with_a_range() {
+        let song = song();
+        let start = SONG_SIZE - 1000;
+        let mut server = Server::new_async().await;
+        server
+            .mock("GET", "/stream")
+            .match_header("range", Matcher::Missing)
+            .with_header("content-type", "audio/flac")
+            .with_body(&song)
+            .create_async()
+            .await;
+        let ranged = server
+            .mock("GET", "/stream")
+            .match_header("range", format!("bytes={}-", start).as_str())
+            .with_status(206)
+            .with_header("content-type", "audio/flac")
+            .with_header(
+                "content-range",
+                &format!("bytes {}-{}/{}", start, SONG_SIZE - 1, SONG_SIZE),
+            )
+            .with_body(&song[start..])
+            .create_async()
+            .await;
+
+        let client = ServerClient::new(ServerClientConfig::default());
+        let url = format!("{}/stream", server.url());
+        let (mut stream, content_type) = RemoteStream::open(client, url).await.unwrap();
+        assert_eq!(content_type.as_deref(), Some("audio/flac"));
+        assert_eq!(stream.byte_len(), Some(SONG_SIZE as u64));
+
+        //Reads block until the bytes arrive, so not on the runtime's workers
+        let (head, tail) = tokio::task::spawn_blocking(move || {
+            let mut head = vec![0; 100];
+            stream.read_exact(&mut head).unwrap();
+            stream.seek(SeekFrom::Start(start as u64)).unwrap();
+            let mut tail = vec![];
+            stream.read_to_end(&mut tail).unwrap();
+            (head, tail)
+        })
+        .await
+        .unwrap();
+        assert_eq!(head, song[..100]);
+        assert_eq!(tail, song[start..]);
+        ranged.assert_async().await;
+    }
+}
@@ -1,242 +1,372 @@
+use glob::glob;
 use std::fs::File;
 use std::io::{Cursor, Write};
-use glob::glob;
 
 use image::{ImageFormat, ImageReader};
-use reqwest::Client;
 
-use crate::formatter::create_connection_string;
-use crate::models::Library;
+use crate::client::ServerClient;
+use crate::errors::ApiError;
+use crate::formatter::{create_connection_string, encode_query_value};
+use crate::models::{Library, RatingTarget, SearchPage};
 use crate::responses::{
-    SubsonicBaseResponse, SubsonicGetAlbumList2Response, SubsonicGetAlbumsResponse,
-    SubsonicGetArtistsResponse, SubsonicGetPlaylistResponse, SubsonicGetPlaylistsResponse,
-    SubsonicGetSongsResponse, SubsonicPlaylist, SubsonicResponse,
+    SubsonicCreatePlaylistResponse, SubsonicGetAlbumList2Response, SubsonicGetAlbumsResponse,
+    SubsonicGetArtistsResponse, SubsonicGetIndexesResponse, SubsonicGetPlayQueueResponse,
+    SubsonicGetPlaylistResponse, SubsonicGetPlaylistsResponse, SubsonicGetSongsResponse,
+    SubsonicGetStarred2Response, SubsonicPlaylist, SubsonicResponse, SubsonicSearch3Response,
+    SubsonicStatus,
 };
 
+mod source;
+pub use source::SubsonicSource;
+
 /* Ping
 * https://opensubsonic.netlify.app/docs/endpoints/ping */
-pub async fn ping_server(library: &Library) -> Result<(), anyhow::Error> {
+pub async fn ping_server(client: &ServerClient, library: &Library) -> Result<(), ApiError> {
     let url = create_connection_string(library, "ping");
-    match reqwest::get(&url).await {
-        Ok(res) => match res.json::<SubsonicResponse<SubsonicBaseResponse>>().await {
-            Ok(sub_response) => {
-                if sub_response.data.status == "ok" {
-                    Ok(())
-                } else {
-                    Err(anyhow::anyhow!(
-                        "Failed to ping server: {}",
-                        sub_response.data.error.unwrap().message
-                    ))
-                }
-            }
-            Err(e) => Err(anyhow::anyhow!("Failed to parse response: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Failed to ping server: {}", e)),
-    }
+    client.get_response::<SubsonicStatus>(&url).await?;
+    Ok(())
 }
 
 /* getArtists
 *  https://opensubsonic.netlify.app/docs/endpoints/getartists */
 
 pub async fn get_artists(
+    client: &ServerClient,
     library: &Library,
-) -> Result<SubsonicResponse<SubsonicGetArtistsResponse>, anyhow::Error> {
+) -> Result<SubsonicResponse<SubsonicGetArtistsResponse>, ApiError> {
     let url = create_connection_string(library, "getArtists");
-    match reqwest::get(&url).await {
-        Ok(res) => match res
-            .json::<SubsonicResponse<SubsonicGetArtistsResponse>>()
-            .await
-        {
-            Ok(artist_response) => Ok(artist_response),
-            Err(e) => Err(anyhow::anyhow!("Error: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Error: {}", e)),
-    }
+    client
+        .get_response::<SubsonicGetArtistsResponse>(&url)
+        .await
+}
+
+/* getIndexes
+*  https://opensubsonic.netlify.app/docs/endpoints/getindexes */
+pub async fn get_indexes_modified_since(
+    client: &ServerClient,
+    library: &Library,
+    if_modified_since: &str,
+) -> Result<SubsonicResponse<SubsonicGetIndexesResponse>, ApiError> {
+    let mut url = create_connection_string(library, "getIndexes");
+    url.push_str(&format!("&ifModifiedSince={}", if_modified_since));
+    client
+        .get_response::<SubsonicGetIndexesResponse>(&url)
+        .await
 }
 
 /* getArtist
 *  https://opensubsonic.netlify.app/docs/endpoints/getartist */
 pub async fn get_albums_for_artist(
     url: String,
-    client: Client,
-) -> Result<SubsonicResponse<SubsonicGetAlbumsResponse>, anyhow::Error> {
-    match client.get(&url).send().await {
-        Ok(res) => match res
-            .json::<SubsonicResponse<SubsonicGetAlbumsResponse>>()
-            .await
-        {
-            Ok(album_response) => Ok(album_response),
-            Err(e) => Err(anyhow::anyhow!("Album Error: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Album Error: {}", e)),
-    }
+    client: &ServerClient,
+) -> Result<SubsonicResponse<SubsonicGetAlbumsResponse>, ApiError> {
+    client.get_response::<SubsonicGetAlbumsResponse>(&url).await
 }
 
 /* getAlbum
 *  https://opensubsonic.netlify.app/docs/endpoints/getalbum */
 pub async fn get_songs_for_album(
     url: String,
-    client: Client,
-) -> Result<SubsonicResponse<SubsonicGetSongsResponse>, anyhow::Error> {
-    match client.get(&url).send().await {
-        Ok(res) => match res
-            .json::<SubsonicResponse<SubsonicGetSongsResponse>>()
-            .await
-        {
-            Ok(album_response) => Ok(album_response),
-            Err(e) => Err(anyhow::anyhow!("Song Error: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Song Error: {}", e)),
-    }
+    client: &ServerClient,
+) -> Result<SubsonicResponse<SubsonicGetSongsResponse>, ApiError> {
+    client.get_response::<SubsonicGetSongsResponse>(&url).await
 }
 
-/* getCoverArt
+/* getCoverArt, saved as <path>/<cover_id>.<ext> (path being the cover_art folder)
 *  https://opensubsonic.netlify.app/docs/endpoints/getcoverart */
 pub async fn get_album_art(
     url: String,
     cover_id: String,
-    client: Client,
+    client: &ServerClient,
     path: &String,
 ) -> Result<String, anyhow::Error> {
-    for entry in glob(&format!("{}/cover_art/{}*", path, cover_id))? {
+    if let Some(entry) = glob(&format!("{}/{}.*", path, cover_id))?.next() {
         match entry {
-            Ok(_) => {
-                return Ok("".to_string())
+            Ok(existing) => {
+                //Already cached, so report the existing file rather than downloading again
+                let extension = existing.extension().unwrap_or_default().to_string_lossy();
+                return Ok(format!("{}.{}", cover_id, extension));
             }
-            Err(e) => return Err(anyhow::anyhow!("Art Error: {}", e))
+            Err(e) => return Err(anyhow::anyhow!("Art Error: {}", e)),
         }
     }
 
-    match client.get(&url).send().await {
-        Ok(res) => match res.bytes().await {
-            Ok(buf) => {
-                let mut file_buffer = buf.clone();
-                let mut file_extension = "";
-                //Determine file type
-                let mut reader = ImageReader::new(Cursor::new(&buf));
-                match reader.with_guessed_format().unwrap().format() {
-                    Some(format) => {
-                        if format == ImageFormat::Jpeg {
-                            file_extension = ".jpg";
-                        } else if format == ImageFormat::Png {
-                            file_extension = ".png";
-                        } else if format == ImageFormat::Gif {
-                            file_extension = ".gif";
-                        } else if format == ImageFormat::WebP {
-                            file_extension = ".webp";
-                        }
-                    }
-                    None => {
-                        //Retry (TODO: Seems race-condition related. Can we limit futures instead?)
-                        match client.get(&url).send().await {
-                            Ok(res) => match res.bytes().await {
-                                Ok(retry_buf) => {
-                                    file_buffer = retry_buf.clone();
-                                    reader = ImageReader::new(Cursor::new(&retry_buf));
-                                    match reader.with_guessed_format().unwrap().format() {
-                                        Some(format) => {
-                                            if format == ImageFormat::Jpeg {
-                                                file_extension = ".jpg";
-                                            } else if format == ImageFormat::Png {
-                                                file_extension = ".png";
-                                            } else if format == ImageFormat::Gif {
-                                                file_extension = ".gif";
-                                            } else if format == ImageFormat::WebP {
-                                                file_extension = ".webp";
-                                            }
-                                        }
-                                        None => {
-                                            //Default to png
-                                            file_extension = ".png"
-                                        }
-                                    }
-                                }
-                                Err(e) => return Err(anyhow::anyhow!("Art Error: {}", e)),
-                            },
-                            Err(e) => return Err(anyhow::anyhow!("Art Error: {}", e)),
-                        }
-                    }
-                }
-
-                //Save file
-                let mut file =
-                    File::create(format!("{}/cover_art/{}{}", path, cover_id, file_extension))?;
-                return match file.write_all(&file_buffer) {
-                    Ok(_) => Ok(format!("{}{}", cover_id, file_extension)),
-                    Err(e) => Err(anyhow::anyhow!("Art Error: {}", e)),
-                }
-            },
-            Err(e) => return Err(anyhow::anyhow!("Art Error: {}", e)),
-        },
-        Err(e) => return Err(anyhow::anyhow!("Art Error: {}", e)),
+    let buf = client.get_bytes(&url).await?;
+    //Determine file type
+    let reader = ImageReader::new(Cursor::new(&buf)).with_guessed_format()?;
+    let file_extension = match reader.format() {
+        Some(ImageFormat::Jpeg) => ".jpg",
+        Some(ImageFormat::Gif) => ".gif",
+        Some(ImageFormat::WebP) => ".webp",
+        //Default to png
+        _ => ".png",
+    };
+
+    //Save file
+    let mut file = File::create(format!("{}/{}{}", path, cover_id, file_extension))?;
+    match file.write_all(&buf) {
+        Ok(_) => Ok(format!("{}{}", cover_id, file_extension)),
+        Err(e) => Err(anyhow::anyhow!("Art Error: {}", e)),
     }
 }
 
 /* getPlaylists */
-pub async fn get_playlists(library: &Library) -> Result<Vec<SubsonicPlaylist>, anyhow::Error> {
+pub async fn get_playlists(
+    client: &ServerClient,
+    library: &Library,
+) -> Result<Vec<SubsonicPlaylist>, ApiError> {
     let url = create_connection_string(library, "getPlaylists");
-    match reqwest::get(&url).await {
-        Ok(res) => match res
-            .json::<SubsonicResponse<SubsonicGetPlaylistsResponse>>()
-            .await
-        {
-            Ok(playlist_response) => Ok(playlist_response.data.playlists.playlist),
-            Err(e) => Err(anyhow::anyhow!("Error: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Error: {}", e)),
+    let playlist_response = client
+        .get_response::<SubsonicGetPlaylistsResponse>(&url)
+        .await?;
+    Ok(playlist_response.data.playlists.playlist)
+}
+
+/* createPlaylist
+*  https://opensubsonic.netlify.app/docs/endpoints/createplaylist */
+pub async fn create_playlist(
+    client: &ServerClient,
+    library: &Library,
+    name: &str,
+    song_ids: &[&str],
+) -> Result<SubsonicResponse<SubsonicCreatePlaylistResponse>, ApiError> {
+    let mut url = create_connection_string(library, "createPlaylist");
+    url.push_str(&format!("&name={}", encode_query_value(name)));
+    for song_id in song_ids {
+        url.push_str(&format!("&songId={}", encode_query_value(song_id)));
     }
+    client
+        .get_response::<SubsonicCreatePlaylistResponse>(&url)
+        .await
 }
 
-/* stream
-* https://opensubsonic.netlify.app/docs/endpoints/stream */
-pub async fn stream(library: &Library, song_id: &str) -> Result<Vec<u8>, anyhow::Error> {
-    let base_url = create_connection_string(library, "stream");
+/* updatePlaylist. Removals refer to indexes before the update, and additions go on the end.
+*  https://opensubsonic.netlify.app/docs/endpoints/updateplaylist */
+pub async fn update_playlist(
+    client: &ServerClient,
+    library: &Library,
+    playlist_id: &str,
+    name: Option<&str>,
+    song_ids_to_add: &[&str],
+    song_indexes_to_remove: &[u32],
+) -> Result<(), ApiError> {
+    let mut url = create_connection_string(library, "updatePlaylist");
+    url.push_str(&format!("&playlistId={}", encode_query_value(playlist_id)));
+    if let Some(name) = name {
+        url.push_str(&format!("&name={}", encode_query_value(name)));
+    }
+    for song_id in song_ids_to_add {
+        url.push_str(&format!("&songIdToAdd={}", encode_query_value(song_id)));
+    }
+    for index in song_indexes_to_remove {
+        url.push_str(&format!("&songIndexToRemove={}", index));
+    }
+    client.get_response::<SubsonicStatus>(&url).await?;
+    Ok(())
+}
+
+/* deletePlaylist
+*  https://opensubsonic.netlify.app/docs/endpoints/deleteplaylist */
+pub async fn delete_playlist(
+    client: &ServerClient,
+    library: &Library,
+    playlist_id: &str,
+) -> Result<(), ApiError> {
+    let mut url = create_connection_string(library, "deletePlaylist");
+    url.push_str(&format!("&id={}", encode_query_value(playlist_id)));
+    client.get_response::<SubsonicStatus>(&url).await?;
+    Ok(())
+}
+
+/* download (the original file, no transcoding)
+* https://opensubsonic.netlify.app/docs/endpoints/download */
+pub async fn download(
+    client: &ServerClient,
+    library: &Library,
+    song_id: &str,
+) -> Result<Vec<u8>, ApiError> {
+    let base_url = create_connection_string(library, "download");
     let url = format!("{}&id={}", base_url, song_id);
 
-    match reqwest::get(&url).await {
-        Ok(res) => match res.bytes().await {
-            Ok(buf) => Ok(buf.to_vec()),
-            Err(e) => Err(anyhow::anyhow!("Stream Error: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Stream Error: {}", e)),
-    }
+    client.get_bytes(&url).await
+}
+
+/* stream with transcoding turned off, for users without download permission */
+pub async fn stream_raw(
+    client: &ServerClient,
+    library: &Library,
+    song_id: &str,
+) -> Result<Vec<u8>, ApiError> {
+    let base_url = create_connection_string(library, "stream");
+    let url = format!("{}&id={}&format=raw", base_url, song_id);
+
+    client.get_bytes(&url).await
 }
 
 /* getAlbumList2
 */
 pub async fn get_album_list(
+    client: &ServerClient,
     library: &Library,
     list_type: String,
-) -> Result<SubsonicResponse<SubsonicGetAlbumList2Response>, anyhow::Error> {
+) -> Result<SubsonicResponse<SubsonicGetAlbumList2Response>, ApiError> {
     let mut url = create_connection_string(library, "getAlbumList2");
     url.push_str(&format!("&type={}&size=42", list_type));
-    match reqwest::get(&url).await {
-        Ok(res) => match res
-            .json::<SubsonicResponse<SubsonicGetAlbumList2Response>>()
-            .await
-        {
-            Ok(album_list_response) => Ok(album_list_response),
-            Err(e) => Err(anyhow::anyhow!("Album Error: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Album Error: {}", e)),
-    }
+    client
+        .get_response::<SubsonicGetAlbumList2Response>(&url)
+        .await
+}
+
+pub async fn get_album_list_page(
+    client: &ServerClient,
+    library: &Library,
+    list_type: &str,
+    size: u32,
+    offset: u32,
+) -> Result<SubsonicResponse<SubsonicGetAlbumList2Response>, ApiError> {
+    let mut url = create_connection_string(library, "getAlbumList2");
+    url.push_str(&format!(
+        "&type={}&size={}&offset={}",
+        list_type, size, offset
+    ));
+    client
+        .get_response::<SubsonicGetAlbumList2Response>(&url)
+        .await
 }
 
 /* getPlaylist */
 pub async fn get_playlist_songs(
+    client: &ServerClient,
     library: &Library,
     playlist_id: &str,
-) -> Result<SubsonicResponse<SubsonicGetPlaylistResponse>, anyhow::Error> {
+) -> Result<SubsonicResponse<SubsonicGetPlaylistResponse>, ApiError> {
     let mut url = create_connection_string(library, "getPlaylist");
     url.push_str(&format!("&id={}", playlist_id));
-    match reqwest::get(&url).await {
-        Ok(res) => match res
-            .json::<SubsonicResponse<SubsonicGetPlaylistResponse>>()
-            .await
-        {
-            Ok(playlist_response) => Ok(playlist_response),
-            Err(e) => Err(anyhow::anyhow!("Song Error: {}", e)),
-        },
-        Err(e) => Err(anyhow::anyhow!("Song Error: {}", e)),
+    client
+        .get_response::<SubsonicGetPlaylistResponse>(&url)
+        .await
+}
+
+/* scrobble
+*  https://opensubsonic.netlify.app/docs/endpoints/scrobble */
+pub async fn scrobble(
+    client: &ServerClient,
+    library: &Library,
+    song_id: &str,
+    time: Option<i64>,
+    submission: bool,
+) -> Result<(), ApiError> {
+    let mut url = create_connection_string(library, "scrobble");
+    url.push_str(&format!("&id={}&submission={}", song_id, submission));
+    if let Some(time) = time {
+        url.push_str(&format!("&time={}", time));
+    }
+    client.get_response::<SubsonicStatus>(&url).await?;
+    Ok(())
+}
+
+/* star / unstar
+*  https://opensubsonic.netlify.app/docs/endpoints/star
+*  https://opensubsonic.netlify.app/docs/endpoints/unstar */
+pub async fn set_starred(
+    client: &ServerClient,
+    library: &Library,
+    target: RatingTarget,
+    id: &str,
+    starred: bool,
+) -> Result<(), ApiError> {
+    let endpoint = if starred { "star" } else { "unstar" };
+    let param = match target {
+        RatingTarget::Song => "id",
+        RatingTarget::Album => "albumId",
+        RatingTarget::Artist => "artistId",
+    };
+    let mut url = create_connection_string(library, endpoint);
+    url.push_str(&format!("&{}={}", param, id));
+    client.get_response::<SubsonicStatus>(&url).await?;
+    Ok(())
+}
+
+/* setRating (0 removes the rating)
+*  https://opensubsonic.netlify.app/docs/endpoints/setrating */
+pub async fn set_rating(
+    client: &ServerClient,
+    library: &Library,
+    id: &str,
+    rating: u32,
+) -> Result<(), ApiError> {
+    let mut url = create_connection_string(library, "setRating");
+    url.push_str(&format!("&id={}&rating={}", id, rating));
+    client.get_response::<SubsonicStatus>(&url).await?;
+    Ok(())
+}
+
+/* getStarred2
+*  https://opensubsonic.netlify.app/docs/endpoints/getstarred2 */
+pub async fn get_starred(
+    client: &ServerClient,
+    library: &Library,
+) -> Result<SubsonicResponse<SubsonicGetStarred2Response>, ApiError> {
+    let url = create_connection_string(library, "getStarred2");
+    client
+        .get_response::<SubsonicGetStarred2Response>(&url)
+        .await
+}
+
+/* search3
+*  https://opensubsonic.netlify.app/docs/endpoints/search3 */
+pub async fn search3(
+    client: &ServerClient,
+    library: &Library,
+    query: &str,
+    page: &SearchPage,
+) -> Result<SubsonicResponse<SubsonicSearch3Response>, ApiError> {
+    let mut url = create_connection_string(library, "search3");
+    url.push_str(&format!(
+        "&query={}&artistCount={}&artistOffset={}&albumCount={}&albumOffset={}&songCount={}&songOffset={}",
+        encode_query_value(query),
+        page.artist_count,
+        page.artist_offset,
+        page.album_count,
+        page.album_offset,
+        page.song_count,
+        page.song_offset
+    ));
+    client.get_response::<SubsonicSearch3Response>(&url).await
+}
+
+/* getPlayQueue
+*  https://opensubsonic.netlify.app/docs/endpoints/getplayqueue */
+pub async fn get_play_queue(
+    client: &ServerClient,
+    library: &Library,
+) -> Result<SubsonicResponse<SubsonicGetPlayQueueResponse>, ApiError> {
+    let url = create_connection_string(library, "getPlayQueue");
+    client
+        .get_response::<SubsonicGetPlayQueueResponse>(&url)
+        .await
+}
+
+/* savePlayQueue (no songs clears it)
+*  https://opensubsonic.netlify.app/docs/endpoints/saveplayqueue */
+pub async fn save_play_queue(
+    client: &ServerClient,
+    library: &Library,
+    song_ids: &[&str],
+    current: Option<&str>,
+    position_ms: i64,
+) -> Result<(), ApiError> {
+    let mut url = create_connection_string(library, "savePlayQueue");
+    for song_id in song_ids {
+        url.push_str(&format!("&id={}", encode_query_value(song_id)));
+    }
+    if let Some(current) = current {
+        url.push_str(&format!(
+            "&current={}&position={}",
+            encode_query_value(current),
+            position_ms
+        ));
     }
+    client.get_response::<SubsonicStatus>(&url).await?;
+    Ok(())
 }
@@ -0,0 +1,337 @@
+use std::{collections::HashMap, path::Path};
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use futures::StreamExt;
+
+use crate::{
+    formatter::{create_connection_string, encode_query_value, namespace_id, strip_namespace},
+    models::{Album, Artist, Library, Playlist, Song, TranscodeSettings},
+    music::{cover_art_key, SyncProgressReporter},
+    responses::{SubsonicAlbumID3, SubsonicChild, SubsonicPlaylist},
+    source::{MusicSource, SourceAlbum},
+};
+
+use super::{
+    get_album_art, get_album_list_page, get_albums_for_artist, get_artists,
+    get_indexes_modified_since, get_playlist_songs, get_playlists, get_songs_for_album,
+    ping_server, ServerClient,
+};
+
+const ALBUM_LIST_PAGE_SIZE: u32 = 500;
+
+/* A Subsonic (or OpenSubsonic) server */
+pub struct SubsonicSource {
+    library: Library,
+    client: ServerClient,
+}
+
+impl SubsonicSource {
+    pub fn new(library: Library, client: ServerClient) -> Self {
+        SubsonicSource { library, client }
+    }
+}
+
+#[async_trait]
+impl MusicSource for SubsonicSource {
+    fn library(&self) -> &Library {
+        &self.library
+    }
+
+    async fn ping(&self) -> Result<(), anyhow::Error> {
+        Ok(ping_server(&self.client, &self.library).await?)
+    }
+
+    /* getIndexes only lists artists if something changed since ifModifiedSince */
+    async fn changed_since(&self, since: i64) -> Result<Option<bool>, anyhow::Error> {
+        let index_response =
+            get_indexes_modified_since(&self.client, &self.library, &since.to_string()).await?;
+        Ok(Some(index_response.data.indexes.index.is_some()))
+    }
+
+    async fn list_artists(
+        &self,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Artist>, anyhow::Error> {
+        progress.start(1);
+        let artist_response = get_artists(&self.client, &self.library).await?;
+        progress.advance();
+        let library_id = &self.library.id;
+        let mut artists: Vec<Artist> = vec![];
+        for index in &artist_response.data.artists.index {
+            for artist in &index.artist {
+                artists.push(Artist {
+                    id: namespace_id(library_id, &artist.id),
+                    name: artist.name.clone(),
+                    library_id: library_id.clone(),
+                    starred: artist.starred.clone(),
+                    rating: artist.user_rating.unwrap_or(0),
+                });
+            }
+        }
+        Ok(artists)
+    }
+
+    /* getArtist for each artist */
+    async fn list_albums(
+        &self,
+        artists: &[Artist],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<SourceAlbum>, anyhow::Error> {
+        let mut albums: Vec<SourceAlbum> = vec![];
+        let base_url = create_connection_string(&self.library, "getArtist");
+        let mut futures = vec![];
+        for artist in artists {
+            let url = format!(
+                "{}&id={}",
+                base_url,
+                strip_namespace(&self.library.id, &artist.id)
+            );
+            futures.push(get_albums_for_artist(url, &self.client));
+        }
+        progress.start(futures.len());
+        let mut album_calls =
+            futures::stream::iter(futures).buffered(self.client.max_concurrent_requests());
+        while let Some(album_call) = album_calls.next().await {
+            progress.advance();
+            //A skipped artist would have its albums removed by the sync, so one failure fails it
+            let album_response = album_call?;
+            for album in &album_response.data.artist.album {
+                albums.push(self.to_source_album(album));
+            }
+        }
+        Ok(albums)
+    }
+
+    /* Page through getAlbumList2 (newest first) until the server runs out of albums. A handful of
+     *  paged calls instead of one getArtist per artist. */
+    async fn list_all_albums(
+        &self,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<SourceAlbum>, anyhow::Error> {
+        progress.start(1);
+        let mut albums: Vec<SourceAlbum> = vec![];
+        let mut offset = 0;
+        loop {
+            let album_list_response = get_album_list_page(
+                &self.client,
+                &self.library,
+                "newest",
+                ALBUM_LIST_PAGE_SIZE,
+                offset,
+            )
+            .await?;
+            let page = album_list_response.data.album_list_2.album;
+            let page_size = page.len();
+            albums.extend(page.iter().map(|album| self.to_source_album(album)));
+            if page_size < ALBUM_LIST_PAGE_SIZE as usize {
+                break;
+            }
+            offset += ALBUM_LIST_PAGE_SIZE;
+        }
+        progress.advance();
+        Ok(albums)
+    }
+
+    /* getAlbum for each album */
+    async fn list_songs(
+        &self,
+        albums: &[Album],
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<Vec<Song>, anyhow::Error> {
+        let mut songs: Vec<Song> = vec![];
+        let base_url = create_connection_string(&self.library, "getAlbum");
+        let mut futures = vec![];
+        for album in albums {
+            let url = format!(
+                "{}&id={}",
+                base_url,
+                strip_namespace(&self.library.id, &album.id)
+            );
+            futures.push(get_songs_for_album(url, &self.client));
+        }
+        progress.start(futures.len());
+        let mut album_calls =
+            futures::stream::iter(futures).buffered(self.client.max_concurrent_requests());
+        while let Some(album_call) = album_calls.next().await {
+            progress.advance();
+            //As with getArtist, a skipped album would lose its songs
+            let album_response = album_call?;
+            //Songs share their album's art
+            let cover_art = &album_response.data.album.cover_art;
+            for song in &album_response.data.album.song {
+                songs.push(self.to_song(song, cover_art));
+            }
+        }
+        Ok(songs)
+    }
+
+    /* getCoverArt for each album */
+    async fn fetch_art(
+        &self,
+        albums: &[Album],
+        cover_art_dir: &Path,
+        progress: &mut SyncProgressReporter<'_>,
+    ) -> Result<HashMap<String, String>, anyhow::Error> {
+        //Create cover_art folder (one subfolder per library) if it doesn't exist
+        std::fs::create_dir_all(cover_art_dir.join(&self.library.id))?;
+
+        let cover_art_dir = cover_art_dir.to_string_lossy().to_string();
+        let base_url = create_connection_string(&self.library, "getCoverArt");
+        let mut futures = vec![];
+        for album in albums.iter().filter(|album| !album.cover_art.is_empty()) {
+            //Cover art keys are <library_id>/<cover_id>
+            let cover_id = album
+                .cover_art
+                .strip_prefix(self.library.id.as_str())
+                .and_then(|cover_id| cover_id.strip_prefix('/'))
+                .unwrap_or(&album.cover_art);
+            let url = format!("{}&id={}", base_url, cover_id);
+            futures.push(get_album_art(
+                url,
+                album.cover_art.clone(),
+                &self.client,
+                &cover_art_dir,
+            ));
+        }
+
+        let mut cover_art_map: HashMap<String, String> = HashMap::new();
+        progress.start(futures.len());
+        let mut stream =
+            futures::stream::iter(futures).buffer_unordered(self.client.max_concurrent_requests());
+        while let Some(result) = stream.next().await {
+            progress.advance();
+            match result {
+                Ok(file_name) => {
+                    if !file_name.is_empty() {
+                        cover_art_map.insert(
+                            Path::new(&file_name)
+                                .with_extension("")
+                                .to_string_lossy()
+                                .to_string(),
+                            file_name,
+                        );
+                    }
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        Ok(cover_art_map)
+    }
+
+    async fn playlists(&self) -> Result<Vec<Playlist>, anyhow::Error> {
+        let playlists = get_playlists(&self.client, &self.library).await?;
+        Ok(playlists
+            .iter()
+            .map(|playlist| to_playlist(&self.library.id, playlist))
+            .collect())
+    }
+
+    /* getPlaylist */
+    async fn playlist(&self, playlist_id: &str) -> Result<(Playlist, Vec<String>), anyhow::Error> {
+        let server_id = strip_namespace(&self.library.id, playlist_id);
+        let response = get_playlist_songs(&self.client, &self.library, server_id).await?;
+        let details = response.data.playlist;
+        let song_ids = details
+            .entry
+            .iter()
+            .map(|song| namespace_id(&self.library.id, &song.id))
+            .collect();
+        Ok((to_playlist(&self.library.id, &details.info), song_ids))
+    }
+
+    /* stream
+     *  https://opensubsonic.netlify.app/docs/endpoints/stream */
+    async fn stream_url(
+        &self,
+        song_id: &str,
+        transcode: &TranscodeSettings,
+    ) -> Result<String, anyhow::Error> {
+        let base_url = create_connection_string(&self.library, "stream");
+        let mut url = format!(
+            "{}&id={}",
+            base_url,
+            strip_namespace(&self.library.id, song_id)
+        );
+        if let Some(format) = &transcode.format {
+            url.push_str(&format!("&format={}", encode_query_value(format)));
+        }
+        if let Some(max_bit_rate) = transcode.max_bit_rate {
+            url.push_str(&format!("&maxBitRate={}", max_bit_rate));
+        }
+        if transcode.estimate_content_length {
+            url.push_str("&estimateContentLength=true");
+        }
+        Ok(url)
+    }
+}
+
+impl SubsonicSource {
+    fn to_source_album(&self, album: &SubsonicAlbumID3) -> SourceAlbum {
+        let library_id = &self.library.id;
+        SourceAlbum {
+            album: Album {
+                id: namespace_id(library_id, &album.id),
+                name: album.name.clone(),
+                artist_id: namespace_id(library_id, album.artist_id.as_deref().unwrap_or("")),
+                artist_name: album.artist.clone(),
+                library_id: library_id.clone(),
+                cover_art: cover_art_key(library_id, &album.cover_art),
+                year: album.year,
+                duration: album.duration,
+                starred: album.starred.clone(),
+                rating: album.user_rating.unwrap_or(0),
+            },
+            changed: album_changed(album),
+        }
+    }
+
+    fn to_song(&self, song: &SubsonicChild, cover_art: &str) -> Song {
+        let library_id = &self.library.id;
+        Song {
+            id: namespace_id(library_id, &song.id),
+            title: song.title.clone(),
+            artist_id: namespace_id(library_id, song.artist_id.as_deref().unwrap_or("")),
+            artist_name: song.artist.clone(),
+            album_id: namespace_id(library_id, &song.album_id),
+            album_name: song.album.clone(),
+            library_id: library_id.clone(),
+            track: song.track,
+            duration: song.duration,
+            disc_number: song.disc_number.unwrap_or(1),
+            year: song.year,
+            content_type: song.content_type.clone(),
+            cover_art: cover_art_key(library_id, cover_art),
+            starred: song.starred.clone(),
+            rating: song.user_rating.unwrap_or(0),
+            replay_gain_track: song
+                .replay_gain
+                .as_ref()
+                .and_then(|gain| gain.track_gain.or(gain.fallback_gain)),
+            replay_gain_album: song.replay_gain.as_ref().and_then(|gain| gain.album_gain),
+            replay_gain_track_peak: song.replay_gain.as_ref().and_then(|gain| gain.track_peak),
+            replay_gain_album_peak: song.replay_gain.as_ref().and_then(|gain| gain.album_peak),
+        }
+    }
+}
+
+/* When an album last changed (ms), falling back to when it was created */
+fn album_changed(album: &SubsonicAlbumID3) -> Option<i64> {
+    let timestamp = album.changed.as_ref().or(album.created.as_ref())?;
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|date| date.timestamp_millis())
+}
+
+pub fn to_playlist(library_id: &str, playlist: &SubsonicPlaylist) -> Playlist {
+    Playlist {
+        id: namespace_id(library_id, &playlist.id),
+        library_id: library_id.to_string(),
+        name: playlist.name.clone(),
+        owner: playlist.owner.clone(),
+        created: playlist.created.clone(),
+        modified: playlist.changed.clone(),
+        song_count: playlist.song_count.unwrap_or(0),
+        duration: playlist.duration.unwrap_or(0),
+    }
+}
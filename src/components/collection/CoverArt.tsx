@@ -1,4 +1,5 @@
 import { SyntheticEvent, useEffect, useState } from 'react';
+import { fetchCoverArt } from '@/util/db';
 
 interface AlbumArtProps {
   className?: string
@@ -10,15 +11,25 @@ interface AlbumArtProps {
 
 export default function CoverArt({ className = '', src, fallbackSrc, alt, style }: AlbumArtProps) {
   const [imageSrc, setImageSrc] = useState(src);
+  const [refetched, setRefetched] = useState(false);
 
   const handleImageError = (e: SyntheticEvent<HTMLImageElement, Event>) => {
     e.preventDefault();
-    setImageSrc(fallbackSrc);
+    if (imageSrc === src) {
+      setImageSrc(fallbackSrc);
+    } else if (!refetched) {
+      //Neither file exists, so the art was evicted from the cache. Fetched again once.
+      setRefetched(true);
+      fetchCoverArt(src)
+        .then((fetchedSrc) => fetchedSrc && setImageSrc(fetchedSrc))
+        .catch((e) => console.log(e));
+    }
     return true
   };
 
   useEffect(() => {
     setImageSrc(src);
+    setRefetched(false);
   }, [src]);
 
   return (
@@ -1,6 +1,7 @@
 import { Library } from '@/types/Config';
 import { Song } from '@/types/Music'
-import { scrobble, stream } from '@/util/subsonic';
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import { RefObject, useContext, useEffect, useRef, useState } from 'react';
 import { FaPlayCircle, FaPauseCircle, FaVolumeUp, FaVolumeMute } from "react-icons/fa";
 import { MdSkipNext, MdSkipPrevious } from "react-icons/md";
@@ -8,11 +9,20 @@ import CoverArt from '@/components/collection/CoverArt';
 import Spinner from '@/components/ui/spinner';
 import QueueContext from '@/contexts/QueueContext';
 import QueueList from './QueueList';
+import { getQueueSongs, getSavedQueue, getServerPlayQueue, SavedQueue, saveServerPlayQueue } from '@/util/db';
 
 //Make default lower volume for better UX
 const DEFAULT_VOLUME = 65;
 //Progress color for input range sliders
 const PROGRESS_COLOR = '#588364';
+//Scrobble once half the song (or 4 minutes of it) has played
+const SCROBBLE_MAX_THRESHOLD_SECONDS = 240;
+
+interface PendingScrobble {
+  song: Song
+  playedAt: number
+  submitted: boolean
+}
 
 enum PlaybackState {
   Playing,
@@ -21,6 +31,38 @@ enum PlaybackState {
   Loading,
 }
 
+//Sent by the playback engine
+interface PlaybackStatus {
+  state: 'playing' | 'paused' | 'stopped' | 'loading'
+  index: number | null
+}
+
+interface PlaybackPosition {
+  index: number
+  position: number
+  duration: number | null
+}
+
+interface PlaybackError {
+  index: number
+  message: string
+}
+
+const PLAYBACK_STATES = {
+  playing: PlaybackState.Playing,
+  paused: PlaybackState.Paused,
+  stopped: PlaybackState.Stopped,
+  loading: PlaybackState.Loading,
+}
+
+function toPlaybackItems(queue: Song[]) {
+  return queue.map((song) => ({ library_id: song.library_id, song_id: song.id }))
+}
+
+function formatTime(seconds: number) {
+  return `${Math.floor(seconds / 60)}:${String(Math.floor(seconds % 60)).padStart(2, '0')}`
+}
+
 interface NowPlayingProps {
   libraries: Map<String, Library>
   onPlay: (song: Song | undefined) => void
@@ -28,7 +70,7 @@ interface NowPlayingProps {
 }
 
 export default function NowPlaying({ libraries, onPlay, onAlbumClick }: NowPlayingProps) {
-  const { queue, currentSong, setCurrentSong, queueOrigin } = useContext(QueueContext)
+  const { queue, setQueue, currentSong, setCurrentSong, queueOrigin } = useContext(QueueContext)
   const [nowPlaying, setNowPlaying] = useState<Song | undefined>(undefined)
   const [playbackState, setPlaybackState] = useState<PlaybackState>(PlaybackState.Stopped)
 
@@ -40,260 +82,215 @@ export default function NowPlaying({ libraries, onPlay, onAlbumClick }: NowPlayi
   const [duration, setDuration] = useState('0:00');
 
   //Refs
-  const audioRef = useRef<HTMLAudioElement>(null);
   const progressRef = useRef<HTMLInputElement>(null);
   const volumeRef = useRef<HTMLInputElement>(null);
-  const songLoadingRef = useRef<number>(0);
-  const abortControllerRef = useRef<AbortController | null>(null);
+  const scrobbleRef = useRef<PendingScrobble | null>(null);
+  const durationRef = useRef(0);
+  //Queue the engine was last sent, and the index of the song it is playing from it
+  const sentQueueRef = useRef<Song[] | null>(null);
+  const playingIndexRef = useRef<number | undefined>(undefined);
+  //Where a restored queue was left, until it is played
+  const resumeRef = useRef<{ index: number, position: number } | null>(null);
+  //Engine events are listened to once, so they go through this to see the latest state
+  const eventHandlersRef = useRef({ playbackStatusChanged, updateTime });
+  eventHandlersRef.current = { playbackStatusChanged, updateTime };
+
+  //Submit any scrobbles queued while offline
+  useEffect(() => {
+    invoke('flush_scrobble_queue').catch((e) => console.log(e))
+  }, [])
 
-  const [cachedSongData, setCachedSongData] = useState<Map<string, string>>(new Map())
+  useEffect(() => {
+    invoke('set_volume', { volume: DEFAULT_VOLUME / 100 }).catch((e) => console.log(e))
+    const unlisteners = [
+      listen<PlaybackStatus>('playback-state', (event) => eventHandlersRef.current.playbackStatusChanged(event.payload)),
+      listen<PlaybackPosition>('playback-position', (event) => eventHandlersRef.current.updateTime(event.payload)),
+      listen<PlaybackError>('playback-error', (event) => console.log("Failed to play song", event.payload)),
+    ]
+    return () => {
+      unlisteners.forEach((unlisten) => unlisten.then((f) => f()))
+    }
+  }, [])
 
+  //Pick the queue up where it was left when the app was closed
   useEffect(() => {
-    async function getSongData() {
-      if (currentSong !== undefined && currentSong !== -1) {
-        await loadSong(queue[currentSong], currentSong)
-      }
+    getSavedQueue()
+      .then((saved) => restoreQueue(saved, false))
+      .catch((e) => console.log("Failed to restore queue", e))
+  }, [])
+
+  useEffect(() => {
+    if (currentSong !== undefined && currentSong !== -1) {
+      playFromQueue(currentSong)
     }
-    getSongData()
   }, [queueOrigin])
 
-  async function loadSong(song: Song, indexToPlay: number) {
-    if (song !== undefined && audioRef.current !== null) {
-
-      //Cancel any pending song loads
-      if (abortControllerRef.current) {
-        abortControllerRef.current.abort()
-      }
-
-      //Create new pending song load (and store locally)
-      const abortController = new AbortController()
-      abortControllerRef.current = abortController
-
-      //Create new overall song loading ref (just increment "id")
-      const songLoadingId = ++songLoadingRef.current
-
-      setNowPlaying(song)
-      onPlay(song)
-
-      audioRef.current.pause()
-      setPlaybackState(PlaybackState.Paused)
-
-      //Reset seekbar
-      if (progressRef.current) {
-        progressRef.current.value = '0';
-        progressRef.current.style.background = `linear-gradient(to right, ${PROGRESS_COLOR} ${progressRef.current.value}%, #ccc ${progressRef.current.value}%)`;
-      }
-
-      //If song is cached, play it
-      let audioData, songDataMap
-      if (cachedSongData.has(queue[indexToPlay!].id)) {
-        audioData = cachedSongData.get(queue[indexToPlay!].id)!
-        songDataMap = cachedSongData
-      } else {
-        setPlaybackState(PlaybackState.Loading)
-        try{
-          audioData = await stream(song, libraries.get(song.library_id)!, abortController.signal)
-        }catch(e: any) {
-          if(e.name !== "AbortError") {
-            console.log("Failed to stream song", e)
-            return
-          }
-        }
-        if(songLoadingId !== songLoadingRef.current) {
-          return
-        }
-        if (audioData === undefined) {
-          console.log("Failed to stream song")
-          return
-        }
-        songDataMap = new Map<string, string>()
-        songDataMap.set(song.id, audioData)
-      }
-
-      //Check if song load was cancelled
-      if(songLoadingId !== songLoadingRef.current) {
-        return
-      }
-
-      audioRef.current.src = audioData
-      audioRef.current.load()
-      let secondsDate = new Date(0)
-      secondsDate.setSeconds(song.duration)
-      var timestring = secondsDate.toISOString().slice(11, 19).replace(/^0+:(0+)?/, '')
-      setDuration(timestring)
-      audioRef.current.play()
-      setPlaybackState(PlaybackState.Playing)
-
-      //Scrobble song
-      scrobble(song.id, libraries.get(song.library_id)!)
-
-      //Load nearby songs (if not present)
-      songDataMap = new Map<string, string>()
-
-      //Grab previous if possible
-      if (indexToPlay && indexToPlay !== 0) {
-        let previousTwo = indexToPlay - 2
-        if (previousTwo < 0) {
-          previousTwo = 0
-        }
-        for (let i = previousTwo; i <= indexToPlay; i++) {
-          //Check if song load was cancelled
-          if(songLoadingId !== songLoadingRef.current) {
-            return
-          }
-          if (cachedSongData.has(queue[i].id)) {
-            continue
-          }
-          let song = queue[i]
-          let audioData = await stream(song, libraries.get(song.library_id)!, abortController.signal)
-          //Check if song load was cancelled
-          if(songLoadingId !== songLoadingRef.current) {
-            return
-          }
-
-          if (audioData === undefined) {
-            console.log("Failed to stream song")
-            return
-          }
-          songDataMap.set(song.id, audioData)
-        }
-      }
-
-      //Grab next if possible
-      if (indexToPlay && indexToPlay !== queue.length - 1) {
-        let nextTwo = indexToPlay + 2
-        if (nextTwo > queue.length - 1) {
-          nextTwo = queue.length - 1
-        }
-        for (let i = indexToPlay + 1; i <= nextTwo; i++) {
-          //Check if song load was cancelled
-          if(songLoadingId !== songLoadingRef.current) {
-            return
-          }
-          if (cachedSongData.has(queue[i].id)) {
-            continue
-          }
-          let song = queue[i]
-          let audioData = await stream(song, libraries.get(song.library_id)!, abortController.signal)
-
-          //Check if song load was cancelled
-          if(songLoadingId !== songLoadingRef.current) {
-            return
-          }
-          if (audioData === undefined) {
-            console.log("Failed to stream song")
-            return
-          }
-          songDataMap.set(song.id, audioData)
-        }
-      }
-
-      //Check if song load was cancelled
-      if(songLoadingId !== songLoadingRef.current) {
-        return
-      }
-
-      //Save song data to state
-      setCachedSongData(songDataMap)
-    }
+  //Keep the engine's queue in step with edits, without interrupting the current song
+  useEffect(() => {
+    if (sentQueueRef.current === null || sentQueueRef.current === queue) return;
+    const index = nowPlaying ? queue.indexOf(nowPlaying) : -1
+    sentQueueRef.current = queue
+    playingIndexRef.current = index === -1 ? undefined : index
+    invoke('update_queue', { items: toPlaybackItems(queue), index: index === -1 ? null : index })
+      .catch((e) => console.log(e))
+  }, [queue])
+
+  function playFromQueue(index: number, songs: Song[] = queue, position: number = 0) {
+    if (songs[index] === undefined) return;
+    resumeRef.current = null
+    sentQueueRef.current = songs
+    playingIndexRef.current = undefined
+    setPlaybackState(PlaybackState.Loading)
+    invoke('play_queue', { items: toPlaybackItems(songs), index: index, position: position })
+      .catch((e) => console.log("Failed to play song", e))
   }
 
-  function pause() {
-    if (audioRef.current) {
-      audioRef.current.pause()
-      setPlaybackState(PlaybackState.Paused)
+  //Replace the queue with a saved one, playing it straight away or once play is pressed
+  async function restoreQueue(saved: SavedQueue, play: boolean) {
+    const { songs, index, position } = await getQueueSongs(saved)
+    //Something was played in the meantime
+    if (index === undefined || (!play && sentQueueRef.current !== null)) return;
+    setQueue(songs)
+    setCurrentSong(index)
+    if (play) {
+      playFromQueue(index, songs, position)
+      return;
     }
+    resumeRef.current = { index: index, position: position }
+    showSong(songs[index])
+    setCurrentTime(formatTime(position))
   }
 
-  function play() {
-    if (audioRef.current) {
-      audioRef.current.play()
-      setPlaybackState(PlaybackState.Playing)
-    }
+  /* Server play queue, for carrying on from another client */
+  function saveQueueToServer() {
+    if (!nowPlaying) return;
+    saveServerPlayQueue(libraries.get(nowPlaying.library_id)!)
+      .catch((e) => console.log("Failed to save queue to server", e))
+  }
+
+  function loadQueueFromServer() {
+    if (!nowPlaying) return;
+    getServerPlayQueue(libraries.get(nowPlaying.library_id)!)
+      .then((saved) => saved && restoreQueue(saved, true))
+      .catch((e) => console.log("Failed to load queue from server", e))
   }
 
-  async function nextSong() {
-    if (audioRef.current) {
-      if (currentSong && currentSong === queue.length - 1) {
-        setPlaybackState(PlaybackState.Stopped)
-        return
-      } else {
-        let nextSong = currentSong! + 1
-        setCurrentSong(nextSong)
-        await loadSong(queue[nextSong], nextSong)
-      }
+  function playbackStatusChanged(status: PlaybackStatus) {
+    setPlaybackState(PLAYBACK_STATES[status.state])
+    if (status.index !== null && status.index !== playingIndexRef.current) {
+      songStarted(status.index)
     }
   }
 
-  async function previousSong() {
-    if (audioRef.current) {
-      if (currentSong === 0) {
-        return
-      } else {
-        let previousSong = currentSong! - 1
-        setCurrentSong(previousSong)
-        await loadSong(queue[previousSong], previousSong)
-      }
+  //The engine moved on to another song, by itself at the end of one or when asked to
+  function songStarted(index: number) {
+    const song = sentQueueRef.current?.[index]
+    playingIndexRef.current = index
+    if (song === undefined) return;
+    setCurrentSong(index)
+    onPlay(song)
+    showSong(song)
+
+    //Report now playing, the play itself is scrobbled from updateTime
+    scrobbleRef.current = { song, playedAt: Date.now(), submitted: false }
+    invoke('scrobble_now_playing', { library: libraries.get(song.library_id)!, songId: song.id })
+      .catch((e) => console.log(e))
+  }
+
+  function showSong(song: Song) {
+    setNowPlaying(song)
+
+    //Reset seekbar
+    if (progressRef.current) {
+      progressRef.current.value = '0';
+      progressRef.current.style.background = `linear-gradient(to right, ${PROGRESS_COLOR} ${progressRef.current.value}%, #ccc ${progressRef.current.value}%)`;
     }
+    setCurrentTime('0:00')
+    durationRef.current = song.duration
+    let secondsDate = new Date(0)
+    secondsDate.setSeconds(song.duration)
+    var timestring = secondsDate.toISOString().slice(11, 19).replace(/^0+:(0+)?/, '')
+    setDuration(timestring)
+  }
+
+  function pause() {
+    invoke('pause').catch((e) => console.log(e))
   }
 
-  async function playQueueFromBeginning() {
-    if (audioRef.current) {
-      setCurrentSong(0)
-      await loadSong(queue[0], 0)
+  function play() {
+    invoke('play').catch((e) => console.log(e))
+  }
+
+  function nextSong() {
+    invoke('next_track').catch((e) => console.log(e))
+  }
+
+  function previousSong() {
+    invoke('previous_track').catch((e) => console.log(e))
+  }
+
+  function playQueueFromBeginning() {
+    const resume = resumeRef.current
+    if (resume) {
+      playFromQueue(resume.index, queue, resume.position)
+    } else {
+      playFromQueue(0)
     }
   }
 
   /* Volume Related */
   const changeVolume = (newLevel: number) => {
-    if (!audioRef.current) return;
-    audioRef.current.volume = newLevel / 100;
+    invoke('set_volume', { volume: newLevel / 100 }).catch((e) => console.log(e))
     setVolume(newLevel);
-    if (newLevel === 0) {
-      audioRef.current.muted = true;
-    }
-    else {
-      audioRef.current.muted = false;
-    }
     if (!volumeRef.current) return;
     volumeRef.current.value = newLevel.toString();
   }
 
   const toggleMute = () => {
-    if (!audioRef.current) return;
-    if (audioRef.current.muted) {
-      audioRef.current.muted = false;
-      setVolume(savedVolume);
-      if (!volumeRef.current) return;
-      volumeRef.current.value = savedVolume.toString();
+    if (volume === 0) {
+      changeVolume(savedVolume);
     }
     else {
-      audioRef.current.muted = true;
       setSavedVolume(volume);
-      setVolume(0);
-      if (!volumeRef.current) return;
-      volumeRef.current.value = '0';
+      changeVolume(0);
     }
+    if (!volumeRef.current) return;
     volumeRef.current.style.background = `linear-gradient(to right, ${PROGRESS_COLOR} ${volumeRef.current.value}%, #ccc ${volumeRef.current.value}%)`;
   }
 
   /* Seek-Related */
-  const updateTime = () => {
-    if (!audioRef.current || !progressRef.current) return;
-    const { currentTime, duration } = audioRef.current;
-    if(!isNaN(duration)) {
-      const progressPercent = (currentTime / duration) * 100;
+  function updateTime(playback: PlaybackPosition) {
+    if (!progressRef.current || playback.index !== playingIndexRef.current) return;
+    //The file's own duration is more accurate than the server's, when it has one
+    const duration = playback.duration ?? durationRef.current
+    durationRef.current = duration
+    if (duration > 0) {
+      const progressPercent = (playback.position / duration) * 100;
       progressRef.current.value = progressPercent.toString();
       progressRef.current.style.background = `linear-gradient(to right, ${PROGRESS_COLOR} ${progressRef.current.value}%, #ccc ${progressRef.current.value}%)`;
       //Update time strings
-      setCurrentTime(`${Math.floor(currentTime / 60)}:${String(Math.floor(currentTime % 60)).padStart(2, '0')}`);
+      setCurrentTime(formatTime(playback.position));
+      scrobbleIfPlayed(playback.position, duration)
     }
   }
 
+  const scrobbleIfPlayed = (currentTime: number, duration: number) => {
+    const pending = scrobbleRef.current
+    if (!pending || pending.submitted) return;
+    if (currentTime < Math.min(duration / 2, SCROBBLE_MAX_THRESHOLD_SECONDS)) return;
+    pending.submitted = true
+    invoke('scrobble_submission', {
+      library: libraries.get(pending.song.library_id)!,
+      songId: pending.song.id,
+      playedAt: pending.playedAt,
+      playedSeconds: Math.floor(currentTime),
+      duration: Math.floor(duration),
+    }).catch((e) => console.log(e))
+  }
+
   const seek = (e: React.ChangeEvent<HTMLInputElement>) => {
-    if (!audioRef.current) return;
     const percent = Number(e.target.value) / 100;
-    audioRef.current.currentTime = percent * audioRef.current.duration;
+    invoke('seek', { position: percent * durationRef.current }).catch((e) => console.log(e))
   }
 
   const updateProgress = (ref: RefObject<HTMLInputElement>) => {
@@ -303,8 +300,8 @@ export default function NowPlaying({ libraries, onPlay, onAlbumClick }: NowPlayi
     }
   }
 
-  function queueItemClicked(song: Song, index: number) {
-    loadSong(song, index)
+  function queueItemClicked(song: Song) {
+    playFromQueue(queue.indexOf(song))
   }
 
   /* Queue-Related */
@@ -319,10 +316,6 @@ export default function NowPlaying({ libraries, onPlay, onAlbumClick }: NowPlayi
 
   return (
     <>
-      <audio ref={audioRef} onTimeUpdate={updateTime} onEnded={() => nextSong()}>
-        <source />
-        Your browser does not support the audio element.
-      </audio>
       {nowPlaying && (
         <div className={`flex flex-col w-full border-t-2 border-slate-800 dark:border-slate-200`}>
           <div className={'flex flex-row w-full'}>
@@ -384,7 +377,7 @@ export default function NowPlaying({ libraries, onPlay, onAlbumClick }: NowPlayi
               <div className={'flex'}>
                 <input ref={volumeRef} type="range" defaultValue={volume} min={0} max={100} step={1} onChange={(e) => changeVolume(Number(e.target.value))} onInput={() => updateProgress(volumeRef)} />
               </div>
-              <QueueList nowPlayingId={nowPlaying?.id} onPlaySong={queueItemClicked} />
+              <QueueList nowPlayingId={nowPlaying?.id} onPlaySong={queueItemClicked} onSaveToServer={saveQueueToServer} onLoadFromServer={loadQueueFromServer} />
             </div>
           </div>
         </div>
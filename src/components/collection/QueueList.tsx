@@ -1,4 +1,4 @@
-import { DropdownMenu, DropdownMenuContent, DropdownMenuTrigger } from '@/components/ui/dropdown-menu';
+import { DropdownMenu, DropdownMenuContent, DropdownMenuItem, DropdownMenuSeparator, DropdownMenuTrigger } from '@/components/ui/dropdown-menu';
 import { ScrollArea } from '@/components/ui/scroll-area';
 import QueueContext from '@/contexts/QueueContext';
 import { useContext } from 'react';
@@ -13,9 +13,11 @@ import QueueItem from '@/components/collection/QueueItem';
 interface QueueListProps {
   nowPlayingId: string | undefined
   onPlaySong: (song: Song, index: number) => void
+  onSaveToServer: () => void
+  onLoadFromServer: () => void
 }
 
-export default function QueueList( { nowPlayingId, onPlaySong }: QueueListProps) {
+export default function QueueList( { nowPlayingId, onPlaySong, onSaveToServer, onLoadFromServer }: QueueListProps) {
   const { queue, setQueue, setCurrentSong } = useContext(QueueContext)
 
   function reorderQueue(event: DragEndEvent) {
@@ -62,6 +64,9 @@ export default function QueueList( { nowPlayingId, onPlaySong }: QueueListProps)
             </DndContext>
           </ScrollArea>
         )}
+        <DropdownMenuSeparator />
+        <DropdownMenuItem onClick={onSaveToServer}>Save queue to server</DropdownMenuItem>
+        <DropdownMenuItem onClick={onLoadFromServer}>Continue from server queue</DropdownMenuItem>
       </DropdownMenuContent>
     </DropdownMenu>
   )
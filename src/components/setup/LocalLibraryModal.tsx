@@ -0,0 +1,94 @@
+import { zodResolver } from "@hookform/resolvers/zod"
+import { useForm } from "react-hook-form"
+import { z } from "zod"
+import { Form, FormControl, FormDescription, FormField, FormItem, FormLabel, FormMessage } from '@/components/ui/form';
+import { Input } from '@/components/ui/input';
+import { DialogFooter } from '@/components/ui/dialog';
+import { Button } from '@/components/ui/button';
+import { useState } from 'react';
+import { LocalLibraryConfig, Library } from '@/types/Config';
+import { invoke } from '@tauri-apps/api/core';
+
+const LocalLibrarySchema = z.object({
+  name: z.string().min(1),
+  //One folder per line
+  paths: z.string().min(1),
+})
+
+interface LocalLibraryModalProps {
+  libraries: Library[]
+  onClose: () => void
+  onLibraryAdded: (library: Library) => void
+}
+
+export default function LocalLibraryModal({ libraries, onClose, onLibraryAdded }: LocalLibraryModalProps) {
+  const [isLoading, setIsLoading] = useState(false)
+
+  const form = useForm<z.infer<typeof LocalLibrarySchema>>({
+    resolver: zodResolver(LocalLibrarySchema),
+    defaultValues: {
+      name: '',
+      paths: '',
+    },
+  })
+
+  function onSubmit(values: z.infer<typeof LocalLibrarySchema>) {
+    //Ensure id is unique
+    if (libraries.some(l => l.id === values.name)) {
+      form.setError('name', { type: "focus", message: 'A library with this name already exists' }, { shouldFocus: true })
+      return
+    }
+    setIsLoading(true)
+
+    let libraryConfig: LocalLibraryConfig = {
+      id: values.name.replaceAll(/\s/g, '_'),
+      name: values.name,
+      paths: values.paths.split('\n').map(path => path.trim()).filter(path => path.length > 0),
+    }
+    invoke('add_local_library', { library: libraryConfig })
+      .then((res) => {
+        onLibraryAdded(res as Library)
+        setIsLoading(false)
+      }).catch((e) => {
+        form.setError('paths', { type: "focus", message: e?.message ?? 'Failed to add the library' }, { shouldFocus: true })
+        setIsLoading(false)
+      })
+  }
+
+  return (
+    <Form {...form}>
+      <form className={`dark:text-slate-50 flex flex-col gap-2`} onSubmit={form.handleSubmit(onSubmit)}>
+        <FormField control={form.control} name="name" render={({ field }) => (
+          <FormItem>
+            <FormLabel>Name</FormLabel>
+            <FormControl>
+              <Input {...field} />
+            </FormControl>
+            <FormDescription>
+              How you would like Polyphonic to refer to this library as.
+            </FormDescription>
+            <FormMessage />
+          </FormItem>
+        )} />
+        <FormField control={form.control} name="paths" render={({ field }) => (
+          <FormItem>
+            <FormLabel>Folders</FormLabel>
+            <FormControl>
+              <textarea className={`min-h-24 rounded-md border bg-transparent px-3 py-2 text-sm`} {...field} />
+            </FormControl>
+            <FormDescription>
+              The folders containing your music, one per line. Subfolders are included.
+            </FormDescription>
+            <FormMessage />
+          </FormItem>
+        )} />
+        <DialogFooter className={`mt-4 flex flex-row items-center`}>
+          <div className={`ml-auto flex flex-row`}>
+            <Button disabled={isLoading} variant={'outline'} className={`mr-2`} onClick={onClose}>Cancel</Button>
+            <Button disabled={isLoading} className={`ml-auto`} type="submit">Confirm</Button>
+          </div>
+        </DialogFooter>
+      </form>
+    </Form>
+  )
+}
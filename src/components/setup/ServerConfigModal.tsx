@@ -11,6 +11,7 @@ import { LibraryConfig, Library } from '@/types/Config';
 import { invoke } from '@tauri-apps/api/core';
 
 const ServerConfigSchema = z.object({
+  kind: z.enum(['subsonic', 'jellyfin', 'ampache']),
   name: z.string(),
   host: z.string().url(),
   port: z.number().optional(),
@@ -30,6 +31,7 @@ export default function ServerConfigModal({ libraries, onClose, onConnectionSucc
   const form = useForm<z.infer<typeof ServerConfigSchema>>({
     resolver: zodResolver(ServerConfigSchema),
     defaultValues: {
+      kind: 'subsonic',
       name: '',
       host: '',
       port: undefined,
@@ -54,6 +56,7 @@ export default function ServerConfigModal({ libraries, onClose, onConnectionSucc
       port: values.port,
       username: values.username,
       password: values.password,
+      kind: values.kind,
     }
     invoke('add_server', { library: libraryConfig })
       .then((res) => {
@@ -61,8 +64,12 @@ export default function ServerConfigModal({ libraries, onClose, onConnectionSucc
         let newLibrary: Library = res as Library
         onConnectionSuccess(newLibrary)
         setIsLoading(false)
-      }).catch(() => {
-        form.setError('root', { type: "connectionError" })
+      }).catch((e) => {
+        if (e?.kind === 'wrong_credentials') {
+          form.setError('password', { type: "focus", message: 'Incorrect username or password' }, { shouldFocus: true })
+        } else {
+          form.setError('root', { type: "connectionError" })
+        }
         setIsLoading(false)
       })
   }
@@ -71,6 +78,19 @@ export default function ServerConfigModal({ libraries, onClose, onConnectionSucc
     <Form {...form}>
       <form className={`dark:text-slate-50 flex flex-col gap-2`} onSubmit={form.handleSubmit(onSubmit)}>
         {form.formState.errors.root && <p className={`text-red-500`}>Failed to connect to the server. Please check your connection details and try again.</p>}
+        <FormField control={form.control} name="kind" render={({ field }) => (
+          <FormItem>
+            <FormLabel>Server Type</FormLabel>
+            <FormControl>
+              <select className={`h-9 rounded-md border bg-transparent px-3 text-sm`} {...field}>
+                <option value="subsonic">Subsonic / OpenSubsonic</option>
+                <option value="jellyfin">Jellyfin</option>
+                <option value="ampache">Ampache</option>
+              </select>
+            </FormControl>
+            <FormMessage />
+          </FormItem>
+        )} />
         <FormField control={form.control} name="name" render={({ field }) => (
           <FormItem>
             <FormLabel>Name</FormLabel>
@@ -1,7 +1,8 @@
 import { Button } from '@/components/ui/button';
 import { Dialog, DialogContent, DialogTrigger } from '@/components/ui/dialog';
-import { FaCloud, FaTrash } from "react-icons/fa";
+import { FaCloud, FaFolder, FaTrash } from "react-icons/fa";
 import ServerConfigModal from '@/components/setup/ServerConfigModal';
+import LocalLibraryModal from '@/components/setup/LocalLibraryModal';
 import { useState } from 'react';
 import { Library } from '@/types/Config';
 
@@ -14,11 +15,13 @@ interface SetupCollectionProps {
 
 export default function SetupCollection({ configLibraries, setConfigLibraries, onNext, onPrevious }: SetupCollectionProps) {
   const [open, setOpen] = useState(false)
+  const [localOpen, setLocalOpen] = useState(false)
   const [libraries, setLibraries] = useState<Library[]>(configLibraries || [])
 
   function addLibrary(library: Library) {
     setLibraries([...libraries, library])
     setOpen(false)
+    setLocalOpen(false)
   }
 
   function removeLibrary(library: Library) {
@@ -42,7 +45,7 @@ export default function SetupCollection({ configLibraries, setConfigLibraries, o
             {libraries.map((library, index) => (
               <div key={index} className={`w-full px-2 py-3 flex flex-row`}>
                 <div className={`flex flex-row items-center gap-3`}>
-                  <FaCloud className={`h-6 w-6`} />
+                  {library.kind === 'local' ? <FaFolder className={`h-6 w-6`} /> : <FaCloud className={`h-6 w-6`} />}
                   <span>{library.name}</span>
                 </div>
                 <button className={`ml-auto`} onClick={() => removeLibrary(library)}>
@@ -68,6 +71,20 @@ export default function SetupCollection({ configLibraries, setConfigLibraries, o
           <ServerConfigModal libraries={libraries} onClose={() => setOpen(false)} onConnectionSuccess={addLibrary} />
         </DialogContent>
       </Dialog>
+      <Dialog open={localOpen} onOpenChange={setLocalOpen}>
+        <DialogTrigger asChild>
+          <Button variant={'outline'} className={`flex flex-row mt-4 h-20 text-left`}>
+            <FaFolder className={`mr-2`} size={70} />
+            <div className={`flex flex-col`}>
+              <span>Add a Local Library</span>
+              <span className={`mt-2`}>Play music from folders on this computer</span>
+            </div>
+          </Button>
+        </DialogTrigger>
+        <DialogContent>
+          <LocalLibraryModal libraries={libraries} onClose={() => setLocalOpen(false)} onLibraryAdded={addLibrary} />
+        </DialogContent>
+      </Dialog>
       <Button disabled={libraries.length === 0} className={`mt-4 w-32`} onClick={nextClicked}>Next</Button>
       <span className={`underline cursor-pointer mt-2`} onClick={onPrevious}>{`< Back`}</span>
     </>
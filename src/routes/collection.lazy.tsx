@@ -10,6 +10,7 @@ import { Album, ListInfo, ListView, Playlist, Song } from '@/types/Music';
 import { library_modified } from '@/util/subsonic';
 import { createLazyFileRoute } from '@tanstack/react-router'
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import { useEffect, useState } from 'react';
 import { FaGear } from "react-icons/fa6";
 
@@ -122,6 +123,22 @@ function Collection() {
     }
   }, [pendingAlbumId, artistAlbums])
 
+  function refreshCollection() {
+    queryClient.invalidateQueries({ queryKey: [QUERY_ARTIST_ALBUMS] })
+    queryClient.invalidateQueries({ queryKey: [QUERY_KEY_ARTISTS] })
+    queryClient.invalidateQueries({ queryKey: [QUERY_KEY_MOST_RECENTLY_ADDED] })
+    queryClient.invalidateQueries({ queryKey: [QUERY_KEY_MOST_RECENTLY_PLAYED] })
+    queryClient.invalidateQueries({ queryKey: [QUERY_KEY_TAGGED_ALBUMS, currentTagId] })
+  }
+
+  //Local libraries are rescanned in the background as their files change
+  useEffect(() => {
+    const unlisten = listen('library-changed', () => refreshCollection())
+    return () => {
+      unlisten.then((f) => f())
+    }
+  }, [currentTagId])
+
   useEffect(() => {
     async function syncLibraries() {
       //Check if we need to sync
@@ -129,13 +146,15 @@ function Collection() {
         const library_data = Array.from(libraries.values())
         if(await library_modified(library_data) === true) {
           setIsScanning(true)
-          await invoke('sync_collection', { libraries: library_data })
-            .then(() => {
-              queryClient.invalidateQueries({ queryKey: [QUERY_ARTIST_ALBUMS] })
-              queryClient.invalidateQueries({ queryKey: [QUERY_KEY_ARTISTS] })
-              queryClient.invalidateQueries({ queryKey: [QUERY_KEY_MOST_RECENTLY_ADDED] })
-              queryClient.invalidateQueries({ queryKey: [QUERY_KEY_MOST_RECENTLY_PLAYED] })
-              queryClient.invalidateQueries({ queryKey: [QUERY_KEY_TAGGED_ALBUMS, currentTagId] })
+          await invoke<any[]>('sync_collection', { libraries: library_data })
+            .then((results) => {
+              //Each library reports its own failure, without stopping the others
+              for (const result of results) {
+                if (result.error) {
+                  console.log("==Error: ", result.library_id, result.error)
+                }
+              }
+              refreshCollection()
               setIsScanning(false)
             }).catch((e) => {
               console.log("==Error: ", e)
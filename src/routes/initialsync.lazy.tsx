@@ -15,8 +15,13 @@ function InitialSync() {
     async function sync() {
       let store = await load('config.json', { autoSave: false });
       const libraries: any = await store.get('libraries');
-      await invoke('sync_collection', { libraries: libraries.value })
-        .then(() => {
+      await invoke<any[]>('sync_collection', { libraries: libraries.value })
+        .then((results) => {
+          for (const result of results) {
+            if (result.error) {
+              console.log("Failed to sync", result.library_id, result.error)
+            }
+          }
           console.log("Synced")
           navigate({ to: '/collection' })
         }).catch(() => {
@@ -13,8 +13,18 @@ export interface LibraryConfig {
   port?: number;
   username: string;
   password: string;
+  //Subsonic if not given
+  kind?: LibraryKind;
 }
 
+export interface LocalLibraryConfig {
+  id: string;
+  name: string;
+  paths: string[];
+}
+
+export type LibraryKind = 'subsonic' | 'local' | 'jellyfin' | 'ampache';
+
 export interface Library {
   id: string;
   name: string;
@@ -24,6 +34,11 @@ export interface Library {
   hashed_password?: string;
   salt: string;
   last_scanned?: string;
+  kind?: LibraryKind;
+  //Folders of a local library
+  paths?: string[];
+  //User a Jellyfin library logged in as
+  user_id?: string;
 }
 
 export enum SortType {
@@ -1,6 +1,8 @@
 type Artist = {
   id: string;
   name: string;
+  starred?: string | null;
+  rating?: number;
 }
 
 type Album = {
@@ -11,6 +13,8 @@ type Album = {
   cover_art: string;
   year: number;
   duration: number;
+  starred?: string | null;
+  rating?: number;
 }
 
 type Song = {
@@ -27,6 +31,8 @@ type Song = {
   path: string;
   content_type: string;
   cover_art: string;
+  starred?: string | null;
+  rating?: number;
 }
 
 type Playlist = {
@@ -61,7 +67,9 @@ type ListInfo = {
 
 type ListView = 'artist' | 'playlist' | 'tag'
 
-export type { Artist, Album, Song, Playlist, ListInfo, ListView, Tag, AlbumTag }
+type RatingTarget = 'song' | 'album' | 'artist'
+
+export type { Artist, Album, Song, Playlist, ListInfo, ListView, Tag, AlbumTag, RatingTarget }
 
 export function song_sort(a: Song, b: Song) {
   if (a.disc_number !== b.disc_number) {
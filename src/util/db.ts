@@ -24,6 +24,7 @@ export async function getAlbumsForArtist(artistId: string) {
     "SELECT id, name, artist_id, artist_name, cover_art, year, duration FROM albums WHERE artist_id = ? ORDER BY year DESC",
     [artistId]
   );
+  touchCoverArt(albums);
   for (let i = 0; i < albums.length; i++) {
     const filePath = `${appDataDirPath}/cover_art/${albums[i].cover_art}`;
     const assetUrl = convertFileSrc(filePath);
@@ -60,6 +61,7 @@ export async function getAlbumsById(albumIds: string[]) {
     albumIdsWithQuotes.join(",") +
     ")";
   const albums = await db.select<Album[]>(albumQuery);
+  touchCoverArt(albums);
   for (let i = 0; i < albums.length; i++) {
     const filePath = `${appDataDirPath}/cover_art/${albums[i].cover_art}`;
     const assetUrl = convertFileSrc(filePath);
@@ -102,6 +104,7 @@ export async function getAlbumsByTag(tagId: string) {
     "SELECT id, name, artist_id, artist_name, cover_art, year, duration FROM albums WHERE id IN (SELECT album_id FROM album_tags WHERE tag_id = ?) ORDER BY name ASC",
     [tagId]
   );
+  touchCoverArt(albums);
   for (let i = 0; i < albums.length; i++) {
     const filePath = `${appDataDirPath}/cover_art/${albums[i].cover_art}`;
     const assetUrl = convertFileSrc(filePath);
@@ -136,6 +139,269 @@ export async function getSongsFromPlaylist(library: Library, playlist_id: string
   return songs
 }
 
+//Favourites view: everything starred, most recently starred first
+export async function getFavouriteAlbums() {
+  const appDataDirPath = await appDataDir();
+  const db = await getDb();
+  const albums = await db.select<Album[]>(
+    "SELECT id, name, artist_id, artist_name, cover_art, year, duration, starred, rating FROM albums WHERE starred IS NOT NULL ORDER BY starred DESC"
+  );
+  touchCoverArt(albums);
+  for (let i = 0; i < albums.length; i++) {
+    const filePath = `${appDataDirPath}/cover_art/${albums[i].cover_art}`;
+    const assetUrl = convertFileSrc(filePath);
+    albums[i].cover_art = assetUrl;
+  }
+  return albums;
+}
+
+export async function getFavouriteSongs() {
+  const appDataDirPath = await appDataDir();
+  const db = await getDb();
+  const songs = await db.select<Song[]>(
+    "SELECT id, library_id, title, artist_id, artist_name, album_id, album_name, track, disc_number, duration, content_type, cover_art, starred, rating FROM songs WHERE starred IS NOT NULL ORDER BY starred DESC"
+  );
+  for (let i = 0; i < songs.length; i++) {
+    const filePath = `${appDataDirPath}/cover_art/${songs[i].cover_art}`;
+    const assetUrl = convertFileSrc(filePath);
+    songs[i].cover_art = assetUrl;
+  }
+  return songs;
+}
+
+//Refresh starred state from the server (getStarred2) before showing favourites
+export async function refreshFavourites(libraries: Library[]) {
+  for (let library of libraries) {
+    try {
+      await invoke('get_favourites', { library: library })
+    } catch (e) {
+      console.log(e)
+    }
+  }
+}
+
+export type SearchResults = {
+  source: 'local' | 'server';
+  artists: string[];
+  albums: string[];
+  songs: string[];
+}
+
+//Search a library (local index, or the server when the index is out of date). Returns IDs
+export async function searchLibrary(library: Library, query: string) {
+  return await invoke('search_library', { library: library, query: query }) as SearchResults
+}
+
+// Fetches art again after the cache evicted it. Takes the asset URL an image failed to load, and returns the URL of the fetched art (null if there is none).
+export async function fetchCoverArt(src: string): Promise<string | null> {
+  const coverArtDir = `${await appDataDir()}/cover_art/`;
+  const prefix = convertFileSrc(coverArtDir);
+  if (!src.startsWith(prefix) || src.length === prefix.length) {
+    return null;
+  }
+  const coverArt = decodeURIComponent(src.slice(prefix.length));
+  const fileName = await invoke('fetch_cover_art', { coverArt: coverArt }) as string | null;
+  return fileName ? convertFileSrc(`${coverArtDir}${fileName}`) : null;
+}
+
+// Marks art as recently used so the cache evicts it last. Fire and forget, a failure only affects eviction order.
+function touchCoverArt(albums: Album[]) {
+  const keys = albums.map((album) => album.cover_art).filter((key) => key);
+  if (keys.length > 0) {
+    invoke('touch_cover_art', { keys: keys }).catch((e) => console.log(e));
+  }
+}
+
+export type CacheStats = {
+  library_id: string,
+  art_bytes: number,
+  art_files: number,
+  audio_bytes: number,
+  audio_files: number,
+  download_bytes: number,
+  download_files: number,
+}
+
+export type CacheSettings = {
+  art_quota_bytes: number,
+  audio_quota_bytes: number,
+}
+
+export async function getCacheStats() {
+  return await invoke('get_cache_stats') as CacheStats[]
+}
+
+export async function getCacheSettings() {
+  return await invoke('get_cache_settings') as CacheSettings
+}
+
+export async function setCacheSettings(settings: CacheSettings) {
+  return await invoke('set_cache_settings', { settings: settings })
+}
+
+export type NetworkProfile = "unmetered" | "metered"
+
+export type TranscodeSettings = {
+  format: string | null,
+  max_bit_rate: number | null,
+  estimate_content_length: boolean,
+}
+
+export async function getNetworkProfile() {
+  return await invoke('get_network_profile') as NetworkProfile
+}
+
+export async function setNetworkProfile(profile: NetworkProfile) {
+  return await invoke('set_network_profile', { profile: profile })
+}
+
+export async function getTranscodeSettings(libraryId: string, profile: NetworkProfile) {
+  return await invoke('get_transcode_settings', { libraryId: libraryId, profile: profile }) as TranscodeSettings
+}
+
+export async function setTranscodeSettings(libraryId: string, profile: NetworkProfile, settings: TranscodeSettings) {
+  return await invoke('set_transcode_settings', { libraryId: libraryId, profile: profile, settings: settings })
+}
+
+export type PlaybackSettings = {
+  crossfade_seconds: number,
+  crossfade_curve: "linear" | "equal_power" | "s_curve",
+  replay_gain_mode: "off" | "track" | "album",
+  replay_gain_preamp_db: number,
+  prevent_clipping: boolean,
+}
+
+export async function getPlaybackSettings() {
+  return await invoke('get_playback_settings') as PlaybackSettings
+}
+
+export async function setPlaybackSettings(settings: PlaybackSettings) {
+  return await invoke('set_playback_settings', { settings: settings })
+}
+
+export type EqBand = {
+  filter: "peaking" | "low_shelf" | "high_shelf" | "low_pass" | "high_pass",
+  frequency: number,
+  gain_db: number,
+  q: number,
+}
+
+export type DspSettings = {
+  preamp_db: number,
+  bands: EqBand[],
+  mono: boolean,
+  balance: number,
+}
+
+export type DspPreset = {
+  id: number | null,
+  name: string,
+  library_id: string | null,
+  device: string | null,
+  settings: DspSettings,
+}
+
+//Centre frequencies of a 10-band graphic EQ, an octave apart
+export const GRAPHIC_EQ_FREQUENCIES = [31, 62, 125, 250, 500, 1000, 2000, 4000, 8000, 16000];
+
+export function graphicEqBands(gains: number[]): EqBand[] {
+  return GRAPHIC_EQ_FREQUENCIES.map((frequency, i) => ({ filter: "peaking", frequency: frequency, gain_db: gains[i] ?? 0, q: 1.41 }));
+}
+
+export async function getDspPresets() {
+  return await invoke('get_dsp_presets') as DspPreset[]
+}
+
+export async function saveDspPreset(preset: DspPreset) {
+  return await invoke('save_dsp_preset', { preset: preset }) as number
+}
+
+export async function deleteDspPreset(id: number) {
+  return await invoke('delete_dsp_preset', { id: id })
+}
+
+export async function getActiveDspPreset() {
+  return await invoke('get_active_dsp_preset') as number | null
+}
+
+export async function setActiveDspPreset(id: number | null) {
+  return await invoke('set_active_dsp_preset', { id: id })
+}
+
+export async function getDspResponse(settings: DspSettings, frequencies: number[]) {
+  return await invoke('get_dsp_response', { settings: settings, frequencies: frequencies }) as number[]
+}
+
+export async function getOutputDevices() {
+  return await invoke('get_output_devices') as string[]
+}
+
+export type PlaybackItem = {
+  library_id: string,
+  song_id: string,
+}
+
+export type SavedQueue = {
+  items: PlaybackItem[],
+  index: number | null,
+  position: number,
+  changed: number,
+}
+
+//Songs of a saved queue, in order. Songs no longer in the library are left out.
+export async function getQueueSongs(saved: SavedQueue) {
+  const appDataDirPath = await appDataDir();
+  const ids = [...new Set(saved.items.map((item) => item.song_id))];
+  if (ids.length === 0) {
+    return { songs: [] as Song[], index: undefined, position: 0 };
+  }
+  const db = await getDb();
+  const found = await db.select<Song[]>(
+    "SELECT id, library_id, title, artist_id, artist_name, album_id, album_name, track, disc_number, duration, content_type, cover_art FROM songs WHERE id IN (" + ids.map(() => "?").join(",") + ")",
+    ids
+  );
+  const byId = new Map(found.map((song) => [song.id, song]));
+  let songs: Song[] = [];
+  let index: number | undefined = undefined;
+  saved.items.forEach((item, i) => {
+    const song = byId.get(item.song_id);
+    if (song === undefined) return;
+    if (i === saved.index) index = songs.length;
+    //A copy per entry, so repeats of a song are separate queue entries
+    songs.push({ ...song, cover_art: convertFileSrc(`${appDataDirPath}/cover_art/${song.cover_art}`) });
+  });
+  return { songs: songs, index: index, position: index === undefined ? 0 : saved.position };
+}
+
+export async function getSavedQueue() {
+  return await invoke('get_saved_queue') as SavedQueue
+}
+
+export async function saveServerPlayQueue(library: Library) {
+  return await invoke('save_server_play_queue', { library: library })
+}
+
+export async function getServerPlayQueue(library: Library) {
+  return await invoke('get_server_play_queue', { library: library }) as SavedQueue | null
+}
+
+export async function createPlaylist(library: Library, name: string, songIds: string[]) {
+  return await invoke('create_playlist', { library: library, name: name, songIds: songIds }) as Playlist
+}
+
+//Removal indexes are positions before the update, added songs go on the end
+export async function updatePlaylist(library: Library, playlistId: string, name: string | null, songIdsToAdd: string[], songIndexesToRemove: number[]) {
+  return await invoke('update_playlist', { library: library, playlistId: playlistId, name: name, songIdsToAdd: songIdsToAdd, songIndexesToRemove: songIndexesToRemove }) as Playlist
+}
+
+export async function reorderPlaylist(library: Library, playlistId: string, songIds: string[]) {
+  return await invoke('reorder_playlist', { library: library, playlistId: playlistId, songIds: songIds }) as Playlist
+}
+
+export async function deletePlaylist(library: Library, playlistId: string) {
+  return await invoke('delete_playlist', { library: library, playlistId: playlistId })
+}
+
 export async function createTags(tags: string[]) {
   const db = await getDb();
   for(let tag of tags) {
@@ -163,7 +429,9 @@ export async function resyncCollection() {
   await db.execute("DELETE FROM albums");
   await db.execute("DELETE FROM artists");
   await db.execute("DELETE FROM playlists");
+  await db.execute("DELETE FROM playlist_songs");
   await db.execute("DELETE FROM songs");
+  await db.execute("DELETE FROM local_files");
   await db.execute("DELETE FROM tags");
   await db.execute("DELETE FROM album_tags");
 }
\ No newline at end of file
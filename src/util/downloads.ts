@@ -0,0 +1,36 @@
+import { invoke } from "@tauri-apps/api/core";
+import { Library } from '@/types/Config';
+
+type DownloadPin = {
+  id: string;
+  library_id: string;
+  kind: 'album' | 'playlist';
+  created_at: number;
+}
+
+//Payload of the "download-progress" event
+type DownloadProgress = {
+  pin_id: string;
+  done: number;
+  total: number;
+  failed: number;
+}
+
+export type { DownloadPin, DownloadProgress }
+
+//Pinned albums/playlists are downloaded and played from disk from then on
+export async function pinAlbum(library: Library, albumId: string) {
+  return await invoke('pin_album', { library: library, albumId: albumId }) as number
+}
+
+export async function pinPlaylist(library: Library, playlistId: string) {
+  return await invoke('pin_playlist', { library: library, playlistId: playlistId }) as number
+}
+
+export async function unpin(pinId: string) {
+  return await invoke('unpin', { pinId: pinId }) as boolean
+}
+
+export async function getDownloadPins() {
+  return await invoke('get_download_pins') as DownloadPin[]
+}